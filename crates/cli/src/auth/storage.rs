@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use butterflow_core::config::RetentionPolicy;
 use butterflow_core::registry::RegistryConfig;
 use dirs::config_dir;
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,52 @@ use crate::auth::types::{AuthTokens, UserInfo};
 pub struct Config {
     pub default_registry: String,
     pub registries: HashMap<String, RegistryAuthConfig>,
+    /// Stored workflow runs to delete whenever the CLI starts an engine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    /// Where workflow runs and their state are stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateConfig>,
+}
+
+/// Storage for workflow runs, tasks and state
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateBackend {
+    /// JSON files in the local data directory
+    #[default]
+    Local,
+    /// A SQLite database in the local data directory
+    Sqlite,
+    /// A remote API, e.g. a self-hosted dashboard
+    Cloud,
+}
+
+impl StateBackend {
+    /// Name used on the command line, in the config and in
+    /// `BUTTERFLOW_STATE_BACKEND`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StateBackend::Local => "local",
+            StateBackend::Sqlite => "sqlite",
+            StateBackend::Cloud => "cloud",
+        }
+    }
+}
+
+/// State backend settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateConfig {
+    /// Backend to use when neither `--state-backend` nor
+    /// `BUTTERFLOW_STATE_BACKEND` is set
+    #[serde(default)]
+    pub backend: StateBackend,
+    /// API endpoint of the cloud backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Database file of the SQLite backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sqlite_path: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -39,6 +86,8 @@ impl Default for Config {
         Self {
             default_registry: registry_url.to_string(),
             registries,
+            retention: None,
+            state: None,
         }
     }
 }
@@ -1,13 +1,18 @@
-use anyhow::Result;
-use butterflow_core::execution::CodemodExecutionConfig;
+use anyhow::{Context, Result};
+use ast_grep_language::SupportLang;
+use butterflow_core::{
+    emitted_state::EmittedState, execution::CodemodExecutionConfig, file_ops::FileChanges, utils,
+};
 use clap::Args;
 use codemod_sandbox::sandbox::{
-    engine::execute_codemod_with_quickjs, filesystem::RealFileSystem, resolvers::OxcResolver,
+    engine::{ExecutionStats, RuntimePool, TransformOptions},
+    resolvers::OxcResolver,
 };
 use log::{debug, error, info, warn};
 use std::{
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, Mutex},
     time::Instant,
 };
 
@@ -39,6 +44,10 @@ pub struct Command {
     /// Allow dirty git status
     #[arg(long)]
     pub allow_dirty: bool,
+
+    /// Parameters passed to the codemod's transform (format: key=value)
+    #[arg(long = "param", value_name = "KEY=VALUE")]
+    pub params: Vec<String>,
 }
 
 pub async fn handler(args: &Command) -> Result<()> {
@@ -60,7 +69,6 @@ pub async fn handler(args: &Command) -> Result<()> {
     }
 
     // Set up the new modular system with OxcResolver
-    let filesystem = Arc::new(RealFileSystem::new());
     let script_base_dir = js_file_path
         .parent()
         .unwrap_or(Path::new("."))
@@ -70,6 +78,21 @@ pub async fn handler(args: &Command) -> Result<()> {
 
     let resolver = Arc::new(OxcResolver::new(script_base_dir.clone(), tsconfig_path)?);
 
+    let language: SupportLang = args
+        .language
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid language: {}", args.language))?;
+
+    let options = TransformOptions {
+        params: utils::parse_params(&args.params).context("Failed to parse parameters")?,
+        ..TransformOptions::default()
+    };
+
+    // Each worker thread loads the codemod once and reuses it for its files
+    let runtime_pool =
+        RuntimePool::new(js_file_path, resolver, options).with_project(&target_directory);
+    let runtime_handle = tokio::runtime::Handle::current();
+
     let config = CodemodExecutionConfig {
         pre_run_callback: None,
         progress_callback: Arc::new(Some(create_progress_callback())),
@@ -82,6 +105,9 @@ pub async fn handler(args: &Command) -> Result<()> {
     };
 
     let started = Instant::now();
+    let emitted = Mutex::new(EmittedState::new());
+    let changes = FileChanges::new(&target_directory, args.dry_run)
+        .context("Failed to set up the undo log")?;
 
     let _ = config.execute(|file_path, _config| {
         // Only process files
@@ -91,9 +117,8 @@ pub async fn handler(args: &Command) -> Result<()> {
 
         info!("Processing file with JS AST grep: {}", file_path.display());
 
-        // Use a tokio runtime to handle the async execution within the sync callback
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
+        // Use the captured runtime handle to run the async execution within the sync callback
+        runtime_handle.block_on(async {
             // Read file content
             let content = match tokio::fs::read_to_string(&file_path).await {
                 Ok(content) => content,
@@ -104,35 +129,17 @@ pub async fn handler(args: &Command) -> Result<()> {
             };
 
             // Execute the codemod on this file
-            match execute_codemod_with_quickjs(
-                js_file_path,
-                filesystem.clone(),
-                resolver.clone(),
-                args.language
-                    .clone()
-                    .parse()
-                    .unwrap_or_else(|_| panic!("Invalid language: {}", args.language)),
-                file_path,
-                &content,
-            )
-            .await
-            {
+            match runtime_pool.execute(language, file_path, &content).await {
                 Ok(execution_output) => {
-                    // Handle the execution output (write back if modified and not dry run)
-                    if let Some(ref new_content) = execution_output.content {
-                        if new_content != &content {
-                            if !config.dry_run {
-                                if let Err(e) = tokio::fs::write(&file_path, new_content).await {
-                                    error!(
-                                        "Failed to write modified file {}: {}",
-                                        file_path.display(),
-                                        e
-                                    );
-                                } else {
-                                    debug!("Modified file: {}", file_path.display());
-                                }
-                            } else if config.dry_run {
-                                debug!("Would modify file (dry run): {}", file_path.display());
+                    // The file operations are only requested if the new
+                    // content could be written
+                    let mut written = true;
+                    if let Some(new_content) = execution_output.content {
+                        if new_content != content {
+                            debug!("Modifying file: {}", file_path.display());
+                            if let Err(e) = changes.rewrite(file_path, &new_content) {
+                                warn!("Failed to write {}: {e}", file_path.display());
+                                written = false;
                             }
                         }
                     }
@@ -145,6 +152,13 @@ pub async fn handler(args: &Command) -> Result<()> {
                             error_msg
                         );
                     }
+
+                    if !execution_output.state_writes.is_empty() {
+                        emitted.lock().unwrap().add(execution_output.state_writes);
+                    }
+                    if written && !execution_output.file_operations.is_empty() {
+                        changes.request(file_path, execution_output.file_operations);
+                    }
                 }
                 Err(e) => {
                     error!(
@@ -157,8 +171,38 @@ pub async fn handler(args: &Command) -> Result<()> {
         });
     });
 
+    // File operations wait until all files were processed, so the walk
+    // doesn't pick up files they create
+    let stats = ExecutionStats::new();
+    let conflicts = changes
+        .finish(&stats)
+        .context("Failed to apply file operations")?;
+    for conflict in &conflicts {
+        println!("⚠️  {conflict}");
+    }
+    println!(
+        "📁 {} files modified, {} created, {} renamed, {} deleted",
+        stats.files_modified.load(Ordering::Relaxed),
+        stats.files_created.load(Ordering::Relaxed),
+        stats.files_renamed.load(Ordering::Relaxed),
+        stats.files_deleted.load(Ordering::Relaxed)
+    );
+
     let seconds = started.elapsed().as_millis() as f64 / 1000.0;
     println!("✨ Done in {seconds:.3}s");
 
+    // Outside of a workflow there's no state to write to, show it instead
+    let emitted = emitted.into_inner().unwrap();
+    if !emitted.is_empty() {
+        let fields = emitted
+            .to_fields(&HashMap::new(), &HashMap::new())
+            .context("Failed to combine state writes")?;
+        let state: BTreeMap<_, _> = fields
+            .into_iter()
+            .filter_map(|(key, field)| Some((key, field.value?)))
+            .collect();
+        println!("Emitted state:\n{}", serde_json::to_string_pretty(&state)?);
+    }
+
     Ok(())
 }
@@ -13,7 +13,9 @@ use crate::commands::jssg::testing::{
 };
 use ast_grep_language::SupportLang;
 use codemod_sandbox::sandbox::{
-    engine::execute_codemod_with_quickjs, errors::ExecutionError, filesystem::RealFileSystem,
+    engine::{execute_codemod_with_quickjs, TransformOptions},
+    errors::ExecutionError,
+    filesystem::RealFileSystem,
     resolvers::OxcResolver,
 };
 
@@ -272,6 +274,7 @@ impl TestRunner {
                 language,
                 &input_file.path,
                 &input_file.content,
+                &TransformOptions::default(),
             )
             .await
             .map_err(|e| anyhow::anyhow!("{}", Self::format_execution_error(&e)))?;
@@ -340,6 +343,7 @@ impl TestRunner {
                 language,
                 &input_file.path,
                 &input_file.content,
+                &TransformOptions::default(),
             )
             .await
             .map_err(|e| anyhow::anyhow!("{}", Self::format_execution_error(&e)))?;
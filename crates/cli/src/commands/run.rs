@@ -5,11 +5,12 @@ use console::style;
 use log::info;
 use rand::Rng;
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::process::Command as ProcessCommand;
 use std::sync::atomic::Ordering;
 
-use crate::engine::{create_engine, create_registry_client};
+use crate::engine::{apply_retention_policy, create_engine, create_registry_client};
 use crate::progress_bar::download_progress_bar;
 use crate::workflow_runner::run_workflow;
 use butterflow_core::registry::RegistryError;
@@ -44,6 +45,10 @@ pub struct Command {
     /// Optional target path to run the codemod on (default: current directory)
     #[arg(long = "target", short = 't')]
     target_path: Option<PathBuf>,
+
+    /// Maximum number of tasks to run at the same time
+    #[arg(long, value_name = "N")]
+    max_concurrency: Option<NonZeroUsize>,
 }
 
 pub async fn handler(args: &Command, telemetry: &dyn TelemetrySender) -> Result<()> {
@@ -111,7 +116,12 @@ pub async fn handler(args: &Command, telemetry: &dyn TelemetrySender) -> Result<
         args.allow_dirty,
         params,
         args.registry.clone(),
-    )?;
+        args.max_concurrency.map(NonZeroUsize::get),
+    )
+    .await?;
+    if !args.dry_run {
+        apply_retention_policy(&engine).await;
+    }
 
     run_workflow(&engine, config).await?;
 
@@ -142,6 +152,16 @@ pub async fn handler(args: &Command, telemetry: &dyn TelemetrySender) -> Result<
     println!("\n📝 Modified files: {files_modified}");
     println!("✅ Unmodified files: {files_unmodified}");
     println!("❌ Files with errors: {files_with_errors}");
+    for (label, count) in [
+        ("🆕 Created files", &stats.files_created),
+        ("🔀 Renamed files", &stats.files_renamed),
+        ("🗑️ Deleted files", &stats.files_deleted),
+    ] {
+        let count = count.load(Ordering::Relaxed);
+        if count > 0 {
+            println!("{label}: {count}");
+        }
+    }
 
     let cli_version = env!("CARGO_PKG_VERSION");
     let execution_id: [u8; 20] = rand::thread_rng().gen();
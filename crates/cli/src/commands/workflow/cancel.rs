@@ -20,7 +20,9 @@ pub async fn handler(args: &Command) -> Result<()> {
         Default::default(),
         Default::default(),
         None,
-    )?;
+        None,
+    )
+    .await?;
 
     println!("Canceling workflow run {}...", args.id);
 
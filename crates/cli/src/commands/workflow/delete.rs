@@ -0,0 +1,35 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use uuid::Uuid;
+
+use crate::engine::create_engine;
+
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Workflow run ID
+    #[arg(short, long)]
+    id: Uuid,
+}
+
+/// Delete a workflow run
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    engine
+        .delete_workflow_run(args.id)
+        .await
+        .context("Failed to delete workflow run")?;
+
+    println!("✅ Workflow run {} deleted", args.id);
+
+    Ok(())
+}
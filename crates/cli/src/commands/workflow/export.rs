@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::engine::create_engine;
+
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Workflow run ID
+    #[arg(short, long)]
+    id: Uuid,
+
+    /// Path of the archive to write
+    #[arg(short, long, default_value = "run.tar.gz")]
+    output: PathBuf,
+}
+
+/// Export a workflow run to an archive
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    engine
+        .export_workflow_run(args.id, &args.output)
+        .await
+        .context("Failed to export workflow run")?;
+
+    println!(
+        "✅ Workflow run {} exported to {}",
+        args.id,
+        args.output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use butterflow_core::utils::get_imported_bundles_dir;
+use clap::Args;
+use std::path::PathBuf;
+
+use crate::engine::create_engine;
+
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Archive written by `workflow export`
+    archive: PathBuf,
+}
+
+/// Import a workflow run from an archive
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    let bundles_dir = get_imported_bundles_dir()?;
+    let workflow_run = engine
+        .import_workflow_run(&args.archive, &bundles_dir)
+        .await
+        .context("Failed to import workflow run")?;
+
+    println!("✅ Imported workflow run {}", workflow_run.id);
+    println!("Status: {:?}", workflow_run.status);
+    println!(
+        "Inspect it with `codemod workflow status -i {}`",
+        workflow_run.id
+    );
+    if let Some(bundle_path) = &workflow_run.bundle_path {
+        println!(
+            "Continue it with `codemod workflow resume -w {} -i {} --trigger-all`",
+            bundle_path.display(),
+            workflow_run.id
+        );
+    }
+
+    Ok(())
+}
@@ -1,5 +1,10 @@
+use std::path::PathBuf;
+
 use anyhow::{Context, Result};
 use butterflow_core::utils;
+use butterflow_models::{parse_duration, WorkflowStatus};
+use butterflow_state::{RunOrder, RunQuery};
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::Args;
 use tabled::settings::{object::Columns, Alignment, Modify, Style};
 use tabled::{Table, Tabled};
@@ -11,6 +16,50 @@ pub struct Command {
     /// Number of workflow runs to show
     #[arg(short, long, default_value = "10")]
     limit: usize,
+
+    /// Only show runs with this status (can be specified multiple times)
+    #[arg(long)]
+    status: Vec<WorkflowStatus>,
+
+    /// Only show runs started since this time: a duration like `7d`, a date
+    /// or an RFC 3339 timestamp
+    #[arg(long, value_name = "TIME")]
+    since: Option<String>,
+
+    /// Only show runs started before this time, in the same formats as `--since`
+    #[arg(long, value_name = "TIME")]
+    until: Option<String>,
+
+    /// Only show runs of this workflow: the package name, or the workflow
+    /// file for workflows that aren't packages
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Only show runs on this directory or below it
+    #[arg(long = "target", short = 't')]
+    target_path: Option<PathBuf>,
+
+    /// Only show runs with this parameter value (format: key=value)
+    #[arg(long = "param", value_name = "KEY=VALUE")]
+    params: Vec<String>,
+
+    /// Show the oldest runs first
+    #[arg(long)]
+    oldest_first: bool,
+
+    /// Continue a previous listing where it stopped
+    #[arg(long)]
+    cursor: Option<String>,
+
+    /// Output format
+    #[arg(short, long, default_value = "table")]
+    output: OutputFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum OutputFormat {
+    Table,
+    Json,
 }
 
 #[derive(Tabled)]
@@ -19,6 +68,8 @@ pub struct WorkflowRunRow {
     pub id: String,
     #[tabled(rename = "Name")]
     pub name: String,
+    #[tabled(rename = "Source")]
+    pub source: String,
     #[tabled(rename = "Status")]
     pub status: String,
     #[tabled(rename = "Started At")]
@@ -38,21 +89,48 @@ pub async fn handler(args: &Command) -> Result<()> {
         Default::default(),
         Default::default(),
         None,
-    )?;
+        None,
+    )
+    .await?;
+
+    let query = RunQuery {
+        statuses: args.status.clone(),
+        started_after: args.since.as_deref().map(parse_time).transpose()?,
+        started_before: args.until.as_deref().map(parse_time).transpose()?,
+        source: args.source.clone(),
+        // Runs store their target as an absolute path
+        target_path: args
+            .target_path
+            .as_ref()
+            .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone())),
+        params: utils::parse_params(&args.params).context("Failed to parse parameters")?,
+        order: if args.oldest_first {
+            RunOrder::OldestFirst
+        } else {
+            RunOrder::NewestFirst
+        },
+        limit: Some(args.limit),
+        cursor: args.cursor.clone(),
+    };
 
-    // Get workflow runs
-    let workflow_runs = engine
-        .list_workflow_runs(args.limit)
+    let page = engine
+        .query_workflow_runs(&query)
         .await
         .context("Failed to list workflow runs")?;
 
-    if workflow_runs.is_empty() {
+    if let OutputFormat::Json = args.output {
+        println!("{}", serde_json::to_string_pretty(&page)?);
+        return Ok(());
+    }
+
+    if page.runs.is_empty() {
         println!("No workflow runs found");
         return Ok(());
     }
 
     println!("Recent workflow runs:");
-    let rows: Vec<WorkflowRunRow> = workflow_runs
+    let rows: Vec<WorkflowRunRow> = page
+        .runs
         .iter()
         .map(|workflow_run| WorkflowRunRow {
             id: workflow_run.id.to_string(),
@@ -62,6 +140,7 @@ pub async fn handler(args: &Command) -> Result<()> {
                 .first()
                 .map(|n| n.name.clone())
                 .unwrap_or("unknown".to_string()),
+            source: workflow_run.source.clone().unwrap_or("unknown".to_string()),
             status: format!("{:?}", workflow_run.status),
             started_at: workflow_run.started_at.to_string(),
             ended_at: workflow_run
@@ -86,5 +165,24 @@ pub async fn handler(args: &Command) -> Result<()> {
         .with(Modify::new(Columns::new(..)).with(Alignment::left())); // align all columns left
     println!("{table}");
 
+    if let Some(cursor) = page.next_cursor {
+        println!("More runs match, add `--cursor {cursor}` to see them");
+    }
+
     Ok(())
 }
+
+/// Parse a point in time given as a duration ago, a date or a timestamp
+fn parse_time(input: &str) -> Result<DateTime<Utc>> {
+    if let Ok(duration) = parse_duration(input) {
+        return Ok(Utc::now() - chrono::Duration::from_std(duration)?);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    DateTime::parse_from_rfc3339(input)
+        .map(|time| time.with_timezone(&Utc))
+        .with_context(|| {
+            format!("Invalid time '{input}', expected a duration like 7d, a date or a timestamp")
+        })
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use butterflow_models::{LogStream, Task, WorkflowStatus};
+use clap::Args;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::engine::create_engine;
+
+/// How often new output is fetched with `--follow`
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Workflow run ID
+    #[arg(short, long)]
+    id: Uuid,
+
+    /// Only show the output of this task
+    #[arg(short, long)]
+    task: Option<Uuid>,
+
+    /// Keep printing new output until the workflow run stops running
+    #[arg(short, long)]
+    follow: bool,
+}
+
+/// Show the output of workflow run tasks
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    // Number of lines already printed for each task
+    let mut offsets: HashMap<Uuid, usize> = HashMap::new();
+
+    loop {
+        // Check the status before reading so output written before the run
+        // stopped is always printed
+        let status = engine
+            .get_workflow_status(args.id)
+            .await
+            .context("Failed to get workflow run")?;
+
+        let mut tasks = engine
+            .get_tasks(args.id)
+            .await
+            .context("Failed to get tasks")?;
+        if let Some(task_id) = args.task {
+            tasks.retain(|task| task.id == task_id);
+            if tasks.is_empty() {
+                anyhow::bail!("Task {task_id} not found in workflow run {}", args.id);
+            }
+        }
+
+        let mut lines = Vec::new();
+        for task in &tasks {
+            let offset = offsets.entry(task.id).or_default();
+            let logs = engine
+                .get_task_logs(task.id, *offset)
+                .await
+                .context("Failed to get task logs")?;
+            *offset += logs.len();
+            lines.extend(logs.into_iter().map(|line| (task, line)));
+        }
+        lines.sort_by_key(|(_, line)| line.timestamp);
+
+        for (task, line) in lines {
+            let stream = match line.stream {
+                LogStream::Stdout => "stdout",
+                LogStream::Stderr => "stderr",
+            };
+            println!(
+                "{} {} [{stream}] {}",
+                line.timestamp.format("%Y-%m-%d %H:%M:%S%.3f"),
+                task_label(task),
+                line.line
+            );
+        }
+
+        if !args.follow || !matches!(status, WorkflowStatus::Pending | WorkflowStatus::Running) {
+            return Ok(());
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+/// Name a task by its node and, for matrix tasks, its matrix values
+pub(crate) fn task_label(task: &Task) -> String {
+    match &task.matrix_values {
+        Some(values) => {
+            let mut values = values
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>();
+            values.sort();
+            format!("{}[{}]", task.node_id, values.join(", "))
+        }
+        None => task.node_id.clone(),
+    }
+}
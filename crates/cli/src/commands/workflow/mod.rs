@@ -1,6 +1,12 @@
 pub mod cancel;
+pub mod delete;
+pub mod export;
+pub mod import;
 pub mod list;
+pub mod logs;
+pub mod prune;
 pub mod resume;
 pub mod run;
+pub mod state;
 pub mod status;
 pub mod validate;
@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use butterflow_core::config::RetentionPolicy;
+use butterflow_models::WorkflowStatus;
+use clap::{ArgGroup, Args};
+
+use crate::engine::{apply_retention_policy, create_engine};
+
+#[derive(Args, Debug)]
+#[command(group(
+    ArgGroup::new("limit")
+        .required(true)
+        .multiple(true)
+        .args(["older_than", "keep_last"])
+))]
+pub struct Command {
+    /// Only delete runs that ended longer ago than this, e.g. `30d` or `12h`
+    #[arg(long, value_name = "DURATION")]
+    older_than: Option<String>,
+
+    /// Only delete runs with this status (can be specified multiple times,
+    /// default: completed, failed and canceled)
+    #[arg(long)]
+    status: Vec<WorkflowStatus>,
+
+    /// Keep this many of the most recent matching runs
+    #[arg(long, value_name = "N")]
+    keep_last: Option<usize>,
+
+    /// List the runs that would be deleted without deleting them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Delete stored workflow runs
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    let policy = RetentionPolicy {
+        older_than: args.older_than.clone(),
+        statuses: args.status.clone(),
+        keep_last: args.keep_last,
+    };
+
+    let workflow_runs = if args.dry_run {
+        engine.find_prunable_workflow_runs(&policy).await
+    } else {
+        apply_retention_policy(&engine).await;
+        engine.prune_workflow_runs(&policy).await
+    }
+    .context("Failed to prune workflow runs")?;
+
+    for workflow_run in &workflow_runs {
+        println!(
+            "{} {:?} started {}",
+            workflow_run.id, workflow_run.status, workflow_run.started_at
+        );
+    }
+    if args.dry_run {
+        println!("{} workflow runs would be deleted", workflow_runs.len());
+    } else {
+        println!("✅ Deleted {} workflow runs", workflow_runs.len());
+    }
+
+    Ok(())
+}
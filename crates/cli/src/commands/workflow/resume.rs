@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use crate::engine::create_engine;
@@ -42,6 +43,10 @@ pub struct Command {
     /// Optional target path to run the codemod on (default: current directory)
     #[arg(long = "target", short = 't')]
     target_path: Option<PathBuf>,
+
+    /// Maximum number of tasks to run at the same time
+    #[arg(long, value_name = "N")]
+    max_concurrency: Option<NonZeroUsize>,
 }
 
 /// Resume a workflow
@@ -63,7 +68,9 @@ pub async fn handler(args: &Command) -> Result<()> {
         // TODO: Load params from workflow run
         HashMap::new(),
         None,
-    )?;
+        args.max_concurrency.map(NonZeroUsize::get),
+    )
+    .await?;
 
     if args.trigger_all {
         // Trigger all awaiting tasks
@@ -91,60 +98,49 @@ pub async fn handler(args: &Command) -> Result<()> {
     }
 
     // Wait for workflow to complete or pause again
-    loop {
-        // Get workflow status
-        let status = engine
-            .get_workflow_status(args.id)
-            .await
-            .context("Failed to get workflow status")?;
-
-        match status {
-            WorkflowStatus::Completed => {
-                println!("✅ Workflow completed successfully");
-                break;
-            }
-            WorkflowStatus::Failed => {
-                println!("❌ Workflow failed");
-                break;
-            }
-            WorkflowStatus::AwaitingTrigger => {
-                // Get tasks awaiting trigger
-                let tasks = engine
-                    .get_tasks(args.id)
-                    .await
-                    .context("Failed to get tasks")?;
-
-                let awaiting_tasks: Vec<&Task> = tasks
-                    .iter()
-                    .filter(|t| t.status == TaskStatus::AwaitingTrigger)
-                    .collect();
-
-                println!("⏸️ Workflow paused: Manual triggers still required");
-                println!("Workflow is still awaiting manual triggers for the following tasks:");
-                let mut tasks_table = Table::new(awaiting_tasks.iter().map(|t| TaskRow {
-                    id: t.id.to_string(),
-                    node_id: t.node_id.clone(),
-                    status: format!("{:?}", t.status),
-                    matrix_info: "-".to_string(),
-                }));
-
-                tasks_table
-                    .with(Style::rounded())
-                    .with(Modify::new(Columns::new(..)).with(Alignment::left())); // align all columns left
-                println!("Tasks:");
-                println!("{tasks_table}");
-
-                break;
-            }
-            WorkflowStatus::Canceled => {
-                println!("❌ Workflow was canceled");
-                break;
-            }
-            _ => {
-                // Wait a bit before checking again
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
+    let status = engine
+        .wait_for_workflow_run(args.id)
+        .await
+        .context("Failed to get workflow status")?;
+
+    match status {
+        WorkflowStatus::Completed => {
+            println!("✅ Workflow completed successfully");
+        }
+        WorkflowStatus::Failed => {
+            println!("❌ Workflow failed");
+        }
+        WorkflowStatus::AwaitingTrigger => {
+            // Get tasks awaiting trigger
+            let tasks = engine
+                .get_tasks(args.id)
+                .await
+                .context("Failed to get tasks")?;
+
+            let awaiting_tasks: Vec<&Task> = tasks
+                .iter()
+                .filter(|t| t.status == TaskStatus::AwaitingTrigger)
+                .collect();
+
+            println!("⏸️ Workflow paused: Manual triggers still required");
+            println!("Workflow is still awaiting manual triggers for the following tasks:");
+            let mut tasks_table = Table::new(awaiting_tasks.iter().map(|t| TaskRow {
+                id: t.id.to_string(),
+                node_id: t.node_id.clone(),
+                status: format!("{:?}", t.status),
+                matrix_info: "-".to_string(),
+            }));
+
+            tasks_table
+                .with(Style::rounded())
+                .with(Modify::new(Columns::new(..)).with(Alignment::left())); // align all columns left
+            println!("Tasks:");
+            println!("{tasks_table}");
+        }
+        WorkflowStatus::Canceled => {
+            println!("❌ Workflow was canceled");
         }
+        _ => {}
     }
 
     Ok(())
@@ -1,10 +1,11 @@
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use butterflow_core::utils;
 use clap::Args;
 
-use crate::engine::create_engine;
+use crate::engine::{apply_retention_policy, create_engine};
 use crate::workflow_runner::{resolve_workflow_source, run_workflow};
 
 #[derive(Args, Debug)]
@@ -28,6 +29,10 @@ pub struct Command {
     /// Dry run mode - don't make actual changes
     #[arg(long)]
     dry_run: bool,
+
+    /// Maximum number of tasks to run at the same time
+    #[arg(long, value_name = "N")]
+    max_concurrency: Option<NonZeroUsize>,
 }
 
 /// Run a workflow
@@ -50,7 +55,12 @@ pub async fn handler(args: &Command) -> Result<()> {
         args.allow_dirty,
         params,
         None,
-    )?;
+        args.max_concurrency.map(NonZeroUsize::get),
+    )
+    .await?;
+    if !args.dry_run {
+        apply_retention_policy(&engine).await;
+    }
 
     // Run workflow using the extracted workflow runner
     run_workflow(&engine, config).await?;
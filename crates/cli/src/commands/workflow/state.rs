@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+use crate::commands::workflow::logs::task_label;
+use crate::engine::create_engine;
+
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Workflow run ID
+    #[arg(short, long)]
+    id: Uuid,
+
+    /// Show the state as it was right after the last write of this task
+    #[arg(long, value_name = "TASK_ID")]
+    at: Option<Uuid>,
+
+    /// Show every write to the state, with the values before and after it
+    #[arg(long)]
+    diff: bool,
+}
+
+/// Show the state of a workflow run
+pub async fn handler(args: &Command) -> Result<()> {
+    let (engine, _) = create_engine(
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        None,
+        None,
+    )
+    .await?;
+
+    // Fail early on unknown runs, which have an empty state and journal
+    engine
+        .get_workflow_run(args.id)
+        .await
+        .context("Failed to get workflow run")?;
+
+    if !args.diff {
+        let state = match args.at {
+            Some(task_id) => engine
+                .get_state_at(args.id, task_id)
+                .await
+                .context("Failed to rebuild state")?,
+            None => engine
+                .get_state(args.id)
+                .await
+                .context("Failed to get state")?,
+        };
+        // Sorted so the same state always prints the same way
+        let state: BTreeMap<_, _> = state.into_iter().collect();
+        println!("{}", serde_json::to_string_pretty(&state)?);
+        return Ok(());
+    }
+
+    let mut journal = engine
+        .get_state_journal(args.id)
+        .await
+        .context("Failed to get state journal")?;
+    if let Some(task_id) = args.at {
+        let last = journal
+            .iter()
+            .rposition(|entry| entry.task_id == Some(task_id))
+            .with_context(|| format!("Task {task_id} did not write to the state"))?;
+        journal.truncate(last + 1);
+    }
+    if journal.is_empty() {
+        println!("No state writes recorded");
+        return Ok(());
+    }
+
+    let tasks = engine
+        .get_tasks(args.id)
+        .await
+        .context("Failed to get tasks")?;
+    let labels: HashMap<Uuid, String> = tasks
+        .iter()
+        .map(|task| (task.id, task_label(task)))
+        .collect();
+
+    let mut state = HashMap::new();
+    for entry in &journal {
+        let writer = match (entry.task_id, &entry.step) {
+            (Some(task_id), Some(step)) => format!(
+                "{} step '{step}'",
+                labels.get(&task_id).cloned().unwrap_or(task_id.to_string())
+            ),
+            (Some(task_id), None) => labels.get(&task_id).cloned().unwrap_or(task_id.to_string()),
+            _ => "engine".to_string(),
+        };
+        println!(
+            "#{} {} {writer}",
+            entry.sequence,
+            entry.recorded_at.format("%Y-%m-%d %H:%M:%S%.3f"),
+        );
+
+        let mut keys = entry.fields.keys().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            println!(
+                "  {key}: {} -> {}",
+                display(state.get(key)),
+                display(entry.values.get(key))
+            );
+        }
+        entry.replay(&mut state);
+    }
+
+    Ok(())
+}
+
+/// Format a value, or its absence
+fn display(value: Option<&Value>) -> String {
+    value.map_or("(unset)".to_string(), Value::to_string)
+}
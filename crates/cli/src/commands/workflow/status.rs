@@ -49,7 +49,9 @@ pub async fn handler(args: &Command) -> Result<()> {
         Default::default(),
         Default::default(),
         None,
-    )?;
+        None,
+    )
+    .await?;
 
     // Get workflow run
     let workflow_run = engine
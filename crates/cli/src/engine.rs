@@ -2,14 +2,19 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use butterflow_core::config::{PreRunCallback, WorkflowRunConfig};
 use butterflow_core::engine::Engine;
 use butterflow_core::execution::ProgressCallback;
 use butterflow_core::registry::{RegistryClient, RegistryConfig};
 use butterflow_core::utils::get_cache_dir;
 use butterflow_state::cloud_adapter::CloudStateAdapter;
+use butterflow_state::sqlite_adapter::SqliteStateAdapter;
+use clap::ValueEnum;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
 
+use crate::auth::storage::{StateBackend, TokenStorage};
 use crate::auth_provider::CliAuthProvider;
 use crate::{dirty_git_check, progress_bar};
 
@@ -74,13 +79,14 @@ pub fn create_progress_callback() -> ProgressCallback {
 }
 
 /// Create an engine based on configuration
-pub fn create_engine(
+pub async fn create_engine(
     workflow_file_path: PathBuf,
     target_path: PathBuf,
     dry_run: bool,
     allow_dirty: bool,
     params: HashMap<String, String>,
     registry: Option<String>,
+    max_concurrency: Option<usize>,
 ) -> Result<(Engine, WorkflowRunConfig)> {
     let dirty_check = dirty_git_check::dirty_check();
     let bundle_path = if workflow_file_path.is_file() {
@@ -99,6 +105,8 @@ pub fn create_engine(
 
     let registry_client = create_registry_client(registry)?;
 
+    let retention_policy = TokenStorage::new()?.load_config()?.retention;
+
     let config = WorkflowRunConfig {
         pre_run_callback: Arc::new(Some(pre_run_callback)),
         progress_callback: Arc::new(Some(progress_callback)),
@@ -108,28 +116,107 @@ pub fn create_engine(
         bundle_path,
         params,
         registry_client,
+        max_concurrency,
+        retention_policy,
         ..WorkflowRunConfig::default()
     };
 
-    // Check for environment variables first
-    if let (Some(backend), Some(endpoint), auth_token) = (
-        std::env::var("BUTTERFLOW_STATE_BACKEND").ok(),
-        std::env::var("BUTTERFLOW_API_ENDPOINT").ok(),
-        std::env::var("BUTTERFLOW_API_AUTH_TOKEN")
-            .ok()
-            .unwrap_or_default(),
-    ) {
-        if backend == "cloud" {
-            // Create API state adapter
-            let state_adapter = Box::new(CloudStateAdapter::new(endpoint, auth_token));
-            return Ok((
-                Engine::with_state_adapter(state_adapter, config.clone()),
+    let engine = create_engine_with_state_backend(&config)?;
+
+    Ok((engine, config))
+}
+
+/// Delete stored workflow runs according to the retention policy of the CLI config
+///
+/// Only commands that start a run or prune runs call this, and not in dry
+/// runs, so commands that read runs never delete them.
+pub async fn apply_retention_policy(engine: &Engine) {
+    match engine.apply_retention_policy().await {
+        Ok(pruned) if !pruned.is_empty() => {
+            info!(
+                "Deleted {} workflow runs according to the retention policy",
+                pruned.len()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to apply the retention policy: {e}"),
+    }
+}
+
+/// Create an engine using the selected state backend
+///
+/// `BUTTERFLOW_STATE_BACKEND`, which `--state-backend` sets, takes precedence
+/// over the `state` section of the CLI config.
+fn create_engine_with_state_backend(config: &WorkflowRunConfig) -> Result<Engine> {
+    let storage = TokenStorage::new()?;
+    let state_config = storage.load_config()?.state.unwrap_or_default();
+
+    let backend = match std::env::var("BUTTERFLOW_STATE_BACKEND") {
+        Ok(backend) => StateBackend::from_str(&backend, true)
+            .map_err(|e| anyhow::anyhow!("Invalid BUTTERFLOW_STATE_BACKEND: {e}"))?,
+        Err(_) => state_config.backend,
+    };
+
+    match backend {
+        StateBackend::Cloud => {
+            let endpoint = std::env::var("BUTTERFLOW_API_ENDPOINT")
+                .ok()
+                .or(state_config.endpoint)
+                .context(
+                    "The cloud state backend needs an endpoint, set BUTTERFLOW_API_ENDPOINT or state.endpoint in the config",
+                )?;
+            // Only a login for this very endpoint, other hosts mustn't get
+            // the registry's credentials
+            let auth_token = match std::env::var("BUTTERFLOW_API_AUTH_TOKEN") {
+                Ok(token) => token,
+                Err(_) => storage
+                    .get_auth_for_registry(&endpoint)?
+                    .map(|auth| auth.tokens.access_token)
+                    .with_context(|| {
+                        format!(
+                            "The cloud state backend needs credentials, log in with `codemod login --registry {endpoint}` or set BUTTERFLOW_API_AUTH_TOKEN"
+                        )
+                    })?,
+            };
+            let queue_path = dirs::data_dir()
+                .context("Could not find data directory")?
+                .join("butterflow")
+                .join(cloud_queue_file_name(&endpoint));
+            let state_adapter = CloudStateAdapter::new(endpoint, auth_token)
+                .with_queue_path(queue_path)
+                .context("Failed to load queued state writes")?;
+            Ok(Engine::with_state_adapter(
+                Box::new(state_adapter),
                 config.clone(),
-            ));
+            ))
         }
+        StateBackend::Sqlite => {
+            let sqlite_path = std::env::var("BUTTERFLOW_SQLITE_PATH")
+                .ok()
+                .map(PathBuf::from)
+                .or(state_config.sqlite_path);
+            let state_adapter = match sqlite_path {
+                Some(path) => SqliteStateAdapter::with_path(path),
+                None => SqliteStateAdapter::new(),
+            }
+            .context("Failed to open SQLite state database")?;
+            Ok(Engine::with_state_adapter(
+                Box::new(state_adapter),
+                config.clone(),
+            ))
+        }
+        StateBackend::Local => Ok(Engine::with_workflow_run_config(config.clone())),
     }
+}
 
-    Ok((Engine::with_workflow_run_config(config.clone()), config))
+/// Name of the file the cloud backend queues writes for `endpoint` in
+///
+/// Writes must only be delivered to the endpoint they were made for, so
+/// every endpoint gets its own file.
+fn cloud_queue_file_name(endpoint: &str) -> String {
+    let digest = Sha256::digest(endpoint.trim_end_matches('/').as_bytes());
+    let key: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+    format!("cloud_queue-{key}.jsonl")
 }
 
 pub fn create_registry_client(registry: Option<String>) -> Result<RegistryClient> {
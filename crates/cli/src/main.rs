@@ -15,6 +15,7 @@ use codemod_telemetry::{
     send_null::NullSender,
 };
 
+use crate::auth::storage::StateBackend;
 use crate::auth::TokenStorage;
 
 #[derive(Parser)]
@@ -32,6 +33,10 @@ struct Cli {
     #[arg(short, long)]
     verbose: bool,
 
+    /// Where workflow runs and their state are stored
+    #[arg(long, global = true, value_enum)]
+    state_backend: Option<StateBackend>,
+
     #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
     trailing_args: Vec<String>,
 }
@@ -101,8 +106,26 @@ enum WorkflowCommands {
     /// List workflow runs
     List(commands::workflow::list::Command),
 
+    /// Show the output of workflow run tasks
+    Logs(commands::workflow::logs::Command),
+
+    /// Show the state of a workflow run and how tasks changed it
+    State(commands::workflow::state::Command),
+
     /// Cancel a workflow run
     Cancel(commands::workflow::cancel::Command),
+
+    /// Export a workflow run to a portable archive
+    Export(commands::workflow::export::Command),
+
+    /// Import a workflow run from an archive
+    Import(commands::workflow::import::Command),
+
+    /// Delete a workflow run
+    Delete(commands::workflow::delete::Command),
+
+    /// Delete stored workflow runs that are old or finished
+    Prune(commands::workflow::prune::Command),
 }
 
 #[derive(Subcommand, Debug)]
@@ -199,6 +222,12 @@ async fn main() -> Result<()> {
         std::env::set_var("RUST_LOG", "info");
     }
 
+    // Engines are created deep inside the commands, which read the backend
+    // from the environment
+    if let Some(state_backend) = cli.state_backend {
+        std::env::set_var("BUTTERFLOW_STATE_BACKEND", state_backend.as_str());
+    }
+
     let telemetry_sender: Box<dyn codemod_telemetry::send_event::TelemetrySender> =
         if std::env::var("DISABLE_ANALYTICS") == Ok("true".to_string())
             || std::env::var("DISABLE_ANALYTICS") == Ok("1".to_string())
@@ -241,9 +270,27 @@ async fn main() -> Result<()> {
             WorkflowCommands::List(args) => {
                 commands::workflow::list::handler(args).await?;
             }
+            WorkflowCommands::Logs(args) => {
+                commands::workflow::logs::handler(args).await?;
+            }
+            WorkflowCommands::State(args) => {
+                commands::workflow::state::handler(args).await?;
+            }
             WorkflowCommands::Cancel(args) => {
                 commands::workflow::cancel::handler(args).await?;
             }
+            WorkflowCommands::Export(args) => {
+                commands::workflow::export::handler(args).await?;
+            }
+            WorkflowCommands::Import(args) => {
+                commands::workflow::import::handler(args).await?;
+            }
+            WorkflowCommands::Delete(args) => {
+                commands::workflow::delete::handler(args).await?;
+            }
+            WorkflowCommands::Prune(args) => {
+                commands::workflow::prune::handler(args).await?;
+            }
         },
         Some(Commands::Jssg(args)) => match &args.command {
             JssgCommands::Bundle(args) => {
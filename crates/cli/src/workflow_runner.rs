@@ -37,63 +37,53 @@ pub async fn run_workflow(engine: &Engine, config: WorkflowRunConfig) -> Result<
 
 /// Wait for workflow to complete or pause
 pub async fn wait_for_workflow_completion(engine: &Engine, workflow_run_id: String) -> Result<()> {
-    loop {
-        // Get workflow status
-        let status = engine
-            .get_workflow_status(workflow_run_id.clone().parse::<Uuid>()?)
-            .await
-            .context("Failed to get workflow status")?;
-
-        match status {
-            WorkflowStatus::Completed => {
-                info!("Workflow completed successfully");
-                break;
-            }
-            WorkflowStatus::Failed => {
-                error!("Workflow failed");
-                return Err(anyhow::anyhow!("Workflow failed"));
-            }
-            WorkflowStatus::AwaitingTrigger => {
-                // Get tasks awaiting trigger
-                let tasks = engine
-                    .get_tasks(workflow_run_id.clone().parse::<Uuid>()?)
-                    .await
-                    .context("Failed to get tasks")?;
-
-                let awaiting_tasks: Vec<&Task> = tasks
-                    .iter()
-                    .filter(|t| t.status == TaskStatus::AwaitingTrigger)
-                    .collect();
-
-                info!("Workflow paused: Manual triggers required");
-                info!("");
-                info!("Workflow is awaiting manual triggers for the following tasks:");
-                for task in awaiting_tasks {
-                    info!("- {} ({})", task.id, task.node_id);
-                }
-                info!("");
-                info!("Use 'butterflow status -i {workflow_run_id}' to check status");
-                info!(
-                    "Run 'butterflow resume -i {workflow_run_id} -t <TASK_ID>' to trigger a specific task"
-                );
-                info!(
-                    "Run 'butterflow resume -i {workflow_run_id} --trigger-all' to trigger all awaiting tasks"
-                );
-                break;
-            }
-            WorkflowStatus::Running => {
-                // Still running, wait a bit before checking again
-                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            }
-            WorkflowStatus::Canceled => {
-                info!("Workflow was canceled");
-                break;
-            }
-            _ => {
-                // Wait a bit before checking again
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    let workflow_run_id = workflow_run_id.parse::<Uuid>()?;
+
+    // Get the status once the run stops running
+    let status = engine
+        .wait_for_workflow_run(workflow_run_id)
+        .await
+        .context("Failed to get workflow status")?;
+
+    match status {
+        WorkflowStatus::Completed => {
+            info!("Workflow completed successfully");
+        }
+        WorkflowStatus::Failed => {
+            error!("Workflow failed");
+            return Err(anyhow::anyhow!("Workflow failed"));
+        }
+        WorkflowStatus::AwaitingTrigger => {
+            // Get tasks awaiting trigger
+            let tasks = engine
+                .get_tasks(workflow_run_id)
+                .await
+                .context("Failed to get tasks")?;
+
+            let awaiting_tasks: Vec<&Task> = tasks
+                .iter()
+                .filter(|t| t.status == TaskStatus::AwaitingTrigger)
+                .collect();
+
+            info!("Workflow paused: Manual triggers required");
+            info!("");
+            info!("Workflow is awaiting manual triggers for the following tasks:");
+            for task in awaiting_tasks {
+                info!("- {} ({})", task.id, task.node_id);
             }
+            info!("");
+            info!("Use 'butterflow status -i {workflow_run_id}' to check status");
+            info!(
+                "Run 'butterflow resume -i {workflow_run_id} -t <TASK_ID>' to trigger a specific task"
+            );
+            info!(
+                "Run 'butterflow resume -i {workflow_run_id} --trigger-all' to trigger all awaiting tasks"
+            );
+        }
+        WorkflowStatus::Canceled => {
+            info!("Workflow was canceled");
         }
+        _ => {}
     }
 
     Ok(())
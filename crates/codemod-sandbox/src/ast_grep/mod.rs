@@ -20,7 +20,8 @@ use ast_grep_core::language::Language;
 use rquickjs::module::{Declarations, Exports, ModuleDef};
 use rquickjs::{prelude::Func, Class, Ctx, Exception, Object, Result};
 
-use sg_node::{SgNodeRjs, SgRootRjs};
+use sg_node::SgNodeRjs;
+pub(crate) use sg_node::SgRootRjs;
 
 pub(crate) mod scanner;
 mod serde;
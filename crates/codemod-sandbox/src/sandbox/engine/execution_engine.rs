@@ -1,15 +1,21 @@
+use super::project::{self, Project};
 use super::quickjs_adapters::{QuickJSLoader, QuickJSResolver};
 use crate::ast_grep::AstGrepModule;
-use crate::sandbox::errors::ExecutionError;
+use crate::sandbox::errors::{ExecutionError, RuntimeError};
 use crate::sandbox::filesystem::FileSystem;
 use crate::sandbox::resolvers::ModuleResolver;
+use crate::utils::quickjs_utils::maybe_promise;
 use ast_grep_language::SupportLang;
 use llrt_modules::module_builder::ModuleBuilder;
-use rquickjs::{async_with, AsyncContext, AsyncRuntime};
-use rquickjs::{CatchResultExt, Function, Module};
+use rquickjs::loader::{BuiltinLoader, BuiltinResolver};
+use rquickjs::{async_with, AsyncContext, AsyncRuntime, Ctx};
+use rquickjs::{CatchResultExt, Function, Module, Persistent};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 /// Statistics about the execution results
@@ -18,6 +24,9 @@ pub struct ExecutionStats {
     pub files_modified: AtomicUsize,
     pub files_unmodified: AtomicUsize,
     pub files_with_errors: AtomicUsize,
+    pub files_created: AtomicUsize,
+    pub files_renamed: AtomicUsize,
+    pub files_deleted: AtomicUsize,
 }
 
 impl ExecutionStats {
@@ -65,7 +74,18 @@ impl fmt::Display for ExecutionStats {
             self.files_modified.load(Ordering::Relaxed),
             self.files_unmodified.load(Ordering::Relaxed),
             self.files_with_errors.load(Ordering::Relaxed)
-        )
+        )?;
+
+        let created = self.files_created.load(Ordering::Relaxed);
+        let renamed = self.files_renamed.load(Ordering::Relaxed);
+        let deleted = self.files_deleted.load(Ordering::Relaxed);
+        if created + renamed + deleted > 0 {
+            write!(
+                f,
+                ", {created} created, {renamed} renamed, {deleted} deleted"
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -86,6 +106,10 @@ pub struct ExecutionOutput {
     pub modified: bool,
     /// Error message if execution failed
     pub error: Option<String>,
+    /// State writes the codemod emitted through `codemod:workflow`, in order
+    pub state_writes: Vec<StateWrite>,
+    /// File operations the codemod requested through `codemod:files`, in order
+    pub file_operations: Vec<FileOperation>,
 }
 
 impl ExecutionOutput {
@@ -100,6 +124,8 @@ impl ExecutionOutput {
             content,
             modified,
             error: None,
+            state_writes: Vec::new(),
+            file_operations: Vec::new(),
         }
     }
 
@@ -109,6 +135,8 @@ impl ExecutionOutput {
             content: None,
             modified: false,
             error: Some(message),
+            state_writes: Vec::new(),
+            file_operations: Vec::new(),
         }
     }
 
@@ -123,8 +151,76 @@ impl ExecutionOutput {
     }
 }
 
+/// A write to workflow state emitted by a codemod for one file
+///
+/// Writes are only kept for files the codemod ran on successfully; it's up
+/// to the caller to combine those of all files and apply them.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum StateWrite {
+    /// Set the key to the value
+    Set {
+        key: String,
+        #[serde(default)]
+        value: serde_json::Value,
+    },
+    /// Append the value to the array at the key
+    Append {
+        key: String,
+        #[serde(default)]
+        value: serde_json::Value,
+    },
+    /// Add the value to the number at the key
+    Increment {
+        key: String,
+        value: serde_json::Value,
+    },
+}
+
+impl StateWrite {
+    /// The state key written to
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Set { key, .. } | Self::Append { key, .. } | Self::Increment { key, .. } => key,
+        }
+    }
+}
+
+/// A change to a file other than writing the content of the processed one
+///
+/// Paths are as the codemod gave them, relative ones being relative to the
+/// directory of the processed file. Like state writes, operations are only
+/// kept for files the codemod ran on successfully.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum FileOperation {
+    /// Write the content to the file, creating it if needed
+    Write { path: PathBuf, content: String },
+    /// Rename or move a file
+    Rename { from: PathBuf, to: PathBuf },
+    /// Delete a file
+    Delete { path: PathBuf },
+}
+
+/// Second argument of the codemod's `transform`, the same for every file
+///
+/// The codemod gets it frozen, along with the language of the file.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransformOptions {
+    /// Workflow parameters, with those of the step on top
+    pub params: HashMap<String, String>,
+    /// Matrix values of the task running the codemod
+    pub matrix_values: HashMap<String, serde_json::Value>,
+    /// Snapshot of the workflow state
+    pub state: HashMap<String, serde_json::Value>,
+}
+
 /// Execute a codemod on string content using QuickJS
 /// This is the core execution logic that doesn't touch the filesystem
+///
+/// Sets up a new runtime for the single file; use a
+/// [`RuntimePool`](super::RuntimePool) to run a codemod on many files.
 #[cfg(feature = "native")]
 pub async fn execute_codemod_with_quickjs<F, R>(
     script_path: &Path,
@@ -133,91 +229,144 @@ pub async fn execute_codemod_with_quickjs<F, R>(
     language: SupportLang,
     file_path: &Path,
     content: &str,
+    options: &TransformOptions,
 ) -> Result<ExecutionOutput, ExecutionError>
 where
-    F: FileSystem,
+    F: FileSystem + 'static,
     R: ModuleResolver + 'static,
 {
-    use crate::utils::quickjs_utils::maybe_promise;
-
-    let script_name = script_path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("main.js");
-
-    let js_code = format!(
-        include_str!("scripts/main_script.js.txt"),
-        script_name = script_name
-    );
-
-    // Initialize QuickJS runtime and context
-    let runtime = AsyncRuntime::new().map_err(|e| ExecutionError::Runtime {
-        source: crate::sandbox::errors::RuntimeError::InitializationFailed {
-            message: format!("Failed to create AsyncRuntime: {e}"),
-        },
-    })?;
-
-    // Set up built-in modules
-    let module_builder = ModuleBuilder::default();
-    let (mut built_in_resolver, mut built_in_loader, global_attachment) = module_builder.build();
-
-    // Add AstGrepModule
-    built_in_resolver = built_in_resolver.add_name("codemod:ast-grep");
-    built_in_loader = built_in_loader.with_module("codemod:ast-grep", AstGrepModule);
-
-    let fs_resolver = QuickJSResolver::new(Arc::clone(&resolver));
-    let fs_loader = QuickJSLoader;
-
-    // Combine resolvers and loaders
-    runtime
-        .set_loader(
-            (built_in_resolver, fs_resolver),
-            (built_in_loader, fs_loader),
-        )
-        .await;
+    match CodemodRuntime::new(script_path, resolver, options, None::<Arc<Project<F>>>).await {
+        Ok(runtime) => Ok(runtime.execute(language, file_path, content).await),
+        Err(e) => Ok(error_output(e)),
+    }
+}
+
+/// A QuickJS runtime with a codemod loaded, ready to run on file after file
+///
+/// The codemod module is evaluated once, when the runtime is created, so
+/// its top-level code doesn't run again for every file. Everything reachable
+/// from the global object is snapshotted once the codemod is loaded and put
+/// back after every run, including prototypes and non-enumerable properties,
+/// so files don't see each other's leftovers. A runtime whose transform threw,
+/// or whose globals couldn't be put back, is no longer
+/// [reusable](Self::is_reusable).
+#[cfg(feature = "native")]
+pub struct CodemodRuntime {
+    // Declared first so they're dropped before the context they belong to
+    execute_codemod: Persistent<Function<'static>>,
+    take_state_writes: Persistent<Function<'static>>,
+    take_file_operations: Persistent<Function<'static>>,
+    finish_run: Persistent<Function<'static>>,
+    /// Whether every run so far left the runtime as it found it
+    reusable: AtomicBool,
+    context: AsyncContext,
+    _runtime: AsyncRuntime,
+}
 
-    let context = AsyncContext::full(&runtime)
-        .await
-        .map_err(|e| ExecutionError::Runtime {
-            source: crate::sandbox::errors::RuntimeError::ContextCreationFailed {
-                message: format!("Failed to create AsyncContext: {e}"),
+#[cfg(feature = "native")]
+impl CodemodRuntime {
+    /// Set up a runtime and load the codemod at `script_path` into it
+    ///
+    /// Without a `project`, the codemod can import `codemod:project` but
+    /// its functions throw.
+    pub async fn new<R, F>(
+        script_path: &Path,
+        resolver: Arc<R>,
+        options: &TransformOptions,
+        project: Option<Arc<Project<F>>>,
+    ) -> Result<Self, ExecutionError>
+    where
+        R: ModuleResolver + 'static,
+        F: FileSystem + 'static,
+    {
+        let options_json = serde_json::to_string(options).map_err(|e| ExecutionError::Runtime {
+            source: RuntimeError::InitializationFailed {
+                message: format!("Failed to serialize transform options: {e}"),
             },
         })?;
 
-    // Execute JavaScript code
-    let result: Result<Option<String>, ExecutionError> = async_with!(context => |ctx| {
-        global_attachment.attach(&ctx).map_err(|e| ExecutionError::Runtime {
-            source: crate::sandbox::errors::RuntimeError::InitializationFailed {
-                message: format!("Failed to attach global modules: {e}"),
+        let script_name = script_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("main.js");
+
+        let js_code = format!(
+            include_str!("scripts/main_script.js.txt"),
+            script_name = script_name
+        );
+
+        // Initialize QuickJS runtime and context
+        let runtime = AsyncRuntime::new().map_err(|e| ExecutionError::Runtime {
+            source: RuntimeError::InitializationFailed {
+                message: format!("Failed to create AsyncRuntime: {e}"),
             },
         })?;
 
-        let execution = async {
-            let module = Module::declare(ctx.clone(), "__codemod_entry.js", js_code)
-                .catch(&ctx)
-                .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
-                        message: format!("Failed to declare module: {e}"),
-                    },
-                })?;
+        // Set up built-in modules
+        let module_builder = ModuleBuilder::default();
+        let (mut built_in_resolver, mut built_in_loader, global_attachment) =
+            module_builder.build();
+
+        // Add AstGrepModule
+        built_in_resolver = built_in_resolver.add_name("codemod:ast-grep");
+        built_in_loader = built_in_loader.with_module("codemod:ast-grep", AstGrepModule);
+
+        // Modules through which the codemod writes to workflow state,
+        // changes other files and reads the project
+        let script_resolver = BuiltinResolver::default()
+            .with_module("codemod:workflow")
+            .with_module("codemod:files")
+            .with_module("codemod:project");
+        let script_loader = BuiltinLoader::default()
+            .with_module(
+                "codemod:workflow",
+                include_str!("scripts/workflow_module.js.txt"),
+            )
+            .with_module("codemod:files", include_str!("scripts/files_module.js.txt"))
+            .with_module(
+                "codemod:project",
+                include_str!("scripts/project_module.js.txt"),
+            );
+
+        let fs_resolver = QuickJSResolver::new(Arc::clone(&resolver));
+        let fs_loader = QuickJSLoader;
+
+        // Combine resolvers and loaders
+        runtime
+            .set_loader(
+                (built_in_resolver, script_resolver, fs_resolver),
+                (built_in_loader, script_loader, fs_loader),
+            )
+            .await;
+
+        let context = AsyncContext::full(&runtime)
+            .await
+            .map_err(|e| ExecutionError::Runtime {
+                source: RuntimeError::ContextCreationFailed {
+                    message: format!("Failed to create AsyncContext: {e}"),
+                },
+            })?;
 
-            // Set the current file path for the codemod
-            let file_path_str = file_path.to_string_lossy();
-            ctx.globals()
-                .set("CODEMOD_TARGET_FILE_PATH", file_path_str.as_ref())
-                .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
-                        message: format!("Failed to set global variable: {e}"),
+        // Load the codemod and keep hold of its entry point
+        let loaded: Result<_, ExecutionError> = async_with!(context => |ctx| {
+            global_attachment.attach(&ctx).map_err(|e| ExecutionError::Runtime {
+                source: RuntimeError::InitializationFailed {
+                    message: format!("Failed to attach global modules: {e}"),
+                },
+            })?;
+            if let Some(project) = project {
+                project::install(&ctx, project).map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: format!("Failed to expose the project: {e}"),
                     },
                 })?;
+            }
 
-            // Set the language for the codemod
-            let language_str = language.to_string();
-            ctx.globals()
-                .set("CODEMOD_LANGUAGE", language_str)
+            let module = Module::declare(ctx.clone(), "__codemod_entry.js", js_code)
+                .catch(&ctx)
                 .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
-                        message: format!("Failed to set language global variable: {e}"),
+                    source: RuntimeError::InitializationFailed {
+                        message: format!("Failed to declare module: {e}"),
                     },
                 })?;
 
@@ -226,87 +375,265 @@ where
                 .eval()
                 .catch(&ctx)
                 .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
+                    source: RuntimeError::InitializationFailed {
                         message: e.to_string(),
                     },
                 })?;
             while ctx.execute_pending_job() {}
 
-            // Get the default export.
             let namespace = evaluated
                 .namespace()
                 .catch(&ctx)
                 .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
+                    source: RuntimeError::InitializationFailed {
                         message: e.to_string(),
                     },
                 })?;
 
-
             let func = namespace
                 .get::<_, Function>("executeCodemod")
                 .catch(&ctx)
                 .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
+                    source: RuntimeError::InitializationFailed {
                         message: e.to_string(),
                     },
                 })?;
 
+            let take_state_writes = namespace
+                .get::<_, Function>("takeWrites")
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: e.to_string(),
+                    },
+                })?;
 
-            // Call it and return value.
-            let result_obj_promise = func.call(()).catch(&ctx).map_err(|e| {
-                ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
+            let take_file_operations = namespace
+                .get::<_, Function>("takeFileOperations")
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
                         message: e.to_string(),
                     },
-                }
-            })?;
-            let result_obj = maybe_promise(result_obj_promise)
-                .await
+                })?;
+
+            let configure = namespace
+                .get::<_, Function>("configure")
                 .catch(&ctx)
                 .map_err(|e| ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::InitializationFailed {
+                    source: RuntimeError::InitializationFailed {
                         message: e.to_string(),
                     },
                 })?;
+            configure
+                .call::<_, ()>((options_json,))
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: format!("Failed to set transform options: {e}"),
+                    },
+                })?;
 
-            if result_obj.is_string() {
-                Ok(Some(result_obj.get::<String>().unwrap()))
-            } else if result_obj.is_null() || result_obj.is_undefined() {
-                Ok(None)
-            } else {
-                Err(ExecutionError::Runtime {
-                    source: crate::sandbox::errors::RuntimeError::ExecutionFailed {
-                        message: "Invalid result type".to_string(),
+            let finish_run = namespace
+                .get::<_, Function>("finishRun")
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: e.to_string(),
                     },
-                })
-            }
-        };
-        execution.await
-    })
-    .await;
-
-    // Convert the result to ExecutionOutput
-    match result {
-        Ok(new_content) => Ok(ExecutionOutput::success(new_content, content)),
-        Err(e) => {
-            // Format the error message for better readability
-            let error_msg = match &e {
-                ExecutionError::Runtime { source } => {
-                    match source {
-                        crate::sandbox::errors::RuntimeError::InitializationFailed { message } => {
-                            // Unescape newlines in JavaScript error messages
-                            message.replace("\\n", "\n")
-                        }
-                        crate::sandbox::errors::RuntimeError::ExecutionFailed { message } => {
-                            message.replace("\\n", "\n")
-                        }
-                        _ => e.to_string(),
+                })?;
+
+            // Taken last, so the globals the codemod set up while loading
+            // are kept for every file
+            let snapshot_globals = namespace
+                .get::<_, Function>("snapshotGlobals")
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: e.to_string(),
+                    },
+                })?;
+            snapshot_globals
+                .call::<_, ()>(())
+                .catch(&ctx)
+                .map_err(|e| ExecutionError::Runtime {
+                    source: RuntimeError::InitializationFailed {
+                        message: format!("Failed to snapshot globals: {e}"),
+                    },
+                })?;
+
+            Ok((
+                Persistent::save(&ctx, func),
+                Persistent::save(&ctx, take_state_writes),
+                Persistent::save(&ctx, take_file_operations),
+                Persistent::save(&ctx, finish_run),
+            ))
+        })
+        .await;
+        let (execute_codemod, take_state_writes, take_file_operations, finish_run) = loaded?;
+
+        Ok(Self {
+            execute_codemod,
+            take_state_writes,
+            take_file_operations,
+            finish_run,
+            reusable: AtomicBool::new(true),
+            context,
+            _runtime: runtime,
+        })
+    }
+
+    /// Whether the next file can run on this runtime
+    ///
+    /// False once a transform threw, since it may have left things half
+    /// changed, or once globals it changed couldn't be put back.
+    pub fn is_reusable(&self) -> bool {
+        self.reusable.load(Ordering::Relaxed)
+    }
+
+    /// Run the codemod on the content of a single file
+    pub async fn execute(
+        &self,
+        language: SupportLang,
+        file_path: &Path,
+        content: &str,
+    ) -> ExecutionOutput {
+        let result: Result<_, ExecutionError> = async_with!(self.context => |ctx| {
+            let execution = async {
+                // Set the current file path for the codemod
+                let file_path_str = file_path.to_string_lossy();
+                ctx.globals()
+                    .set("CODEMOD_TARGET_FILE_PATH", file_path_str.as_ref())
+                    .map_err(|e| ExecutionError::Runtime {
+                        source: RuntimeError::InitializationFailed {
+                            message: format!("Failed to set global variable: {e}"),
+                        },
+                    })?;
+
+                // Set the language for the codemod
+                let language_str = language.to_string();
+                ctx.globals()
+                    .set("CODEMOD_LANGUAGE", language_str)
+                    .map_err(|e| ExecutionError::Runtime {
+                        source: RuntimeError::InitializationFailed {
+                            message: format!("Failed to set language global variable: {e}"),
+                        },
+                    })?;
+
+                let func = self
+                    .execute_codemod
+                    .clone()
+                    .restore(&ctx)
+                    .map_err(|e| ExecutionError::Runtime {
+                        source: RuntimeError::InitializationFailed {
+                            message: e.to_string(),
+                        },
+                    })?;
+
+                // Call it and return value.
+                let result_obj_promise = func.call(()).catch(&ctx).map_err(|e| {
+                    ExecutionError::Runtime {
+                        source: RuntimeError::InitializationFailed {
+                            message: e.to_string(),
+                        },
                     }
+                })?;
+                let result_obj = maybe_promise(result_obj_promise)
+                    .await
+                    .catch(&ctx)
+                    .map_err(|e| ExecutionError::Runtime {
+                        source: RuntimeError::InitializationFailed {
+                            message: e.to_string(),
+                        },
+                    })?;
+
+                if result_obj.is_string() {
+                    Ok(Some(result_obj.get::<String>().unwrap()))
+                } else if result_obj.is_null() || result_obj.is_undefined() {
+                    Ok(None)
+                } else {
+                    Err(ExecutionError::Runtime {
+                        source: RuntimeError::ExecutionFailed {
+                            message: "Invalid result type".to_string(),
+                        },
+                    })
                 }
-                _ => e.to_string(),
             };
-            Ok(ExecutionOutput::error(error_msg))
+            let result = execution.await;
+
+            // Leave the globals as the next file expects them, once jobs
+            // the run left behind had their chance to change them. This
+            // also undoes patches to what taking the emitted values uses.
+            while ctx.execute_pending_job() {}
+            let restored = self
+                .finish_run
+                .clone()
+                .restore(&ctx)
+                .ok()
+                .and_then(|finish_run| finish_run.call::<_, bool>(()).ok());
+            if restored != Some(true) {
+                self.reusable.store(false, Ordering::Relaxed);
+            }
+
+            // Taken even when the run failed, so they don't leak into the
+            // next file
+            let writes = take_emitted(&ctx, &self.take_state_writes, "state writes");
+            let operations =
+                take_emitted(&ctx, &self.take_file_operations, "file operations");
+            result.and_then(|content| Ok((content, writes?, operations?)))
+        })
+        .await;
+
+        match result {
+            Ok((new_content, state_writes, file_operations)) => ExecutionOutput {
+                state_writes,
+                file_operations,
+                ..ExecutionOutput::success(new_content, content)
+            },
+            Err(e) => error_output(e),
         }
     }
 }
+
+/// Take what the codemod emitted since the last call, through a function
+/// of the entry module returning it as JSON
+#[cfg(feature = "native")]
+fn take_emitted<T: DeserializeOwned>(
+    ctx: &Ctx<'_>,
+    take: &Persistent<Function<'static>>,
+    what: &str,
+) -> Result<Vec<T>, ExecutionError> {
+    let failed = |message: String| ExecutionError::Runtime {
+        source: RuntimeError::ExecutionFailed { message },
+    };
+
+    let func = take
+        .clone()
+        .restore(ctx)
+        .map_err(|e| failed(e.to_string()))?;
+    let json: String = func
+        .call(())
+        .catch(ctx)
+        .map_err(|e| failed(format!("Failed to take {what}: {e}")))?;
+    serde_json::from_str(&json).map_err(|e| failed(format!("Invalid {what}: {e}")))
+}
+
+/// Report a failed execution as the output for the file
+#[cfg(feature = "native")]
+pub(crate) fn error_output(e: ExecutionError) -> ExecutionOutput {
+    // Format the error message for better readability
+    let error_msg = match &e {
+        ExecutionError::Runtime { source } => {
+            match source {
+                RuntimeError::InitializationFailed { message } => {
+                    // Unescape newlines in JavaScript error messages
+                    message.replace("\\n", "\n")
+                }
+                RuntimeError::ExecutionFailed { message } => message.replace("\\n", "\n"),
+                _ => e.to_string(),
+            }
+        }
+        _ => e.to_string(),
+    };
+    ExecutionOutput::error(error_msg)
+}
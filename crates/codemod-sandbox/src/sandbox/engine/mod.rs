@@ -1,5 +1,9 @@
 pub mod execution_engine;
+pub mod project;
 pub mod quickjs_adapters;
+pub mod runtime_pool;
 
 pub use execution_engine::*;
+pub use project::Project;
+pub use runtime_pool::RuntimePool;
 pub mod language_data;
@@ -0,0 +1,327 @@
+use super::language_data::get_language_from_extension;
+use crate::ast_grep::SgRootRjs;
+use crate::sandbox::errors::{ExecutionError, RuntimeError};
+use crate::sandbox::filesystem::{FileSystem, WalkOptions};
+use crate::sandbox::resolvers::{ModuleResolver, OxcResolver};
+use crate::utils::project_discovery::find_tsconfig;
+use rquickjs::prelude::Async;
+use rquickjs::{Ctx, Exception, Function, Object};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// Name of the global through which `codemod:project` reaches the project
+const PROJECT_GLOBAL: &str = "__codemodProject";
+
+/// Read-only view of the project a codemod runs on, for `codemod:project`
+///
+/// Files are only read through the [`FileSystem`], and only once their
+/// canonical path, symlinks resolved, turns out to be under the root.
+/// Imports are resolved like the project's own tooling would, with its
+/// `tsconfig.json` if it has one. The project's files are listed once, on
+/// first use, since the files a codemod changes are only written after the
+/// run.
+pub struct Project<F> {
+    root: PathBuf,
+    filesystem: Arc<F>,
+    resolver: OxcResolver,
+    files: OnceCell<Vec<PathBuf>>,
+}
+
+impl<F> Project<F>
+where
+    F: FileSystem + 'static,
+{
+    pub async fn new(root: &Path, filesystem: Arc<F>) -> Result<Self, ExecutionError> {
+        let root = filesystem.canonicalize(root).await?;
+        let tsconfig_path = find_tsconfig(&root).filter(|path| path.starts_with(&root));
+        let resolver =
+            OxcResolver::new(root.clone(), tsconfig_path).map_err(|e| ExecutionError::Runtime {
+                source: RuntimeError::InitializationFailed {
+                    message: format!("Failed to create project resolver: {e}"),
+                },
+            })?;
+
+        Ok(Self {
+            root,
+            filesystem,
+            resolver,
+            files: OnceCell::new(),
+        })
+    }
+
+    /// The canonical path of `path` as seen from the file `from`, if it is
+    /// in the project
+    async fn locate(&self, from: &str, path: &str) -> Result<PathBuf, String> {
+        let from = absolute(Path::new(from));
+        let base = from.parent().unwrap_or(&self.root);
+        self.confine(&base.join(path)).await
+    }
+
+    /// The canonical path of `path`, if it is in the project
+    async fn confine(&self, path: &Path) -> Result<PathBuf, String> {
+        let path = self
+            .filesystem
+            .canonicalize(path)
+            .await
+            .map_err(|e| e.to_string())?;
+        if path.starts_with(&self.root) {
+            Ok(path)
+        } else {
+            Err(format!("{} is outside of the project", path.display()))
+        }
+    }
+
+    /// The project file an import of the file `from` refers to
+    async fn resolve_import(&self, from: &str, specifier: &str) -> Option<String> {
+        let from = absolute(Path::new(from));
+        let resolved = self
+            .resolver
+            .resolve(&from.to_string_lossy(), specifier)
+            .ok()?;
+        let path = self.confine(Path::new(&resolved)).await.ok()?;
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    async fn read_file(&self, from: &str, path: &str) -> Result<(PathBuf, String), String> {
+        let path = self.locate(from, path).await?;
+        let content = self
+            .filesystem
+            .read_to_string(&path)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok((path, content))
+    }
+
+    async fn list_files(&self, from: &str, dir: Option<&str>) -> Result<Vec<String>, String> {
+        let dir = match dir {
+            Some(dir) => self.locate(from, dir).await?,
+            None => self.root.clone(),
+        };
+        let files = self
+            .files
+            .get_or_try_init(|| async {
+                let files = self
+                    .filesystem
+                    .walk_dir(&self.root, WalkOptions::default())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>(
+                    files
+                        .into_iter()
+                        .filter(|path| path.starts_with(&self.root))
+                        .collect(),
+                )
+            })
+            .await?;
+        Ok(files
+            .iter()
+            .filter(|path| path.starts_with(&dir))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+}
+
+/// Expose the project to the codemod as a global object, which the
+/// `codemod:project` module wraps
+///
+/// Paths the codemod passes are relative to the file it is transforming,
+/// which the module passes along as the first argument.
+pub(crate) fn install<'js, F>(ctx: &Ctx<'js>, project: Arc<Project<F>>) -> rquickjs::Result<()>
+where
+    F: FileSystem + 'static,
+{
+    let native = Object::new(ctx.clone())?;
+    native.set("root", project.root.to_string_lossy().into_owned())?;
+
+    let resolve_project = Arc::clone(&project);
+    let resolve_import = Async(move |from: String, specifier: String| {
+        let project = Arc::clone(&resolve_project);
+        async move { rquickjs::Result::Ok(project.resolve_import(&from, &specifier).await) }
+    });
+    native.set("resolveImport", Function::new(ctx.clone(), resolve_import)?)?;
+
+    let read_project = Arc::clone(&project);
+    let read_file = Async(move |ctx: Ctx<'js>, from: String, path: String| {
+        let project = Arc::clone(&read_project);
+        async move {
+            let (_, content) = project.read_file(&from, &path).await.map_err(|e| {
+                Exception::throw_message(&ctx, &format!("Failed to read file: {e}"))
+            })?;
+            Ok(content)
+        }
+    });
+    native.set("readFile", Function::new(ctx.clone(), read_file)?)?;
+
+    let parse_project = Arc::clone(&project);
+    let parse_file = Async(
+        move |ctx: Ctx<'js>, from: String, path: String, language: Option<String>| {
+            let project = Arc::clone(&parse_project);
+            async move {
+                let (path, content) = project.read_file(&from, &path).await.map_err(|e| {
+                    Exception::throw_message(&ctx, &format!("Failed to read file: {e}"))
+                })?;
+                let language = match language {
+                    Some(language) => language,
+                    None => path
+                        .extension()
+                        .and_then(|extension| {
+                            get_language_from_extension(&format!(
+                                ".{}",
+                                extension.to_string_lossy()
+                            ))
+                        })
+                        .map(|language| language.to_string())
+                        .ok_or_else(|| {
+                            Exception::throw_message(
+                                &ctx,
+                                &format!(
+                                    "Unknown language of {}, pass it to parseFile",
+                                    path.display()
+                                ),
+                            )
+                        })?,
+                };
+                SgRootRjs::try_new(language, content, Some(path.to_string_lossy().into_owned()))
+                    .map_err(|e| Exception::throw_message(&ctx, &format!("Failed to parse: {e}")))
+            }
+        },
+    );
+    native.set("parseFile", Function::new(ctx.clone(), parse_file)?)?;
+
+    let list_project = Arc::clone(&project);
+    let list_files = Async(move |ctx: Ctx<'js>, from: String, dir: Option<String>| {
+        let project = Arc::clone(&list_project);
+        async move {
+            project
+                .list_files(&from, dir.as_deref())
+                .await
+                .map_err(|e| Exception::throw_message(&ctx, &format!("Failed to list files: {e}")))
+        }
+    });
+    native.set("listFiles", Function::new(ctx.clone(), list_files)?)?;
+
+    ctx.globals().set(PROJECT_GLOBAL, native)
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::filesystem::RealFileSystem;
+    use std::fs;
+    use tempfile::TempDir;
+
+    async fn project(root: &Path) -> Project<RealFileSystem> {
+        Project::new(root, Arc::new(RealFileSystem::new()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_project_reads_relative_to_current_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("project");
+        fs::create_dir_all(root.join("src/utils")).unwrap();
+        fs::write(root.join("src/utils/math.ts"), "export const one = 1;").unwrap();
+        let from = root.join("src/index.ts").to_string_lossy().into_owned();
+
+        let project = project(&root).await;
+        let (path, content) = project.read_file(&from, "./utils/math.ts").await.unwrap();
+        assert_eq!(path, root.canonicalize().unwrap().join("src/utils/math.ts"));
+        assert_eq!(content, "export const one = 1;");
+    }
+
+    #[tokio::test]
+    async fn test_project_is_confined_to_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("project");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(temp_dir.path().join("secret.txt"), "secret").unwrap();
+        let from = root.join("index.ts").to_string_lossy().into_owned();
+
+        let project = project(&root).await;
+        assert!(project.read_file(&from, "../secret.txt").await.is_err());
+        assert!(project.list_files(&from, Some("..")).await.is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(temp_dir.path().join("secret.txt"), root.join("link.txt"))
+                .unwrap();
+            assert!(project.read_file(&from, "./link.txt").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_project_resolves_imports_and_lists_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("project");
+        fs::create_dir_all(root.join("src/utils")).unwrap();
+        fs::write(root.join("src/index.ts"), "import { one } from './utils';").unwrap();
+        fs::write(root.join("src/utils/index.ts"), "export const one = 1;").unwrap();
+        let canonical_root = root.canonicalize().unwrap();
+        let from = root.join("src/index.ts").to_string_lossy().into_owned();
+
+        let project = project(&root).await;
+        assert_eq!(
+            project.resolve_import(&from, "./utils").await,
+            Some(
+                canonical_root
+                    .join("src/utils/index.ts")
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+        assert_eq!(project.resolve_import(&from, "./missing").await, None);
+
+        let mut files = project.list_files(&from, None).await.unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                canonical_root
+                    .join("src/index.ts")
+                    .to_string_lossy()
+                    .into_owned(),
+                canonical_root
+                    .join("src/utils/index.ts")
+                    .to_string_lossy()
+                    .into_owned(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_project_lists_files_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("index.ts"), "").unwrap();
+        fs::write(root.join("src/a.ts"), "").unwrap();
+        let canonical_root = root.canonicalize().unwrap();
+        let from = root.join("index.ts").to_string_lossy().into_owned();
+
+        let project = project(&root).await;
+        assert_eq!(project.list_files(&from, None).await.unwrap().len(), 2);
+
+        // Later calls, for the whole project or a directory, reuse the first walk
+        fs::write(root.join("src/b.ts"), "").unwrap();
+        assert_eq!(project.list_files(&from, None).await.unwrap().len(), 2);
+        assert_eq!(
+            project.list_files(&from, Some("./src")).await.unwrap(),
+            vec![canonical_root
+                .join("src/a.ts")
+                .to_string_lossy()
+                .into_owned()]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use super::execution_engine::{error_output, CodemodRuntime, ExecutionOutput, TransformOptions};
+use super::project::Project;
+use crate::sandbox::errors::ExecutionError;
+use crate::sandbox::filesystem::RealFileSystem;
+use crate::sandbox::resolvers::ModuleResolver;
+use ast_grep_language::SupportLang;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+static NEXT_POOL_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Runtime of the pool this thread last ran a codemod for
+    static RUNTIME: RefCell<Option<(u64, Rc<CodemodRuntime>)>> = const { RefCell::new(None) };
+}
+
+/// Runs a codemod on many files, keeping a warmed runtime per worker thread
+///
+/// Setting up a QuickJS runtime and loading the codemod costs far more than
+/// transforming a typical file, so each thread that calls
+/// [`RuntimePool::execute`] sets up one runtime and reuses it for all its
+/// files. A runtime never leaves the thread that created it; it is dropped
+/// when the thread exits or starts working for another pool, and replaced
+/// after a run that failed or left the runtime no longer
+/// [reusable](CodemodRuntime::is_reusable).
+///
+/// Globals are restored between files, but the codemod module isn't loaded
+/// again, so its top-level variables keep their values across the files of
+/// a thread. Codemods are told, in the jssg docs and types, that such state
+/// may only serve as a cache, since which files share a thread varies.
+pub struct RuntimePool<R> {
+    id: u64,
+    script_path: PathBuf,
+    resolver: Arc<R>,
+    options: TransformOptions,
+    project_root: Option<PathBuf>,
+    /// Shared by the runtimes of all threads, so the project is only
+    /// listed once
+    project: OnceCell<Arc<Project<RealFileSystem>>>,
+}
+
+impl<R> RuntimePool<R>
+where
+    R: ModuleResolver + 'static,
+{
+    pub fn new(script_path: &Path, resolver: Arc<R>, options: TransformOptions) -> Self {
+        Self {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            script_path: script_path.to_path_buf(),
+            resolver,
+            options,
+            project_root: None,
+            project: OnceCell::new(),
+        }
+    }
+
+    /// Let the codemod read the project under `root` through `codemod:project`
+    pub fn with_project(mut self, root: &Path) -> Self {
+        self.project_root = Some(root.to_path_buf());
+        self
+    }
+
+    /// Run the codemod on the content of a single file, with the runtime of
+    /// the current thread
+    pub async fn execute(
+        &self,
+        language: SupportLang,
+        file_path: &Path,
+        content: &str,
+    ) -> Result<ExecutionOutput, ExecutionError> {
+        let runtime = match self.runtime().await {
+            Ok(runtime) => runtime,
+            Err(e) => return Ok(error_output(e)),
+        };
+
+        let output = runtime.execute(language, file_path, content).await;
+        if output.is_error() || !runtime.is_reusable() {
+            // Start the next file from a freshly loaded codemod
+            RUNTIME.with(|slot| slot.borrow_mut().take());
+        }
+        Ok(output)
+    }
+
+    /// The runtime of the current thread, set up on first use
+    async fn runtime(&self) -> Result<Rc<CodemodRuntime>, ExecutionError> {
+        let warmed = RUNTIME.with(|slot| match &*slot.borrow() {
+            Some((id, runtime)) if *id == self.id => Some(Rc::clone(runtime)),
+            _ => None,
+        });
+        if let Some(runtime) = warmed {
+            return Ok(runtime);
+        }
+
+        let project = match &self.project_root {
+            Some(root) => Some(Arc::clone(
+                self.project
+                    .get_or_try_init(|| async {
+                        Ok::<_, ExecutionError>(Arc::new(
+                            Project::new(root, Arc::new(RealFileSystem::new())).await?,
+                        ))
+                    })
+                    .await?,
+            )),
+            None => None,
+        };
+        let runtime = Rc::new(
+            CodemodRuntime::new(
+                &self.script_path,
+                Arc::clone(&self.resolver),
+                &self.options,
+                project,
+            )
+            .await?,
+        );
+        RUNTIME.with(|slot| *slot.borrow_mut() = Some((self.id, Rc::clone(&runtime))));
+        Ok(runtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::resolvers::OxcResolver;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn pool(temp_dir: &TempDir, codemod: &str) -> RuntimePool<OxcResolver> {
+        let script_path = temp_dir.path().join("codemod.js");
+        fs::write(&script_path, codemod).unwrap();
+        let resolver = Arc::new(OxcResolver::new(temp_dir.path().to_path_buf(), None).unwrap());
+        RuntimePool::new(&script_path, resolver, TransformOptions::default())
+    }
+
+    /// Run the codemod on a new file, returning what it returned
+    async fn run(
+        pool: &RuntimePool<OxcResolver>,
+        temp_dir: &TempDir,
+        name: &str,
+    ) -> Option<String> {
+        let path = temp_dir.path().join(name);
+        fs::write(&path, "const a = 1;").unwrap();
+        let output = pool
+            .execute(SupportLang::TypeScript, &path, "const a = 1;")
+            .await
+            .unwrap();
+        assert!(output.error.is_none(), "{:?}", output.error);
+        output.content
+    }
+
+    /// Module state carrying over is part of the documented contract, it's
+    /// how codemods cache work between the files of a thread
+    #[tokio::test]
+    async fn test_runtime_is_reused() {
+        let temp_dir = TempDir::new().unwrap();
+        let pool = pool(
+            &temp_dir,
+            r#"
+let calls = 0;
+export default function transform() {
+    calls += 1;
+    return String(calls);
+}
+"#,
+        );
+
+        assert_eq!(run(&pool, &temp_dir, "a.ts").await.as_deref(), Some("1"));
+        assert_eq!(run(&pool, &temp_dir, "b.ts").await.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn test_globals_do_not_leak_between_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let pool = pool(
+            &temp_dir,
+            r#"
+let calls = 0;
+globalThis.config = { mode: "loaded" };
+export default function transform() {
+    calls += 1;
+    const seen = [
+        typeof leaked,
+        typeof hidden,
+        typeof [].patched,
+        JSON.stringify(1),
+        config.mode,
+        calls,
+    ].join(",");
+
+    globalThis.leaked = 1;
+    Object.defineProperty(globalThis, "hidden", { value: 1, configurable: true });
+    Array.prototype.patched = () => {};
+    JSON.stringify = () => "patched";
+    config.mode = "changed";
+    globalThis.config = null;
+    return seen;
+}
+"#,
+        );
+
+        assert_eq!(
+            run(&pool, &temp_dir, "a.ts").await.as_deref(),
+            Some("undefined,undefined,undefined,1,loaded,1")
+        );
+        // Same runtime, as the counter shows, but none of the changes
+        assert_eq!(
+            run(&pool, &temp_dir, "b.ts").await.as_deref(),
+            Some("undefined,undefined,undefined,1,loaded,2")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_runtime_is_recycled_after_transform_throws() {
+        let temp_dir = TempDir::new().unwrap();
+        let pool = pool(
+            &temp_dir,
+            r#"
+let calls = 0;
+export default function transform() {
+    calls += 1;
+    if (CODEMOD_TARGET_FILE_PATH.endsWith("bad.ts")) {
+        throw new Error("bad file");
+    }
+    return String(calls);
+}
+"#,
+        );
+
+        assert_eq!(run(&pool, &temp_dir, "a.ts").await.as_deref(), Some("1"));
+        assert_eq!(run(&pool, &temp_dir, "bad.ts").await, None);
+        // A fresh runtime counts from the start again
+        assert_eq!(run(&pool, &temp_dir, "c.ts").await.as_deref(), Some("1"));
+    }
+}
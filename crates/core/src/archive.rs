@@ -0,0 +1,373 @@
+//! Portable archives of workflow runs
+//!
+//! An archive is a gzipped tarball holding everything a state adapter knows
+//! about a run, plus the workflow bundle it was started from, so that the run
+//! can be inspected or resumed on another machine:
+//!
+//! ```text
+//! manifest.json          archive format version and run ID
+//! workflow_run.json      the run itself
+//! tasks.json             all tasks of the run
+//! state.json             shared state of the run
+//! journal.jsonl          every write to the shared state, oldest first
+//! logs/<task_id>.jsonl   output of each task that printed anything
+//! bundle/...             files of the workflow bundle
+//! ```
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ignore::WalkBuilder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tempfile::{NamedTempFile, TempDir};
+use uuid::Uuid;
+
+use butterflow_models::{
+    Error, LogLine, Result, StateDiff, StateJournalEntry, Task, TaskStatus, WorkflowRun,
+    WorkflowStatus,
+};
+use butterflow_state::StateAdapter;
+
+/// Version of the archive layout written by this build
+///
+/// Version 1 archives, which have no state journal, can still be imported.
+pub const ARCHIVE_VERSION: u32 = 2;
+
+/// Describes the content of an archive
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// Version of the archive layout
+    version: u32,
+
+    /// ID of the archived workflow run
+    workflow_run_id: Uuid,
+
+    /// When the archive was created
+    exported_at: DateTime<Utc>,
+}
+
+/// Write a workflow run, its tasks, state, logs and bundle to `output`
+///
+/// Runs that are still pending or running, or have tasks running, can't be
+/// exported, since their tasks would be archived half way through. The
+/// archive is written to a temporary file first, so `output` is only created
+/// once it is complete.
+pub async fn export_workflow_run(
+    state_adapter: &dyn StateAdapter,
+    workflow_run_id: Uuid,
+    output: &Path,
+) -> Result<()> {
+    let workflow_run = state_adapter.get_workflow_run(workflow_run_id).await?;
+    if matches!(
+        workflow_run.status,
+        WorkflowStatus::Pending | WorkflowStatus::Running
+    ) {
+        return Err(Error::Other(format!(
+            "Workflow run {workflow_run_id} is still {:?}, wait for it to stop before exporting it",
+            workflow_run.status
+        )));
+    }
+
+    let tasks = state_adapter.get_tasks(workflow_run_id).await?;
+    if tasks.iter().any(|task| task.status == TaskStatus::Running) {
+        return Err(Error::Other(format!(
+            "Workflow run {workflow_run_id} still has running tasks, wait for them to finish before exporting it"
+        )));
+    }
+    let state = state_adapter.get_state(workflow_run_id).await?;
+    let journal = state_adapter.get_state_journal(workflow_run_id).await?;
+    let mut logs = Vec::new();
+    for task in &tasks {
+        let lines = state_adapter.get_task_logs(task.id, 0).await?;
+        if !lines.is_empty() {
+            logs.push((task.id, lines));
+        }
+    }
+
+    let output_dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file = NamedTempFile::new_in(output_dir)?;
+    // The output may be written inside the bundle, which must not archive it
+    let skipped = [file.path().canonicalize().ok(), output.canonicalize().ok()];
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        workflow_run_id,
+        exported_at: Utc::now(),
+    };
+    append_json(&mut archive, "manifest.json", &manifest)?;
+    append_json(&mut archive, "workflow_run.json", &workflow_run)?;
+    append_json(&mut archive, "tasks.json", &tasks)?;
+    append_json(&mut archive, "state.json", &state)?;
+    append_file(&mut archive, "journal.jsonl", &to_json_lines(&journal)?)?;
+
+    for (task_id, lines) in logs {
+        append_file(
+            &mut archive,
+            &format!("logs/{task_id}.jsonl"),
+            &to_json_lines(&lines)?,
+        )?;
+    }
+
+    if let Some(bundle_path) = workflow_run
+        .bundle_path
+        .as_ref()
+        .filter(|path| path.is_dir())
+    {
+        // Skip what the bundle ignores, like dependencies and build output
+        let walker = WalkBuilder::new(bundle_path)
+            .hidden(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .build();
+        for entry in walker {
+            let entry = entry.map_err(|e| Error::Other(format!("Failed to read bundle: {e}")))?;
+            let path = entry.path();
+            // Links are skipped, so they can't pull in files from outside the bundle
+            let is_file = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file());
+            if !is_file || skipped.contains(&Some(path.canonicalize()?)) {
+                continue;
+            }
+            let relative_path = path.strip_prefix(bundle_path).unwrap();
+            archive.append_path_with_name(path, Path::new("bundle").join(relative_path))?;
+        }
+    }
+
+    let mut file = archive.into_inner()?.finish()?;
+    file.flush()?;
+    file.persist(output)
+        .map_err(|e| Error::Other(format!("Failed to write {}: {e}", output.display())))?;
+    Ok(())
+}
+
+/// Import a workflow run from an archive written by [`export_workflow_run`]
+///
+/// The bundle is extracted to `bundles_dir/<workflow_run_id>`, which becomes
+/// the bundle path of the imported run. Importing a run that the state
+/// adapter already knows is an error. If the import fails half way, the run
+/// and its bundle are removed again.
+///
+/// Writes to the shared state are replayed from the journal, keeping the
+/// task and step that made each of them; they are recorded at the time of
+/// the import.
+pub async fn import_workflow_run(
+    state_adapter: &dyn StateAdapter,
+    input: &Path,
+    bundles_dir: &Path,
+) -> Result<WorkflowRun> {
+    // Extract to temporary directory first
+    let temp_dir = TempDir::new()?;
+    let temp_path = temp_dir.path();
+    unpack(input, temp_path)?;
+
+    let manifest: Manifest = read_json(temp_path, "manifest.json")?;
+    if !(1..=ARCHIVE_VERSION).contains(&manifest.version) {
+        return Err(Error::Other(format!(
+            "Unsupported archive version {}, expected at most {ARCHIVE_VERSION}",
+            manifest.version
+        )));
+    }
+    let mut workflow_run: WorkflowRun = read_json(temp_path, "workflow_run.json")?;
+    let tasks: Vec<Task> = read_json(temp_path, "tasks.json")?;
+    let state: HashMap<String, Value> = read_json(temp_path, "state.json")?;
+    let journal: Vec<StateJournalEntry> = if manifest.version >= 2 {
+        read_json_lines(&temp_path.join("journal.jsonl"))?
+    } else {
+        Vec::new()
+    };
+
+    if state_adapter
+        .get_workflow_run(workflow_run.id)
+        .await
+        .is_ok()
+    {
+        return Err(Error::Other(format!(
+            "Workflow run {} already exists",
+            workflow_run.id
+        )));
+    }
+
+    let bundle = temp_path.join("bundle");
+    let bundle_path = bundles_dir.join(workflow_run.id.to_string());
+    workflow_run.bundle_path = bundle.is_dir().then(|| bundle_path.clone());
+
+    let result = async {
+        if bundle.is_dir() {
+            if bundle_path.exists() {
+                fs::remove_dir_all(&bundle_path)?;
+            }
+            copy_dir(&bundle, &bundle_path)?;
+        }
+        save_workflow_run(
+            state_adapter,
+            temp_path,
+            &workflow_run,
+            &tasks,
+            &journal,
+            state,
+        )
+        .await
+    }
+    .await;
+
+    if let Err(e) = result {
+        if let Err(delete_error) = state_adapter.delete_workflow_run(workflow_run.id).await {
+            log::warn!(
+                "Failed to remove partially imported workflow run {}: {delete_error}",
+                workflow_run.id
+            );
+        }
+        if bundle.is_dir() {
+            fs::remove_dir_all(&bundle_path).ok();
+        }
+        return Err(e);
+    }
+
+    Ok(workflow_run)
+}
+
+/// Store an extracted workflow run with the state adapter
+async fn save_workflow_run(
+    state_adapter: &dyn StateAdapter,
+    dir: &Path,
+    workflow_run: &WorkflowRun,
+    tasks: &[Task],
+    journal: &[StateJournalEntry],
+    state: HashMap<String, Value>,
+) -> Result<()> {
+    state_adapter.save_workflow_run(workflow_run).await?;
+    for task in tasks {
+        state_adapter.save_task(task).await?;
+
+        let log_path = dir.join("logs").join(format!("{}.jsonl", task.id));
+        if log_path.is_file() {
+            let lines: Vec<LogLine> = read_json_lines(&log_path)?;
+            state_adapter.append_task_logs(task.id, &lines).await?;
+        }
+    }
+
+    for entry in journal {
+        // Revisions start over, so only the writes themselves are replayed
+        let fields = entry
+            .fields
+            .iter()
+            .map(|(key, field)| {
+                let mut field = field.clone();
+                field.expected_revision = None;
+                (key.clone(), field)
+            })
+            .collect();
+        state_adapter
+            .apply_state_diff(&StateDiff {
+                workflow_run_id: workflow_run.id,
+                fields,
+                task_id: entry.task_id,
+                step: entry.step.clone(),
+            })
+            .await?;
+    }
+
+    // Version 1 archives only have the final state, which the journal should
+    // have led to otherwise
+    if state_adapter.get_state(workflow_run.id).await? != state {
+        state_adapter.update_state(workflow_run.id, state).await?;
+    }
+    Ok(())
+}
+
+/// Add a JSON file to the archive
+fn append_json<W: Write, T: Serialize>(
+    archive: &mut tar::Builder<W>,
+    name: &str,
+    value: &T,
+) -> Result<()> {
+    append_file(archive, name, &serde_json::to_vec_pretty(value)?)
+}
+
+/// Serialize values as JSON, one per line
+fn to_json_lines<T: Serialize>(values: &[T]) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    for value in values {
+        serde_json::to_writer(&mut content, value)?;
+        content.push(b'\n');
+    }
+    Ok(content)
+}
+
+/// Add a file with the given content to the archive
+fn append_file<W: Write>(archive: &mut tar::Builder<W>, name: &str, content: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, name, content)?;
+    Ok(())
+}
+
+/// Read a JSON file extracted from an archive
+fn read_json<T: DeserializeOwned>(dir: &Path, name: &str) -> Result<T> {
+    let content = fs::read_to_string(dir.join(name))
+        .map_err(|e| Error::Other(format!("Invalid archive, failed to read {name}: {e}")))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Read a file of JSON values, one per line
+fn read_json_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        Error::Other(format!(
+            "Invalid archive, failed to read {}: {e}",
+            path.display()
+        ))
+    })?;
+    Ok(content
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+/// Extract an archive to `dir`
+///
+/// Archives with links are rejected, since reading through them would pull
+/// files from outside the archive into the imported run.
+fn unpack(input: &Path, dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(input)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            return Err(Error::Other(format!(
+                "Invalid archive, {} is a link",
+                entry.path()?.display()
+            )));
+        }
+        entry.unpack_in(dir)?;
+    }
+    Ok(())
+}
+
+/// Copy a directory and the regular files below it
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.map_err(|e| Error::Other(format!("Failed to read bundle: {e}")))?;
+        let dst_path = dst.join(entry.path().strip_prefix(src).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dst_path)?;
+        } else if entry.file_type().is_file() {
+            fs::copy(entry.path(), &dst_path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use butterflow_models::Workflow;
+
+/// Limits on the number of tasks of a workflow run executing at the same time
+///
+/// The global limit is the smallest of the workflow's `concurrency` and the
+/// configured maximum; nodes can further limit their own tasks with
+/// `strategy.max_parallel`.
+pub struct ConcurrencyLimits {
+    global: Option<Arc<Semaphore>>,
+    nodes: HashMap<String, Arc<Semaphore>>,
+}
+
+/// Permits held by a running task, released when dropped
+pub struct TaskPermits {
+    _global: Option<OwnedSemaphorePermit>,
+    _node: Option<OwnedSemaphorePermit>,
+}
+
+impl ConcurrencyLimits {
+    /// Create the limits for a workflow
+    pub fn new(workflow: &Workflow, max_concurrency: Option<usize>) -> Self {
+        let global = match (workflow.concurrency, max_concurrency) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (limit, None) | (None, limit) => limit,
+        };
+
+        let nodes = workflow
+            .nodes
+            .iter()
+            .filter_map(|node| {
+                let max_parallel = node.strategy.as_ref()?.max_parallel?;
+                Some((node.id.clone(), Arc::new(Semaphore::new(max_parallel))))
+            })
+            .collect();
+
+        Self {
+            global: global.map(|limit| Arc::new(Semaphore::new(limit))),
+            nodes,
+        }
+    }
+
+    /// Reserve a slot for a task of the given node, if one is free
+    pub fn try_acquire(&self, node_id: &str) -> Option<TaskPermits> {
+        let node = match self.nodes.get(node_id) {
+            Some(semaphore) => Some(Arc::clone(semaphore).try_acquire_owned().ok()?),
+            None => None,
+        };
+        let global = match &self.global {
+            Some(semaphore) => Some(Arc::clone(semaphore).try_acquire_owned().ok()?),
+            None => None,
+        };
+
+        Some(TaskPermits {
+            _global: global,
+            _node: node,
+        })
+    }
+}
@@ -4,6 +4,10 @@ use std::{
     sync::Arc,
 };
 
+use butterflow_models::{parse_duration, Error, Result, WorkflowRun, WorkflowStatus};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
 use crate::{execution::ProgressCallback, registry::RegistryClient};
 
 pub type PreRunCallback = Box<dyn Fn(&Path, bool) + Send + Sync>;
@@ -20,6 +24,10 @@ pub struct WorkflowRunConfig {
     pub pre_run_callback: Arc<Option<PreRunCallback>>,
     pub registry_client: RegistryClient,
     pub dry_run: bool,
+    /// Maximum number of tasks running at the same time, on top of the workflow's `concurrency`
+    pub max_concurrency: Option<usize>,
+    /// Stored workflow runs to delete when the engine starts
+    pub retention_policy: Option<RetentionPolicy>,
 }
 
 impl Default for WorkflowRunConfig {
@@ -34,6 +42,85 @@ impl Default for WorkflowRunConfig {
             pre_run_callback: Arc::new(None),
             registry_client: RegistryClient::default(),
             dry_run: false,
+            max_concurrency: None,
+            retention_policy: None,
+        }
+    }
+}
+
+/// Which stored workflow runs to delete
+///
+/// Runs that are pending or running are never deleted. A policy needs
+/// `older_than` or `keep_last`, since without either of them it would
+/// delete every finished run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Only delete runs that ended longer ago than this, e.g. `30d`
+    #[serde(default)]
+    pub older_than: Option<String>,
+
+    /// Only delete runs with one of these statuses, by default runs that
+    /// completed, failed or were canceled
+    #[serde(default)]
+    pub statuses: Vec<WorkflowStatus>,
+
+    /// Keep this many of the most recent matching runs, however old they are
+    #[serde(default)]
+    pub keep_last: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Check that the policy limits what it deletes
+    pub fn validate(&self) -> Result<()> {
+        if self.older_than.is_none() && self.keep_last.is_none() {
+            return Err(Error::Other(
+                "Retention policy needs `older_than` or `keep_last`, otherwise it deletes every finished run"
+                    .to_string(),
+            ));
         }
+        Ok(())
+    }
+
+    /// Pick the runs to delete out of `workflow_runs`
+    pub fn select(&self, mut workflow_runs: Vec<WorkflowRun>) -> Result<Vec<WorkflowRun>> {
+        self.validate()?;
+        let cutoff = match &self.older_than {
+            Some(age) => {
+                let age = parse_duration(age)
+                    .ok()
+                    .and_then(|age| chrono::Duration::from_std(age).ok())
+                    .ok_or_else(|| Error::Other(format!("Invalid retention age `{age}`")))?;
+                Some(Utc::now() - age)
+            }
+            None => None,
+        };
+        let statuses: &[WorkflowStatus] = if self.statuses.is_empty() {
+            &[
+                WorkflowStatus::Completed,
+                WorkflowStatus::Failed,
+                WorkflowStatus::Canceled,
+            ]
+        } else {
+            &self.statuses
+        };
+
+        // Newest first, so the runs to keep come first
+        workflow_runs.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        Ok(workflow_runs
+            .into_iter()
+            .filter(|run| {
+                statuses.contains(&run.status)
+                    && !matches!(
+                        run.status,
+                        WorkflowStatus::Pending | WorkflowStatus::Running
+                    )
+            })
+            .skip(self.keep_last.unwrap_or(0))
+            .filter(|run| {
+                cutoff.map_or(true, |cutoff| {
+                    run.ended_at.unwrap_or(run.started_at) < cutoff
+                })
+            })
+            .collect())
     }
 }
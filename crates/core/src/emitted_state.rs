@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use butterflow_models::{DiffOperation, FieldDiff, Result};
+use butterflow_state::apply_operation;
+use codemod_sandbox::sandbox::engine::StateWrite;
+use serde_json::Value;
+
+/// State writes a jssg codemod emitted over all the files of a step
+///
+/// Files are processed in parallel, so writes are combined per key rather
+/// than kept in a global order: a set comes first, whichever file made it,
+/// and the increments and appends of all files go on top of it. Increments
+/// add up and appends are collected. If several files set a key, the last
+/// one processed wins.
+#[derive(Debug, Default)]
+pub struct EmittedState {
+    writes: HashMap<String, KeyWrites>,
+}
+
+/// Writes to a single key
+#[derive(Debug, Default)]
+struct KeyWrites {
+    /// Value the key is set to before the updates
+    set: Option<Value>,
+
+    /// Increments and appends, with consecutive increments added up
+    updates: Vec<StateWrite>,
+}
+
+impl EmittedState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the writes of a single file, in the order they were emitted
+    pub fn add(&mut self, writes: Vec<StateWrite>) {
+        for write in writes {
+            let key_writes = self.writes.entry(write.key().to_string()).or_default();
+            if let StateWrite::Set { value, .. } = write {
+                key_writes.set = Some(value);
+                continue;
+            }
+            if let (
+                Some(StateWrite::Increment { value: total, .. }),
+                StateWrite::Increment { value, .. },
+            ) = (key_writes.updates.last_mut(), &write)
+            {
+                if let Some(sum) = add_numbers(total, value) {
+                    *total = sum;
+                    continue;
+                }
+            }
+            key_writes.updates.push(write);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Fields of a state diff applying the writes on top of `state`
+    ///
+    /// Keys the codemod sets are overwritten. The other keys are computed
+    /// from their current value, so their fields expect the revision from
+    /// `revisions`; the diff conflicts if they changed in the meantime.
+    pub fn to_fields(
+        &self,
+        state: &HashMap<String, Value>,
+        revisions: &HashMap<String, u64>,
+    ) -> Result<HashMap<String, FieldDiff>> {
+        let mut fields = HashMap::new();
+        for (key, writes) in &self.writes {
+            let (mut value, expected_revision) = match &writes.set {
+                Some(value) => (Some(value.clone()), None),
+                None => (
+                    state.get(key).cloned(),
+                    Some(revisions.get(key).copied().unwrap_or(0)),
+                ),
+            };
+            for write in &writes.updates {
+                value = apply_operation(key, value, &field_diff(write))?;
+            }
+
+            fields.insert(
+                key.clone(),
+                FieldDiff {
+                    operation: if value.is_some() {
+                        DiffOperation::Update
+                    } else {
+                        DiffOperation::Remove
+                    },
+                    value,
+                    expected_revision,
+                },
+            );
+        }
+        Ok(fields)
+    }
+}
+
+/// The diff of a field that a single write amounts to
+fn field_diff(write: &StateWrite) -> FieldDiff {
+    let (operation, value) = match write {
+        StateWrite::Set { value, .. } => (DiffOperation::Update, value),
+        StateWrite::Append { value, .. } => (DiffOperation::Append, value),
+        StateWrite::Increment { value, .. } => (DiffOperation::Increment, value),
+    };
+    FieldDiff {
+        operation,
+        value: Some(value.clone()),
+        expected_revision: None,
+    }
+}
+
+/// Sum of two increments, kept an integer unless either is fractional
+///
+/// `None` if either isn't a number, which the state rejects when applying.
+pub(crate) fn add_numbers(a: &Value, b: &Value) -> Option<Value> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        if let Some(sum) = a.checked_add(b) {
+            return Some(Value::from(sum));
+        }
+    }
+    Some(Value::from(a.as_f64()? + b.as_f64()?))
+}
@@ -1,30 +1,39 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::future::Future;
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use crate::config::WorkflowRunConfig;
+use crate::archive;
+use crate::concurrency::{ConcurrencyLimits, TaskPermits};
+use crate::config::{RetentionPolicy, WorkflowRunConfig};
+use crate::emitted_state::EmittedState;
 use crate::execution::CodemodExecutionConfig;
-use crate::file_ops::AsyncFileWriter;
-use crate::utils::validate_workflow;
+use crate::file_ops::{AsyncFileWriter, FileChanges};
+use crate::utils::{
+    get_imported_bundles_dir, get_workflow_source, parse_state_outputs, validate_workflow,
+};
 use chrono::Utc;
 use codemod_sandbox::{scan_file_with_combined_scan, with_combined_scan};
 use log::{debug, error, info, warn};
 use std::path::Path;
 use tokio::fs::read_to_string;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time;
 use uuid::Uuid;
 
 use crate::registry::ResolvedPackage;
+use butterflow_models::expression::to_display_string;
 use butterflow_models::runtime::RuntimeType;
 use butterflow_models::step::{StepAction, UseAstGrep, UseCodemod, UseJSAstGrep};
 use butterflow_models::{
-    resolve_variables, DiffOperation, Error, FieldDiff, Node, Result, StateDiff, Task, TaskDiff,
-    TaskStatus, Workflow, WorkflowRun, WorkflowRunDiff, WorkflowStatus,
+    evaluate_condition, parse_duration, resolve_variables, Backoff, BackoffType, DiffOperation,
+    Error, FieldDiff, LogLine, Node, Result, RetryPolicy, StateDiff, StateJournalEntry, Step, Task,
+    TaskDiff, TaskStatus, VariableContext, Workflow, WorkflowRun, WorkflowRunDiff, WorkflowStatus,
 };
+#[cfg(any(feature = "docker", feature = "podman"))]
+use butterflow_runners::container::ContainerConfig;
 use butterflow_runners::direct_runner::DirectRunner;
 #[cfg(feature = "docker")]
 use butterflow_runners::docker_runner::DockerRunner;
@@ -33,11 +42,10 @@ use butterflow_runners::podman_runner::PodmanRunner;
 use butterflow_runners::Runner;
 use butterflow_scheduler::Scheduler;
 use butterflow_state::local_adapter::LocalStateAdapter;
-use butterflow_state::StateAdapter;
+use butterflow_state::{RunPage, RunQuery, StateAdapter};
 use codemod_sandbox::{
     sandbox::{
-        engine::{execution_engine::execute_codemod_with_quickjs, ExecutionStats},
-        filesystem::RealFileSystem,
+        engine::{ExecutionStats, RuntimePool, TransformOptions},
         resolvers::OxcResolver,
     },
     utils::project_discovery::find_tsconfig,
@@ -46,7 +54,7 @@ use codemod_sandbox::{
 /// Workflow engine
 pub struct Engine {
     /// State adapter for persisting workflow state
-    state_adapter: Arc<Mutex<Box<dyn StateAdapter>>>,
+    state_adapter: Arc<dyn StateAdapter>,
 
     scheduler: Scheduler,
 
@@ -56,8 +64,22 @@ pub struct Engine {
 
     /// Async file writer for batched I/O operations
     file_writer: Arc<AsyncFileWriter>,
+
+    /// Notifies execution loops and waiters that a workflow run changed
+    events: broadcast::Sender<Uuid>,
+
+    /// Workflow runs with an execution loop in this process, and whether the
+    /// loop was asked to look at the run again before it stops
+    execution_loops: Arc<Mutex<HashMap<Uuid, bool>>>,
 }
 
+/// Number of run events kept for subscribers that fall behind
+const EVENT_CAPACITY: usize = 256;
+
+/// How long to wait for an event before reading a run again, which picks up
+/// changes made by other processes such as a cancel
+const EVENT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
 /// Represents a codemod dependency chain for cycle detection
 #[derive(Debug, Clone)]
 pub struct CodemodDependency {
@@ -74,8 +96,7 @@ impl Default for Engine {
 impl Engine {
     /// Create a new engine with a local state adapter
     pub fn new() -> Self {
-        let state_adapter: Arc<Mutex<Box<dyn StateAdapter>>> =
-            Arc::new(Mutex::new(Box::new(LocalStateAdapter::new())));
+        let state_adapter: Arc<dyn StateAdapter> = Arc::new(LocalStateAdapter::new());
 
         Self {
             state_adapter: Arc::clone(&state_adapter),
@@ -83,13 +104,14 @@ impl Engine {
             workflow_run_config: WorkflowRunConfig::default(),
             execution_stats: Arc::new(ExecutionStats::default()),
             file_writer: Arc::new(AsyncFileWriter::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            execution_loops: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
     /// Create a new engine with a local state adapter
     pub fn with_workflow_run_config(workflow_run_config: WorkflowRunConfig) -> Self {
-        let state_adapter: Arc<Mutex<Box<dyn StateAdapter>>> =
-            Arc::new(Mutex::new(Box::new(LocalStateAdapter::new())));
+        let state_adapter: Arc<dyn StateAdapter> = Arc::new(LocalStateAdapter::new());
 
         Self {
             state_adapter: Arc::clone(&state_adapter),
@@ -97,6 +119,8 @@ impl Engine {
             workflow_run_config,
             execution_stats: Arc::new(ExecutionStats::default()),
             file_writer: Arc::new(AsyncFileWriter::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            execution_loops: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -105,7 +129,7 @@ impl Engine {
         state_adapter: Box<dyn StateAdapter>,
         workflow_run_config: WorkflowRunConfig,
     ) -> Self {
-        let state_adapter: Arc<Mutex<Box<dyn StateAdapter>>> = Arc::new(Mutex::new(state_adapter));
+        let state_adapter: Arc<dyn StateAdapter> = Arc::from(state_adapter);
 
         Self {
             state_adapter: Arc::clone(&state_adapter),
@@ -113,6 +137,8 @@ impl Engine {
             workflow_run_config,
             execution_stats: Arc::new(ExecutionStats::default()),
             file_writer: Arc::new(AsyncFileWriter::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            execution_loops: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -121,12 +147,25 @@ impl Engine {
         self.workflow_run_config.workflow_file_path.clone()
     }
 
+    /// Target directory with symlinks resolved, or as configured if it
+    /// doesn't exist
+    ///
+    /// Runs record it so they can be listed by the directory they ran on,
+    /// and jssg steps compare the canonical paths of the files they touch
+    /// against it.
+    fn target_root(&self) -> PathBuf {
+        self.workflow_run_config
+            .target_path
+            .canonicalize()
+            .unwrap_or_else(|_| self.workflow_run_config.target_path.clone())
+    }
+
     /// Create initial tasks for all nodes
     async fn create_initial_tasks(&self, workflow_run: &WorkflowRun) -> Result<()> {
         let tasks = self.scheduler.calculate_initial_tasks(workflow_run).await?;
 
         for task in tasks {
-            self.state_adapter.lock().await.save_task(&task).await?;
+            self.state_adapter.save_task(&task).await?;
 
             if task.is_master {
                 self.update_matrix_master_status(task.id).await?;
@@ -152,24 +191,20 @@ impl Engine {
             workflow: workflow.clone(),
             status: WorkflowStatus::Pending,
             params: params.clone(),
+            source: Some(get_workflow_source(
+                &self.workflow_run_config.workflow_file_path,
+                bundle_path.as_deref(),
+            )),
+            target_path: Some(self.target_root()),
             bundle_path,
             tasks: Vec::new(),
             started_at: Utc::now(),
             ended_at: None,
         };
 
-        self.state_adapter
-            .lock()
-            .await
-            .save_workflow_run(&workflow_run)
-            .await?;
+        self.state_adapter.save_workflow_run(&workflow_run).await?;
 
-        let engine = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = engine.execute_workflow(workflow_run_id).await {
-                error!("Workflow execution failed: {e}");
-            }
-        });
+        self.start_execution(workflow_run_id);
 
         Ok(workflow_run_id)
     }
@@ -177,16 +212,11 @@ impl Engine {
     /// Resume a workflow run
     pub async fn resume_workflow(&self, workflow_run_id: Uuid, task_ids: Vec<Uuid>) -> Result<()> {
         // TODO: Do we need this?
-        let _workflow_run = self
-            .state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await?;
+        let _workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
 
         let mut triggered = false;
         for task_id in task_ids {
-            let task = self.state_adapter.lock().await.get_task(task_id).await?;
+            let task = self.state_adapter.get_task(task_id).await?;
 
             // If the task is awaiting trigger we can trigger it
             // OR if it is in a terminal state, we can trigger it again
@@ -194,29 +224,11 @@ impl Engine {
                 || task.status == TaskStatus::Completed
                 || task.status == TaskStatus::Failed
             {
-                let mut fields = HashMap::new();
-                fields.insert(
-                    "status".to_string(),
-                    FieldDiff {
-                        operation: DiffOperation::Update,
-                        value: Some(serde_json::to_value(TaskStatus::Pending)?),
-                    },
-                );
-                let task_diff = TaskDiff { task_id, fields };
-
+                // The execution loop starts it once the concurrency limits allow
                 self.state_adapter
-                    .lock()
-                    .await
-                    .apply_task_diff(&task_diff)
+                    .apply_task_diff(&trigger_diff(task_id)?)
                     .await?;
 
-                let engine = self.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = engine.execute_task(task_id).await {
-                        error!("Task execution failed: {e}");
-                    }
-                });
-
                 triggered = true;
                 info!("Triggered task {} ({})", task_id, task.node_id);
             } else {
@@ -234,6 +246,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(WorkflowStatus::Running)?),
+                expected_revision: None,
             },
         );
         let workflow_run_diff = WorkflowRunDiff {
@@ -242,17 +255,10 @@ impl Engine {
         };
 
         self.state_adapter
-            .lock()
-            .await
             .apply_workflow_run_diff(&workflow_run_diff)
             .await?;
 
-        let engine = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = engine.execute_workflow(workflow_run_id).await {
-                error!("Workflow execution failed: {e}");
-            }
-        });
+        self.start_execution(workflow_run_id);
 
         Ok(())
     }
@@ -260,19 +266,9 @@ impl Engine {
     /// Trigger all awaiting tasks in a workflow run
     pub async fn trigger_all(&self, workflow_run_id: Uuid) -> Result<bool> {
         // TODO: Do we need this?
-        let _workflow_run = self
-            .state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await?;
+        let _workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
 
-        let tasks = self
-            .state_adapter
-            .lock()
-            .await
-            .get_tasks(workflow_run_id)
-            .await?;
+        let tasks = self.state_adapter.get_tasks(workflow_run_id).await?;
 
         let awaiting_tasks: Vec<&Task> = tasks
             .iter()
@@ -296,6 +292,7 @@ impl Engine {
                     FieldDiff {
                         operation: DiffOperation::Update,
                         value: Some(serde_json::to_value(WorkflowStatus::Completed)?),
+                        expected_revision: None,
                     },
                 );
                 let workflow_run_diff = WorkflowRunDiff {
@@ -304,8 +301,6 @@ impl Engine {
                 };
 
                 self.state_adapter
-                    .lock()
-                    .await
                     .apply_workflow_run_diff(&workflow_run_diff)
                     .await?;
 
@@ -320,33 +315,11 @@ impl Engine {
 
         let mut triggered = false;
         for task in awaiting_tasks {
-            let mut fields = HashMap::new();
-            fields.insert(
-                "status".to_string(),
-                FieldDiff {
-                    operation: DiffOperation::Update,
-                    value: Some(serde_json::to_value(TaskStatus::Pending)?),
-                },
-            );
-            let task_diff = TaskDiff {
-                task_id: task.id,
-                fields,
-            };
-
+            // The execution loop starts it once the concurrency limits allow
             self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
+                .apply_task_diff(&trigger_diff(task.id)?)
                 .await?;
 
-            let engine = self.clone();
-            let task_id = task.id;
-            tokio::spawn(async move {
-                if let Err(e) = engine.execute_task(task_id).await {
-                    error!("Task execution failed: {e}");
-                }
-            });
-
             triggered = true;
             info!("Triggered task {} ({})", task.id, task.node_id);
         }
@@ -363,6 +336,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(WorkflowStatus::Running)?),
+                expected_revision: None,
             },
         );
         let workflow_run_diff = WorkflowRunDiff {
@@ -371,29 +345,17 @@ impl Engine {
         };
 
         self.state_adapter
-            .lock()
-            .await
             .apply_workflow_run_diff(&workflow_run_diff)
             .await?;
 
-        let engine = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = engine.execute_workflow(workflow_run_id).await {
-                error!("Workflow execution failed: {e}");
-            }
-        });
+        self.start_execution(workflow_run_id);
         Ok(true)
     }
 
     /// Cancel a workflow run
     pub async fn cancel_workflow(&self, workflow_run_id: Uuid) -> Result<()> {
         // Get the workflow run
-        let workflow_run = self
-            .state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await?;
+        let workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
 
         // Check if the workflow is running or awaiting triggers
         if workflow_run.status != WorkflowStatus::Running
@@ -405,12 +367,7 @@ impl Engine {
         }
 
         // Get all tasks
-        let tasks = self
-            .state_adapter
-            .lock()
-            .await
-            .get_tasks(workflow_run_id)
-            .await?;
+        let tasks = self.state_adapter.get_tasks(workflow_run_id).await?;
 
         // Cancel all running tasks
         for task in tasks.iter().filter(|t| t.status == TaskStatus::Running) {
@@ -421,6 +378,7 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(TaskStatus::Failed)?),
+                    expected_revision: None,
                 },
             );
             fields.insert(
@@ -428,6 +386,7 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Add,
                     value: Some(serde_json::to_value("Canceled by user")?),
+                    expected_revision: None,
                 },
             );
             let task_diff = TaskDiff {
@@ -436,11 +395,7 @@ impl Engine {
             };
 
             // Apply the diff
-            self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
-                .await?;
+            self.state_adapter.apply_task_diff(&task_diff).await?;
 
             info!("Canceled task {} ({})", task.id, task.node_id);
         }
@@ -452,6 +407,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(WorkflowStatus::Canceled)?),
+                expected_revision: None,
             },
         );
         fields.insert(
@@ -459,6 +415,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(Utc::now())?),
+                expected_revision: None,
             },
         );
         let workflow_run_diff = WorkflowRunDiff {
@@ -468,50 +425,166 @@ impl Engine {
 
         // Apply the diff
         self.state_adapter
-            .lock()
-            .await
             .apply_workflow_run_diff(&workflow_run_diff)
             .await?;
+        self.notify(workflow_run_id);
 
         Ok(())
     }
 
     /// Get workflow run status
     pub async fn get_workflow_status(&self, workflow_run_id: Uuid) -> Result<WorkflowStatus> {
-        let workflow_run = self
-            .state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await?;
+        let workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
         Ok(workflow_run.status)
     }
 
+    /// Wait until a workflow run stops running and return its status
+    ///
+    /// Returns as soon as the run completes, fails, is canceled or pauses for
+    /// manual triggers.
+    pub async fn wait_for_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowStatus> {
+        let mut events = self.events.subscribe();
+        loop {
+            let status = self.get_workflow_status(workflow_run_id).await?;
+            if !matches!(status, WorkflowStatus::Pending | WorkflowStatus::Running) {
+                return Ok(status);
+            }
+            if !self.next_event(&mut events, workflow_run_id).await {
+                return Ok(status);
+            }
+        }
+    }
+
     /// Get workflow run
     pub async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
-        self.state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await
+        self.state_adapter.get_workflow_run(workflow_run_id).await
     }
 
     /// Get tasks for a workflow run
     pub async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
+        self.state_adapter.get_tasks(workflow_run_id).await
+    }
+
+    /// Get the output lines of a task, skipping the first `offset` lines
+    pub async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>> {
+        self.state_adapter.get_task_logs(task_id, offset).await
+    }
+
+    /// Get the current state of a workflow run
+    pub async fn get_state(
+        &self,
+        workflow_run_id: Uuid,
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        self.state_adapter.get_state(workflow_run_id).await
+    }
+
+    /// Get every write to the state of a workflow run, oldest first
+    pub async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
+        self.state_adapter.get_state_journal(workflow_run_id).await
+    }
+
+    /// Rebuild the state of a workflow run as it was right after the last
+    /// write of a task
+    pub async fn get_state_at(
+        &self,
+        workflow_run_id: Uuid,
+        task_id: Uuid,
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        let journal = self
+            .state_adapter
+            .get_state_journal(workflow_run_id)
+            .await?;
+        let last = journal
+            .iter()
+            .rposition(|entry| entry.task_id == Some(task_id))
+            .ok_or_else(|| {
+                Error::Other(format!(
+                    "Task {task_id} did not write to the state of workflow run {workflow_run_id}"
+                ))
+            })?;
+
+        let mut state = HashMap::new();
+        for entry in &journal[..=last] {
+            entry.replay(&mut state);
+        }
+        Ok(state)
+    }
+
+    /// Delete a workflow run along with its tasks, state, logs and the
+    /// bundle it was imported with
+    pub async fn delete_workflow_run(&self, workflow_run_id: Uuid) -> Result<()> {
+        let workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
+        if matches!(
+            workflow_run.status,
+            WorkflowStatus::Pending | WorkflowStatus::Running
+        ) {
+            return Err(Error::Other(format!(
+                "Workflow run {workflow_run_id} is still {:?}, cancel it before deleting it",
+                workflow_run.status
+            )));
+        }
         self.state_adapter
-            .lock()
-            .await
-            .get_tasks(workflow_run_id)
-            .await
+            .delete_workflow_run(workflow_run_id)
+            .await?;
+        remove_imported_bundle(workflow_run_id);
+        Ok(())
+    }
+
+    /// Find the stored workflow runs a retention policy would delete
+    pub async fn find_prunable_workflow_runs(
+        &self,
+        policy: &RetentionPolicy,
+    ) -> Result<Vec<WorkflowRun>> {
+        let workflow_runs = self.state_adapter.list_workflow_runs(usize::MAX).await?;
+        policy.select(workflow_runs)
+    }
+
+    /// Delete the stored workflow runs selected by a retention policy,
+    /// returning the deleted runs
+    pub async fn prune_workflow_runs(&self, policy: &RetentionPolicy) -> Result<Vec<WorkflowRun>> {
+        let workflow_runs = self.find_prunable_workflow_runs(policy).await?;
+        let ids: Vec<Uuid> = workflow_runs.iter().map(|run| run.id).collect();
+        self.state_adapter.delete_workflow_runs(&ids).await?;
+        for id in ids {
+            remove_imported_bundle(id);
+        }
+        Ok(workflow_runs)
+    }
+
+    /// Apply the configured retention policy, if any
+    ///
+    /// Meant to be called before starting a run, not by dry runs or commands
+    /// that only read runs.
+    pub async fn apply_retention_policy(&self) -> Result<Vec<WorkflowRun>> {
+        match &self.workflow_run_config.retention_policy {
+            Some(policy) => self.prune_workflow_runs(policy).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Write a finished or paused workflow run to a portable archive
+    pub async fn export_workflow_run(&self, workflow_run_id: Uuid, output: &Path) -> Result<()> {
+        archive::export_workflow_run(self.state_adapter.as_ref(), workflow_run_id, output).await
+    }
+
+    /// Import a workflow run from an archive, extracting its bundle below
+    /// `bundles_dir`
+    pub async fn import_workflow_run(
+        &self,
+        input: &Path,
+        bundles_dir: &Path,
+    ) -> Result<WorkflowRun> {
+        archive::import_workflow_run(self.state_adapter.as_ref(), input, bundles_dir).await
     }
 
     /// List workflow runs
     pub async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>> {
-        self.state_adapter
-            .lock()
-            .await
-            .list_workflow_runs(limit)
-            .await
+        self.state_adapter.list_workflow_runs(limit).await
+    }
+
+    /// Find the workflow runs matching a query, a page at a time
+    pub async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage> {
+        self.state_adapter.query_workflow_runs(query).await
     }
 
     /// Validate codemod dependencies to prevent infinite recursion cycles
@@ -638,13 +711,61 @@ impl Engine {
 
     /// Execute a workflow
     async fn execute_workflow(&self, workflow_run_id: Uuid) -> Result<()> {
+        // Tasks started by this loop, which may not be marked as running yet
+        let mut started_tasks = HashSet::new();
+
+        loop {
+            let result = self
+                .run_execution_loop(workflow_run_id, &mut started_tasks)
+                .await;
+
+            // Look again if tasks were triggered while the loop was stopping
+            let mut execution_loops = self.execution_loops.lock().unwrap();
+            if result.is_ok() && execution_loops.get(&workflow_run_id) == Some(&true) {
+                execution_loops.insert(workflow_run_id, false);
+                continue;
+            }
+            execution_loops.remove(&workflow_run_id);
+            return result;
+        }
+    }
+
+    /// Start the execution loop of a workflow run, unless one is running
+    ///
+    /// A running loop is woken instead, so tasks are only ever started by one
+    /// loop and under one set of concurrency limits.
+    fn start_execution(&self, workflow_run_id: Uuid) {
+        {
+            let mut execution_loops = self.execution_loops.lock().unwrap();
+            if let Some(look_again) = execution_loops.get_mut(&workflow_run_id) {
+                *look_again = true;
+                drop(execution_loops);
+                self.notify(workflow_run_id);
+                return;
+            }
+            execution_loops.insert(workflow_run_id, false);
+        }
+
+        let engine = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.execute_workflow(workflow_run_id).await {
+                error!("Workflow execution failed: {e}");
+            }
+        });
+    }
+
+    /// Schedule the tasks of a workflow run until it finishes or waits for
+    /// triggers
+    async fn run_execution_loop(
+        &self,
+        workflow_run_id: Uuid,
+        started_tasks: &mut HashSet<Uuid>,
+    ) -> Result<()> {
+        // Listen before anything is spawned, so no task completion is missed
+        let mut events = self.events.subscribe();
+
         // Get the workflow run
-        let workflow_run = self
-            .state_adapter
-            .lock()
-            .await
-            .get_workflow_run(workflow_run_id)
-            .await?;
+        let workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
 
         // Create a workflow run diff to update the status
         let mut fields = HashMap::new();
@@ -653,6 +774,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(WorkflowStatus::Running)?),
+                expected_revision: None,
             },
         );
         let workflow_run_diff = WorkflowRunDiff {
@@ -662,41 +784,34 @@ impl Engine {
 
         // Apply the diff
         self.state_adapter
-            .lock()
-            .await
             .apply_workflow_run_diff(&workflow_run_diff)
             .await?;
 
         info!("Starting workflow run {workflow_run_id}");
 
         // Create tasks for all nodes if they don't exist yet
-        let existing_tasks = self
-            .state_adapter
-            .lock()
-            .await
-            .get_tasks(workflow_run_id)
-            .await?;
+        let existing_tasks = self.state_adapter.get_tasks(workflow_run_id).await?;
         if existing_tasks.is_empty() {
             self.create_initial_tasks(&workflow_run).await?;
         }
 
+        // Bound the number of tasks executing at the same time
+        let limits = ConcurrencyLimits::new(
+            &workflow_run.workflow,
+            self.workflow_run_config.max_concurrency,
+        );
+
         // Main execution loop
         loop {
             // Get the current workflow run state
-            let current_workflow_run = self
-                .state_adapter
-                .lock()
-                .await
-                .get_workflow_run(workflow_run_id)
-                .await?;
+            let current_workflow_run = self.state_adapter.get_workflow_run(workflow_run_id).await?;
+            if current_workflow_run.status == WorkflowStatus::Canceled {
+                info!("Workflow run {workflow_run_id} was canceled");
+                break;
+            }
 
             // Get all tasks
-            let current_tasks = self
-                .state_adapter
-                .lock()
-                .await
-                .get_tasks(workflow_run_id)
-                .await?;
+            let current_tasks = self.state_adapter.get_tasks(workflow_run_id).await?;
 
             // --- Recompile matrix tasks based on current state ---
             // This ensures the task list reflects the latest state before scheduling
@@ -710,20 +825,13 @@ impl Engine {
             }
 
             // Get potentially updated tasks after recompilation
-            let tasks_after_recompilation = self
-                .state_adapter
-                .lock()
-                .await
-                .get_tasks(workflow_run_id)
-                .await?;
+            let tasks_after_recompilation = self.state_adapter.get_tasks(workflow_run_id).await?;
             // --- End of Recompilation ---
 
-            // Check if all tasks are completed or failed
-            let all_done = tasks_after_recompilation.iter().all(|t| {
-                t.status == TaskStatus::Completed
-                    || t.status == TaskStatus::Failed
-                    || t.status == TaskStatus::WontDo
-            });
+            // Check if all tasks are completed, failed or won't run
+            let all_done = tasks_after_recompilation
+                .iter()
+                .all(|t| t.status.is_terminal());
 
             if all_done {
                 // Check if any tasks failed
@@ -742,6 +850,7 @@ impl Engine {
                         } else {
                             WorkflowStatus::Completed
                         })?),
+                        expected_revision: None,
                     },
                 );
                 fields.insert(
@@ -749,6 +858,7 @@ impl Engine {
                     FieldDiff {
                         operation: DiffOperation::Update,
                         value: Some(serde_json::to_value(Utc::now())?),
+                        expected_revision: None,
                     },
                 );
                 let workflow_run_diff = WorkflowRunDiff {
@@ -758,10 +868,9 @@ impl Engine {
 
                 // Apply the diff
                 self.state_adapter
-                    .lock()
-                    .await
                     .apply_workflow_run_diff(&workflow_run_diff)
                     .await?;
+                self.notify(workflow_run_id);
 
                 info!(
                     "Workflow run {} {}",
@@ -779,6 +888,7 @@ impl Engine {
                 .await?;
 
             let tasks_to_await_trigger = runnable_tasks_result.tasks_to_await_trigger;
+            let marked_awaiting_trigger = !tasks_to_await_trigger.is_empty();
             for task_id in tasks_to_await_trigger {
                 // Create a task diff to update the status
                 let mut fields = HashMap::new();
@@ -787,24 +897,41 @@ impl Engine {
                     FieldDiff {
                         operation: DiffOperation::Update,
                         value: Some(serde_json::to_value(TaskStatus::AwaitingTrigger)?),
+                        expected_revision: None,
                     },
                 );
                 let task_diff = TaskDiff { task_id, fields };
 
                 // Apply the diff
-                self.state_adapter
-                    .lock()
-                    .await
-                    .apply_task_diff(&task_diff)
+                self.state_adapter.apply_task_diff(&task_diff).await?;
+            }
+
+            // Skip tasks whose dependencies failed, so the run can finish
+            let tasks_to_skip = runnable_tasks_result.tasks_to_skip;
+            for task_id in &tasks_to_skip {
+                info!("Skipping task {task_id}: a dependency failed");
+                self.finish_task(*task_id, TaskStatus::Skipped, None)
                     .await?;
+
+                let master_task_id = tasks_after_recompilation
+                    .iter()
+                    .find(|t| t.id == *task_id)
+                    .and_then(|t| t.master_task_id);
+                if let Some(master_task_id) = master_task_id {
+                    self.update_matrix_master_status(master_task_id).await?;
+                }
             }
 
-            let runnable_tasks = runnable_tasks_result.runnable_tasks;
+            let runnable_tasks: Vec<Uuid> = runnable_tasks_result
+                .runnable_tasks
+                .into_iter()
+                .filter(|task_id| !started_tasks.contains(task_id))
+                .collect();
 
             // Check if any tasks are awaiting trigger
             let awaiting_trigger = tasks_after_recompilation
                 .iter()
-                .any(|t| t.status == TaskStatus::AwaitingTrigger);
+                .any(|t| t.status == TaskStatus::AwaitingTrigger && !tasks_to_skip.contains(&t.id));
             let any_running = tasks_after_recompilation
                 .iter()
                 .any(|t| t.status == TaskStatus::Running);
@@ -819,6 +946,7 @@ impl Engine {
                     FieldDiff {
                         operation: DiffOperation::Update,
                         value: Some(serde_json::to_value(WorkflowStatus::AwaitingTrigger)?),
+                        expected_revision: None,
                     },
                 );
                 let workflow_run_diff = WorkflowRunDiff {
@@ -828,10 +956,9 @@ impl Engine {
 
                 // Apply the diff
                 self.state_adapter
-                    .lock()
-                    .await
                     .apply_workflow_run_diff(&workflow_run_diff)
                     .await?;
+                self.notify(workflow_run_id);
 
                 info!("Workflow run {workflow_run_id} is awaiting triggers");
 
@@ -852,23 +979,100 @@ impl Engine {
                     .find(|n| n.id == task.node_id)
                     .unwrap(); // Should exist based on how tasks are created
 
+                // Tasks over the limit stay pending until a running task finishes
+                let Some(permits) = limits.try_acquire(&task.node_id) else {
+                    debug!("Concurrency limit reached, task {task_id} stays pending");
+                    continue;
+                };
+
                 // Start task execution
-                let engine = self.clone();
-                let task_id = task.id;
-                tokio::spawn(async move {
-                    if let Err(e) = engine.execute_task(task_id).await {
-                        error!("Task execution failed: {e}");
-                    }
-                });
+                started_tasks.insert(task.id);
+                self.spawn_task(workflow_run_id, task.id, permits);
+            }
+
+            // Tasks changed by this pass can unblock others, so look again right away
+            if !tasks_to_skip.is_empty() || marked_awaiting_trigger {
+                continue;
             }
 
-            // Wait a bit before checking again
-            time::sleep(Duration::from_secs(1)).await;
+            // Wait until a task of this run finishes or changes the state
+            if !self.next_event(&mut events, workflow_run_id).await {
+                break;
+            }
         }
 
         Ok(())
     }
 
+    /// Execute a task in the background and notify the run when it is done
+    fn spawn_task(&self, workflow_run_id: Uuid, task_id: Uuid, permits: TaskPermits) {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.execute_task(task_id).await {
+                error!("Task execution failed: {e}");
+                engine.fail_unfinished_task(task_id, &e).await;
+            }
+            // Release the permits before waking the loop, so it can reuse them
+            drop(permits);
+            engine.notify(workflow_run_id);
+        });
+    }
+
+    /// Mark a task failed if an error left it pending or running
+    ///
+    /// Errors raised before a task reaches a final status, e.g. by the state
+    /// adapter or while creating its runner, would otherwise leave it in a
+    /// status the execution loop keeps waiting on.
+    async fn fail_unfinished_task(&self, task_id: Uuid, error: &Error) {
+        let result = async {
+            let task = self.state_adapter.get_task(task_id).await?;
+            if task.status.is_terminal() {
+                return Ok(());
+            }
+            self.finish_task(task_id, TaskStatus::Failed, Some(error.to_string()))
+                .await?;
+            if let Some(master_task_id) = task.master_task_id {
+                self.update_matrix_master_status(master_task_id).await?;
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Failed to mark task {task_id} as failed: {e}");
+        }
+    }
+
+    /// Wake everything waiting on a workflow run
+    fn notify(&self, workflow_run_id: Uuid) {
+        // Sending only fails when nobody is listening
+        let _ = self.events.send(workflow_run_id);
+    }
+
+    /// Wait for the next event of a workflow run
+    ///
+    /// Also returns after [`EVENT_TIMEOUT`] without an event, since other
+    /// processes change runs without notifying this one. Returns `false` once
+    /// no more events can arrive.
+    async fn next_event(
+        &self,
+        events: &mut broadcast::Receiver<Uuid>,
+        workflow_run_id: Uuid,
+    ) -> bool {
+        let deadline = time::Instant::now() + EVENT_TIMEOUT;
+        loop {
+            let Ok(event) = time::timeout_at(deadline, events.recv()).await else {
+                return true;
+            };
+            match event {
+                Ok(id) if id == workflow_run_id => return true,
+                Ok(_) => continue,
+                // Missed events may have been for this run
+                Err(broadcast::error::RecvError::Lagged(_)) => return true,
+                Err(broadcast::error::RecvError::Closed) => return false,
+            }
+        }
+    }
+
     /// Recompile matrix tasks based on the current state.
     /// Creates new tasks for added matrix items and marks tasks for removed items as WontDo.
     async fn recompile_matrix_tasks(
@@ -879,12 +1083,7 @@ impl Engine {
     ) -> Result<()> {
         debug!("Starting matrix task recompilation for run {workflow_run_id}");
 
-        let state = self
-            .state_adapter
-            .lock()
-            .await
-            .get_state(workflow_run_id)
-            .await?;
+        let state = self.state_adapter.get_state(workflow_run_id).await?;
 
         // Use scheduler to calculate matrix task changes
         let changes = self
@@ -895,7 +1094,7 @@ impl Engine {
         // Create new tasks
         for task in changes.new_tasks {
             debug!("Creating new matrix task for node '{}'", task.node_id);
-            self.state_adapter.lock().await.save_task(&task).await?;
+            self.state_adapter.save_task(&task).await?;
         }
 
         // Mark tasks as WontDo
@@ -907,14 +1106,11 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(TaskStatus::WontDo)?),
+                    expected_revision: None,
                 },
             );
             let task_diff = TaskDiff { task_id, fields };
-            self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
-                .await?;
+            self.state_adapter.apply_task_diff(&task_diff).await?;
         }
 
         // Update master task status
@@ -929,12 +1125,10 @@ impl Engine {
 
     /// Execute a task
     async fn execute_task(&self, task_id: Uuid) -> Result<()> {
-        let task = self.state_adapter.lock().await.get_task(task_id).await?;
+        let task = self.state_adapter.get_task(task_id).await?;
 
         let workflow_run = self
             .state_adapter
-            .lock()
-            .await
             .get_workflow_run(task.workflow_run_id)
             .await?;
 
@@ -945,6 +1139,49 @@ impl Engine {
             .find(|n| n.id == task.node_id)
             .ok_or_else(|| Error::NodeNotFound(task.node_id.clone()))?;
 
+        // Results and outputs of upstream nodes are visible to this node
+        let needs = self.dependency_context(workflow_run.id, node).await?;
+
+        // Skip the node when its condition doesn't hold
+        if let Some(condition) = &node.r#if {
+            let state = self.state_adapter.get_state(workflow_run.id).await?;
+            let context = VariableContext {
+                params: Some(&workflow_run.params),
+                state: Some(&state),
+                matrix_values: task.matrix_values.as_ref(),
+                steps: None,
+                inputs: None,
+                needs: Some(&needs),
+            };
+
+            match evaluate_condition(condition, &context) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!(
+                        "Skipping task {} ({}): condition {} is false",
+                        task_id, node.id, condition
+                    );
+                    self.finish_task(task_id, TaskStatus::WontDo, None).await?;
+                    if let Some(master_task_id) = task.master_task_id {
+                        self.update_matrix_master_status(master_task_id).await?;
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.finish_task(
+                        task_id,
+                        TaskStatus::Failed,
+                        Some(format!("Failed to evaluate condition {condition}: {e}")),
+                    )
+                    .await?;
+                    if let Some(master_task_id) = task.master_task_id {
+                        self.update_matrix_master_status(master_task_id).await?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
         // Create a task diff to update the status
         let mut fields = HashMap::new();
         fields.insert(
@@ -952,6 +1189,7 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(TaskStatus::Running)?),
+                expected_revision: None,
             },
         );
         fields.insert(
@@ -959,16 +1197,13 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(Utc::now())?),
+                expected_revision: None,
             },
         );
         let task_diff = TaskDiff { task_id, fields };
 
         // Apply the diff
-        self.state_adapter
-            .lock()
-            .await
-            .apply_task_diff(&task_diff)
-            .await?;
+        self.state_adapter.apply_task_diff(&task_diff).await?;
 
         info!("Executing task {} ({})", task_id, node.id);
 
@@ -983,7 +1218,9 @@ impl Engine {
             RuntimeType::Docker => {
                 #[cfg(feature = "docker")]
                 {
-                    Box::new(DockerRunner::new())
+                    Box::new(DockerRunner::new(
+                        self.container_config(node, &workflow_run.bundle_path),
+                    ))
                 }
                 #[cfg(not(feature = "docker"))]
                 {
@@ -993,7 +1230,9 @@ impl Engine {
             RuntimeType::Podman => {
                 #[cfg(feature = "podman")]
                 {
-                    Box::new(PodmanRunner::new())
+                    Box::new(PodmanRunner::new(
+                        self.container_config(node, &workflow_run.bundle_path),
+                    ))
                 }
                 #[cfg(not(feature = "podman"))]
                 {
@@ -1002,76 +1241,53 @@ impl Engine {
             }
         };
 
-        // Execute each step in the node
-        for step in &node.steps {
-            let state = self
-                .state_adapter
-                .lock()
-                .await
-                .get_state(workflow_run.id)
-                .await?;
-
-            let result = self
-                .execute_step_action(
-                    runner.as_ref(),
-                    &step.action,
-                    &step.env,
-                    node,
+        // Run the steps, retrying the whole node as its policy declares
+        let result = run_with_policy(
+            &format!("Task {task_id} ({})", node.id),
+            node.retry.as_ref(),
+            node.timeout.as_deref(),
+            |attempt| {
+                self.execute_node_steps(
+                    attempt,
                     &task,
-                    &workflow_run.params,
-                    &state,
-                    &workflow_run.workflow,
-                    &workflow_run.bundle_path,
-                    &[],
+                    node,
+                    &workflow_run,
+                    runner.as_ref(),
+                    &needs,
                 )
-                .await;
+            },
+        )
+        .await;
 
-            match result {
-                Ok(_) => {}
-                Err(e) => {
-                    // Create a task diff to update the status
-                    let mut fields = HashMap::new();
-                    fields.insert(
-                        "status".to_string(),
-                        FieldDiff {
-                            operation: DiffOperation::Update,
-                            value: Some(serde_json::to_value(TaskStatus::Failed)?),
-                        },
-                    );
-                    fields.insert(
-                        "ended_at".to_string(),
-                        FieldDiff {
-                            operation: DiffOperation::Update,
-                            value: Some(serde_json::to_value(Utc::now())?),
-                        },
-                    );
-                    fields.insert(
-                        "error".to_string(),
-                        FieldDiff {
-                            operation: DiffOperation::Add,
-                            value: Some(serde_json::to_value(format!(
-                                "Step {} failed: {}",
-                                step.name, e
-                            ))?),
-                        },
-                    );
-                    let task_diff = TaskDiff { task_id, fields };
+        if let Err(e) = result {
+            let message = match &e {
+                Error::StepExecution(message) => message.clone(),
+                e => e.to_string(),
+            };
 
-                    // Apply the diff
-                    self.state_adapter
-                        .lock()
-                        .await
-                        .apply_task_diff(&task_diff)
-                        .await?;
+            if !node.continue_on_error {
+                self.finish_task(task_id, TaskStatus::Failed, Some(message))
+                    .await?;
+                if let Some(master_task_id) = task.master_task_id {
+                    self.update_matrix_master_status(master_task_id).await?;
+                }
 
-                    error!(
-                        "Task {} ({}) step {} failed: {}",
-                        task_id, node.id, step.name, e
-                    );
+                error!("Task {} ({}) failed: {}", task_id, node.id, e);
 
-                    return Err(e);
-                }
+                return Err(e);
+            }
+
+            // The error is kept on the task, but dependents run as if it succeeded
+            warn!(
+                "Task {} ({}) failed, continuing as the node allows errors: {}",
+                task_id, node.id, e
+            );
+            self.finish_task(task_id, TaskStatus::Completed, Some(message))
+                .await?;
+            if let Some(master_task_id) = task.master_task_id {
+                self.update_matrix_master_status(master_task_id).await?;
             }
+            return Ok(());
         }
 
         // Prepare environment variables
@@ -1082,28 +1298,274 @@ impl Engine {
             env.insert(format!("PARAM_{}", key.to_uppercase()), value.clone());
         }
 
-        // Add node environment variables
-        for (key, value) in &node.env {
-            env.insert(key.clone(), value.clone());
+        // Add node environment variables
+        for (key, value) in &node.env {
+            env.insert(key.clone(), value.clone());
+        }
+
+        // Add matrix values
+        if let Some(matrix_values) = &task.matrix_values {
+            for (key, value) in matrix_values {
+                env.insert(
+                    key.clone(),
+                    serde_json::to_string(value).unwrap_or(value.to_string()),
+                );
+            }
+        }
+
+        // Create a task diff to update the status
+        let mut fields = HashMap::new();
+        fields.insert(
+            "status".to_string(),
+            FieldDiff {
+                operation: DiffOperation::Update,
+                value: Some(serde_json::to_value(TaskStatus::Completed)?),
+                expected_revision: None,
+            },
+        );
+        fields.insert(
+            "ended_at".to_string(),
+            FieldDiff {
+                operation: DiffOperation::Update,
+                value: Some(serde_json::to_value(Utc::now())?),
+                expected_revision: None,
+            },
+        );
+        let task_diff = TaskDiff { task_id, fields };
+
+        // Apply the diff
+        self.state_adapter.apply_task_diff(&task_diff).await?;
+
+        info!("Task {} ({}) completed", task_id, node.id);
+
+        // If this is a matrix task, update the master task status
+        if let Some(master_task_id) = task.master_task_id {
+            self.update_matrix_master_status(master_task_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the steps of a task once
+    ///
+    /// `attempt` starts at 1 and is recorded on the task, along with the
+    /// attempts of each step.
+    #[allow(clippy::too_many_arguments)]
+    async fn execute_node_steps(
+        &self,
+        attempt: u32,
+        task: &Task,
+        node: &Node,
+        workflow_run: &WorkflowRun,
+        runner: &dyn Runner,
+        needs: &HashMap<String, serde_json::Value>,
+    ) -> Result<()> {
+        let task_id = task.id;
+
+        let mut fields = HashMap::new();
+        fields.insert(
+            "attempts".to_string(),
+            FieldDiff {
+                operation: DiffOperation::Update,
+                value: Some(serde_json::to_value(attempt)?),
+                expected_revision: None,
+            },
+        );
+        let mut step_attempts: HashMap<String, u32> = HashMap::new();
+        fields.insert(
+            "step_attempts".to_string(),
+            FieldDiff {
+                operation: DiffOperation::Update,
+                value: Some(serde_json::to_value(&step_attempts)?),
+                expected_revision: None,
+            },
+        );
+        self.state_adapter
+            .apply_task_diff(&TaskDiff { task_id, fields })
+            .await?;
+
+        // Every attempt starts over, outputs of dependencies are read
+        // through `needs`
+        let mut step_outputs = HashMap::new();
+        for step in &node.steps {
+            let state = self.state_adapter.get_state(workflow_run.id).await?;
+
+            let context = VariableContext {
+                params: Some(&workflow_run.params),
+                state: Some(&state),
+                matrix_values: task.matrix_values.as_ref(),
+                steps: Some(&step_outputs),
+                inputs: None,
+                needs: Some(needs),
+            };
+            let result = match step_condition_met(step, &context) {
+                Ok(false) => {
+                    info!(
+                        "Skipping step {} of task {task_id}: condition is false",
+                        step.name
+                    );
+                    continue;
+                }
+                Ok(true) => {
+                    run_with_policy(
+                        &format!("Step {} of task {task_id}", step.name),
+                        step.retry.as_ref(),
+                        step.timeout.as_deref(),
+                        |step_attempt| {
+                            step_attempts.insert(step.output_key().to_string(), step_attempt);
+                            let fields = HashMap::from([(
+                                "step_attempts".to_string(),
+                                FieldDiff {
+                                    operation: DiffOperation::Update,
+                                    value: serde_json::to_value(&step_attempts).ok(),
+                                    expected_revision: None,
+                                },
+                            )]);
+                            let action = self.execute_step_action(
+                                runner,
+                                step,
+                                node,
+                                task,
+                                &workflow_run.params,
+                                &state,
+                                &workflow_run.workflow,
+                                &workflow_run.bundle_path,
+                                &step_outputs,
+                                None,
+                                needs,
+                                &[],
+                            );
+                            async move {
+                                self.state_adapter
+                                    .apply_task_diff(&TaskDiff { task_id, fields })
+                                    .await?;
+                                action.await
+                            }
+                        },
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(outputs) => {
+                    if !outputs.is_empty() {
+                        step_outputs.insert(step.output_key().to_string(), outputs);
+
+                        let mut fields = HashMap::new();
+                        fields.insert(
+                            "outputs".to_string(),
+                            FieldDiff {
+                                operation: DiffOperation::Update,
+                                value: Some(serde_json::to_value(&step_outputs)?),
+                                expected_revision: None,
+                            },
+                        );
+                        self.state_adapter
+                            .apply_task_diff(&TaskDiff { task_id, fields })
+                            .await?;
+                    }
+                }
+                Err(e) if step.continue_on_error => {
+                    warn!(
+                        "Step {} of task {task_id} failed, continuing: {}",
+                        step.name, e
+                    );
+                }
+                Err(e) => {
+                    return Err(Error::StepExecution(format!(
+                        "Step {} failed: {}",
+                        step.name, e
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the container configuration for a node, mounting the target and the bundle
+    #[cfg(any(feature = "docker", feature = "podman"))]
+    fn container_config(&self, node: &Node, bundle_path: &Option<PathBuf>) -> ContainerConfig {
+        let runtime = node
+            .runtime
+            .as_ref()
+            .expect("Container runtimes are always declared on the node");
+        ContainerConfig::from_runtime(runtime)
+            .with_workspace(&self.workflow_run_config.target_path, bundle_path.as_ref())
+    }
+
+    /// Collect the results of the nodes this node depends on
+    ///
+    /// Returns for each dependency an object with its `status` and the
+    /// `outputs` of its completed tasks, by step and name. The tasks of a
+    /// matrix node have to agree on the outputs they share, since none of
+    /// them could be picked over the others.
+    async fn dependency_context(
+        &self,
+        workflow_run_id: Uuid,
+        node: &Node,
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        let mut needs = HashMap::new();
+        if node.depends_on.is_empty() {
+            return Ok(needs);
         }
 
-        // Add matrix values
-        if let Some(matrix_values) = &task.matrix_values {
-            for (key, value) in matrix_values {
-                env.insert(
-                    key.clone(),
-                    serde_json::to_string(value).unwrap_or(value.to_string()),
-                );
+        let tasks = self.state_adapter.get_tasks(workflow_run_id).await?;
+
+        for dep_id in &node.depends_on {
+            let mut dep_outputs: HashMap<String, HashMap<String, String>> = HashMap::new();
+            let mut status = None;
+            for task in tasks.iter().filter(|t| &t.node_id == dep_id) {
+                // Matrix masters carry the aggregated status of their children
+                if task.master_task_id.is_none() {
+                    status = Some(task.status);
+                }
+                if task.status != TaskStatus::Completed {
+                    continue;
+                }
+                for (step, outputs) in &task.outputs {
+                    let step_outputs = dep_outputs.entry(step.clone()).or_default();
+                    for (name, value) in outputs {
+                        match step_outputs.get(name) {
+                            Some(other) if other != value => {
+                                return Err(Error::Other(format!(
+                                    "Tasks of node {dep_id} set output {step}.{name} to different values"
+                                )));
+                            }
+                            Some(_) => {}
+                            None => {
+                                step_outputs.insert(name.clone(), value.clone());
+                            }
+                        }
+                    }
+                }
             }
+
+            needs.insert(
+                dep_id.clone(),
+                serde_json::json!({ "status": status, "outputs": dep_outputs }),
+            );
         }
 
-        // Create a task diff to update the status
+        Ok(needs)
+    }
+
+    /// Mark a task as finished with the given status
+    async fn finish_task(
+        &self,
+        task_id: Uuid,
+        status: TaskStatus,
+        error: Option<String>,
+    ) -> Result<()> {
         let mut fields = HashMap::new();
         fields.insert(
             "status".to_string(),
             FieldDiff {
                 operation: DiffOperation::Update,
-                value: Some(serde_json::to_value(TaskStatus::Completed)?),
+                value: Some(serde_json::to_value(status)?),
+                expected_revision: None,
             },
         );
         fields.insert(
@@ -1111,55 +1573,60 @@ impl Engine {
             FieldDiff {
                 operation: DiffOperation::Update,
                 value: Some(serde_json::to_value(Utc::now())?),
+                expected_revision: None,
             },
         );
-        let task_diff = TaskDiff { task_id, fields };
+        if let Some(error) = error {
+            fields.insert(
+                "error".to_string(),
+                FieldDiff {
+                    operation: DiffOperation::Add,
+                    value: Some(serde_json::to_value(error)?),
+                    expected_revision: None,
+                },
+            );
+        }
 
-        // Apply the diff
         self.state_adapter
-            .lock()
+            .apply_task_diff(&TaskDiff { task_id, fields })
             .await
-            .apply_task_diff(&task_diff)
-            .await?;
-
-        info!("Task {} ({}) completed", task_id, node.id);
-
-        // If this is a matrix task, update the master task status
-        if let Some(master_task_id) = task.master_task_id {
-            self.update_matrix_master_status(master_task_id).await?;
-        }
-
-        Ok(())
     }
 
     /// Execute a specific step action with dependency chain tracking for cycle detection
+    ///
+    /// Returns the outputs produced by the action, which only templates declare.
     #[allow(clippy::too_many_arguments)]
     async fn execute_step_action(
         &self,
         runner: &dyn Runner,
-        action: &StepAction,
-        step_env: &Option<HashMap<String, String>>,
+        step: &Step,
         node: &Node,
         task: &Task,
         params: &HashMap<String, String>,
         state: &HashMap<String, serde_json::Value>,
         workflow: &Workflow,
         bundle_path: &Option<PathBuf>,
+        steps: &HashMap<String, HashMap<String, String>>,
+        inputs: Option<&HashMap<String, serde_json::Value>>,
+        needs: &HashMap<String, serde_json::Value>,
         dependency_chain: &[CodemodDependency],
-    ) -> Result<()> {
-        match action {
+    ) -> Result<HashMap<String, String>> {
+        match &step.action {
             StepAction::RunScript(run) => {
                 self.execute_run_script_step(
                     runner,
                     run,
-                    step_env,
+                    step,
                     node,
                     task,
                     params,
-                    state,
                     bundle_path,
+                    steps,
+                    inputs,
+                    needs,
                 )
-                .await
+                .await?;
+                Ok(HashMap::new())
             }
             StepAction::UseTemplate(template_use) => {
                 // Find the template using the passed workflow reference
@@ -1171,38 +1638,155 @@ impl Engine {
                         Error::Template(format!("Template not found: {}", template_use.template))
                     })?;
 
+                // Inputs and defaults are resolved in the caller's scope, then
+                // checked against the template
+                let template_inputs = template.resolve_inputs(&template_use.inputs, |value| {
+                    resolve_variables(
+                        value,
+                        &VariableContext {
+                            params: Some(params),
+                            state: Some(state),
+                            matrix_values: task.matrix_values.as_ref(),
+                            steps: Some(steps),
+                            inputs,
+                            needs: Some(needs),
+                        },
+                    )
+                })?;
+
                 // Combine workflow params with template-specific inputs
                 let mut combined_params = params.clone();
-                combined_params.extend(template_use.inputs.clone());
+                combined_params.extend(
+                    template_inputs
+                        .iter()
+                        .map(|(name, value)| (name.clone(), to_display_string(value))),
+                );
 
+                // Template steps only see the outputs of the template's own steps
+                let mut template_steps = HashMap::new();
                 for template_step in &template.steps {
-                    Box::pin(self.execute_step_action(
-                        runner,
-                        &template_step.action,
-                        &template_step.env,
-                        node,
-                        task,
-                        &combined_params,
-                        state,
-                        workflow,
-                        bundle_path,
-                        dependency_chain,
-                    ))
-                    .await?;
+                    let context = VariableContext {
+                        params: Some(&combined_params),
+                        state: Some(state),
+                        matrix_values: task.matrix_values.as_ref(),
+                        steps: Some(&template_steps),
+                        inputs: Some(&template_inputs),
+                        needs: Some(needs),
+                    };
+                    if !step_condition_met(template_step, &context)? {
+                        info!(
+                            "Skipping step {} of template {}: condition is false",
+                            template_step.name, template.id
+                        );
+                        continue;
+                    }
+
+                    let result = run_with_policy(
+                        &format!("Step {} of template {}", template_step.name, template.id),
+                        template_step.retry.as_ref(),
+                        template_step.timeout.as_deref(),
+                        |_| {
+                            Box::pin(self.execute_step_action(
+                                runner,
+                                template_step,
+                                node,
+                                task,
+                                &combined_params,
+                                state,
+                                workflow,
+                                bundle_path,
+                                &template_steps,
+                                Some(&template_inputs),
+                                needs,
+                                dependency_chain,
+                            ))
+                        },
+                    )
+                    .await;
+                    let outputs = match result {
+                        Ok(outputs) => outputs,
+                        Err(e) if template_step.continue_on_error => {
+                            warn!(
+                                "Step {} of template {} failed, continuing: {e}",
+                                template_step.name, template.id
+                            );
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if !outputs.is_empty() {
+                        template_steps.insert(template_step.output_key().to_string(), outputs);
+                    }
                 }
-                Ok(())
+
+                if template.outputs.is_empty() {
+                    return Ok(HashMap::new());
+                }
+
+                // Resolve outputs against the state left behind by the template's steps
+                let state = self.state_adapter.get_state(task.workflow_run_id).await?;
+
+                let mut outputs = HashMap::new();
+                for output in &template.outputs {
+                    let value = resolve_variables(
+                        &output.value,
+                        &VariableContext {
+                            params: Some(&combined_params),
+                            state: Some(&state),
+                            matrix_values: task.matrix_values.as_ref(),
+                            steps: Some(&template_steps),
+                            inputs: Some(&template_inputs),
+                            needs: Some(needs),
+                        },
+                    )
+                    .map_err(|e| {
+                        Error::Template(format!(
+                            "Failed to resolve output {} of template {}: {e}",
+                            output.name, template.id
+                        ))
+                    })?;
+                    outputs.insert(output.name.clone(), value);
+                }
+                Ok(outputs)
             }
             StepAction::AstGrep(ast_grep) => {
-                self.execute_ast_grep_step(node.id.clone(), ast_grep).await
+                self.execute_ast_grep_step(node.id.clone(), ast_grep)
+                    .await?;
+                Ok(HashMap::new())
             }
             StepAction::JSAstGrep(js_ast_grep) => {
-                self.execute_js_ast_grep_step(node.id.clone(), js_ast_grep)
-                    .await
+                // Step params take precedence over workflow params of the same name
+                let mut transform_params = params.clone();
+                for (key, value) in js_ast_grep.params.iter().flatten() {
+                    let value = resolve_variables(
+                        value,
+                        &VariableContext {
+                            params: Some(params),
+                            state: Some(state),
+                            matrix_values: task.matrix_values.as_ref(),
+                            steps: Some(steps),
+                            inputs,
+                            needs: Some(needs),
+                        },
+                    )?;
+                    transform_params.insert(key.clone(), value);
+                }
+                let options = TransformOptions {
+                    params: transform_params,
+                    matrix_values: task.matrix_values.clone().unwrap_or_default(),
+                    state: self.state_adapter.get_state(task.workflow_run_id).await?,
+                };
+
+                let emitted = self
+                    .execute_js_ast_grep_step(node.id.clone(), js_ast_grep, options)
+                    .await?;
+                self.apply_emitted_state(task, step, &emitted).await?;
+                Ok(HashMap::new())
             }
             StepAction::Codemod(codemod) => {
                 Box::pin(self.execute_codemod_step(
                     codemod,
-                    step_env,
+                    &step.env,
                     node,
                     task,
                     params,
@@ -1210,7 +1794,8 @@ impl Engine {
                     bundle_path,
                     dependency_chain,
                 ))
-                .await
+                .await?;
+                Ok(HashMap::new())
             }
         }
     }
@@ -1234,33 +1819,33 @@ impl Engine {
             );
         }
 
-        let config_path_clone = config_path.clone();
-
-        with_combined_scan(
-            &config_path_clone.to_string_lossy(),
-            |combined_scan_with_rule| {
+        // The scan blocks, so it runs on its own thread
+        let engine = self.clone();
+        let ast_grep = ast_grep.clone();
+        run_blocking_walk(move |cancelled| {
+            with_combined_scan(&config_path.to_string_lossy(), |combined_scan_with_rule| {
                 let rule_refs = combined_scan_with_rule.rule_refs.clone();
                 let languages = rule_refs.iter().map(|r| r.language).collect::<Vec<_>>();
 
                 let execution_config = CodemodExecutionConfig {
                     pre_run_callback: None,
-                    progress_callback: self.workflow_run_config.progress_callback.clone(),
-                    target_path: Some(self.workflow_run_config.target_path.clone()),
+                    progress_callback: engine.workflow_run_config.progress_callback.clone(),
+                    target_path: Some(engine.workflow_run_config.target_path.clone()),
                     base_path: ast_grep.base_path.as_deref().map(PathBuf::from),
                     include_globs: ast_grep.include.as_deref().map(|v| v.to_vec()),
                     exclude_globs: ast_grep.exclude.as_deref().map(|v| v.to_vec()),
-                    dry_run: self.workflow_run_config.dry_run,
+                    dry_run: engine.workflow_run_config.dry_run,
                     languages: Some(languages.iter().map(|l| l.to_string()).collect()),
                 };
 
                 // Clone variables needed in the closure
                 let id_clone = id.clone();
-                let file_writer = Arc::clone(&self.file_writer);
+                let file_writer = Arc::clone(&engine.file_writer);
                 let runtime_handle = tokio::runtime::Handle::current();
 
                 let _ = execution_config.execute(|path, config| {
                     // Only process files, not directories
-                    if !path.is_file() {
+                    if cancelled.load(Ordering::Relaxed) || !path.is_file() {
                         return;
                     }
 
@@ -1291,48 +1876,79 @@ impl Engine {
                                             path.display(),
                                             e
                                         );
-                                        self.execution_stats
+                                        engine
+                                            .execution_stats
                                             .files_with_errors
                                             .fetch_add(1, Ordering::Relaxed);
                                         return;
                                     }
                                 }
-                                self.execution_stats
+                                engine
+                                    .execution_stats
                                     .files_modified
                                     .fetch_add(1, Ordering::Relaxed);
                             } else {
-                                self.execution_stats
+                                engine
+                                    .execution_stats
                                     .files_unmodified
                                     .fetch_add(1, Ordering::Relaxed);
                             }
                         }
                         Err(e) => {
                             error!("{e}");
-                            self.execution_stats
+                            engine
+                                .execution_stats
                                 .files_with_errors
                                 .fetch_add(1, Ordering::Relaxed);
                         }
                     };
 
-                    if let Some(callback) = self.workflow_run_config.progress_callback.as_ref() {
+                    if let Some(callback) = engine.workflow_run_config.progress_callback.as_ref() {
                         let callback = callback.callback.clone();
                         callback(&id_clone, &path.to_string_lossy(), "next", Some(&1), &0);
                     }
                 });
 
                 Ok(())
-            },
-        )
-        .map_err(|e| Error::StepExecution(e.to_string()))?;
+            })
+            .map_err(|e| Error::StepExecution(e.to_string()))
+        })
+        .await
+    }
 
-        Ok(())
+    /// Write the state a jssg codemod emitted to the state of the run
+    ///
+    /// Tasks running in parallel may write the same keys; a write that
+    /// conflicts with theirs is recomputed on top of the state they left,
+    /// until the conflict retries run out.
+    async fn apply_emitted_state(
+        &self,
+        task: &Task,
+        step: &Step,
+        emitted: &EmittedState,
+    ) -> Result<()> {
+        if emitted.is_empty() {
+            return Ok(());
+        }
+
+        self.apply_state_writes(task, step, true, |state, revisions| {
+            emitted.to_fields(state, revisions)
+        })
+        .await
     }
 
+    /// Run a jssg codemod, passing `options` to its transform
+    ///
+    /// Transformed files are written as they're processed, and the file
+    /// operations the codemod requests are applied once every file was, see
+    /// [`FileChanges`]. Returns the state writes the codemod emitted for the
+    /// files it processed without errors.
     pub async fn execute_js_ast_grep_step(
         &self,
         id: String,
         js_ast_grep: &UseJSAstGrep,
-    ) -> Result<()> {
+        options: TransformOptions,
+    ) -> Result<EmittedState> {
         let js_file_path = self
             .workflow_run_config
             .bundle_path
@@ -1364,16 +1980,17 @@ impl Engine {
 
         let tsconfig_path = find_tsconfig(&script_base_dir);
 
-        let filesystem = Arc::new(RealFileSystem::new());
         let resolver = Arc::new(
             OxcResolver::new(script_base_dir.clone(), tsconfig_path)
                 .map_err(|e| Error::Other(format!("Failed to create resolver: {e}")))?,
         );
 
+        let target_root = self.target_root();
+
         let config = CodemodExecutionConfig {
             pre_run_callback: None,
             progress_callback: self.workflow_run_config.progress_callback.clone(),
-            target_path: Some(self.workflow_run_config.target_path.clone()),
+            target_path: Some(target_root.clone()),
             base_path: js_ast_grep.base_path.as_deref().map(PathBuf::from),
             include_globs: js_ast_grep.include.as_deref().map(|v| v.to_vec()),
             exclude_globs: js_ast_grep.exclude.as_deref().map(|v| v.to_vec()),
@@ -1396,20 +2013,24 @@ impl Engine {
             })?
         };
 
-        // Capture variables for use in parallel threads
-        let runtime_handle = tokio::runtime::Handle::current();
-        let js_file_path_clone = js_file_path.clone();
-        let filesystem_clone = filesystem.clone();
-        let resolver_clone = resolver.clone();
-        let id_clone = Arc::new(id);
-        let progress_callback = self.workflow_run_config.progress_callback.clone();
-        let file_writer = Arc::clone(&self.file_writer);
-
-        // Execute the codemod on each file using the config's multi-threading
-        config
-            .execute(move |file_path, config| {
+        // The walk blocks, so it runs on its own thread
+        let engine = self.clone();
+        run_blocking_walk(move |cancelled| {
+            // Capture variables for use in parallel threads; each worker thread
+            // loads the codemod once and reuses it for the files it processes
+            let runtime_handle = tokio::runtime::Handle::current();
+            let runtime_pool =
+                RuntimePool::new(&js_file_path, resolver, options).with_project(&target_root);
+            let id_clone = Arc::new(id);
+            let progress_callback = engine.workflow_run_config.progress_callback.clone();
+            let emitted = Mutex::new(EmittedState::new());
+            let changes = FileChanges::new(&target_root, config.dry_run)
+                .map_err(|e| Error::StepExecution(format!("Failed to set up the undo log: {e}")))?;
+
+            // Execute the codemod on each file using the config's multi-threading
+            let walked = config.execute(|file_path, _config| {
                 // Only process files
-                if !file_path.is_file() {
+                if cancelled.load(Ordering::Relaxed) || !file_path.is_file() {
                     return;
                 }
 
@@ -1425,67 +2046,37 @@ impl Engine {
                 };
 
                 // Execute the async codemod using the captured runtime handle
-                let execution_result = runtime_handle.block_on(async {
-                    execute_codemod_with_quickjs(
-                        &js_file_path_clone,
-                        filesystem_clone.clone(),
-                        resolver_clone.clone(),
-                        language,
-                        file_path,
-                        &content,
-                    )
-                    .await
-                });
+                let execution_result =
+                    runtime_handle.block_on(runtime_pool.execute(language, file_path, &content));
 
                 match execution_result {
                     Ok(execution_output) => {
                         debug!("Successfully processed file: {}", file_path.display());
 
-                        // Handle the execution output (write back if modified and not dry run)
-                        if let Some(ref new_content) = execution_output.content {
-                            if new_content != &content {
-                                if config.dry_run {
-                                    debug!("Would modify file (dry run): {}", file_path.display());
-                                    self.execution_stats
-                                        .files_modified
-                                        .fetch_add(1, Ordering::Relaxed);
-                                } else {
-                                    // Use async file writing to avoid blocking the thread
-                                    let write_result = runtime_handle.block_on(async {
-                                        file_writer
-                                            .write_file(
-                                                file_path.to_path_buf(),
-                                                new_content.clone(),
-                                            )
-                                            .await
-                                    });
-
-                                    if let Err(e) = write_result {
-                                        error!(
-                                            "Failed to write modified file {}: {}",
-                                            file_path.display(),
-                                            e
-                                        );
-                                        self.execution_stats
+                        // The file operations are only requested if the
+                        // new content could be written
+                        let written = match execution_output.content {
+                            Some(new_content) if new_content != content => {
+                                match changes.rewrite(file_path, &new_content) {
+                                    Ok(()) => true,
+                                    Err(e) => {
+                                        warn!("Failed to write {}: {e}", file_path.display());
+                                        engine
+                                            .execution_stats
                                             .files_with_errors
                                             .fetch_add(1, Ordering::Relaxed);
-                                    } else {
-                                        debug!("Modified file: {}", file_path.display());
-                                        self.execution_stats
-                                            .files_modified
-                                            .fetch_add(1, Ordering::Relaxed);
+                                        false
                                     }
                                 }
-                            } else {
-                                self.execution_stats
+                            }
+                            _ => {
+                                engine
+                                    .execution_stats
                                     .files_unmodified
                                     .fetch_add(1, Ordering::Relaxed);
+                                true
                             }
-                        } else {
-                            self.execution_stats
-                                .files_unmodified
-                                .fetch_add(1, Ordering::Relaxed);
-                        }
+                        };
 
                         // Handle execution errors
                         if let Some(ref error_msg) = execution_output.error {
@@ -1494,10 +2085,18 @@ impl Engine {
                                 file_path.display(),
                                 error_msg
                             );
-                            self.execution_stats
+                            engine
+                                .execution_stats
                                 .files_with_errors
                                 .fetch_add(1, Ordering::Relaxed);
                         }
+
+                        if !execution_output.state_writes.is_empty() {
+                            emitted.lock().unwrap().add(execution_output.state_writes);
+                        }
+                        if written && !execution_output.file_operations.is_empty() {
+                            changes.request(file_path, execution_output.file_operations);
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -1505,7 +2104,8 @@ impl Engine {
                             file_path.display(),
                             e
                         );
-                        self.execution_stats
+                        engine
+                            .execution_stats
                             .files_with_errors
                             .fetch_add(1, Ordering::Relaxed);
                     }
@@ -1521,10 +2121,33 @@ impl Engine {
                         &0,
                     );
                 }
-            })
-            .map_err(|e| Error::StepExecution(e.to_string()))?;
+            });
 
-        Ok(())
+            // A step that failed or timed out leaves the files as they were
+            if let Err(e) = walked {
+                changes.revert();
+                return Err(Error::StepExecution(e.to_string()));
+            }
+            if cancelled.load(Ordering::Relaxed) {
+                changes.revert();
+                return Err(Error::StepExecution("Step was cancelled".to_string()));
+            }
+
+            // Applied after the walk, so it doesn't pick up files they create
+            let conflicts = changes.finish(&engine.execution_stats).map_err(|e| {
+                Error::StepExecution(format!("Failed to apply file operations: {e}"))
+            })?;
+            for conflict in &conflicts {
+                warn!("{conflict}");
+            }
+            engine
+                .execution_stats
+                .files_with_errors
+                .fetch_add(conflicts.len(), Ordering::Relaxed);
+
+            Ok(emitted.into_inner().unwrap())
+        })
+        .await
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -1692,21 +2315,56 @@ impl Engine {
         let runner: Box<dyn Runner> = Box::new(DirectRunner::new());
 
         // Execute each node in the codemod workflow
+        let mut step_outputs = HashMap::new();
         for node in &codemod_workflow.nodes {
             for step in &node.steps {
-                Box::pin(self.execute_step_action(
-                    runner.as_ref(),
-                    &step.action,
-                    &step.env,
-                    node,
-                    task, // Use the current task context
-                    &codemod_params,
-                    state,
-                    &codemod_workflow,
-                    &Some(resolved_package.package_dir.clone()),
-                    dependency_chain,
-                ))
-                .await?;
+                let context = VariableContext {
+                    params: Some(&codemod_params),
+                    state: Some(state),
+                    matrix_values: task.matrix_values.as_ref(),
+                    steps: Some(&step_outputs),
+                    ..Default::default()
+                };
+                if !step_condition_met(step, &context)? {
+                    info!("Skipping step {}: condition is false", step.name);
+                    continue;
+                }
+
+                let bundle_path = Some(resolved_package.package_dir.clone());
+                let needs = HashMap::new();
+                let result = run_with_policy(
+                    &format!("Step {}", step.name),
+                    step.retry.as_ref(),
+                    step.timeout.as_deref(),
+                    |_| {
+                        Box::pin(self.execute_step_action(
+                            runner.as_ref(),
+                            step,
+                            node,
+                            task, // Use the current task context
+                            &codemod_params,
+                            state,
+                            &codemod_workflow,
+                            &bundle_path,
+                            &step_outputs,
+                            None,
+                            &needs,
+                            dependency_chain,
+                        ))
+                    },
+                )
+                .await;
+                let outputs = match result {
+                    Ok(outputs) => outputs,
+                    Err(e) if step.continue_on_error => {
+                        warn!("Step {} failed, continuing: {e}", step.name);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if !outputs.is_empty() {
+                    step_outputs.insert(step.output_key().to_string(), outputs);
+                }
             }
         }
 
@@ -1720,15 +2378,31 @@ impl Engine {
         &self,
         runner: &dyn Runner,
         run: &str,
-        step_env: &Option<HashMap<String, String>>,
+        step: &Step,
         node: &Node,
         task: &Task,
         params: &HashMap<String, String>,
-        state: &HashMap<String, serde_json::Value>,
         bundle_path: &Option<PathBuf>,
+        steps: &HashMap<String, HashMap<String, String>>,
+        inputs: Option<&HashMap<String, serde_json::Value>>,
+        needs: &HashMap<String, serde_json::Value>,
     ) -> Result<()> {
-        // Start with a copy of the parent process's environment
-        let mut env: HashMap<String, String> = std::env::vars().collect();
+        // Snapshot the state the command sees. Revisions are read first, so a
+        // write in between makes `key?=value` outputs conflict rather than
+        // silently overwrite a value the command never saw.
+        let revisions = self
+            .state_adapter
+            .get_state_revisions(task.workflow_run_id)
+            .await?;
+        let state = self.state_adapter.get_state(task.workflow_run_id).await?;
+
+        // Start with a copy of the parent process's environment, unless the
+        // command runs in an isolated container
+        let mut env: HashMap<String, String> = if runner.inherits_host_env() {
+            std::env::vars().collect()
+        } else {
+            HashMap::new()
+        };
 
         // Add node environment variables
         for (key, value) in &node.env {
@@ -1736,7 +2410,7 @@ impl Engine {
         }
 
         // Add step environment variables
-        if let Some(step_env) = step_env {
+        if let Some(step_env) = &step.env {
             for (key, value) in step_env {
                 env.insert(key.clone(), value.clone());
             }
@@ -1782,23 +2456,45 @@ impl Engine {
         );
 
         // Resolve variables
-        let resolved_command = resolve_variables(run, params, state, task.matrix_values.as_ref())?;
-
-        // Execute the command
-        let output = runner.run_command(&resolved_command, &env).await?;
-
-        // Get the current task
-        let mut current_task = self.state_adapter.lock().await.get_task(task.id).await?;
-
-        // Append to the logs
-        current_task.logs.push(output.clone());
-
-        // Save the updated task
-        self.state_adapter
-            .lock()
-            .await
-            .save_task(&current_task)
-            .await?;
+        let resolved_command = resolve_variables(
+            run,
+            &VariableContext {
+                params: Some(params),
+                state: Some(&state),
+                matrix_values: task.matrix_values.as_ref(),
+                steps: Some(steps),
+                inputs,
+                needs: Some(needs),
+            },
+        )?;
+
+        // Execute the command, persisting its output as it is printed
+        let (log_sender, mut log_receiver) = mpsc::unbounded_channel();
+        let run = async {
+            let log_sender = log_sender;
+            runner
+                .run_command(&resolved_command, &env, &log_sender)
+                .await
+        };
+        let forward_logs = async {
+            let mut result = Ok(());
+            while let Some(line) = log_receiver.recv().await {
+                // Write whatever arrived in the meantime in a single batch
+                let mut lines = vec![line];
+                while let Ok(line) = log_receiver.try_recv() {
+                    lines.push(line);
+                }
+                if result.is_ok() {
+                    result = self.state_adapter.append_task_logs(task.id, &lines).await;
+                }
+            }
+            result
+        };
+        let (output, logs_result) = tokio::join!(run, forward_logs);
+        if let Err(e) = logs_result {
+            warn!("Failed to persist logs of task {}: {e}", task.id);
+        }
+        let output = output?;
 
         debug!("Command output: {output}");
 
@@ -1808,69 +2504,75 @@ impl Engine {
         std::fs::remove_file(&step_outputs_path).ok();
 
         // Update state
-        let mut state_diff = HashMap::new();
-        for line in outputs.lines() {
-            // Check for empty lines
-            if line.trim().is_empty() {
-                continue;
-            }
+        let state_outputs = parse_state_outputs(&outputs, &revisions);
+        if state_outputs.is_empty() {
+            return Ok(());
+        }
+        self.apply_state_writes(
+            task,
+            step,
+            state_outputs.reads_state(),
+            |state, revisions| state_outputs.to_fields(state, revisions),
+        )
+        .await
+    }
 
-            // Determine if this is an append operation (@=) or a regular assignment (=)
-            let (key, operation, value_str) = if let Some((k, v)) = line.split_once("@=") {
-                (k, DiffOperation::Append, v)
-            } else if let Some((k, v)) = line.split_once('=') {
-                (k, DiffOperation::Update, v)
-            } else {
-                // Malformed line, log and skip
-                warn!("Malformed state output line: {line}");
-                continue;
+    /// Write to state in a single diff, computed from the current state
+    ///
+    /// With `retry_conflicts`, a diff that conflicts with a write of another
+    /// task is computed again from the new state, a bounded number of times.
+    async fn apply_state_writes<F>(
+        &self,
+        task: &Task,
+        step: &Step,
+        retry_conflicts: bool,
+        to_fields: F,
+    ) -> Result<()>
+    where
+        F: Fn(
+            &HashMap<String, serde_json::Value>,
+            &HashMap<String, u64>,
+        ) -> Result<HashMap<String, FieldDiff>>,
+    {
+        let retry = state_conflict_retry();
+        let mut attempt = 1;
+        loop {
+            let revisions = self
+                .state_adapter
+                .get_state_revisions(task.workflow_run_id)
+                .await?;
+            let state = self.state_adapter.get_state(task.workflow_run_id).await?;
+            let diff = StateDiff {
+                workflow_run_id: task.workflow_run_id,
+                fields: to_fields(&state, &revisions)?,
+                task_id: Some(task.id),
+                step: Some(step.name.clone()),
             };
-
-            // Try to parse value as JSON first, fall back to string if that fails
-            let value = match serde_json::from_str::<serde_json::Value>(value_str) {
-                Ok(json_value) => json_value,
-                Err(_) => {
-                    // Not valid JSON, treat as a plain string
-                    serde_json::Value::String(value_str.to_string())
+            match self.state_adapter.apply_state_diff(&diff).await {
+                Ok(()) => break,
+                Err(Error::StateConflict(e)) if retry_conflicts && attempt < retry.max_attempts => {
+                    let delay = retry.delay_after(attempt).map_err(Error::Runtime)?;
+                    debug!("Retrying state write of task {} in {delay:?}: {e}", task.id);
+                    time::sleep(delay).await;
+                    attempt += 1;
                 }
-            };
-
-            // Add to state diff
-            state_diff.insert(
-                key.to_string(),
-                FieldDiff {
-                    operation,
-                    value: Some(value),
-                },
-            );
+                Err(e) => return Err(e),
+            }
         }
 
-        self.state_adapter
-            .lock()
-            .await
-            .apply_state_diff(&StateDiff {
-                workflow_run_id: task.workflow_run_id,
-                fields: state_diff,
-            })
-            .await?;
+        // Matrix nodes reading this state can get new tasks right away
+        self.notify(task.workflow_run_id);
         Ok(())
     }
 
     /// Update the status of a matrix master task
     async fn update_matrix_master_status(&self, master_task_id: Uuid) -> Result<()> {
         // Get the master task
-        let master_task = self
-            .state_adapter
-            .lock()
-            .await
-            .get_task(master_task_id)
-            .await?;
+        let master_task = self.state_adapter.get_task(master_task_id).await?;
 
         // Get all child tasks
         let tasks = self
             .state_adapter
-            .lock()
-            .await
             .get_tasks(master_task.workflow_run_id)
             .await?;
         let child_tasks: Vec<&Task> = tasks
@@ -1896,6 +2598,7 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(final_status)?),
+                    expected_revision: None,
                 },
             );
             // Add ended_at if moving to Completed/Failed
@@ -1905,6 +2608,7 @@ impl Engine {
                     FieldDiff {
                         operation: DiffOperation::Update,
                         value: Some(serde_json::to_value(Utc::now())?),
+                        expected_revision: None,
                     },
                 );
             }
@@ -1912,20 +2616,12 @@ impl Engine {
                 task_id: master_task_id,
                 fields,
             };
-            self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
-                .await?;
+            self.state_adapter.apply_task_diff(&task_diff).await?;
             return Ok(());
         }
 
         // Check status based on existing children
-        let all_terminal = child_tasks.iter().all(|t| {
-            t.status == TaskStatus::Completed
-                || t.status == TaskStatus::Failed
-                || t.status == TaskStatus::WontDo
-        });
+        let all_terminal = child_tasks.iter().all(|t| t.status.is_terminal());
 
         // If all children are in a terminal state, determine the final master status
         if all_terminal {
@@ -1934,6 +2630,8 @@ impl Engine {
             // Let's say Failed if any child failed, otherwise Completed (even if some are WontDo).
             let final_status = if any_failed {
                 TaskStatus::Failed
+            } else if child_tasks.iter().all(|t| t.status == TaskStatus::Skipped) {
+                TaskStatus::Skipped
             } else {
                 TaskStatus::Completed
             };
@@ -1948,6 +2646,7 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(final_status)?),
+                    expected_revision: None,
                 },
             );
             fields.insert(
@@ -1955,17 +2654,14 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(Utc::now())?),
+                    expected_revision: None,
                 },
             );
             let task_diff = TaskDiff {
                 task_id: master_task_id,
                 fields,
             };
-            self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
-                .await?;
+            self.state_adapter.apply_task_diff(&task_diff).await?;
             return Ok(());
         }
 
@@ -2006,29 +2702,22 @@ impl Engine {
                 FieldDiff {
                     operation: DiffOperation::Update,
                     value: Some(serde_json::to_value(new_status)?),
+                    expected_revision: None,
                 },
             );
 
             // Clear ended_at if moving away from a terminal state (e.g., Failed -> Running if retried, although retry isn't implemented here)
             // Or add ended_at if moving *to* Failed from a non-terminal state
-            if new_status == TaskStatus::Failed
-                && !matches!(
-                    master_task.status,
-                    TaskStatus::Completed | TaskStatus::Failed | TaskStatus::WontDo
-                )
-            {
+            if new_status == TaskStatus::Failed && !master_task.status.is_terminal() {
                 fields.insert(
                     "ended_at".to_string(),
                     FieldDiff {
                         operation: DiffOperation::Update, // Add or update ended_at
                         value: Some(serde_json::to_value(Utc::now())?),
+                        expected_revision: None,
                     },
                 );
-            } else if matches!(
-                master_task.status,
-                TaskStatus::Completed | TaskStatus::Failed | TaskStatus::WontDo
-            ) && new_status != TaskStatus::Failed
-            {
+            } else if master_task.status.is_terminal() && new_status != TaskStatus::Failed {
                 // If moving from terminal (except Failed) to non-terminal, clear ended_at? Or is this impossible?
                 // For now, only add ended_at when entering Failed/Completed.
             }
@@ -2039,11 +2728,7 @@ impl Engine {
             };
 
             // Apply the diff
-            self.state_adapter
-                .lock()
-                .await
-                .apply_task_diff(&task_diff)
-                .await?;
+            self.state_adapter.apply_task_diff(&task_diff).await?;
         } else {
             debug!("Master task {master_task_id} status {new_status:?} remains unchanged.");
         }
@@ -2060,6 +2745,134 @@ impl Clone for Engine {
             workflow_run_config: self.workflow_run_config.clone(),
             execution_stats: Arc::clone(&self.execution_stats),
             file_writer: Arc::clone(&self.file_writer),
+            events: self.events.clone(),
+            execution_loops: Arc::clone(&self.execution_loops),
+        }
+    }
+}
+
+/// Make a task pending again, releasing it from a manual trigger
+fn trigger_diff(task_id: Uuid) -> Result<TaskDiff> {
+    let mut fields = HashMap::new();
+    fields.insert(
+        "status".to_string(),
+        FieldDiff {
+            operation: DiffOperation::Update,
+            value: Some(serde_json::to_value(TaskStatus::Pending)?),
+            expected_revision: None,
+        },
+    );
+    fields.insert(
+        "triggered".to_string(),
+        FieldDiff {
+            operation: DiffOperation::Update,
+            value: Some(serde_json::to_value(true)?),
+            expected_revision: None,
+        },
+    );
+    Ok(TaskDiff { task_id, fields })
+}
+
+/// Whether a step's `if:` condition holds (steps without one always run)
+fn step_condition_met(step: &Step, context: &VariableContext) -> Result<bool> {
+    match &step.r#if {
+        Some(condition) => evaluate_condition(condition, context),
+        None => Ok(true),
+    }
+}
+
+/// Remove the bundle extracted for an imported workflow run, if there is one
+fn remove_imported_bundle(workflow_run_id: Uuid) {
+    let Ok(bundles_dir) = get_imported_bundles_dir() else {
+        return;
+    };
+    let bundle_path = bundles_dir.join(workflow_run_id.to_string());
+    if bundle_path.is_dir() {
+        if let Err(e) = std::fs::remove_dir_all(&bundle_path) {
+            warn!(
+                "Failed to remove the bundle of workflow run {workflow_run_id} at {}: {e}",
+                bundle_path.display()
+            );
+        }
+    }
+}
+
+/// Retries of a state write that conflicted with another task's
+fn state_conflict_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 5,
+        backoff: Backoff {
+            r#type: BackoffType::Exponential,
+            delay: "50ms".to_string(),
+            max_delay: Some("1s".to_string()),
+        },
+    }
+}
+
+/// Run a walk over the files of the target on a blocking thread
+///
+/// Dropping the returned future, as a timeout does, can't interrupt the
+/// thread, so the walk gets a flag that is set from then on. It should check
+/// the flag before each file and stop doing work once it is set.
+async fn run_blocking_walk<T, F>(walk: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&AtomicBool) -> Result<T> + Send + 'static,
+{
+    struct CancelOnDrop(Arc<AtomicBool>);
+
+    impl Drop for CancelOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(Arc::clone(&cancelled));
+    tokio::task::spawn_blocking(move || walk(&cancelled))
+        .await
+        .map_err(|e| Error::StepExecution(format!("Step panicked: {e}")))?
+}
+
+/// Run an operation under a retry policy, bounding each attempt by a timeout
+///
+/// The operation receives the attempt number, starting at 1. An attempt that
+/// times out is cancelled by dropping its future, which stops its process.
+async fn run_with_policy<T, F, Fut>(
+    what: &str,
+    retry: Option<&RetryPolicy>,
+    timeout: Option<&str>,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let timeout = match timeout {
+        Some(timeout) => Some((timeout, parse_duration(timeout).map_err(Error::Runtime)?)),
+        None => None,
+    };
+    let max_attempts = retry.map_or(1, |retry| retry.max_attempts.max(1));
+
+    let mut attempt = 1;
+    loop {
+        let result = match timeout {
+            Some((timeout, limit)) => time::timeout(limit, operation(attempt))
+                .await
+                .unwrap_or_else(|_| Err(Error::Runtime(format!("Timed out after {timeout}")))),
+            None => operation(attempt).await,
+        };
+
+        match (result, retry) {
+            (Err(e), Some(retry)) if attempt < max_attempts => {
+                let delay = retry.delay_after(attempt).map_err(Error::Runtime)?;
+                warn!(
+                    "{what} failed on attempt {attempt}/{max_attempts}, retrying in {delay:?}: {e}"
+                );
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+            (result, _) => return result,
         }
     }
 }
@@ -1,4 +1,12 @@
-use std::path::PathBuf;
+use codemod_sandbox::sandbox::engine::{ExecutionStats, FileOperation};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tempfile::TempDir;
 use tokio::sync::mpsc;
 
 /// File write operation for batched async I/O
@@ -6,7 +14,7 @@ use tokio::sync::mpsc;
 pub struct FileWriteOperation {
     path: PathBuf,
     content: String,
-    sender: tokio::sync::oneshot::Sender<std::io::Result<()>>,
+    sender: tokio::sync::oneshot::Sender<Result<()>>,
 }
 
 /// Async file writer that batches writes to reduce I/O contention
@@ -34,7 +42,7 @@ impl AsyncFileWriter {
         Self { sender }
     }
 
-    pub async fn write_file(&self, path: PathBuf, content: String) -> std::io::Result<()> {
+    pub async fn write_file(&self, path: PathBuf, content: String) -> Result<()> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let operation = FileWriteOperation {
             path,
@@ -43,14 +51,448 @@ impl AsyncFileWriter {
         };
 
         if self.sender.send(operation).is_err() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "File writer channel closed",
+            return Err(Error::new(ErrorKind::Other, "File writer channel closed"));
+        }
+
+        rx.await
+            .map_err(|_| Error::new(ErrorKind::Other, "File write operation canceled"))?
+    }
+}
+
+/// Make the paths of a file operation requested while processing
+/// `file_path` absolute, relative ones being relative to its directory
+pub fn resolve_file_operation(operation: FileOperation, file_path: &Path) -> FileOperation {
+    let dir = file_path.parent().unwrap_or(Path::new("."));
+    let resolve = |path: PathBuf| normalize(&dir.join(path));
+    match operation {
+        FileOperation::Write { path, content } => FileOperation::Write {
+            path: resolve(path),
+            content,
+        },
+        FileOperation::Rename { from, to } => FileOperation::Rename {
+            from: resolve(from),
+            to: resolve(to),
+        },
+        FileOperation::Delete { path } => FileOperation::Delete {
+            path: resolve(path),
+        },
+    }
+}
+
+/// Apply file operations requested by a codemod, all of them or none
+///
+/// Every path must be under `root`, once symlinks are resolved. If an
+/// operation fails, those applied before it are undone, and the stats are
+/// only updated once all succeeded. Renaming onto an existing file is
+/// refused rather than overwriting it.
+pub fn apply_file_operations(
+    root: &Path,
+    operations: &[FileOperation],
+    dry_run: bool,
+    stats: &ExecutionStats,
+) -> Result<()> {
+    let root = resolve(root)?;
+    for operation in operations {
+        for path in operation_paths(operation) {
+            if !resolve(path)?.starts_with(&root) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{} is outside of {}", path.display(), root.display()),
+                ));
+            }
+        }
+    }
+
+    let mut undo = Vec::new();
+    let mut counts = OperationCounts::default();
+    for operation in operations {
+        // Counted before applying, to tell writes of new files apart
+        counts.count(operation);
+        if dry_run {
+            info!("Would {} (dry run)", describe(operation));
+            continue;
+        }
+        if let Err(e) = apply(operation, &mut undo) {
+            for step in undo.into_iter().rev() {
+                if let Err(undo_error) = step.revert() {
+                    warn!("Failed to undo file operation: {undo_error}");
+                }
+            }
+            return Err(Error::new(
+                e.kind(),
+                format!("Failed to {}: {e}", describe(operation)),
             ));
         }
+    }
+
+    stats
+        .files_modified
+        .fetch_add(counts.modified, Ordering::Relaxed);
+    stats
+        .files_created
+        .fetch_add(counts.created, Ordering::Relaxed);
+    stats
+        .files_renamed
+        .fetch_add(counts.renamed, Ordering::Relaxed);
+    stats
+        .files_deleted
+        .fetch_add(counts.deleted, Ordering::Relaxed);
+    Ok(())
+}
 
-        rx.await.map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::Other, "File write operation canceled")
-        })?
+/// Changes a jssg step makes to the files under its root
+///
+/// Transformed files are written as soon as they're processed, keeping
+/// their previous content in an undo log on disk, so the walk doesn't have
+/// to hold every new content in memory. The file operations the codemod
+/// requests are buffered and applied by [`FileChanges::finish`]. Until then,
+/// [`FileChanges::revert`] puts every file back the way it was.
+pub struct FileChanges {
+    root: PathBuf,
+    dry_run: bool,
+    /// Previous content of the transformed files, one file each
+    undo_log: TempDir,
+    backups: AtomicUsize,
+    changes: Mutex<Changes>,
+}
+
+#[derive(Default)]
+struct Changes {
+    /// Transformed files, with the copy of their previous content in the
+    /// undo log, `None` in dry runs
+    rewritten: Vec<(PathBuf, Option<PathBuf>)>,
+    /// Requested operations, with the file each was requested for
+    operations: Vec<(PathBuf, FileOperation)>,
+}
+
+impl FileChanges {
+    pub fn new(root: &Path, dry_run: bool) -> Result<Self> {
+        Ok(Self {
+            root: resolve(root)?,
+            dry_run,
+            undo_log: tempfile::tempdir()?,
+            backups: AtomicUsize::new(0),
+            changes: Mutex::default(),
+        })
+    }
+
+    /// Replace the content of a transformed file
+    ///
+    /// If writing fails, the file keeps its previous content.
+    pub fn rewrite(&self, path: &Path, content: &str) -> Result<()> {
+        if !resolve(path)?.starts_with(&self.root) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is outside of {}", path.display(), self.root.display()),
+            ));
+        }
+        if self.dry_run {
+            info!("Would write {} (dry run)", path.display());
+            self.record_rewrite(path, None);
+            return Ok(());
+        }
+
+        let backup = self
+            .undo_log
+            .path()
+            .join(self.backups.fetch_add(1, Ordering::Relaxed).to_string());
+        std::fs::copy(path, &backup)?;
+        if let Err(e) = std::fs::write(path, content) {
+            restore(path, &backup);
+            return Err(e);
+        }
+        self.record_rewrite(path, Some(backup));
+        Ok(())
+    }
+
+    fn record_rewrite(&self, path: &Path, backup: Option<PathBuf>) {
+        self.changes
+            .lock()
+            .unwrap()
+            .rewritten
+            .push((path.to_path_buf(), backup));
+    }
+
+    /// Buffer the file operations requested while processing `file_path`
+    pub fn request(&self, file_path: &Path, operations: Vec<FileOperation>) {
+        let operations = operations.into_iter().map(|operation| {
+            (
+                file_path.to_path_buf(),
+                resolve_file_operation(operation, file_path),
+            )
+        });
+        self.changes.lock().unwrap().operations.extend(operations);
+    }
+
+    /// Apply the buffered file operations, skipping the files in conflict
+    ///
+    /// The changes for a file are left out, including its new content, if
+    /// they touch a path that the changes for another file touch too: which
+    /// of them came first would be down to chance. The operations of the
+    /// other files are applied all or none, and if they fail the transformed
+    /// files are reverted as well.
+    pub fn finish(self, stats: &ExecutionStats) -> Result<Vec<Conflict>> {
+        let Changes {
+            mut rewritten,
+            mut operations,
+        } = self.changes.into_inner().unwrap();
+
+        let conflicts = find_conflicts(
+            rewritten
+                .iter()
+                .map(|(file, _)| (file.as_path(), file.as_path()))
+                .chain(operations.iter().flat_map(|(file, operation)| {
+                    operation_paths(operation)
+                        .into_iter()
+                        .map(move |path| (file.as_path(), path.as_path()))
+                })),
+        )?;
+        let conflicting: HashSet<_> = conflicts.iter().map(|c| c.file.clone()).collect();
+        rewritten.retain(|(file, backup)| {
+            if !conflicting.contains(file) {
+                return true;
+            }
+            if let Some(backup) = backup {
+                restore(file, backup);
+            }
+            false
+        });
+        operations.retain(|(file, _)| !conflicting.contains(file));
+
+        let operations: Vec<_> = operations
+            .into_iter()
+            .map(|(_, operation)| operation)
+            .collect();
+        if let Err(e) = apply_file_operations(&self.root, &operations, self.dry_run, stats) {
+            revert(&rewritten);
+            return Err(e);
+        }
+        stats
+            .files_modified
+            .fetch_add(rewritten.len(), Ordering::Relaxed);
+        Ok(conflicts)
+    }
+
+    /// Put the transformed files back the way they were, dropping the
+    /// buffered file operations
+    pub fn revert(self) {
+        revert(&self.changes.into_inner().unwrap().rewritten);
+    }
+}
+
+/// Restore transformed files from the undo log, latest first
+fn revert(rewritten: &[(PathBuf, Option<PathBuf>)]) {
+    for (path, backup) in rewritten.iter().rev() {
+        if let Some(backup) = backup {
+            restore(path, backup);
+        }
+    }
+}
+
+fn restore(path: &Path, backup: &Path) {
+    if let Err(e) = std::fs::copy(backup, path) {
+        warn!("Failed to restore {}: {e}", path.display());
+    }
+}
+
+/// Changes for a file that were skipped because the changes for another
+/// file touch one of the same paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// File the changes were requested for
+    pub file: PathBuf,
+    /// Path changed for both files
+    pub path: PathBuf,
+    /// The other file
+    pub other: PathBuf,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Skipped the changes for {}, {} is also changed for {}",
+            self.file.display(),
+            self.path.display(),
+            self.other.display()
+        )
+    }
+}
+
+/// Files whose changes touch a path that the changes for another file touch
+///
+/// Takes each path that is changed along with the file it's changed for, and
+/// returns a conflict for every such file, ordered by file. A file may
+/// touch the same path several times, e.g. to change itself and then move.
+pub fn find_conflicts<'a>(
+    changes: impl IntoIterator<Item = (&'a Path, &'a Path)>,
+) -> Result<Vec<Conflict>> {
+    let mut changed_for: BTreeMap<PathBuf, Vec<&Path>> = BTreeMap::new();
+    for (file, path) in changes {
+        let files = changed_for.entry(normalize(&absolute(path)?)).or_default();
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+
+    let mut conflicts = BTreeMap::new();
+    for (path, files) in &changed_for {
+        for file in files {
+            let Some(other) = files.iter().find(|other| *other != file) else {
+                continue;
+            };
+            conflicts.entry(*file).or_insert_with(|| Conflict {
+                file: file.to_path_buf(),
+                path: path.clone(),
+                other: other.to_path_buf(),
+            });
+        }
+    }
+    Ok(conflicts.into_values().collect())
+}
+
+/// Paths an operation writes, creates or removes
+fn operation_paths(operation: &FileOperation) -> Vec<&PathBuf> {
+    match operation {
+        FileOperation::Write { path, .. } | FileOperation::Delete { path } => vec![path],
+        FileOperation::Rename { from, to } => vec![from, to],
+    }
+}
+
+#[derive(Default)]
+struct OperationCounts {
+    modified: usize,
+    created: usize,
+    renamed: usize,
+    deleted: usize,
+}
+
+impl OperationCounts {
+    fn count(&mut self, operation: &FileOperation) {
+        match operation {
+            FileOperation::Write { path, .. } if path.exists() => self.modified += 1,
+            FileOperation::Write { .. } => self.created += 1,
+            FileOperation::Rename { .. } => self.renamed += 1,
+            FileOperation::Delete { .. } => self.deleted += 1,
+        }
+    }
+}
+
+/// How to revert an applied file operation
+enum Undo {
+    Remove(PathBuf),
+    Restore(PathBuf, Vec<u8>),
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+impl Undo {
+    fn revert(self) -> Result<()> {
+        match self {
+            Undo::Remove(path) => std::fs::remove_file(path),
+            Undo::Restore(path, content) => std::fs::write(path, content),
+            Undo::Rename { from, to } => std::fs::rename(from, to),
+        }
+    }
+}
+
+/// Apply a single operation, recording how to revert it
+fn apply(operation: &FileOperation, undo: &mut Vec<Undo>) -> Result<()> {
+    match operation {
+        FileOperation::Write { path, content } => {
+            let previous = match std::fs::read(path) {
+                Ok(previous) => Some(previous),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, content)?;
+            undo.push(match previous {
+                Some(previous) => Undo::Restore(path.clone(), previous),
+                None => Undo::Remove(path.clone()),
+            });
+        }
+        FileOperation::Rename { from, to } => {
+            if to.exists() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", to.display()),
+                ));
+            }
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(from, to)?;
+            undo.push(Undo::Rename {
+                from: to.clone(),
+                to: from.clone(),
+            });
+        }
+        FileOperation::Delete { path } => {
+            let previous = std::fs::read(path)?;
+            std::fs::remove_file(path)?;
+            undo.push(Undo::Restore(path.clone(), previous));
+        }
+    }
+    Ok(())
+}
+
+fn describe(operation: &FileOperation) -> String {
+    match operation {
+        FileOperation::Write { path, .. } => format!("write {}", path.display()),
+        FileOperation::Rename { from, to } => {
+            format!("rename {} to {}", from.display(), to.display())
+        }
+        FileOperation::Delete { path } => format!("delete {}", path.display()),
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+/// Where a path, which may not exist yet, really points to
+///
+/// The nearest ancestor that exists is canonicalized, so a symlink on the
+/// way can't lead out of the root; the components below it are appended.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let path = normalize(&absolute(path)?);
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    // Symlinks count as existing even if broken, so they can't be skipped
+    while existing.symlink_metadata().is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Resolve `.` and `..` components without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            _ => normalized.push(component),
+        }
     }
+    normalized
 }
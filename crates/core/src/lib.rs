@@ -1,4 +1,7 @@
+pub mod archive;
+pub mod concurrency;
 pub mod config;
+pub mod emitted_state;
 pub mod engine;
 pub mod execution;
 pub mod file_ops;
@@ -5,7 +5,13 @@ use std::path::{Path, PathBuf};
 use butterflow_models::step::StepAction;
 use serde_yaml;
 
-use butterflow_models::{Error, Node, Result, Workflow};
+use butterflow_models::{
+    check_expression, parse_duration, DiffOperation, Error, FieldDiff, Node, Result, RetryPolicy,
+    StateSchemaType, Workflow,
+};
+use butterflow_state::apply_operation;
+
+use crate::emitted_state::add_numbers;
 
 /// Parse a workflow definition from a file
 pub fn parse_workflow_file<P: AsRef<Path>>(path: P) -> Result<Workflow> {
@@ -53,6 +59,18 @@ pub fn validate_workflow(workflow: &Workflow, package_path: &Path) -> Result<()>
         }
     }
 
+    // Check that template input declarations are well-formed
+    for template in &workflow.templates {
+        for input in &template.inputs {
+            input.validate().map_err(|e| {
+                Error::WorkflowValidation(format!(
+                    "Template {} declares an invalid input: {e}",
+                    template.id
+                ))
+            })?;
+        }
+    }
+
     // Check that all dependencies exist
     for node in &workflow.nodes {
         for dep_id in &node.depends_on {
@@ -72,12 +90,23 @@ pub fn validate_workflow(workflow: &Workflow, package_path: &Path) -> Result<()>
     for node in &workflow.nodes {
         for step in &node.steps {
             if let StepAction::UseTemplate(template_use) = &step.action {
-                if !template_ids.contains(&template_use.template) {
-                    return Err(Error::WorkflowValidation(format!(
-                        "Step {} in node {} uses non-existent template: {}",
-                        step.name, node.id, template_use.template
-                    )));
-                }
+                let template = workflow
+                    .templates
+                    .iter()
+                    .find(|t| t.id == template_use.template)
+                    .ok_or_else(|| {
+                        Error::WorkflowValidation(format!(
+                            "Step {} in node {} uses non-existent template: {}",
+                            step.name, node.id, template_use.template
+                        ))
+                    })?;
+
+                template.check_inputs(&template_use.inputs).map_err(|e| {
+                    Error::WorkflowValidation(format!(
+                        "Step {} in node {} has invalid inputs: {e}",
+                        step.name, node.id
+                    ))
+                })?;
             } else if let StepAction::JSAstGrep(js_step) = &step.action {
                 let js_file_path = package_path.join(&js_step.js_file);
                 if !js_file_path.exists() {
@@ -98,18 +127,131 @@ pub fn validate_workflow(workflow: &Workflow, package_path: &Path) -> Result<()>
         }
     }
 
+    // Check that conditions parse
+    for node in &workflow.nodes {
+        if let Some(condition) = &node.r#if {
+            check_expression(condition).map_err(|e| {
+                Error::WorkflowValidation(format!("Invalid condition on node {}: {e}", node.id))
+            })?;
+        }
+    }
+    let steps = workflow.nodes.iter().flat_map(|node| &node.steps).chain(
+        workflow
+            .templates
+            .iter()
+            .flat_map(|template| &template.steps),
+    );
+    for step in steps {
+        if let Some(condition) = &step.r#if {
+            check_expression(condition).map_err(|e| {
+                Error::WorkflowValidation(format!("Invalid condition on step {}: {e}", step.name))
+            })?;
+        }
+    }
+
+    // Check retry policies and timeouts
+    for node in &workflow.nodes {
+        check_execution_policy(&format!("node {}", node.id), &node.retry, &node.timeout)?;
+    }
+    let steps = workflow.nodes.iter().flat_map(|node| &node.steps).chain(
+        workflow
+            .templates
+            .iter()
+            .flat_map(|template| &template.steps),
+    );
+    for step in steps {
+        check_execution_policy(&format!("step {}", step.name), &step.retry, &step.timeout)?;
+    }
+
     // Check matrix strategies
     for node in &workflow.nodes {
         if let Some(strategy) = &node.strategy {
-            if strategy.values.is_none() && strategy.from_state.is_none() {
+            if strategy.values.is_none()
+                && strategy.from_state.is_none()
+                && strategy.axes.is_none()
+                && strategy.include.is_none()
+            {
                 return Err(Error::WorkflowValidation(format!(
-                    "Matrix strategy for node {} requires either 'values' or 'from_state'",
+                    "Matrix strategy for node {} requires 'values', 'from_state', 'axes' or 'include'",
                     node.id
                 )));
             }
+            if strategy.values.is_some() && strategy.from_state.is_some() {
+                return Err(Error::WorkflowValidation(format!(
+                    "Matrix strategy for node {} cannot use both 'values' and 'from_state'",
+                    node.id
+                )));
+            }
+            if let Some((axis, _)) = strategy
+                .axes
+                .iter()
+                .flatten()
+                .find(|(_, values)| values.is_empty())
+            {
+                return Err(Error::WorkflowValidation(format!(
+                    "Matrix axis '{}' of node {} has no values",
+                    axis, node.id
+                )));
+            }
+            let mut entries = strategy.include.iter().chain(&strategy.exclude).flatten();
+            if entries.any(|entry| entry.is_empty()) {
+                return Err(Error::WorkflowValidation(format!(
+                    "Matrix include and exclude entries of node {} must not be empty",
+                    node.id
+                )));
+            }
+            if strategy.max_parallel == Some(0) {
+                return Err(Error::WorkflowValidation(format!(
+                    "Matrix strategy for node {} must allow at least 1 parallel task",
+                    node.id
+                )));
+            }
+        }
+    }
+
+    // Check the state schema and the state keys matrices are generated from
+    let state = workflow.state.clone().unwrap_or_default();
+    let mut state_names = HashSet::new();
+    for schema in &state.schema {
+        if !state_names.insert(&schema.name) {
+            return Err(Error::WorkflowValidation(format!(
+                "Duplicate state schema: {}",
+                schema.name
+            )));
+        }
+        schema
+            .validate()
+            .map_err(|e| Error::WorkflowValidation(format!("Invalid state schema: {e}")))?;
+    }
+    if !state.schema.is_empty() {
+        for node in &workflow.nodes {
+            let Some(key) = node.strategy.as_ref().and_then(|s| s.from_state.as_ref()) else {
+                continue;
+            };
+            match state.get(key) {
+                Some(schema) if schema.r#type == StateSchemaType::Array => {}
+                Some(schema) => {
+                    return Err(Error::WorkflowValidation(format!(
+                        "Matrix strategy for node {} reads state {key}, which is declared with type {} instead of array",
+                        node.id, schema.r#type
+                    )));
+                }
+                None => {
+                    return Err(Error::WorkflowValidation(format!(
+                        "Matrix strategy for node {} reads state {key}, which the state schema doesn't declare",
+                        node.id
+                    )));
+                }
+            }
         }
     }
 
+    if workflow.concurrency == Some(0) {
+        return Err(Error::WorkflowValidation(
+            "Workflow concurrency must be at least 1".to_string(),
+        ));
+    }
+
     Ok(())
 }
 
@@ -206,6 +348,195 @@ pub fn parse_params(params: &[String]) -> Result<HashMap<String, String>> {
     Ok(result)
 }
 
+/// Operations selected by a `key[operation]=value` line of `STATE_OUTPUTS`
+const STATE_OUTPUT_OPERATIONS: [(&str, DiffOperation); 4] = [
+    ("append", DiffOperation::Append),
+    ("remove", DiffOperation::RemoveFromArray),
+    ("increment", DiffOperation::Increment),
+    ("merge", DiffOperation::Merge),
+];
+
+/// Writes a step made through its `STATE_OUTPUTS` file
+#[derive(Debug, Default)]
+pub struct StateOutputs {
+    /// Writes per key, in the order they were made
+    writes: HashMap<String, Vec<FieldDiff>>,
+}
+
+impl StateOutputs {
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Whether a key is written in a way that can only be combined on top of
+    /// its current value, so the diff may conflict with other tasks
+    pub fn reads_state(&self) -> bool {
+        self.writes
+            .values()
+            .any(|writes| combine_writes(writes).is_none())
+    }
+
+    /// Fields of a single diff making every write on top of `state`
+    ///
+    /// A key written once keeps its operation, and so do repeated increments
+    /// and merges, which add up. After a replacement the rest of a key's
+    /// writes are applied to the new value. Other keys written more than once,
+    /// e.g. appended to twice, are computed from their current value, so their
+    /// fields expect the revision from `revisions`.
+    pub fn to_fields(
+        &self,
+        state: &HashMap<String, serde_json::Value>,
+        revisions: &HashMap<String, u64>,
+    ) -> Result<HashMap<String, FieldDiff>> {
+        let mut fields = HashMap::new();
+        for (key, writes) in &self.writes {
+            let field = match combine_writes(writes) {
+                Some(field) => field,
+                None => {
+                    let mut value = state.get(key).cloned();
+                    for write in writes {
+                        value = apply_operation(key, value, write)?;
+                    }
+                    FieldDiff {
+                        operation: if value.is_some() {
+                            DiffOperation::Update
+                        } else {
+                            DiffOperation::Remove
+                        },
+                        value,
+                        expected_revision: Some(revisions.get(key).copied().unwrap_or(0)),
+                    }
+                }
+            };
+            fields.insert(key.clone(), field);
+        }
+        Ok(fields)
+    }
+}
+
+/// Combine the writes to a key into one field that doesn't depend on its
+/// current value, if they allow it
+fn combine_writes(writes: &[FieldDiff]) -> Option<FieldDiff> {
+    if let [write] = writes {
+        return Some(write.clone());
+    }
+
+    // Earlier writes don't matter once the value is replaced
+    if let Some(last_update) = writes
+        .iter()
+        .rposition(|write| write.operation == DiffOperation::Update)
+    {
+        let replacement = &writes[last_update];
+        let mut value = replacement.value.clone();
+        for write in &writes[last_update + 1..] {
+            // Errors show up again when the writes are applied one by one
+            value = apply_operation("", value, write).ok()?;
+        }
+        return Some(FieldDiff {
+            operation: DiffOperation::Update,
+            value,
+            expected_revision: replacement.expected_revision,
+        });
+    }
+
+    let operation = writes[0].operation.clone();
+    if writes.iter().any(|write| write.operation != operation) {
+        return None;
+    }
+    let mut values = writes.iter().map(|write| write.value.clone());
+    let first = values.next()?;
+    let value = match operation {
+        DiffOperation::Increment => {
+            values.try_fold(first?, |total, value| add_numbers(&total, &value?))?
+        }
+        DiffOperation::Merge => values.try_fold(first?, |mut patch, value| {
+            patch.as_object_mut()?.extend(value?.as_object()?.clone());
+            Some(patch)
+        })?,
+        _ => return None,
+    };
+    Some(FieldDiff {
+        operation,
+        value: Some(value),
+        expected_revision: None,
+    })
+}
+
+/// Parse the lines a step wrote to its `STATE_OUTPUTS` file
+///
+/// Each line is `key=value` or `key[operation]=value`, where the value is
+/// JSON or a plain string:
+///
+/// - `key=value` replaces the value
+/// - `key[if-unchanged]=value` replaces the value unless another task wrote
+///   the key since this step started, i.e. since the state had `revisions`
+/// - `key[append]=value`, or `key@=value`, appends to an array
+/// - `key[remove]=value` removes every occurrence of the value from an array
+/// - `key[increment]=number` increments a number (use a negative number to
+///   decrement)
+/// - `key[merge]=object` shallow-merges into an object
+///
+/// Any other key is taken as written, so `a+=1` sets the key `a+`.
+/// Malformed lines are skipped.
+pub fn parse_state_outputs(outputs: &str, revisions: &HashMap<String, u64>) -> StateOutputs {
+    let mut state_outputs = StateOutputs::default();
+
+    for line in outputs.lines() {
+        // Check for empty lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Some((key, value_str)) = line.split_once('=') else {
+            // Malformed line, log and skip
+            log::warn!("Malformed state output line: {line}");
+            continue;
+        };
+
+        let (key, operation, compare) = parse_state_output_key(key);
+        if key.is_empty() {
+            log::warn!("Malformed state output line: {line}");
+            continue;
+        }
+
+        // Try to parse value as JSON first, fall back to string if that fails
+        let value = serde_json::from_str::<serde_json::Value>(value_str)
+            .unwrap_or_else(|_| serde_json::Value::String(value_str.to_string()));
+
+        state_outputs
+            .writes
+            .entry(key.to_string())
+            .or_default()
+            .push(FieldDiff {
+                operation,
+                value: Some(value),
+                expected_revision: compare.then(|| revisions.get(key).copied().unwrap_or(0)),
+            });
+    }
+
+    state_outputs
+}
+
+/// Split the key of a `STATE_OUTPUTS` line from the operation it selects,
+/// and whether the write compares revisions
+fn parse_state_output_key(key: &str) -> (&str, DiffOperation, bool) {
+    if let Some(key) = key.strip_suffix('@') {
+        return (key, DiffOperation::Append, false);
+    }
+    if let Some((key, operation)) = key.strip_suffix(']').and_then(|key| key.rsplit_once('[')) {
+        if operation == "if-unchanged" {
+            return (key, DiffOperation::Update, true);
+        }
+        if let Some((_, operation)) = STATE_OUTPUT_OPERATIONS
+            .iter()
+            .find(|(name, _)| *name == operation)
+        {
+            return (key, operation.clone(), false);
+        }
+    }
+    (key, DiffOperation::Update, false)
+}
+
 /// Get environment variables as a HashMap
 pub fn get_env_vars() -> HashMap<String, String> {
     std::env::vars().collect()
@@ -226,3 +557,45 @@ pub fn get_cache_dir() -> Result<PathBuf> {
     let cache_dir = home_dir.join("codemod").join("cache").join("packages");
     Ok(cache_dir)
 }
+
+/// Get the directory that bundles of imported workflow runs are extracted to
+pub fn get_imported_bundles_dir() -> Result<PathBuf> {
+    let home_dir = dirs::data_dir()
+        .ok_or_else(|| Error::Other("Could not find home directory".to_string()))?;
+    Ok(home_dir.join("codemod").join("runs"))
+}
+
+/// Describe where a workflow comes from, for finding its runs later
+///
+/// This is the package name from the bundle's `codemod.yaml` if it has one,
+/// otherwise the path of the workflow file.
+pub fn get_workflow_source(workflow_file_path: &Path, bundle_path: Option<&Path>) -> String {
+    #[derive(serde::Deserialize)]
+    struct Manifest {
+        name: String,
+    }
+
+    bundle_path
+        .and_then(|path| fs::read_to_string(path.join("codemod.yaml")).ok())
+        .and_then(|content| serde_yaml::from_str::<Manifest>(&content).ok())
+        .map(|manifest| manifest.name)
+        .unwrap_or_else(|| workflow_file_path.display().to_string())
+}
+
+/// Check the retry policy and timeout of a node or step
+fn check_execution_policy(
+    owner: &str,
+    retry: &Option<RetryPolicy>,
+    timeout: &Option<String>,
+) -> Result<()> {
+    if let Some(retry) = retry {
+        retry.validate().map_err(|e| {
+            Error::WorkflowValidation(format!("Invalid retry policy on {owner}: {e}"))
+        })?;
+    }
+    if let Some(timeout) = timeout {
+        parse_duration(timeout)
+            .map_err(|e| Error::WorkflowValidation(format!("Invalid timeout on {owner}: {e}")))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use butterflow_core::emitted_state::EmittedState;
+use butterflow_models::DiffOperation;
+use codemod_sandbox::sandbox::engine::StateWrite;
+use serde_json::json;
+
+fn set(key: &str, value: serde_json::Value) -> StateWrite {
+    StateWrite::Set {
+        key: key.to_string(),
+        value,
+    }
+}
+
+fn append(key: &str, value: serde_json::Value) -> StateWrite {
+    StateWrite::Append {
+        key: key.to_string(),
+        value,
+    }
+}
+
+fn increment(key: &str, value: serde_json::Value) -> StateWrite {
+    StateWrite::Increment {
+        key: key.to_string(),
+        value,
+    }
+}
+
+#[test]
+fn test_emitted_state_combines_writes_of_all_files() {
+    let mut emitted = EmittedState::new();
+    assert!(emitted.is_empty());
+
+    emitted.add(vec![
+        increment("count", json!(1)),
+        append("files", json!("a.ts")),
+    ]);
+    emitted.add(vec![
+        increment("count", json!(2)),
+        append("files", json!("b.ts")),
+    ]);
+    assert!(!emitted.is_empty());
+
+    let state = HashMap::from([
+        ("count".to_string(), json!(10)),
+        ("files".to_string(), json!(["old.ts"])),
+    ]);
+    let revisions = HashMap::from([("count".to_string(), 3), ("files".to_string(), 5)]);
+    let fields = emitted.to_fields(&state, &revisions).unwrap();
+
+    assert_eq!(fields["count"].operation, DiffOperation::Update);
+    assert_eq!(fields["count"].value, Some(json!(13)));
+    assert_eq!(fields["count"].expected_revision, Some(3));
+    assert_eq!(
+        fields["files"].value,
+        Some(json!(["old.ts", "a.ts", "b.ts"]))
+    );
+    assert_eq!(fields["files"].expected_revision, Some(5));
+}
+
+#[test]
+fn test_emitted_state_applies_set_before_other_writes() {
+    let mut emitted = EmittedState::new();
+    emitted.add(vec![
+        append("found", json!(1)),
+        set("found", json!([])),
+        append("found", json!(2)),
+    ]);
+    emitted.add(vec![set("mode", json!("strict"))]);
+
+    let state = HashMap::from([("found".to_string(), json!([0]))]);
+    let fields = emitted.to_fields(&state, &HashMap::new()).unwrap();
+
+    // Doesn't depend on the current value, so nothing to conflict with
+    assert_eq!(fields["found"].value, Some(json!([1, 2])));
+    assert_eq!(fields["found"].expected_revision, None);
+    assert_eq!(fields["mode"].value, Some(json!("strict")));
+    assert_eq!(fields["mode"].expected_revision, None);
+}
+
+#[test]
+fn test_emitted_state_does_not_depend_on_file_order() {
+    let a = || vec![increment("count", json!(1)), append("files", json!("a.ts"))];
+    let b = || vec![set("count", json!(10)), set("files", json!([]))];
+
+    let mut a_first = EmittedState::new();
+    a_first.add(a());
+    a_first.add(b());
+    let mut b_first = EmittedState::new();
+    b_first.add(b());
+    b_first.add(a());
+
+    let state = HashMap::from([("count".to_string(), json!(5))]);
+    for emitted in [a_first, b_first] {
+        let fields = emitted.to_fields(&state, &HashMap::new()).unwrap();
+        assert_eq!(fields["count"].value, Some(json!(11)));
+        assert_eq!(fields["files"].value, Some(json!(["a.ts"])));
+    }
+}
+
+#[test]
+fn test_emitted_state_new_keys_expect_to_be_unset() {
+    let mut emitted = EmittedState::new();
+    emitted.add(vec![
+        increment("count", json!(0.5)),
+        increment("count", json!(1)),
+    ]);
+
+    let fields = emitted.to_fields(&HashMap::new(), &HashMap::new()).unwrap();
+    assert_eq!(fields["count"].value, Some(json!(1.5)));
+    assert_eq!(fields["count"].expected_revision, Some(0));
+}
+
+#[test]
+fn test_emitted_state_rejects_increment_of_non_number() {
+    let mut emitted = EmittedState::new();
+    emitted.add(vec![increment("name", json!(1))]);
+
+    let state = HashMap::from([("name".to_string(), json!("codemod"))]);
+    assert!(emitted.to_fields(&state, &HashMap::new()).is_err());
+}
@@ -1,4 +1,5 @@
-use butterflow_core::config::WorkflowRunConfig;
+use butterflow_core::concurrency::ConcurrencyLimits;
+use butterflow_core::config::{RetentionPolicy, WorkflowRunConfig};
 use butterflow_state::mock_adapter::MockStateAdapter;
 use std::collections::HashMap;
 use std::fs;
@@ -13,11 +14,28 @@ use butterflow_models::node::NodeType;
 use butterflow_models::step::{StepAction, UseAstGrep, UseJSAstGrep};
 use butterflow_models::strategy::Strategy;
 use butterflow_models::trigger::TriggerType;
-use butterflow_models::{DiffOperation, FieldDiff, TaskDiff};
+use butterflow_models::{
+    Backoff, BackoffType, DiffOperation, FieldDiff, LogStream, RetryPolicy, TaskDiff,
+};
 use butterflow_state::local_adapter::LocalStateAdapter;
-use butterflow_state::StateAdapter;
+use butterflow_state::sqlite_adapter::SqliteStateAdapter;
+use butterflow_state::{RunOrder, RunQuery, StateAdapter};
+use codemod_sandbox::sandbox::engine::TransformOptions;
 use uuid::Uuid;
 
+/// Everything a task wrote to stdout
+async fn task_output(engine: &Engine, task_id: Uuid) -> String {
+    engine
+        .get_task_logs(task_id, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|line| line.stream == LogStream::Stdout)
+        .map(|line| line.line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 // Helper function to create a simple test workflow
 fn create_test_workflow() -> Workflow {
     Workflow {
@@ -31,6 +49,7 @@ fn create_test_workflow() -> Workflow {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -43,9 +62,17 @@ fn create_test_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Hello, World!'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -54,6 +81,7 @@ fn create_test_workflow() -> Workflow {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -66,12 +94,21 @@ fn create_test_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Node 2 executed'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     }
 }
 
@@ -88,6 +125,7 @@ fn create_manual_trigger_workflow() -> Workflow {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -100,9 +138,17 @@ fn create_manual_trigger_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Hello, World!'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -111,6 +157,7 @@ fn create_manual_trigger_workflow() -> Workflow {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: Some(butterflow_models::trigger::Trigger {
                     r#type: TriggerType::Manual,
                 }),
@@ -125,12 +172,21 @@ fn create_manual_trigger_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Node 2 executed'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     }
 }
 
@@ -147,6 +203,7 @@ fn create_manual_node_workflow() -> Workflow {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -159,9 +216,17 @@ fn create_manual_node_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Hello, World!'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -170,6 +235,7 @@ fn create_manual_node_workflow() -> Workflow {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Manual,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -182,12 +248,21 @@ fn create_manual_node_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Node 2 executed'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     }
 }
 
@@ -204,6 +279,7 @@ fn create_matrix_workflow() -> Workflow {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -216,9 +292,17 @@ fn create_matrix_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Hello, World!'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -227,6 +311,7 @@ fn create_matrix_workflow() -> Workflow {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: Some(Strategy {
                     r#type: butterflow_models::strategy::StrategyType::Matrix,
@@ -245,6 +330,10 @@ fn create_matrix_workflow() -> Workflow {
                         )]),
                     ]),
                     from_state: None,
+                    axes: None,
+                    include: None,
+                    exclude: None,
+                    max_parallel: None,
                 }),
                 runtime: Some(Runtime {
                     r#type: RuntimeType::Direct,
@@ -256,12 +345,21 @@ fn create_matrix_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Processing region ${region}'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     }
 }
 
@@ -297,10 +395,15 @@ fn create_template_workflow() -> Workflow {
         }),
         steps: vec![Step {
             name: "Clone repository".to_string(),
+            id: None,
+            r#if: None,
             action: StepAction::RunScript(
                 "echo 'Cloning repository ${inputs.repo_url} branch ${inputs.branch}'".to_string(),
             ),
             env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
         }],
         outputs: vec![],
         env: HashMap::new(),
@@ -316,6 +419,7 @@ fn create_template_workflow() -> Workflow {
             description: Some("Test node 1".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -328,6 +432,8 @@ fn create_template_workflow() -> Workflow {
             }),
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::UseTemplate(butterflow_models::step::TemplateUse {
                     template: "checkout-repo".to_string(),
                     inputs: HashMap::from([
@@ -339,9 +445,16 @@ fn create_template_workflow() -> Workflow {
                     ]),
                 }),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     }
 }
 
@@ -363,8 +476,10 @@ fn create_matrix_from_state_workflow() -> Workflow {
                         },
                     )])),
                 })),
+                properties: None,
                 description: None,
             }],
+            strict: false,
         }),
         templates: vec![],
         nodes: vec![
@@ -374,6 +489,7 @@ fn create_matrix_from_state_workflow() -> Workflow {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -386,9 +502,17 @@ fn create_matrix_from_state_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Setting up state'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -397,11 +521,16 @@ fn create_matrix_from_state_workflow() -> Workflow {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: Some(Strategy {
                     r#type: butterflow_models::strategy::StrategyType::Matrix,
                     values: None,
                     from_state: Some("files".to_string()),
+                    axes: None,
+                    include: None,
+                    exclude: None,
+                    max_parallel: None,
                 }),
                 runtime: Some(Runtime {
                     r#type: RuntimeType::Direct,
@@ -413,12 +542,21 @@ fn create_matrix_from_state_workflow() -> Workflow {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Processing file ${file}'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     }
 }
 
@@ -456,6 +594,137 @@ async fn test_run_workflow() {
     );
 }
 
+#[tokio::test]
+async fn test_run_workflow_with_sqlite_adapter() {
+    let state_adapter = Box::new(SqliteStateAdapter::in_memory().unwrap());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow_run_id = engine
+        .run_workflow(create_test_workflow(), HashMap::new(), None)
+        .await
+        .unwrap();
+
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    assert_eq!(tasks.len(), 2);
+    assert!(tasks.iter().all(|t| t.status == TaskStatus::Completed));
+}
+
+#[tokio::test]
+async fn test_matrix_shards_update_shared_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("state.db");
+    let state_adapter = Box::new(SqliteStateAdapter::with_path(&path).unwrap());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    // Every shard appends twice to the same key and bumps a counter
+    let mut workflow = create_matrix_workflow();
+    workflow.nodes[1].steps[0].action = StepAction::RunScript(
+        "echo 'failures@=${region}' >> $STATE_OUTPUTS && \
+         echo 'failures@=${region}-retry' >> $STATE_OUTPUTS && \
+         echo 'processed[increment]=1' >> $STATE_OUTPUTS"
+            .to_string(),
+    );
+
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    let state = SqliteStateAdapter::with_path(&path)
+        .unwrap()
+        .get_state(workflow_run_id)
+        .await
+        .unwrap();
+    assert_eq!(state["failures"].as_array().unwrap().len(), 6);
+    assert_eq!(state["processed"], serde_json::json!(3));
+}
+
+#[tokio::test]
+async fn test_state_writes_are_journaled_per_task() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let mut workflow = create_test_workflow();
+    workflow.nodes[0].steps[0].action = StepAction::RunScript(
+        "echo 'count=1' >> $STATE_OUTPUTS && echo 'first=yes' >> $STATE_OUTPUTS".to_string(),
+    );
+    workflow.nodes[1].steps[0].action =
+        StepAction::RunScript("echo 'count[increment]=1' >> $STATE_OUTPUTS".to_string());
+
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let task_id = |node_id: &str| tasks.iter().find(|t| t.node_id == node_id).unwrap().id;
+
+    let journal = engine.get_state_journal(workflow_run_id).await.unwrap();
+    assert_eq!(journal.len(), 2);
+    assert_eq!(journal[0].task_id, Some(task_id("node1")));
+    assert_eq!(journal[0].step.as_deref(), Some("Step 1"));
+    assert_eq!(journal[1].task_id, Some(task_id("node2")));
+    assert_eq!(journal[1].values["count"], serde_json::json!(2));
+
+    // State as each task left it
+    let state = engine
+        .get_state_at(workflow_run_id, task_id("node1"))
+        .await
+        .unwrap();
+    assert_eq!(state["count"], serde_json::json!(1));
+    assert_eq!(state["first"], serde_json::json!("yes"));
+    let state = engine
+        .get_state_at(workflow_run_id, task_id("node2"))
+        .await
+        .unwrap();
+    assert_eq!(state["count"], serde_json::json!(2));
+    assert!(engine
+        .get_state_at(workflow_run_id, Uuid::new_v4())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_state_writes_are_checked_against_schema() {
+    let run = |output: &str| {
+        let mut workflow = create_matrix_from_state_workflow();
+        workflow.nodes[0].steps[0].action =
+            StepAction::RunScript(format!("echo '{output}' >> $STATE_OUTPUTS"));
+        async move {
+            let state_adapter = Box::new(MockStateAdapter::new());
+            let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+            let workflow_run_id = engine
+                .run_workflow(workflow, HashMap::new(), None)
+                .await
+                .unwrap();
+            let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+            (status, engine.get_tasks(workflow_run_id).await.unwrap())
+        }
+    };
+
+    let (status, tasks) = run(r#"files@={"file":"a.ts"}"#).await;
+    assert_eq!(status, WorkflowStatus::Completed);
+    assert_eq!(tasks.iter().filter(|t| t.node_id == "node2").count(), 2);
+
+    // A string can't stand in for the array the matrix is generated from
+    let (status, tasks) = run("files=a.ts").await;
+    assert_eq!(status, WorkflowStatus::Failed);
+    let node1 = tasks.iter().find(|t| t.node_id == "node1").unwrap();
+    assert_eq!(node1.status, TaskStatus::Failed);
+    assert!(node1
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("must be of type array"));
+}
+
 #[tokio::test]
 async fn test_get_workflow_status() {
     let state_adapter = Box::new(MockStateAdapter::new());
@@ -534,12 +803,74 @@ async fn test_list_workflow_runs() {
     assert!(run_ids.contains(&workflow_run_id2));
 }
 
+#[tokio::test]
+async fn test_query_workflow_runs() {
+    let temp_dir = TempDir::new().unwrap();
+    let bundle_path = temp_dir.path().to_path_buf();
+    fs::write(
+        bundle_path.join("codemod.yaml"),
+        "name: \"@org/react-19\"\nversion: \"1.0.0\"\n",
+    )
+    .unwrap();
+
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let config = WorkflowRunConfig {
+        target_path: bundle_path.join("src"),
+        ..WorkflowRunConfig::default()
+    };
+    let engine = Engine::with_state_adapter(state_adapter, config);
+
+    let mut run_ids = Vec::new();
+    for mode in ["a", "b", "a"] {
+        let params = HashMap::from([("mode".to_string(), mode.to_string())]);
+        let run_id = engine
+            .run_workflow(create_test_workflow(), params, Some(bundle_path.clone()))
+            .await
+            .unwrap();
+        run_ids.push(run_id);
+    }
+    engine
+        .run_workflow(create_test_workflow(), HashMap::new(), None)
+        .await
+        .unwrap();
+
+    let mut query = RunQuery {
+        source: Some("@org/react-19".to_string()),
+        target_path: Some(bundle_path.clone()),
+        params: HashMap::from([("mode".to_string(), "a".to_string())]),
+        order: RunOrder::OldestFirst,
+        limit: Some(1),
+        ..RunQuery::default()
+    };
+    let page = engine.query_workflow_runs(&query).await.unwrap();
+    assert_eq!(page.runs.len(), 1);
+    assert_eq!(page.runs[0].id, run_ids[0]);
+
+    query.cursor = page.next_cursor;
+    let page = engine.query_workflow_runs(&query).await.unwrap();
+    assert_eq!(page.runs.len(), 1);
+    assert_eq!(page.runs[0].id, run_ids[2]);
+    assert!(page.next_cursor.is_none());
+
+    // Runs without a codemod.yaml are found by their workflow file
+    let query = RunQuery {
+        source: Some("workflow.json".to_string()),
+        ..RunQuery::default()
+    };
+    assert_eq!(
+        engine.query_workflow_runs(&query).await.unwrap().runs.len(),
+        1
+    );
+}
+
 #[tokio::test]
 async fn test_cancel_workflow() {
     let state_adapter = Box::new(MockStateAdapter::new());
     let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
 
-    let workflow = create_test_workflow();
+    // Keep the first node busy so the run is still going when it is canceled
+    let mut workflow = create_test_workflow();
+    workflow.nodes[0].steps[0].action = StepAction::RunScript("sleep 2".to_string());
     let params = HashMap::new();
 
     let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
@@ -571,144 +902,1348 @@ async fn test_manual_trigger_workflow() {
     // Get the tasks
     let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
 
-    // Find the task for node2 which should be awaiting trigger
-    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+    // Find the task for node2 which should be awaiting trigger
+    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+
+    // Check that the task is awaiting trigger
+    assert_eq!(node2_task.status, TaskStatus::AwaitingTrigger);
+
+    // Trigger the task using resume_workflow
+    engine
+        .resume_workflow(workflow_run_id, vec![node2_task.id])
+        .await
+        .unwrap();
+
+    // Allow some time for the task to complete
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    // Get the updated tasks
+    let updated_tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // Find the updated task for node2
+    let updated_node2_task = updated_tasks
+        .iter()
+        .find(|t| t.id == node2_task.id)
+        .unwrap();
+
+    // Check that the task is now running or completed
+    assert!(
+        updated_node2_task.status == TaskStatus::Running
+            || updated_node2_task.status == TaskStatus::Completed
+    );
+}
+
+#[tokio::test]
+async fn test_export_and_import_workflow_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let bundle_path = temp_dir.path().join("bundle");
+    fs::create_dir_all(&bundle_path).unwrap();
+    fs::write(bundle_path.join("notes.txt"), "bundled").unwrap();
+    let archive = temp_dir.path().join("run.tar.gz");
+
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let mut workflow = create_manual_trigger_workflow();
+    workflow.nodes[0].steps[0].action = StepAction::RunScript(
+        "echo 'Hello, World!' && echo 'count[increment]=2' >> $STATE_OUTPUTS".to_string(),
+    );
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), Some(bundle_path))
+        .await
+        .unwrap();
+    // The run pauses for the trigger before node1 has finished
+    engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    for _ in 0..100 {
+        let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+        if tasks.iter().all(|t| t.status != TaskStatus::Running) {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+    engine
+        .export_workflow_run(workflow_run_id, &archive)
+        .await
+        .unwrap();
+    // Nothing but the archive is left next to it
+    let mut entries = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(entries, ["bundle", "run.tar.gz"]);
+
+    // Pick the run up on "another machine" and trigger the rest of it there
+    let imported = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let bundles_dir = temp_dir.path().join("imported");
+    let workflow_run = imported
+        .import_workflow_run(&archive, &bundles_dir)
+        .await
+        .unwrap();
+    assert_eq!(workflow_run.id, workflow_run_id);
+    let bundle_path = workflow_run.bundle_path.unwrap();
+    assert!(bundle_path.starts_with(&bundles_dir));
+    assert_eq!(
+        fs::read_to_string(bundle_path.join("notes.txt")).unwrap(),
+        "bundled"
+    );
+
+    let tasks = imported.get_tasks(workflow_run_id).await.unwrap();
+    assert_eq!(tasks.len(), 2);
+    let task = |node_id: &str| tasks.iter().find(|t| t.node_id == node_id).unwrap();
+    let logs = imported.get_task_logs(task("node1").id, 0).await.unwrap();
+    assert_eq!(logs[0].line, "Hello, World!");
+
+    // State history comes along, still attributed to the task that wrote it
+    let journal = imported.get_state_journal(workflow_run_id).await.unwrap();
+    assert_eq!(journal.len(), 1);
+    assert_eq!(journal[0].task_id, Some(task("node1").id));
+    assert_eq!(journal[0].values["count"], serde_json::json!(2));
+    let state = imported
+        .get_state_at(workflow_run_id, task("node1").id)
+        .await
+        .unwrap();
+    assert_eq!(state["count"], serde_json::json!(2));
+
+    imported
+        .resume_workflow(workflow_run_id, vec![task("node2").id])
+        .await
+        .unwrap();
+    let status = imported
+        .wait_for_workflow_run(workflow_run_id)
+        .await
+        .unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    // The same run can't be imported twice
+    assert!(imported
+        .import_workflow_run(&archive, &bundles_dir)
+        .await
+        .is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_archive_links() {
+    let temp_dir = TempDir::new().unwrap();
+    let secret = temp_dir.path().join("secret.txt");
+    fs::write(&secret, "secret").unwrap();
+    let bundle_path = temp_dir.path().join("bundle");
+    fs::create_dir_all(&bundle_path).unwrap();
+    fs::write(bundle_path.join("notes.txt"), "bundled").unwrap();
+    std::os::unix::fs::symlink(&secret, bundle_path.join("link.txt")).unwrap();
+
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let workflow_run_id = engine
+        .run_workflow(create_test_workflow(), HashMap::new(), Some(bundle_path))
+        .await
+        .unwrap();
+    engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+
+    // Export leaves out links, and with them what they point to
+    let archive = temp_dir.path().join("run.tar.gz");
+    engine
+        .export_workflow_run(workflow_run_id, &archive)
+        .await
+        .unwrap();
+    let bundles_dir = temp_dir.path().join("imported");
+    let imported = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let workflow_run = imported
+        .import_workflow_run(&archive, &bundles_dir)
+        .await
+        .unwrap();
+    let bundle_path = workflow_run.bundle_path.unwrap();
+    assert!(bundle_path.join("notes.txt").is_file());
+    assert!(!bundle_path.join("link.txt").exists());
+
+    // Import refuses archives with links
+    let linked = temp_dir.path().join("linked.tar.gz");
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        fs::File::create(&linked).unwrap(),
+        flate2::Compression::default(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "manifest.json", &secret)
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let error = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    )
+    .import_workflow_run(&linked, &bundles_dir)
+    .await
+    .unwrap_err();
+    assert!(error.to_string().contains("manifest.json is a link"));
+}
+
+#[tokio::test]
+async fn test_prune_and_delete_workflow_runs() {
+    let temp_dir = TempDir::new().unwrap();
+    let state_adapter = LocalStateAdapter::with_base_dir(temp_dir.path());
+    let now = chrono::Utc::now();
+    let save_run = |status, days_ago: i64| {
+        let workflow_run = WorkflowRun {
+            id: Uuid::new_v4(),
+            workflow: create_test_workflow(),
+            status,
+            params: HashMap::new(),
+            tasks: Vec::new(),
+            started_at: now - chrono::Duration::days(days_ago),
+            ended_at: None,
+            bundle_path: None,
+            source: None,
+            target_path: None,
+        };
+        let task = Task::new(workflow_run.id, "node1".to_string(), false);
+        let state_adapter = &state_adapter;
+        async move {
+            state_adapter
+                .save_workflow_run(&workflow_run)
+                .await
+                .unwrap();
+            state_adapter.save_task(&task).await.unwrap();
+            workflow_run.id
+        }
+    };
+    let old_completed = save_run(WorkflowStatus::Completed, 40).await;
+    let older_failed = save_run(WorkflowStatus::Failed, 50).await;
+    let old_running = save_run(WorkflowStatus::Running, 40).await;
+    let recent_completed = save_run(WorkflowStatus::Completed, 1).await;
+
+    let engine = Engine::with_state_adapter(
+        Box::new(LocalStateAdapter::with_base_dir(temp_dir.path())),
+        WorkflowRunConfig::default(),
+    );
+    let ids = |runs: Vec<WorkflowRun>| runs.into_iter().map(|run| run.id).collect::<Vec<_>>();
+
+    // The two most recent matching runs are kept, however old they are
+    let policy = RetentionPolicy {
+        older_than: Some("30d".to_string()),
+        statuses: vec![WorkflowStatus::Completed, WorkflowStatus::Failed],
+        keep_last: Some(2),
+    };
+    assert_eq!(
+        ids(engine.find_prunable_workflow_runs(&policy).await.unwrap()),
+        vec![older_failed]
+    );
+
+    // A policy without a limit would delete every finished run
+    assert!(engine
+        .prune_workflow_runs(&RetentionPolicy::default())
+        .await
+        .is_err());
+    assert_eq!(engine.list_workflow_runs(10).await.unwrap().len(), 4);
+
+    // Running runs are never pruned
+    let policy = RetentionPolicy {
+        older_than: Some("30d".to_string()),
+        ..RetentionPolicy::default()
+    };
+    let pruned = ids(engine.prune_workflow_runs(&policy).await.unwrap());
+    assert_eq!(pruned, vec![old_completed, older_failed]);
+    assert!(engine.get_workflow_run(old_completed).await.is_err());
+    assert!(engine.get_tasks(older_failed).await.unwrap().is_empty());
+    assert_eq!(engine.get_tasks(old_running).await.unwrap().len(), 1);
+
+    assert!(engine.delete_workflow_run(old_running).await.is_err());
+    engine.delete_workflow_run(recent_completed).await.unwrap();
+    assert!(engine.get_workflow_run(recent_completed).await.is_err());
+    assert_eq!(engine.list_workflow_runs(10).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_manual_node_workflow() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_manual_node_workflow();
+    let params = HashMap::new();
+
+    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+
+    // Allow some time for the workflow to start
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    // Get the tasks
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // Find the task for node2 which should be awaiting trigger
+    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+
+    // Check that the task is awaiting trigger
+    assert_eq!(node2_task.status, TaskStatus::AwaitingTrigger);
+
+    // Trigger the task using resume_workflow
+    engine
+        .resume_workflow(workflow_run_id, vec![node2_task.id])
+        .await
+        .unwrap();
+
+    // Allow some time for the task to complete
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    // Get the updated tasks
+    let updated_tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // Find the updated task for node2
+    let updated_node2_task = updated_tasks
+        .iter()
+        .find(|t| t.id == node2_task.id)
+        .unwrap();
+
+    // Check that the task is now running or completed
+    assert!(
+        updated_node2_task.status == TaskStatus::Running
+            || updated_node2_task.status == TaskStatus::Completed
+    );
+}
+
+#[tokio::test]
+async fn test_matrix_workflow() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_matrix_workflow();
+    let params = HashMap::new();
+
+    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+
+    // Allow some time for the workflow to start
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    // Get the tasks
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // There should be at least 4 tasks:
+    // 1 for node1, 1 master task for node2, and 3 matrix tasks for node2
+    assert!(tasks.len() >= 4);
+
+    // Count the number of tasks for node2
+    let node2_tasks = tasks.iter().filter(|t| t.node_id == "node2").count();
+
+    // There should be at least 3 matrix tasks for node2 (one for each region)
+    assert!(node2_tasks >= 3);
+}
+
+#[tokio::test]
+async fn test_matrix_master_finishes_when_last_task_fails() {
+    // The last task to finish fails its step
+    let mut failing_step = create_matrix_workflow();
+    failing_step.nodes[1].steps[0].action = StepAction::RunScript(
+        "if [ '${{matrix.region}}' = 'eu-central' ]; then sleep 0.5; exit 1; fi".to_string(),
+    );
+
+    // The condition of one task can't be evaluated
+    let mut failing_condition = create_matrix_workflow();
+    failing_condition.nodes[1].r#if =
+        Some("matrix.region == 'eu-central' && fromJSON(matrix.region)".to_string());
+
+    for workflow in [failing_step, failing_condition] {
+        let engine = Engine::with_state_adapter(
+            Box::new(MockStateAdapter::new()),
+            WorkflowRunConfig::default(),
+        );
+        let workflow_run_id = engine
+            .run_workflow(workflow, HashMap::new(), None)
+            .await
+            .unwrap();
+
+        let status = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            engine.wait_for_workflow_run(workflow_run_id),
+        )
+        .await
+        .expect("the run should finish")
+        .unwrap();
+        assert_eq!(status, WorkflowStatus::Failed);
+
+        let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+        let master = tasks
+            .iter()
+            .find(|t| t.node_id == "node2" && t.is_master)
+            .unwrap();
+        assert_eq!(master.status, TaskStatus::Failed);
+    }
+}
+
+#[tokio::test]
+async fn test_template_workflow() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_template_workflow();
+    let params = HashMap::new();
+
+    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+
+    // Allow some time for the workflow to start
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    // Get the workflow run
+    let workflow_run = engine.get_workflow_run(workflow_run_id).await.unwrap();
+
+    // Check that the workflow run is running or completed
+    assert!(
+        workflow_run.status == WorkflowStatus::Running
+            || workflow_run.status == WorkflowStatus::Completed
+    );
+
+    // Get the tasks
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // There should be at least 1 task
+    assert!(!tasks.is_empty());
+
+    // Check that the task for node1 exists
+    let node1_task = tasks.iter().find(|t| t.node_id == "node1").unwrap();
+
+    // Print the task status for debugging
+    println!("Node1 task status: {:?}", node1_task.status);
+}
+
+// Helper function to create a workflow whose template exposes outputs
+fn create_template_outputs_workflow() -> Workflow {
+    let runtime = Runtime {
+        r#type: RuntimeType::Direct,
+        image: None,
+        working_dir: None,
+        user: None,
+        network: None,
+        options: None,
+    };
+
+    let template = Template {
+        id: "resolve-branch".to_string(),
+        name: "Resolve Branch".to_string(),
+        description: None,
+        inputs: vec![butterflow_models::TemplateInput {
+            name: "branch".to_string(),
+            r#type: "string".to_string(),
+            required: true,
+            description: None,
+            default: None,
+        }],
+        runtime: Some(runtime.clone()),
+        steps: vec![Step {
+            name: "Echo branch".to_string(),
+            id: None,
+            r#if: None,
+            action: StepAction::RunScript("echo ${{inputs.branch}}".to_string()),
+            env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+        }],
+        outputs: vec![butterflow_models::TemplateOutput {
+            name: "branch".to_string(),
+            value: "refs/heads/${{inputs.branch}}".to_string(),
+            description: None,
+        }],
+        env: HashMap::new(),
+    };
+
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![template],
+        nodes: vec![
+            Node {
+                id: "node1".to_string(),
+                name: "Node 1".to_string(),
+                description: None,
+                r#type: NodeType::Automatic,
+                depends_on: vec![],
+                r#if: None,
+                trigger: None,
+                strategy: None,
+                runtime: Some(runtime.clone()),
+                steps: vec![
+                    Step {
+                        name: "Resolve".to_string(),
+                        id: Some("resolve".to_string()),
+                        r#if: None,
+                        action: StepAction::UseTemplate(butterflow_models::step::TemplateUse {
+                            template: "resolve-branch".to_string(),
+                            inputs: HashMap::from([("branch".to_string(), "feature".to_string())]),
+                        }),
+                        env: None,
+                        retry: None,
+                        timeout: None,
+                        continue_on_error: false,
+                    },
+                    Step {
+                        name: "Use output".to_string(),
+                        id: None,
+                        r#if: None,
+                        action: StepAction::RunScript(
+                            "echo same_node=${{steps.resolve.outputs.branch}}".to_string(),
+                        ),
+                        env: None,
+                        retry: None,
+                        timeout: None,
+                        continue_on_error: false,
+                    },
+                ],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
+                env: HashMap::new(),
+            },
+            Node {
+                id: "node2".to_string(),
+                name: "Node 2".to_string(),
+                description: None,
+                r#type: NodeType::Automatic,
+                depends_on: vec!["node1".to_string()],
+                r#if: None,
+                trigger: None,
+                strategy: None,
+                runtime: Some(runtime),
+                steps: vec![Step {
+                    name: "Use upstream output".to_string(),
+                    id: None,
+                    r#if: None,
+                    action: StepAction::RunScript(
+                        "echo downstream=${{needs.node1.outputs.resolve.branch}} own=${{steps.resolve.outputs.branch || 'none'}}"
+                            .to_string(),
+                    ),
+                    env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
+                }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
+                env: HashMap::new(),
+            },
+        ],
+        concurrency: None,
+    }
+}
+
+#[tokio::test]
+async fn test_template_outputs() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_template_outputs_workflow();
+    let params = HashMap::new();
+
+    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+
+    // Wait for both nodes to run
+    for _ in 0..50 {
+        let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+        if status == WorkflowStatus::Completed || status == WorkflowStatus::Failed {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // The template output is recorded on the task under the step id
+    let node1_task = tasks.iter().find(|t| t.node_id == "node1").unwrap();
+    assert_eq!(node1_task.status, TaskStatus::Completed);
+    assert_eq!(
+        node1_task.outputs["resolve"]["branch"],
+        "refs/heads/feature".to_string()
+    );
+    assert!(task_output(&engine, node1_task.id)
+        .await
+        .contains("same_node=refs/heads/feature"));
+
+    // Downstream nodes read the outputs of their dependencies through
+    // `needs`, their own `steps` only hold their own outputs
+    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+    assert_eq!(node2_task.status, TaskStatus::Completed);
+    assert!(task_output(&engine, node2_task.id)
+        .await
+        .contains("downstream=refs/heads/feature own=none"));
+}
+
+#[tokio::test]
+async fn test_matrix_outputs_must_agree() {
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+
+    // Every task of node1 resolves its own branch
+    let mut workflow = create_template_outputs_workflow();
+    workflow.nodes[0].strategy = Some(Strategy {
+        r#type: butterflow_models::strategy::StrategyType::Matrix,
+        values: Some(vec![
+            HashMap::from([("branch".to_string(), serde_json::json!("a"))]),
+            HashMap::from([("branch".to_string(), serde_json::json!("b"))]),
+        ]),
+        from_state: None,
+        axes: None,
+        include: None,
+        exclude: None,
+        max_parallel: None,
+    });
+    workflow.nodes[0].steps[0].action =
+        StepAction::UseTemplate(butterflow_models::step::TemplateUse {
+            template: "resolve-branch".to_string(),
+            inputs: HashMap::from([("branch".to_string(), "${{matrix.branch}}".to_string())]),
+        });
+    workflow.nodes[0].steps.truncate(1);
+
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Failed);
+
+    // Neither value wins, node2 fails instead of picking one
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+    assert_eq!(node2_task.status, TaskStatus::Failed);
+    assert!(node2_task
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("set output resolve.branch to different values"));
+}
+
+#[tokio::test]
+async fn test_template_inputs_checked_once_resolved() {
+    // A typed input whose default and value are both expressions
+    let mut workflow = create_template_outputs_workflow();
+    workflow.templates[0]
+        .inputs
+        .push(butterflow_models::TemplateInput {
+            name: "depth".to_string(),
+            r#type: "number".to_string(),
+            required: false,
+            description: None,
+            default: Some("${{params.depth}}".to_string()),
+        });
+    workflow.templates[0].outputs[0].value =
+        "refs/heads/${{inputs.branch}}~${{inputs.depth}}".to_string();
+    workflow.nodes.truncate(1);
+    workflow.nodes[0].steps.truncate(1);
+
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let workflow_run_id = engine
+        .run_workflow(
+            workflow.clone(),
+            HashMap::from([("depth".to_string(), "3".to_string())]),
+            None,
+        )
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    // The default is resolved like any other value
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    assert_eq!(
+        tasks[0].outputs["resolve"]["branch"],
+        "refs/heads/feature~3"
+    );
+
+    // And type checked once resolved
+    let workflow_run_id = engine
+        .run_workflow(
+            workflow,
+            HashMap::from([("depth".to_string(), "deep".to_string())]),
+            None,
+        )
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Failed);
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    assert!(tasks[0]
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("Input depth expects a number"));
+}
+
+// Helper function to create a workflow with conditional nodes and steps
+fn create_conditional_workflow() -> Workflow {
+    let node = |id: &str, depends_on: Vec<&str>, condition: Option<&str>, steps: Vec<Step>| Node {
+        id: id.to_string(),
+        name: id.to_string(),
+        description: None,
+        r#type: NodeType::Automatic,
+        depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: condition.map(String::from),
+        trigger: None,
+        strategy: None,
+        runtime: None,
+        steps,
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
+        env: HashMap::new(),
+    };
+    let step = |name: &str, condition: Option<&str>| Step {
+        name: name.to_string(),
+        id: None,
+        r#if: condition.map(String::from),
+        action: StepAction::RunScript(format!("echo ran={name}")),
+        env: None,
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
+    };
+
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![
+            node(
+                "migrate-react",
+                vec![],
+                Some("${{ params.react_version == 17 }}"),
+                vec![step("codemod", None)],
+            ),
+            node(
+                "report",
+                vec!["migrate-react"],
+                None,
+                vec![
+                    step("always", None),
+                    step(
+                        "after-migration",
+                        Some("needs.migrate-react.status == 'Completed'"),
+                    ),
+                ],
+            ),
+        ],
+        concurrency: None,
+    }
+}
+
+#[tokio::test]
+async fn test_conditional_nodes_and_steps() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_conditional_workflow();
+    let params = HashMap::from([("react_version".to_string(), "18".to_string())]);
+
+    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+
+    // Wait for the run to finish
+    for _ in 0..50 {
+        let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+        if status == WorkflowStatus::Completed || status == WorkflowStatus::Failed {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+
+    // The node whose condition is false is skipped
+    let migrate_task = tasks.iter().find(|t| t.node_id == "migrate-react").unwrap();
+    assert_eq!(migrate_task.status, TaskStatus::WontDo);
+    assert!(task_output(&engine, migrate_task.id).await.is_empty());
+
+    // Its dependents still run, but skip steps whose condition is false
+    let report_task = tasks.iter().find(|t| t.node_id == "report").unwrap();
+    assert_eq!(report_task.status, TaskStatus::Completed);
+    let logs = task_output(&engine, report_task.id).await;
+    assert!(logs.contains("ran=always"));
+    assert!(!logs.contains("ran=after-migration"));
+}
+
+// Helper function to create a workflow with a flaky node and a hanging step
+fn create_retry_workflow(marker: &std::path::Path) -> Workflow {
+    let node = |id: &str, retry: Option<RetryPolicy>, step: Step| Node {
+        id: id.to_string(),
+        name: id.to_string(),
+        description: None,
+        r#type: NodeType::Automatic,
+        depends_on: vec![],
+        r#if: None,
+        trigger: None,
+        strategy: None,
+        runtime: None,
+        steps: vec![step],
+        retry,
+        timeout: None,
+        continue_on_error: false,
+        env: HashMap::new(),
+    };
+
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![
+            node(
+                "flaky",
+                Some(RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Backoff {
+                        r#type: BackoffType::Fixed,
+                        delay: "10ms".to_string(),
+                        max_delay: None,
+                    },
+                }),
+                Step {
+                    name: "fail-once".to_string(),
+                    id: None,
+                    r#if: None,
+                    // Fails until the marker exists
+                    action: StepAction::RunScript(
+                        r#"if [ -f "$MARKER" ]; then echo recovered; else touch "$MARKER"; exit 1; fi"#
+                            .to_string(),
+                    ),
+                    env: Some(HashMap::from([(
+                        "MARKER".to_string(),
+                        marker.display().to_string(),
+                    )])),
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
+                },
+            ),
+            node(
+                "hanging",
+                None,
+                Step {
+                    name: "sleep".to_string(),
+                    id: None,
+                    r#if: None,
+                    action: StepAction::RunScript("sleep 30".to_string()),
+                    env: None,
+                    retry: Some(RetryPolicy {
+                        max_attempts: 2,
+                        backoff: Backoff {
+                            r#type: BackoffType::Fixed,
+                            delay: "10ms".to_string(),
+                            max_delay: None,
+                        },
+                    }),
+                    timeout: Some("200ms".to_string()),
+                    continue_on_error: false,
+                },
+            ),
+        ],
+        concurrency: None,
+    }
+}
+
+#[tokio::test]
+async fn test_retry_and_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+
+    let workflow = create_retry_workflow(&temp_dir.path().join("marker"));
+    let started = std::time::Instant::now();
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+
+    // Wait for the run to finish
+    for _ in 0..50 {
+        let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+        if status == WorkflowStatus::Completed || status == WorkflowStatus::Failed {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Failed);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
 
-    // Check that the task is awaiting trigger
-    assert_eq!(node2_task.status, TaskStatus::AwaitingTrigger);
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
 
-    // Trigger the task using resume_workflow
-    engine
-        .resume_workflow(workflow_run_id, vec![node2_task.id])
+    // The flaky node succeeds on its second attempt
+    let flaky_task = tasks.iter().find(|t| t.node_id == "flaky").unwrap();
+    assert_eq!(flaky_task.status, TaskStatus::Completed);
+    assert_eq!(flaky_task.attempts, 2);
+    assert_eq!(flaky_task.step_attempts["fail-once"], 1);
+    assert!(task_output(&engine, flaky_task.id)
         .await
-        .unwrap();
+        .contains("recovered"));
+
+    // The hanging step is cancelled once its timeout expires, on each of
+    // its attempts
+    let hanging_task = tasks.iter().find(|t| t.node_id == "hanging").unwrap();
+    assert_eq!(hanging_task.status, TaskStatus::Failed);
+    assert_eq!(hanging_task.attempts, 1);
+    assert_eq!(hanging_task.step_attempts["sleep"], 2);
+    assert!(hanging_task
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("Timed out after 200ms"));
+}
 
-    // Allow some time for the task to complete
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+// Helper function to create a workflow where a node fails
+fn create_failure_workflow() -> Workflow {
+    let node = |id: &str, depends_on: Vec<&str>, steps: Vec<Step>| Node {
+        id: id.to_string(),
+        name: id.to_string(),
+        description: None,
+        r#type: NodeType::Automatic,
+        depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: None,
+        trigger: None,
+        strategy: None,
+        runtime: None,
+        steps,
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
+        env: HashMap::new(),
+    };
+    let step = |name: &str, run: &str| Step {
+        name: name.to_string(),
+        id: None,
+        r#if: None,
+        action: StepAction::RunScript(run.to_string()),
+        env: None,
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
+    };
 
-    // Get the updated tasks
-    let updated_tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let mut lint = node("lint", vec![], vec![step("lint", "exit 1")]);
+    lint.continue_on_error = true;
 
-    // Find the updated task for node2
-    let updated_node2_task = updated_tasks
-        .iter()
-        .find(|t| t.id == node2_task.id)
-        .unwrap();
+    let mut flaky_step = step("optional", "exit 1");
+    flaky_step.continue_on_error = true;
+    let build = node(
+        "build",
+        vec!["lint"],
+        vec![flaky_step, step("compile", "exit 2")],
+    );
 
-    // Check that the task is now running or completed
-    assert!(
-        updated_node2_task.status == TaskStatus::Running
-            || updated_node2_task.status == TaskStatus::Completed
+    let mut cleanup = node(
+        "cleanup",
+        vec!["deploy"],
+        vec![step("cleanup", "echo cleaned")],
     );
+    cleanup.r#if = Some("always()".to_string());
+
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![
+            lint,
+            build,
+            node(
+                "deploy",
+                vec!["build"],
+                vec![step("deploy", "echo deployed")],
+            ),
+            cleanup,
+        ],
+        concurrency: None,
+    }
 }
 
 #[tokio::test]
-async fn test_manual_node_workflow() {
+async fn test_failure_propagation() {
     let state_adapter = Box::new(MockStateAdapter::new());
     let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
 
-    let workflow = create_manual_node_workflow();
-    let params = HashMap::new();
+    let workflow_run_id = engine
+        .run_workflow(create_failure_workflow(), HashMap::new(), None)
+        .await
+        .unwrap();
 
-    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+    // Wait for the run to finish
+    for _ in 0..100 {
+        let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+        if status == WorkflowStatus::Completed || status == WorkflowStatus::Failed {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
 
-    // Allow some time for the workflow to start
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Failed);
 
-    // Get the tasks
     let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let task = |node_id: &str| tasks.iter().find(|t| t.node_id == node_id).unwrap();
 
-    // Find the task for node2 which should be awaiting trigger
-    let node2_task = tasks.iter().find(|t| t.node_id == "node2").unwrap();
+    // A node allowed to fail completes with its error recorded
+    assert_eq!(task("lint").status, TaskStatus::Completed);
+    assert!(task("lint").error.is_some());
 
-    // Check that the task is awaiting trigger
-    assert_eq!(node2_task.status, TaskStatus::AwaitingTrigger);
+    // A step allowed to fail doesn't stop the node, the next failing step does
+    assert_eq!(task("build").status, TaskStatus::Failed);
+    assert!(task("build").error.as_deref().unwrap().contains("compile"));
 
-    // Trigger the task using resume_workflow
-    engine
-        .resume_workflow(workflow_run_id, vec![node2_task.id])
+    // Dependents of the failed node are skipped, the finalizer still runs
+    assert_eq!(task("deploy").status, TaskStatus::Skipped);
+    assert!(task_output(&engine, task("deploy").id).await.is_empty());
+    assert_eq!(task("cleanup").status, TaskStatus::Completed);
+    assert!(task_output(&engine, task("cleanup").id)
         .await
-        .unwrap();
+        .contains("cleaned"));
+}
 
-    // Allow some time for the task to complete
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+#[cfg(not(feature = "docker"))]
+#[tokio::test]
+async fn test_task_failing_before_it_finishes_fails_the_run() {
+    let state_adapter = Box::new(MockStateAdapter::new());
+    let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
 
-    // Get the updated tasks
-    let updated_tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    // The runner can't be created, which fails the task after it started
+    let mut workflow = create_test_workflow();
+    workflow.nodes[0].runtime.as_mut().unwrap().r#type = RuntimeType::Docker;
 
-    // Find the updated task for node2
-    let updated_node2_task = updated_tasks
-        .iter()
-        .find(|t| t.id == node2_task.id)
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
         .unwrap();
 
-    // Check that the task is now running or completed
-    assert!(
-        updated_node2_task.status == TaskStatus::Running
-            || updated_node2_task.status == TaskStatus::Completed
-    );
+    let status = tokio::time::timeout(
+        tokio::time::Duration::from_secs(10),
+        engine.wait_for_workflow_run(workflow_run_id),
+    )
+    .await
+    .expect("the run should not wait on the task forever")
+    .unwrap();
+    assert_eq!(status, WorkflowStatus::Failed);
+
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let task = tasks.iter().find(|t| t.node_id == "node1").unwrap();
+    assert_eq!(task.status, TaskStatus::Failed);
+    assert!(task.error.as_deref().unwrap().contains("docker"));
 }
 
 #[tokio::test]
-async fn test_matrix_workflow() {
+async fn test_task_logs_are_streamed() {
     let state_adapter = Box::new(MockStateAdapter::new());
     let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
+    let workflow = Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![Node {
+            id: "node1".to_string(),
+            name: "Node 1".to_string(),
+            description: None,
+            r#type: NodeType::Automatic,
+            depends_on: vec![],
+            r#if: None,
+            trigger: None,
+            strategy: None,
+            runtime: None,
+            steps: vec![Step {
+                name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
+                action: StepAction::RunScript("echo first; echo oops >&2; echo second".to_string()),
+                env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
+            }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+            env: HashMap::new(),
+        }],
+        concurrency: None,
+    };
 
-    let workflow = create_matrix_workflow();
-    let params = HashMap::new();
-
-    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
-
-    // Allow some time for the workflow to start
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
 
-    // Get the tasks
     let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let logs = engine.get_task_logs(tasks[0].id, 0).await.unwrap();
+    let lines: Vec<_> = logs
+        .iter()
+        .map(|line| (line.stream, line.line.as_str()))
+        .collect();
 
-    // There should be at least 4 tasks:
-    // 1 for node1, 1 master task for node2, and 3 matrix tasks for node2
-    assert!(tasks.len() >= 4);
+    // Each stream keeps its own order, stdout also stays on the task
+    let stdout: Vec<_> = lines
+        .iter()
+        .filter(|(stream, _)| *stream == LogStream::Stdout)
+        .map(|(_, line)| *line)
+        .collect();
+    assert_eq!(stdout, vec!["first", "second"]);
+    assert!(lines.contains(&(LogStream::Stderr, "oops")));
+    // Output is only kept as log lines, not again on the task
+    assert!(tasks[0].logs.is_empty());
 
-    // Count the number of tasks for node2
-    let node2_tasks = tasks.iter().filter(|t| t.node_id == "node2").count();
+    let rest = engine.get_task_logs(tasks[0].id, 2).await.unwrap();
+    assert_eq!(rest.len(), 1);
+}
 
-    // There should be at least 3 matrix tasks for node2 (one for each region)
-    assert!(node2_tasks >= 3);
+// Helper function to create a matrix workflow whose tasks must not overlap
+fn create_max_parallel_workflow(lock: &std::path::Path) -> Workflow {
+    let values = ["a", "b", "c"]
+        .iter()
+        .map(|shard| HashMap::from([("shard".to_string(), serde_json::json!(shard))]))
+        .collect();
+
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![Node {
+            id: "shards".to_string(),
+            name: "Shards".to_string(),
+            description: None,
+            r#type: NodeType::Automatic,
+            depends_on: vec![],
+            r#if: None,
+            trigger: None,
+            strategy: Some(Strategy {
+                r#type: butterflow_models::strategy::StrategyType::Matrix,
+                values: Some(values),
+                from_state: None,
+                axes: None,
+                include: None,
+                exclude: None,
+                max_parallel: Some(1),
+            }),
+            runtime: None,
+            // Fails if another task holds the lock
+            steps: vec![Step {
+                name: "exclusive".to_string(),
+                id: None,
+                r#if: None,
+                action: StepAction::RunScript(
+                    r#"mkdir "$LOCK" && sleep 0.2 && rmdir "$LOCK""#.to_string(),
+                ),
+                env: Some(HashMap::from([(
+                    "LOCK".to_string(),
+                    lock.display().to_string(),
+                )])),
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
+            }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+            env: HashMap::new(),
+        }],
+        concurrency: None,
+    }
 }
 
 #[tokio::test]
-async fn test_template_workflow() {
+async fn test_matrix_max_parallel() {
+    let temp_dir = TempDir::new().unwrap();
     let state_adapter = Box::new(MockStateAdapter::new());
     let engine = Engine::with_state_adapter(state_adapter, WorkflowRunConfig::default());
 
-    let workflow = create_template_workflow();
-    let params = HashMap::new();
+    let workflow = create_max_parallel_workflow(&temp_dir.path().join("lock"));
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
 
-    let workflow_run_id = engine.run_workflow(workflow, params, None).await.unwrap();
+    // Wait for the run to finish
+    for _ in 0..100 {
+        let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+        if status == WorkflowStatus::Completed || status == WorkflowStatus::Failed {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
 
-    // Allow some time for the workflow to start
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let status = engine.get_workflow_status(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
 
-    // Get the workflow run
-    let workflow_run = engine.get_workflow_run(workflow_run_id).await.unwrap();
+    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let children: Vec<_> = tasks.iter().filter(|t| !t.is_master).collect();
+    assert_eq!(children.len(), 3);
+    assert!(children.iter().all(|t| t.status == TaskStatus::Completed));
+}
 
-    // Check that the workflow run is running or completed
-    assert!(
-        workflow_run.status == WorkflowStatus::Running
-            || workflow_run.status == WorkflowStatus::Completed
+#[tokio::test]
+async fn test_resumed_tasks_respect_max_parallel() {
+    let temp_dir = TempDir::new().unwrap();
+    let slots = temp_dir.path().join("slots");
+    let log = temp_dir.path().join("log");
+    fs::create_dir(&slots).unwrap();
+
+    // Ten manual tasks, each logging how many tasks run alongside it
+    let mut workflow = create_max_parallel_workflow(&temp_dir.path().join("lock"));
+    let node = &mut workflow.nodes[0];
+    node.r#type = NodeType::Manual;
+    let strategy = node.strategy.as_mut().unwrap();
+    strategy.values = Some(
+        (0..10)
+            .map(|shard| HashMap::from([("shard".to_string(), serde_json::json!(shard))]))
+            .collect(),
+    );
+    strategy.max_parallel = Some(2);
+    node.steps[0].action = StepAction::RunScript(
+        r#"mkdir "$SLOTS/${{matrix.shard}}" && ls "$SLOTS" | wc -l >> "$LOG" && sleep 0.1 && rmdir "$SLOTS/${{matrix.shard}}""#
+            .to_string(),
     );
+    node.steps[0].env = Some(HashMap::from([
+        ("SLOTS".to_string(), slots.display().to_string()),
+        ("LOG".to_string(), log.display().to_string()),
+    ]));
+
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+    let status = engine.wait_for_workflow_run(workflow_run_id).await.unwrap();
+    assert_eq!(status, WorkflowStatus::AwaitingTrigger);
 
-    // Get the tasks
-    let tasks = engine.get_tasks(workflow_run_id).await.unwrap();
+    let task_ids = engine
+        .get_tasks(workflow_run_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|t| !t.is_master)
+        .map(|t| t.id)
+        .collect::<Vec<_>>();
+    assert_eq!(task_ids.len(), 10);
+    engine
+        .resume_workflow(workflow_run_id, task_ids)
+        .await
+        .unwrap();
 
-    // There should be at least 1 task
-    assert!(!tasks.is_empty());
+    let status = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        engine.wait_for_workflow_run(workflow_run_id),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    // Every task ran once, never more than two at a time
+    let counts: Vec<usize> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|line| line.trim().parse().unwrap())
+        .collect();
+    assert_eq!(counts.len(), 10);
+    assert!(counts.iter().all(|&count| count <= 2), "{counts:?}");
+}
 
-    // Check that the task for node1 exists
-    let node1_task = tasks.iter().find(|t| t.node_id == "node1").unwrap();
+#[test]
+fn test_concurrency_limits() {
+    let mut workflow = create_max_parallel_workflow(std::path::Path::new("lock"));
+    workflow.concurrency = Some(3);
+    workflow.nodes[0].strategy.as_mut().unwrap().max_parallel = Some(2);
+
+    // The smallest limit wins
+    let limits = ConcurrencyLimits::new(&workflow, Some(1));
+    let permits = limits.try_acquire("shards").unwrap();
+    assert!(limits.try_acquire("shards").is_none());
+    assert!(limits.try_acquire("other").is_none());
+    drop(permits);
+    assert!(limits.try_acquire("other").is_some());
+
+    // Node limits apply on top of the global one
+    let limits = ConcurrencyLimits::new(&workflow, None);
+    let _first = limits.try_acquire("shards").unwrap();
+    let _second = limits.try_acquire("shards").unwrap();
+    assert!(limits.try_acquire("shards").is_none());
+    assert!(limits.try_acquire("other").is_some());
+}
 
-    // Print the task status for debugging
-    println!("Node1 task status: {:?}", node1_task.status);
+#[tokio::test]
+async fn test_event_driven_execution() {
+    let node = |id: usize| Node {
+        id: format!("node{id}"),
+        name: format!("Node {id}"),
+        description: None,
+        r#type: NodeType::Automatic,
+        depends_on: if id == 0 {
+            vec![]
+        } else {
+            vec![format!("node{}", id - 1)]
+        },
+        r#if: None,
+        trigger: None,
+        strategy: None,
+        runtime: None,
+        steps: vec![Step {
+            name: "Step".to_string(),
+            id: None,
+            r#if: None,
+            action: StepAction::RunScript("true".to_string()),
+            env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+        }],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
+        env: HashMap::new(),
+    };
+    let workflow = Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: (0..10).map(node).collect(),
+        concurrency: None,
+    };
+
+    let engine = Engine::with_state_adapter(
+        Box::new(MockStateAdapter::new()),
+        WorkflowRunConfig::default(),
+    );
+    let started = std::time::Instant::now();
+    let workflow_run_id = engine
+        .run_workflow(workflow, HashMap::new(), None)
+        .await
+        .unwrap();
+
+    let status = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        engine.wait_for_workflow_run(workflow_run_id),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(status, WorkflowStatus::Completed);
+
+    // A chain of ten nodes no longer waits for a tick between nodes
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
 }
 
 // Test for trigger_all method
@@ -756,6 +2291,7 @@ fn create_env_var_workflow() -> Workflow {
             description: Some("Test node 1".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -768,17 +2304,26 @@ fn create_env_var_workflow() -> Workflow {
             }),
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::RunScript("echo 'Using env var: $TEST_ENV_VAR'".to_string()),
                 env: Some(HashMap::from([(
                     "STEP_SPECIFIC_VAR".to_string(),
                     "step-value".to_string(),
                 )])),
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::from([
                 ("TEST_ENV_VAR".to_string(), "test-value".to_string()),
                 ("NODE_SPECIFIC_VAR".to_string(), "node-value".to_string()),
             ]),
         }],
+        concurrency: None,
     }
 }
 
@@ -794,6 +2339,7 @@ fn create_variable_resolution_workflow() -> Workflow {
             description: Some("Processing ${params.branch}".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -806,17 +2352,26 @@ fn create_variable_resolution_workflow() -> Workflow {
             }),
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::RunScript(
                     "echo 'Processing repo: ${params.repo_name} on branch: ${params.branch}'"
                         .to_string(),
                 ),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::from([
                 ("REPO_URL".to_string(), "${params.repo_url}".to_string()),
                 ("DEBUG".to_string(), "${env.CI}".to_string()),
             ]),
         }],
+        concurrency: None,
     }
 }
 
@@ -832,6 +2387,7 @@ fn create_env_vars_test_workflow() -> Workflow {
             description: Some("Test node for environment variables".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -844,6 +2400,8 @@ fn create_env_vars_test_workflow() -> Workflow {
             }),
             steps: vec![Step {
                 name: "Test Environment Variables".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::RunScript(
                     r#"echo "CODEMOD_TASK_ID=$CODEMOD_TASK_ID"
 echo "CODEMOD_WORKFLOW_RUN_ID=$CODEMOD_WORKFLOW_RUN_ID"
@@ -853,16 +2411,23 @@ echo "task_id_valid=$(if [ "$CODEMOD_TASK_ID" != "" ] && [ ${#CODEMOD_TASK_ID} -
 echo "workflow_run_id_valid=$(if [ "$CODEMOD_WORKFLOW_RUN_ID" != "" ] && [ ${#CODEMOD_WORKFLOW_RUN_ID} -eq 36 ]; then echo "true"; else echo "false"; fi)""#.to_string(),
                 ),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     }
 }
 
 #[tokio::test]
 async fn test_matrix_recompilation_with_direct_adapter() {
     // Create a mock state adapter
-    let mut state_adapter = MockStateAdapter::new();
+    let state_adapter = MockStateAdapter::new();
 
     // Create a workflow with a matrix node using from_state
     let workflow = create_matrix_from_state_workflow();
@@ -878,6 +2443,8 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: chrono::Utc::now(),
         ended_at: None,
         bundle_path: None,
+        source: None,
+        target_path: None,
     };
 
     // Save the workflow run
@@ -898,6 +2465,10 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: Some(chrono::Utc::now()),
         ended_at: Some(chrono::Utc::now()),
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
+        outputs: HashMap::new(),
         logs: Vec::new(),
     };
 
@@ -916,6 +2487,10 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: None,
         ended_at: None,
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
+        outputs: HashMap::new(),
         logs: Vec::new(),
     };
 
@@ -960,6 +2535,10 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: None,
         ended_at: None,
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
+        outputs: HashMap::new(),
         logs: Vec::new(),
     };
 
@@ -978,6 +2557,10 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: None,
         ended_at: None,
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
+        outputs: HashMap::new(),
         logs: Vec::new(),
     };
 
@@ -1011,6 +2594,7 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         FieldDiff {
             operation: DiffOperation::Update,
             value: Some(serde_json::to_value(TaskStatus::WontDo).unwrap()),
+            expected_revision: None,
         },
     );
 
@@ -1037,6 +2621,10 @@ async fn test_matrix_recompilation_with_direct_adapter() {
         started_at: None,
         ended_at: None,
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
+        outputs: HashMap::new(),
         logs: Vec::new(),
     };
 
@@ -1245,9 +2833,12 @@ async fn test_codemod_environment_variables() {
     // If the task completed, check the logs for the environment variables
     if env_test_task.status == TaskStatus::Completed {
         // The task should have logs showing the environment variables
-        assert!(!env_test_task.logs.is_empty(), "Task should have logs");
+        assert!(
+            !task_output(&engine, env_test_task.id).await.is_empty(),
+            "Task should have logs"
+        );
 
-        let log_output = env_test_task.logs.join("\n");
+        let log_output = task_output(&engine, env_test_task.id).await;
 
         // Check that CODEMOD_TASK_ID is set and matches the task ID
         assert!(
@@ -1300,6 +2891,7 @@ async fn test_codemod_environment_variables_in_matrix() {
                 description: Some("Setup node".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -1312,9 +2904,17 @@ async fn test_codemod_environment_variables_in_matrix() {
                 }),
                 steps: vec![Step {
                     name: "Setup".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Setup complete'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -1323,6 +2923,7 @@ async fn test_codemod_environment_variables_in_matrix() {
                 description: Some("Test environment variables in matrix".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["setup-node".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: Some(Strategy {
                     r#type: butterflow_models::strategy::StrategyType::Matrix,
@@ -1337,6 +2938,10 @@ async fn test_codemod_environment_variables_in_matrix() {
                         )]),
                     ]),
                     from_state: None,
+                    axes: None,
+                    include: None,
+                    exclude: None,
+                    max_parallel: None,
                 }),
                 runtime: Some(Runtime {
                     r#type: RuntimeType::Direct,
@@ -1348,6 +2953,8 @@ async fn test_codemod_environment_variables_in_matrix() {
                 }),
                 steps: vec![Step {
                     name: "Test Environment Variables in Matrix".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript(
                         r#"echo "Matrix region: $region"
 echo "CODEMOD_TASK_ID: $CODEMOD_TASK_ID"
@@ -1356,10 +2963,17 @@ echo "env_vars_in_matrix=true""#
                             .to_string(),
                     ),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     let params = HashMap::new();
@@ -1387,9 +3001,12 @@ echo "env_vars_in_matrix=true""#
     // Check that each matrix task has the environment variables set correctly
     for matrix_task in matrix_tasks {
         if matrix_task.status == TaskStatus::Completed {
-            assert!(!matrix_task.logs.is_empty(), "Matrix task should have logs");
+            assert!(
+                !task_output(&engine, matrix_task.id).await.is_empty(),
+                "Matrix task should have logs"
+            );
 
-            let log_output = matrix_task.logs.join("\n");
+            let log_output = task_output(&engine, matrix_task.id).await;
 
             // Check that CODEMOD_TASK_ID is set to this specific matrix task's ID
             assert!(
@@ -1433,6 +3050,7 @@ async fn test_cyclic_dependency_workflow() {
                 description: Some("Test node 1".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node2".to_string()], // Depends on node2
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -1445,9 +3063,17 @@ async fn test_cyclic_dependency_workflow() {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Hello, World!'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -1456,6 +3082,7 @@ async fn test_cyclic_dependency_workflow() {
                 description: Some("Test node 2".to_string()),
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()], // Depends on node1, creating a cycle
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: Some(Runtime {
@@ -1468,12 +3095,21 @@ async fn test_cyclic_dependency_workflow() {
                 }),
                 steps: vec![Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::RunScript("echo 'Node 2 executed'".to_string()),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 }],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     let params = HashMap::new();
@@ -1501,6 +3137,7 @@ async fn test_invalid_template_reference() {
             description: Some("Test node 1".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -1513,14 +3150,23 @@ async fn test_invalid_template_reference() {
             }),
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::UseTemplate(butterflow_models::step::TemplateUse {
                     template: "non-existent-template".to_string(), // This template doesn't exist
                     inputs: HashMap::new(),
                 }),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     let params = HashMap::new();
@@ -1601,8 +3247,13 @@ message: "Found var declaration"
 
     let step = Step {
         name: "Test AST Grep".to_string(),
+        id: None,
+        r#if: None,
         action: StepAction::AstGrep(ast_grep_step),
         env: None,
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
     };
 
     // Create a simple node for testing
@@ -1613,7 +3264,11 @@ message: "Found var declaration"
         r#type: butterflow_models::node::NodeType::Automatic,
         runtime: None,
         depends_on: vec![],
+        r#if: None,
         steps: vec![step],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
         strategy: None,
         trigger: None,
         env: HashMap::new(),
@@ -1630,8 +3285,12 @@ message: "Found var declaration"
         matrix_values: None,
         started_at: None,
         ended_at: None,
+        outputs: HashMap::new(),
         logs: vec![],
         error: None,
+        triggered: false,
+        attempts: 0,
+        step_attempts: HashMap::new(),
     };
 
     // Create engine with correct bundle path
@@ -1882,7 +3541,9 @@ function helper() {
                 max_threads: Some(2),
                 dry_run: Some(false),
                 language: Some("javascript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -1960,7 +3621,9 @@ interface ApiResponse {
                 max_threads: Some(4),
                 dry_run: Some(false),
                 language: Some("typescript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2016,7 +3679,9 @@ var count = 0;
                 max_threads: None,   // Use default
                 dry_run: Some(true), // Enable dry run
                 language: Some("javascript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2052,7 +3717,9 @@ async fn test_execute_js_ast_grep_step_nonexistent_js_file() {
                 max_threads: None,
                 dry_run: Some(false),
                 language: None,
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2116,7 +3783,9 @@ build/
                 max_threads: Some(1),
                 dry_run: Some(false),
                 language: Some("javascript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2138,7 +3807,9 @@ build/
                 max_threads: Some(1),
                 dry_run: Some(false),
                 language: Some("javascript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2190,7 +3861,9 @@ export default function transform(ast) {
                 max_threads: Some(1),
                 dry_run: Some(false),
                 language: Some("javascript".to_string()),
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2237,7 +3910,9 @@ export default function transform(ast) {
                 max_threads: None,
                 dry_run: Some(false),
                 language: Some("invalid-language".to_string()), // Invalid language
+                params: None,
             },
+            TransformOptions::default(),
         )
         .await;
 
@@ -2248,6 +3923,68 @@ export default function transform(ast) {
     println!("Result with invalid language: {result:?}");
 }
 
+#[tokio::test]
+async fn test_execute_js_ast_grep_step_passes_transform_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_path = temp_dir.path();
+
+    // Writes what it was given on top of the file, the state must be read-only
+    create_test_file(
+        temp_path,
+        "codemod.js",
+        r#"
+export default function transform(root, options) {
+  let frozen = false;
+  try {
+    options.state.count = 0;
+  } catch (e) {
+    frozen = true;
+  }
+  const header = [
+    options.params.greeting,
+    options.matrixValues.region ?? "none",
+    options.state.count,
+    typeof options.language,
+    frozen,
+  ].join(" ");
+  return `// ${header}\n${root.root().text()}`;
+}
+"#,
+    );
+    create_test_file(temp_path, "src/app.js", "main();");
+
+    let config = WorkflowRunConfig {
+        bundle_path: temp_path.to_path_buf(),
+        target_path: temp_path.to_path_buf(),
+        ..WorkflowRunConfig::default()
+    };
+    let engine = Engine::with_workflow_run_config(config);
+    engine
+        .execute_js_ast_grep_step(
+            "test-node".to_string(),
+            &UseJSAstGrep {
+                js_file: "codemod.js".to_string(),
+                base_path: Some("src".to_string()),
+                include: Some(vec!["**/*.js".to_string()]),
+                exclude: None,
+                max_threads: None,
+                dry_run: Some(false),
+                language: Some("javascript".to_string()),
+                params: None,
+            },
+            TransformOptions {
+                params: HashMap::from([("greeting".to_string(), "hello".to_string())]),
+                state: HashMap::from([("count".to_string(), serde_json::json!(3))]),
+                ..TransformOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    let content = fs::read_to_string(temp_path.join("src/app.js")).unwrap();
+    assert_eq!(content, "// hello none 3 string true\nmain();");
+}
+
 // Helper function to create a workflow with JSAstGrep step
 fn create_js_ast_grep_workflow() -> Workflow {
     Workflow {
@@ -2260,6 +3997,7 @@ fn create_js_ast_grep_workflow() -> Workflow {
             description: Some("Test node for JS AST grep".to_string()),
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: Some(Runtime {
@@ -2272,6 +4010,8 @@ fn create_js_ast_grep_workflow() -> Workflow {
             }),
             steps: vec![Step {
                 name: "JS AST Grep Step".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::JSAstGrep(UseJSAstGrep {
                     js_file: "codemod.js".to_string(),
                     base_path: Some("src".to_string()),
@@ -2280,11 +4020,19 @@ fn create_js_ast_grep_workflow() -> Workflow {
                     max_threads: Some(2),
                     dry_run: Some(false),
                     language: Some("javascript".to_string()),
+                    params: None,
                 }),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     }
 }
 
@@ -0,0 +1,363 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use butterflow_core::file_ops::{
+    apply_file_operations, find_conflicts, resolve_file_operation, FileChanges,
+};
+use codemod_sandbox::sandbox::engine::{ExecutionStats, FileOperation};
+use tempfile::TempDir;
+
+#[test]
+fn test_resolve_file_operation_relative_to_file() {
+    let file_path = Path::new("/repo/src/utils/index.js");
+
+    let operation = resolve_file_operation(
+        FileOperation::Rename {
+            from: file_path.to_path_buf(),
+            to: "../helpers/./index.ts".into(),
+        },
+        file_path,
+    );
+    assert_eq!(
+        operation,
+        FileOperation::Rename {
+            from: file_path.to_path_buf(),
+            to: "/repo/src/helpers/index.ts".into(),
+        }
+    );
+
+    let operation = resolve_file_operation(
+        FileOperation::Delete {
+            path: "/repo/old.js".into(),
+        },
+        file_path,
+    );
+    assert_eq!(
+        operation,
+        FileOperation::Delete {
+            path: "/repo/old.js".into(),
+        }
+    );
+}
+
+#[test]
+fn test_apply_file_operations() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::write(root.join("a.js"), "a").unwrap();
+    fs::write(root.join("b.js"), "b").unwrap();
+    fs::write(root.join("c.js"), "c").unwrap();
+
+    let stats = ExecutionStats::new();
+    apply_file_operations(
+        root,
+        &[
+            FileOperation::Write {
+                path: root.join("src/new.ts"),
+                content: "new".to_string(),
+            },
+            FileOperation::Write {
+                path: root.join("c.js"),
+                content: "c2".to_string(),
+            },
+            FileOperation::Rename {
+                from: root.join("a.js"),
+                to: root.join("lib/a.ts"),
+            },
+            FileOperation::Delete {
+                path: root.join("b.js"),
+            },
+        ],
+        false,
+        &stats,
+    )
+    .unwrap();
+
+    assert_eq!(fs::read_to_string(root.join("src/new.ts")).unwrap(), "new");
+    assert_eq!(fs::read_to_string(root.join("c.js")).unwrap(), "c2");
+    assert_eq!(fs::read_to_string(root.join("lib/a.ts")).unwrap(), "a");
+    assert!(!root.join("a.js").exists());
+    assert!(!root.join("b.js").exists());
+
+    assert_eq!(stats.files_created.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.files_modified.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.files_renamed.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.files_deleted.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_apply_file_operations_undoes_all_on_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::write(root.join("a.js"), "a").unwrap();
+    fs::write(root.join("b.js"), "b").unwrap();
+    fs::write(root.join("taken.ts"), "taken").unwrap();
+
+    let stats = ExecutionStats::new();
+    let result = apply_file_operations(
+        root,
+        &[
+            FileOperation::Write {
+                path: root.join("a.js"),
+                content: "a2".to_string(),
+            },
+            FileOperation::Write {
+                path: root.join("new.ts"),
+                content: "new".to_string(),
+            },
+            FileOperation::Delete {
+                path: root.join("b.js"),
+            },
+            // Would overwrite another file
+            FileOperation::Rename {
+                from: root.join("a.js"),
+                to: root.join("taken.ts"),
+            },
+        ],
+        false,
+        &stats,
+    );
+    assert!(result.is_err());
+
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(root.join("b.js")).unwrap(), "b");
+    assert_eq!(fs::read_to_string(root.join("taken.ts")).unwrap(), "taken");
+    assert!(!root.join("new.ts").exists());
+    assert_eq!(stats.files_created.load(Ordering::Relaxed), 0);
+    assert_eq!(stats.files_deleted.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_apply_file_operations_confined_to_root() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path().join("project");
+    fs::create_dir(&root).unwrap();
+
+    let stats = ExecutionStats::new();
+    let result = apply_file_operations(
+        &root,
+        &[
+            FileOperation::Write {
+                path: root.join("ok.ts"),
+                content: "ok".to_string(),
+            },
+            FileOperation::Write {
+                path: root.join("../outside.ts"),
+                content: "outside".to_string(),
+            },
+        ],
+        false,
+        &stats,
+    );
+    assert!(result.is_err());
+
+    // Checked before anything is applied
+    assert!(!root.join("ok.ts").exists());
+    assert!(!temp_dir.path().join("outside.ts").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_apply_file_operations_do_not_follow_symlinks_out_of_root() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path().join("project");
+    let outside = temp_dir.path().join("outside");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::create_dir(&outside).unwrap();
+    fs::write(outside.join("passwd"), "secret").unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("src/link")).unwrap();
+    std::os::unix::fs::symlink(outside.join("passwd"), root.join("alias.ts")).unwrap();
+
+    let stats = ExecutionStats::new();
+    for operation in [
+        FileOperation::Write {
+            path: root.join("src/link/passwd"),
+            content: "changed".to_string(),
+        },
+        FileOperation::Write {
+            path: root.join("src/link/new/file.ts"),
+            content: "new".to_string(),
+        },
+        FileOperation::Write {
+            path: root.join("alias.ts"),
+            content: "changed".to_string(),
+        },
+    ] {
+        assert!(apply_file_operations(&root, &[operation], false, &stats).is_err());
+    }
+    assert_eq!(
+        fs::read_to_string(outside.join("passwd")).unwrap(),
+        "secret"
+    );
+    assert!(!outside.join("new").exists());
+
+    // Symlinks that stay inside the root are fine
+    std::os::unix::fs::symlink(root.join("src"), root.join("lib")).unwrap();
+    apply_file_operations(
+        &root,
+        &[FileOperation::Write {
+            path: root.join("lib/ok.ts"),
+            content: "ok".to_string(),
+        }],
+        false,
+        &stats,
+    )
+    .unwrap();
+    assert_eq!(fs::read_to_string(root.join("src/ok.ts")).unwrap(), "ok");
+}
+
+#[test]
+fn test_find_conflicts() {
+    let a = Path::new("/repo/a.js");
+    let b = Path::new("/repo/b.js");
+    let c = Path::new("/repo/c.js");
+
+    // A file may change itself and then move, one step after the other
+    assert!(
+        find_conflicts([(a, a), (a, a), (a, Path::new("/repo/a.ts")), (b, b),])
+            .unwrap()
+            .is_empty()
+    );
+
+    // Files processed in parallel must not touch the same path, the others
+    // are fine
+    let conflicts = find_conflicts([
+        (c, c),
+        (b, Path::new("/repo/lib/../shared/index.ts")),
+        (a, Path::new("/repo/shared/index.ts")),
+    ])
+    .unwrap();
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(conflicts[0].file, a);
+    assert_eq!(conflicts[0].path, Path::new("/repo/shared/index.ts"));
+    assert_eq!(conflicts[0].other, b);
+    assert_eq!(conflicts[1].file, b);
+    assert_eq!(conflicts[1].other, a);
+}
+
+#[test]
+fn test_file_changes_skip_conflicting_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    for name in ["a.js", "b.js", "c.js"] {
+        fs::write(root.join(name), name).unwrap();
+    }
+
+    let changes = FileChanges::new(root, false).unwrap();
+    changes.rewrite(&root.join("a.js"), "a2").unwrap();
+    changes.rewrite(&root.join("b.js"), "b2").unwrap();
+    changes.rewrite(&root.join("c.js"), "c2").unwrap();
+    // Written right away
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a2");
+
+    changes.request(
+        &root.join("a.js"),
+        vec![FileOperation::Write {
+            path: "shared.ts".into(),
+            content: "a".to_string(),
+        }],
+    );
+    changes.request(
+        &root.join("b.js"),
+        vec![FileOperation::Write {
+            path: root.join("shared.ts"),
+            content: "b".to_string(),
+        }],
+    );
+    changes.request(
+        &root.join("c.js"),
+        vec![FileOperation::Rename {
+            from: root.join("c.js"),
+            to: "c.ts".into(),
+        }],
+    );
+
+    let stats = ExecutionStats::new();
+    let conflicts = changes.finish(&stats).unwrap();
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(conflicts[0].file, root.join("a.js"));
+    assert_eq!(conflicts[1].file, root.join("b.js"));
+
+    // Only the files in conflict are left as they were
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a.js");
+    assert_eq!(fs::read_to_string(root.join("b.js")).unwrap(), "b.js");
+    assert!(!root.join("shared.ts").exists());
+    assert_eq!(fs::read_to_string(root.join("c.ts")).unwrap(), "c2");
+    assert_eq!(stats.files_modified.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.files_renamed.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_file_changes_revert() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::write(root.join("a.js"), "a").unwrap();
+    fs::write(root.join("taken.ts"), "taken").unwrap();
+
+    // Reverted on request, e.g. when the step times out
+    let changes = FileChanges::new(root, false).unwrap();
+    changes.rewrite(&root.join("a.js"), "a2").unwrap();
+    changes.revert();
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a");
+
+    // And when the file operations fail
+    let changes = FileChanges::new(root, false).unwrap();
+    changes.rewrite(&root.join("a.js"), "a2").unwrap();
+    changes.request(
+        &root.join("a.js"),
+        vec![FileOperation::Rename {
+            from: root.join("a.js"),
+            to: root.join("taken.ts"),
+        }],
+    );
+    assert!(changes.finish(&ExecutionStats::new()).is_err());
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a");
+    assert_eq!(fs::read_to_string(root.join("taken.ts")).unwrap(), "taken");
+}
+
+#[test]
+fn test_file_changes_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::write(root.join("a.js"), "a").unwrap();
+
+    let changes = FileChanges::new(root, true).unwrap();
+    changes.rewrite(&root.join("a.js"), "a2").unwrap();
+    let stats = ExecutionStats::new();
+    assert!(changes.finish(&stats).unwrap().is_empty());
+
+    assert_eq!(fs::read_to_string(root.join("a.js")).unwrap(), "a");
+    assert_eq!(stats.files_modified.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_apply_file_operations_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::write(root.join("a.js"), "a").unwrap();
+
+    let stats = ExecutionStats::new();
+    apply_file_operations(
+        root,
+        &[
+            FileOperation::Write {
+                path: root.join("new.ts"),
+                content: "new".to_string(),
+            },
+            FileOperation::Rename {
+                from: root.join("a.js"),
+                to: root.join("a.ts"),
+            },
+        ],
+        true,
+        &stats,
+    )
+    .unwrap();
+
+    assert!(!root.join("new.ts").exists());
+    assert!(root.join("a.js").exists());
+    assert!(!root.join("a.ts").exists());
+    assert_eq!(stats.files_created.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.files_renamed.load(Ordering::Relaxed), 1);
+}
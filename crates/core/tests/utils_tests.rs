@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
 use std::path::Path;
@@ -9,7 +9,10 @@ use butterflow_models::step::StepAction;
 use butterflow_models::step::UseAstGrep;
 use butterflow_models::step::UseJSAstGrep;
 use butterflow_models::strategy::StrategyType;
-use butterflow_models::{Error, Node, Step, Strategy, Template, TemplateOutput, Workflow};
+use butterflow_models::{
+    Backoff, DiffOperation, Error, Node, RetryPolicy, Step, Strategy, Template, TemplateInput,
+    TemplateOutput, Workflow,
+};
 
 #[test]
 fn test_parse_workflow_file_yaml() {
@@ -128,10 +131,14 @@ fn test_validate_workflow_valid() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -140,13 +147,18 @@ fn test_validate_workflow_valid() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -170,10 +182,14 @@ fn test_validate_workflow_duplicate_node_id() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -182,13 +198,18 @@ fn test_validate_workflow_duplicate_node_id() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec![],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -217,12 +238,17 @@ fn test_validate_workflow_nonexistent_dependency() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec!["nonexistent".to_string()], // Non-existent dependency
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -252,10 +278,14 @@ fn test_validate_workflow_cyclic_dependency() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node2".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -264,13 +294,18 @@ fn test_validate_workflow_cyclic_dependency() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node1".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -324,6 +359,82 @@ fn test_parse_params_invalid() {
     }
 }
 
+#[test]
+fn test_parse_state_outputs() {
+    let outputs = "\
+count[increment]=2
+count[increment]=3
+config[merge]={\"debug\":true}
+failures@=a.ts
+failures[append]=b.ts
+done[remove]=a.ts
+owner[if-unchanged]=alice
+list[append]=0
+list=[1]
+list[append]=2
+
+malformed
+";
+    let revisions = HashMap::from([("owner".to_string(), 4), ("failures".to_string(), 7)]);
+    let state = HashMap::from([("failures".to_string(), serde_json::json!(["old.ts"]))]);
+
+    let outputs = utils::parse_state_outputs(outputs, &revisions);
+    // Both appends to `failures` go into the one diff, on top of its current value
+    assert!(outputs.reads_state());
+    let fields = outputs.to_fields(&state, &revisions).unwrap();
+
+    assert_eq!(fields["count"].operation, DiffOperation::Increment);
+    assert_eq!(fields["count"].value, Some(serde_json::json!(5)));
+    assert_eq!(fields["count"].expected_revision, None);
+    assert_eq!(fields["config"].operation, DiffOperation::Merge);
+    assert_eq!(
+        fields["config"].value,
+        Some(serde_json::json!({"debug": true}))
+    );
+    assert_eq!(fields["failures"].operation, DiffOperation::Update);
+    assert_eq!(
+        fields["failures"].value,
+        Some(serde_json::json!(["old.ts", "a.ts", "b.ts"]))
+    );
+    assert_eq!(fields["failures"].expected_revision, Some(7));
+    assert_eq!(fields["done"].operation, DiffOperation::RemoveFromArray);
+    assert_eq!(fields["done"].expected_revision, None);
+    assert_eq!(fields["owner"].operation, DiffOperation::Update);
+    assert_eq!(fields["owner"].expected_revision, Some(4));
+    assert_eq!(fields["list"].value, Some(serde_json::json!([1, 2])));
+    assert_eq!(fields["list"].expected_revision, None);
+}
+
+#[test]
+fn test_parse_state_outputs_plain_keys() {
+    let outputs = "\
+a+=1
+b-=x
+c&={\"k\":1}
+d?=y
+e[0]=z
+f[unknown]=w
+";
+    let outputs = utils::parse_state_outputs(outputs, &HashMap::new());
+    assert!(!outputs.reads_state());
+    let fields = outputs.to_fields(&HashMap::new(), &HashMap::new()).unwrap();
+
+    let expected = [
+        ("a+", serde_json::json!(1)),
+        ("b-", serde_json::json!("x")),
+        ("c&", serde_json::json!({"k": 1})),
+        ("d?", serde_json::json!("y")),
+        ("e[0]", serde_json::json!("z")),
+        ("f[unknown]", serde_json::json!("w")),
+    ];
+    assert_eq!(fields.len(), expected.len());
+    for (key, value) in expected {
+        assert_eq!(fields[key].operation, DiffOperation::Update);
+        assert_eq!(fields[key].value, Some(value));
+        assert_eq!(fields[key].expected_revision, None);
+    }
+}
+
 #[test]
 fn test_format_duration() {
     // Test formatting durations
@@ -374,6 +485,7 @@ fn test_validate_workflow_duplicate_template_id() {
             },
         ],
         nodes: vec![],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -411,19 +523,29 @@ fn test_validate_workflow_nonexistent_template_reference() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::UseTemplate(butterflow_models::step::TemplateUse {
                     template: "nonexistent".to_string(), // Non-existent template
                     inputs: HashMap::new(),
                 }),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -439,6 +561,203 @@ fn test_validate_workflow_nonexistent_template_reference() {
     }
 }
 
+// Helper function to create a workflow using a template with typed inputs
+fn create_template_inputs_workflow(inputs: HashMap<String, String>) -> Workflow {
+    Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![Template {
+            id: "template1".to_string(),
+            name: "Template 1".to_string(),
+            description: None,
+            inputs: vec![
+                TemplateInput {
+                    name: "repo_url".to_string(),
+                    r#type: "string".to_string(),
+                    required: true,
+                    description: None,
+                    default: None,
+                },
+                TemplateInput {
+                    name: "depth".to_string(),
+                    r#type: "number".to_string(),
+                    required: false,
+                    description: None,
+                    default: Some("1".to_string()),
+                },
+                TemplateInput {
+                    name: "shallow".to_string(),
+                    r#type: "boolean".to_string(),
+                    required: false,
+                    description: None,
+                    default: None,
+                },
+            ],
+            runtime: None,
+            steps: vec![],
+            outputs: vec![],
+            env: HashMap::new(),
+        }],
+        nodes: vec![Node {
+            id: "node1".to_string(),
+            name: "Node 1".to_string(),
+            description: None,
+            r#type: NodeType::Automatic,
+            depends_on: vec![],
+            r#if: None,
+            trigger: None,
+            strategy: None,
+            runtime: None,
+            steps: vec![Step {
+                name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
+                action: StepAction::UseTemplate(butterflow_models::step::TemplateUse {
+                    template: "template1".to_string(),
+                    inputs,
+                }),
+                env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
+            }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+            env: HashMap::new(),
+        }],
+        concurrency: None,
+    }
+}
+
+#[test]
+fn test_validate_workflow_template_inputs() {
+    let valid = create_template_inputs_workflow(HashMap::from([
+        (
+            "repo_url".to_string(),
+            "https://github.com/example/repo".to_string(),
+        ),
+        ("depth".to_string(), "10".to_string()),
+        ("shallow".to_string(), "true".to_string()),
+    ]));
+    assert!(utils::validate_workflow(&valid, Path::new("")).is_ok());
+
+    // Expressions are only type checked once resolved
+    let expression = create_template_inputs_workflow(HashMap::from([
+        ("repo_url".to_string(), "${{params.repo_url}}".to_string()),
+        ("depth".to_string(), "${{params.depth}}".to_string()),
+    ]));
+    assert!(utils::validate_workflow(&expression, Path::new("")).is_ok());
+
+    let cases = [
+        (vec![], "Missing required input repo_url"),
+        (
+            vec![("repo_url", "url"), ("depth", "deep")],
+            "Input depth expects a number",
+        ),
+        (
+            vec![("repo_url", "url"), ("shallow", "yes")],
+            "Input shallow expects a boolean",
+        ),
+        (
+            vec![("repo_url", "url"), ("repo_ulr", "url")],
+            "Unknown inputs for template template1: repo_ulr",
+        ),
+    ];
+
+    for (inputs, expected) in cases {
+        let inputs = inputs
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let workflow = create_template_inputs_workflow(inputs);
+        match utils::validate_workflow(&workflow, Path::new("")) {
+            Err(Error::WorkflowValidation(msg)) => {
+                assert!(msg.contains(expected), "{msg} should contain {expected}");
+            }
+            other => panic!("Expected WorkflowValidation error, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_validate_workflow_invalid_condition() {
+    let mut workflow = create_template_inputs_workflow(HashMap::from([(
+        "repo_url".to_string(),
+        "url".to_string(),
+    )]));
+    workflow.nodes[0].r#if = Some("${{ params.framework == 'react' }}".to_string());
+    workflow.nodes[0].steps[0].r#if = Some("contains(params.labels, 'x')".to_string());
+    assert!(utils::validate_workflow(&workflow, Path::new("")).is_ok());
+
+    workflow.nodes[0].steps[0].r#if = Some("params.framework == ".to_string());
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("Invalid condition on step Step 1"));
+            assert!(msg.contains("column 20"));
+        }
+        _ => panic!("Expected WorkflowValidation error"),
+    }
+}
+
+#[test]
+fn test_validate_workflow_retry_and_timeout() {
+    let mut workflow = create_template_inputs_workflow(HashMap::from([(
+        "repo_url".to_string(),
+        "url".to_string(),
+    )]));
+    workflow.nodes[0].timeout = Some("1h30m".to_string());
+    workflow.nodes[0].steps[0].retry = Some(RetryPolicy {
+        max_attempts: 3,
+        backoff: Backoff::default(),
+    });
+    assert!(utils::validate_workflow(&workflow, Path::new("")).is_ok());
+
+    workflow.nodes[0].steps[0].timeout = Some("ten minutes".to_string());
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("Invalid timeout on step Step 1"));
+        }
+        _ => panic!("Expected WorkflowValidation error"),
+    }
+
+    workflow.nodes[0].steps[0].timeout = None;
+    workflow.nodes[0].retry = Some(RetryPolicy {
+        max_attempts: 0,
+        backoff: Backoff::default(),
+    });
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("Invalid retry policy on node node1"));
+        }
+        _ => panic!("Expected WorkflowValidation error"),
+    }
+}
+
+#[test]
+fn test_validate_workflow_template_input_declaration() {
+    let mut workflow = create_template_inputs_workflow(HashMap::from([(
+        "repo_url".to_string(),
+        "url".to_string(),
+    )]));
+    workflow.templates[0].inputs[1].default = Some("one".to_string());
+
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("Template template1 declares an invalid input"));
+        }
+        _ => panic!("Expected WorkflowValidation error"),
+    }
+
+    // Expressions are checked once resolved
+    workflow.templates[0].inputs[1].default = Some("${{params.depth}}".to_string());
+    assert!(utils::validate_workflow(&workflow, Path::new("")).is_ok());
+
+    workflow.templates[0].inputs[1].default = None;
+    workflow.templates[0].inputs[1].r#type = "integer".to_string();
+    assert!(utils::validate_workflow(&workflow, Path::new("")).is_err());
+}
+
 #[test]
 fn test_validate_workflow_invalid_matrix_strategy() {
     // Create a workflow with an invalid matrix strategy (missing both values and from_state)
@@ -452,16 +771,25 @@ fn test_validate_workflow_invalid_matrix_strategy() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: Some(Strategy {
                 r#type: StrategyType::Matrix,
                 values: None,
                 from_state: None,
+                axes: None,
+                include: None,
+                exclude: None,
+                max_parallel: None,
             }),
             runtime: None,
             steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -472,7 +800,7 @@ fn test_validate_workflow_invalid_matrix_strategy() {
     match result {
         Err(Error::WorkflowValidation(msg)) => {
             assert!(msg.contains("Matrix strategy"));
-            assert!(msg.contains("requires either 'values' or 'from_state'"));
+            assert!(msg.contains("requires 'values', 'from_state', 'axes' or 'include'"));
         }
         _ => panic!("Expected WorkflowValidation error"),
     }
@@ -492,10 +820,14 @@ fn test_validate_workflow_complex_cyclic_dependency() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node_c".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -504,10 +836,14 @@ fn test_validate_workflow_complex_cyclic_dependency() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node_a".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
             Node {
@@ -516,13 +852,18 @@ fn test_validate_workflow_complex_cyclic_dependency() {
                 description: None,
                 r#type: NodeType::Automatic,
                 depends_on: vec!["node_b".to_string()],
+                r#if: None,
                 trigger: None,
                 strategy: None,
                 runtime: None,
                 steps: vec![],
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
                 env: HashMap::new(),
             },
         ],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -634,12 +975,17 @@ fn test_validate_workflow_self_dependency() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec!["node1".to_string()], // Self-dependency
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -683,16 +1029,25 @@ fn test_validate_workflow_valid_matrix_strategy_with_values() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: Some(Strategy {
                 r#type: StrategyType::Matrix,
                 values: Some(values),
                 from_state: None,
+                axes: None,
+                include: None,
+                exclude: None,
+                max_parallel: None,
             }),
             runtime: None,
             steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -715,16 +1070,25 @@ fn test_validate_workflow_valid_matrix_strategy_with_from_state() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: Some(Strategy {
                 r#type: StrategyType::Matrix,
                 values: None,
                 from_state: Some("testState".to_string()),
+                axes: None,
+                include: None,
+                exclude: None,
+                max_parallel: None,
             }),
             runtime: None,
             steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -734,6 +1098,121 @@ fn test_validate_workflow_valid_matrix_strategy_with_from_state() {
     assert!(result.is_ok());
 }
 
+#[test]
+fn test_validate_workflow_state_schema() {
+    let workflow = |state_type: &str| -> Workflow {
+        serde_yaml::from_str(&format!(
+            r#"
+version: "1"
+state:
+  schema:
+    - name: files
+      type: {state_type}
+nodes:
+  - id: node1
+    name: Node 1
+    type: automatic
+    strategy:
+      type: matrix
+      from_state: files
+    steps: []
+"#
+        ))
+        .unwrap()
+    };
+    assert!(utils::validate_workflow(&workflow("array"), Path::new("")).is_ok());
+
+    match utils::validate_workflow(&workflow("string"), Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("declared with type string instead of array"))
+        }
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+
+    let mut undeclared = workflow("array");
+    undeclared.state.as_mut().unwrap().schema[0].name = "other".to_string();
+    match utils::validate_workflow(&undeclared, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("which the state schema doesn't declare"))
+        }
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+
+    let mut misplaced_properties = workflow("array");
+    misplaced_properties.state.as_mut().unwrap().schema[0].properties = Some(HashMap::new());
+    match utils::validate_workflow(&misplaced_properties, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => assert!(msg.contains("Invalid state schema")),
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_validate_workflow_matrix_axes() {
+    let mut workflow = Workflow {
+        version: "1".to_string(),
+        state: None,
+        templates: vec![],
+        nodes: vec![Node {
+            id: "node1".to_string(),
+            name: "Node 1".to_string(),
+            description: None,
+            r#type: NodeType::Automatic,
+            depends_on: vec![],
+            r#if: None,
+            trigger: None,
+            strategy: Some(Strategy {
+                r#type: StrategyType::Matrix,
+                values: None,
+                from_state: None,
+                axes: Some(BTreeMap::from([(
+                    "os".to_string(),
+                    vec![serde_json::json!("linux"), serde_json::json!("macos")],
+                )])),
+                include: None,
+                exclude: Some(vec![HashMap::from([(
+                    "os".to_string(),
+                    serde_json::json!("macos"),
+                )])]),
+                max_parallel: None,
+            }),
+            runtime: None,
+            steps: vec![],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
+            env: HashMap::new(),
+        }],
+        concurrency: None,
+    };
+    assert!(utils::validate_workflow(&workflow, Path::new("")).is_ok());
+
+    let strategy = workflow.nodes[0].strategy.as_mut().unwrap();
+    strategy.exclude = Some(vec![HashMap::new()]);
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => assert!(msg.contains("must not be empty")),
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+
+    let strategy = workflow.nodes[0].strategy.as_mut().unwrap();
+    strategy.exclude = None;
+    strategy.axes = Some(BTreeMap::from([("os".to_string(), vec![])]));
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => assert!(msg.contains("has no values")),
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+
+    let strategy = workflow.nodes[0].strategy.as_mut().unwrap();
+    strategy.axes = None;
+    strategy.values = Some(vec![HashMap::new()]);
+    strategy.from_state = Some("files".to_string());
+    match utils::validate_workflow(&workflow, Path::new("")) {
+        Err(Error::WorkflowValidation(msg)) => {
+            assert!(msg.contains("cannot use both 'values' and 'from_state'"))
+        }
+        other => panic!("Expected WorkflowValidation error, got {other:?}"),
+    }
+}
+
 #[test]
 fn test_validate_workflow_with_template_outputs() {
     // Create a workflow with a template that has outputs
@@ -755,6 +1234,7 @@ fn test_validate_workflow_with_template_outputs() {
             env: HashMap::new(),
         }],
         nodes: vec![],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -780,16 +1260,26 @@ fn test_validate_workflow_with_step_env_vars() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::RunScript("echo $STEP_VAR".to_string()),
                 env: Some(step_env),
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -834,12 +1324,15 @@ fix:
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![
                 Step {
                     name: "Step 1".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::AstGrep(UseAstGrep {
                         include: Some(vec!["**/*.ts".to_string()]),
                         exclude: Some(vec!["**/node_modules/**".to_string()]),
@@ -848,9 +1341,14 @@ fix:
                         allow_dirty: Some(false),
                     }),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 },
                 Step {
                     name: "Step 2".to_string(),
+                    id: None,
+                    r#if: None,
                     action: StepAction::JSAstGrep(UseJSAstGrep {
                         js_file: "codemod.ts".to_string(),
                         include: Some(vec!["**/*.ts".to_string()]),
@@ -859,12 +1357,20 @@ fix:
                         max_threads: Some(10),
                         dry_run: Some(true),
                         language: Some("typescript".to_string()),
+                        params: None,
                     }),
                     env: None,
+                    retry: None,
+                    timeout: None,
+                    continue_on_error: false,
                 },
             ],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -887,11 +1393,14 @@ fn test_validate_workflow_with_invalid_config_path() {
             description: None,
             r#type: NodeType::Automatic,
             depends_on: vec![],
+            r#if: None,
             trigger: None,
             strategy: None,
             runtime: None,
             steps: vec![Step {
                 name: "Step 1".to_string(),
+                id: None,
+                r#if: None,
                 action: StepAction::AstGrep(UseAstGrep {
                     config_file: "config.yaml".to_string(),
                     include: Some(vec!["**/*.ts".to_string()]),
@@ -900,9 +1409,16 @@ fn test_validate_workflow_with_invalid_config_path() {
                     allow_dirty: Some(false),
                 }),
                 env: None,
+                retry: None,
+                timeout: None,
+                continue_on_error: false,
             }],
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
             env: HashMap::new(),
         }],
+        concurrency: None,
     };
 
     // Validate the workflow
@@ -34,6 +34,9 @@ pub enum Error {
     #[error("State error: {0}")]
     State(String),
 
+    #[error("State conflict: {0}")]
+    StateConflict(String),
+
     #[error("Template error: {0}")]
     Template(String),
 
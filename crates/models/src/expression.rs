@@ -0,0 +1,990 @@
+//! Expression language used inside `${{ ... }}`
+//!
+//! Supports property and index access (`state.files[0].path`), string, number,
+//! boolean and null literals, `!`, `&&`, `||`, comparisons and a small set of
+//! functions (`contains`, `startsWith`, `endsWith`, `format`, `join`, `toJSON`,
+//! `fromJSON`). `&&` and `||` return one of their operands, so
+//! `params.branch || 'main'` evaluates to the first truthy value.
+//!
+//! In conditions, a property or index that doesn't exist evaluates to `null`.
+//! In interpolated strings it is an error instead, since it would otherwise
+//! be substituted as an empty string; only the left operand of `||` may be
+//! missing there, which is how defaults are spelled out.
+//!
+//! The status functions `success()`, `failure()` and `always()` look at the
+//! statuses in `needs`, i.e. at the results of a node's dependencies.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_json::Value;
+
+/// Error raised while parsing or evaluating an expression
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{message} at column {column}")]
+pub struct ExpressionError {
+    /// Description of the problem
+    pub message: String,
+
+    /// 1-based column of the problem in the evaluated input
+    pub column: usize,
+}
+
+type ExprResult<T> = std::result::Result<T, ExpressionError>;
+
+/// Evaluate a single expression (without the surrounding `${{ }}`)
+pub fn evaluate(expression: &str, context: &Value) -> ExprResult<Value> {
+    let (tokens, end) = tokenize(expression, 0, false)?;
+    debug_assert_eq!(end, expression.len());
+    let expr = Parser::new(expression, tokens).parse()?;
+    eval(&expr, context, expression, Missing::Null)
+}
+
+/// Check that an expression parses, without evaluating it
+pub fn check_syntax(expression: &str) -> ExprResult<()> {
+    let (tokens, _) = tokenize(expression, 0, false)?;
+    Parser::new(expression, tokens).parse().map(|_| ())
+}
+
+/// Whether an expression calls `success()`, `failure()` or `always()`
+pub fn uses_status_functions(expression: &str) -> ExprResult<bool> {
+    fn visit(expr: &Expr) -> bool {
+        match expr {
+            Expr::Literal(_) | Expr::Variable(..) => false,
+            Expr::Property(target, ..) | Expr::Not(target) => visit(target),
+            Expr::Index(left, right, _) | Expr::Binary(_, left, right) => {
+                visit(left) || visit(right)
+            }
+            Expr::Call(name, args, _) => {
+                STATUS_FUNCTIONS.contains(&name.as_str()) || args.iter().any(visit)
+            }
+        }
+    }
+
+    let (tokens, _) = tokenize(expression, 0, false)?;
+    Parser::new(expression, tokens)
+        .parse()
+        .map(|expr| visit(&expr))
+}
+
+const STATUS_FUNCTIONS: [&str; 3] = ["success", "failure", "always"];
+
+/// Replace every `${{ ... }}` in `input` with the display value of its expression
+pub fn interpolate(input: &str, context: &Value) -> ExprResult<String> {
+    let mut result = String::with_capacity(input.len());
+    let mut rest = 0;
+
+    while let Some(offset) = input[rest..].find("${{") {
+        let start = rest + offset;
+        result.push_str(&input[rest..start]);
+
+        let (tokens, end) = tokenize(input, start + 3, true)?;
+        let expr = Parser::new(input, tokens).parse()?;
+        let value = eval(&expr, context, input, Missing::Error)?;
+        result.push_str(&to_display_string(&value));
+
+        // Skip the closing `}}`
+        rest = end + 2;
+    }
+
+    result.push_str(&input[rest..]);
+    Ok(result)
+}
+
+/// Whether a value counts as true in a condition
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0 && !n.is_nan()),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(_) | Value::Object(_) => true,
+    }
+}
+
+/// Render a value the way it is substituted into strings
+pub fn to_display_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Array(_) | Value::Object(_) => value.to_string(),
+    }
+}
+
+fn error<T>(message: impl Into<String>, source: &str, position: usize) -> ExprResult<T> {
+    Err(ExpressionError {
+        message: message.into(),
+        column: source[..position.min(source.len())].chars().count() + 1,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    String(String),
+    Ident(String),
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{n}"),
+            Token::String(s) => write!(f, "'{s}'"),
+            Token::Ident(name) => write!(f, "{name}"),
+            Token::Dot => write!(f, "."),
+            Token::Comma => write!(f, ","),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::Not => write!(f, "!"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Eq => write!(f, "=="),
+            Token::Ne => write!(f, "!="),
+            Token::Lt => write!(f, "<"),
+            Token::Le => write!(f, "<="),
+            Token::Gt => write!(f, ">"),
+            Token::Ge => write!(f, ">="),
+            Token::End => write!(f, "end of expression"),
+        }
+    }
+}
+
+/// Split `source[start..]` into tokens
+///
+/// When `delimited` is set, tokenizing stops at the closing `}}` and its
+/// position is returned; otherwise the whole input is consumed.
+fn tokenize(
+    source: &str,
+    start: usize,
+    delimited: bool,
+) -> ExprResult<(Vec<(Token, usize)>, usize)> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = start;
+
+    loop {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+
+        if pos >= bytes.len() {
+            if delimited {
+                return error("Unterminated expression, expected }}", source, start - 3);
+            }
+            tokens.push((Token::End, pos));
+            return Ok((tokens, pos));
+        }
+
+        let token_start = pos;
+        let two = &bytes[pos..(pos + 2).min(bytes.len())];
+        let token = match bytes[pos] {
+            b'}' if delimited && two == b"}}" => {
+                tokens.push((Token::End, pos));
+                return Ok((tokens, pos));
+            }
+            b'&' if two == b"&&" => Token::And,
+            b'|' if two == b"||" => Token::Or,
+            b'=' if two == b"==" => Token::Eq,
+            b'!' if two == b"!=" => Token::Ne,
+            b'<' if two == b"<=" => Token::Le,
+            b'>' if two == b">=" => Token::Ge,
+            b'!' => Token::Not,
+            b'<' => Token::Lt,
+            b'>' => Token::Gt,
+            b'.' => Token::Dot,
+            b',' => Token::Comma,
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            b'[' => Token::LBracket,
+            b']' => Token::RBracket,
+            b'\'' | b'"' => {
+                let quote = bytes[pos];
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    match source[pos..].chars().next() {
+                        None => return error("Unterminated string literal", source, token_start),
+                        // A doubled quote escapes the quote character
+                        Some(c) if c as u32 == quote as u32 => {
+                            if bytes.get(pos + 1) == Some(&quote) {
+                                value.push(c);
+                                pos += 2;
+                            } else {
+                                pos += 1;
+                                break;
+                            }
+                        }
+                        Some(c) => {
+                            value.push(c);
+                            pos += c.len_utf8();
+                        }
+                    }
+                }
+                tokens.push((Token::String(value), token_start));
+                continue;
+            }
+            b'0'..=b'9' | b'-' => {
+                pos += 1;
+                while pos < bytes.len() && (bytes[pos].is_ascii_digit() || bytes[pos] == b'.') {
+                    pos += 1;
+                }
+                let text = &source[token_start..pos];
+                match text.parse::<f64>() {
+                    Ok(number) => tokens.push((Token::Number(number), token_start)),
+                    Err(_) => return error(format!("Invalid number {text}"), source, token_start),
+                }
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric()
+                        || bytes[pos] == b'_'
+                        || bytes[pos] == b'-')
+                {
+                    pos += 1;
+                }
+                tokens.push((
+                    Token::Ident(source[token_start..pos].to_string()),
+                    token_start,
+                ));
+                continue;
+            }
+            _ => {
+                let c = source[pos..].chars().next().unwrap_or_default();
+                return error(format!("Unexpected character '{c}'"), source, pos);
+            }
+        };
+
+        pos += match token {
+            Token::And | Token::Or | Token::Eq | Token::Ne | Token::Le | Token::Ge => 2,
+            _ => 1,
+        };
+        tokens.push((token, token_start));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Variable(String, usize),
+    Property(Box<Expr>, String, usize),
+    Index(Box<Expr>, Box<Expr>, usize),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>, usize),
+}
+
+/// Recursive descent parser, from lowest to highest precedence:
+/// `||`, `&&`, `==`/`!=`, `<`/`<=`/`>`/`>=`, `!`, property/index access
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, tokens: Vec<(Token, usize)>) -> Self {
+        Self {
+            source,
+            tokens,
+            pos: 0,
+        }
+    }
+
+    fn parse(mut self) -> ExprResult<Expr> {
+        if self.peek() == &Token::End {
+            return error("Empty expression", self.source, self.position());
+        }
+        let expr = self.parse_or()?;
+        match self.peek() {
+            Token::End => Ok(expr),
+            token => error(
+                format!("Unexpected token '{token}'"),
+                self.source,
+                self.position(),
+            ),
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn position(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.pos].clone();
+        if token.0 != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> ExprResult<()> {
+        if self.peek() == &expected {
+            self.advance();
+            Ok(())
+        } else {
+            error(
+                format!("Expected '{expected}', found '{}'", self.peek()),
+                self.source,
+                self.position(),
+            )
+        }
+    }
+
+    fn parse_binary(
+        &mut self,
+        operators: &[(Token, BinaryOp)],
+        next: fn(&mut Self) -> ExprResult<Expr>,
+    ) -> ExprResult<Expr> {
+        let mut left = next(self)?;
+        while let Some((_, op)) = operators.iter().find(|(token, _)| token == self.peek()) {
+            let op = *op;
+            self.advance();
+            let right = next(self)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_or(&mut self) -> ExprResult<Expr> {
+        self.parse_binary(&[(Token::Or, BinaryOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> ExprResult<Expr> {
+        self.parse_binary(&[(Token::And, BinaryOp::And)], Self::parse_equality)
+    }
+
+    fn parse_equality(&mut self) -> ExprResult<Expr> {
+        self.parse_binary(
+            &[(Token::Eq, BinaryOp::Eq), (Token::Ne, BinaryOp::Ne)],
+            Self::parse_comparison,
+        )
+    }
+
+    fn parse_comparison(&mut self) -> ExprResult<Expr> {
+        self.parse_binary(
+            &[
+                (Token::Lt, BinaryOp::Lt),
+                (Token::Le, BinaryOp::Le),
+                (Token::Gt, BinaryOp::Gt),
+                (Token::Ge, BinaryOp::Ge),
+            ],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> ExprResult<Expr> {
+        if self.peek() == &Token::Not {
+            self.advance();
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> ExprResult<Expr> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    match self.advance() {
+                        (Token::Ident(name), position) => {
+                            expr = Expr::Property(Box::new(expr), name, position)
+                        }
+                        (token, position) => {
+                            return error(
+                                format!("Expected property name after '.', found '{token}'"),
+                                self.source,
+                                position,
+                            )
+                        }
+                    }
+                }
+                Token::LBracket => {
+                    let (_, position) = self.advance();
+                    let index = self.parse_or()?;
+                    self.expect(Token::RBracket)?;
+                    expr = Expr::Index(Box::new(expr), Box::new(index), position);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> ExprResult<Expr> {
+        let (token, position) = self.advance();
+        match token {
+            Token::Number(n) => Ok(Expr::Literal(number_value(n))),
+            Token::String(s) => Ok(Expr::Literal(Value::String(s))),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.peek() == &Token::LParen => {
+                    self.advance();
+                    let mut args = Vec::new();
+                    if self.peek() != &Token::RParen {
+                        loop {
+                            args.push(self.parse_or()?);
+                            if self.peek() != &Token::Comma {
+                                break;
+                            }
+                            self.advance();
+                        }
+                    }
+                    self.expect(Token::RParen)?;
+                    Ok(Expr::Call(name, args, position))
+                }
+                _ => Ok(Expr::Variable(name, position)),
+            },
+            Token::LParen => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            token => error(format!("Unexpected token '{token}'"), self.source, position),
+        }
+    }
+}
+
+fn number_value(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        Value::from(n as i64)
+    } else {
+        serde_json::Number::from_f64(n)
+            .map(Value::Number)
+            .unwrap_or(Value::Null)
+    }
+}
+
+/// Numeric view of a value used by loose comparisons
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Null => Some(0.0),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) if s.trim().is_empty() => Some(0.0),
+        Value::String(s) => s.trim().parse().ok(),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn loose_eq(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::String(a), Value::String(b)) => a == b,
+        // Parameters are strings, so `params.flag == true` compares `'true'`
+        (Value::Bool(b), Value::String(s)) | (Value::String(s), Value::Bool(b))
+            if matches!(s.as_str(), "true" | "false") =>
+        {
+            (s == "true") == *b
+        }
+        (Value::Array(_), _)
+        | (Value::Object(_), _)
+        | (_, Value::Array(_))
+        | (_, Value::Object(_)) => left == right,
+        _ => match (as_number(left), as_number(right)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        },
+    }
+}
+
+fn loose_cmp(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => as_number(left)?.partial_cmp(&as_number(right)?),
+    }
+}
+
+/// How a property or index that doesn't exist is evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Missing {
+    Null,
+    Error,
+}
+
+/// The path an access expression spells out, such as `state.files[0]`
+fn path(expr: &Expr) -> String {
+    match expr {
+        Expr::Variable(name, _) => name.clone(),
+        Expr::Literal(value) => value.to_string(),
+        Expr::Property(target, name, _) => format!("{}.{name}", path(target)),
+        Expr::Index(target, index, _) => format!("{}[{}]", path(target), path(index)),
+        _ => "(...)".to_string(),
+    }
+}
+
+fn eval(expr: &Expr, context: &Value, source: &str, missing: Missing) -> ExprResult<Value> {
+    let found = |value: Option<Value>, position: usize| match value {
+        Some(value) => Ok(value),
+        None if missing == Missing::Null => Ok(Value::Null),
+        None => error(format!("{} not found", path(expr)), source, position),
+    };
+
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Variable(name, position) => match context.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => error(format!("Unknown variable {name}"), source, *position),
+        },
+        Expr::Property(target, name, position) => found(
+            eval(target, context, source, missing)?.get(name).cloned(),
+            *position,
+        ),
+        Expr::Index(target, index, position) => {
+            let target = eval(target, context, source, missing)?;
+            let index = eval(index, context, source, missing)?;
+            let value = match (&target, &index) {
+                (Value::Array(items), Value::Number(n)) => {
+                    n.as_u64().and_then(|i| items.get(i as usize)).cloned()
+                }
+                (Value::Object(map), Value::String(key)) => map.get(key).cloned(),
+                _ => None,
+            };
+            found(value, *position)
+        }
+        Expr::Not(inner) => Ok(Value::Bool(!is_truthy(&eval(
+            inner, context, source, missing,
+        )?))),
+        Expr::Binary(op, left, right) => {
+            // The left operand of `||` may be missing, the right one provides the default
+            let left_missing = if *op == BinaryOp::Or {
+                Missing::Null
+            } else {
+                missing
+            };
+            let left = eval(left, context, source, left_missing)?;
+            match op {
+                BinaryOp::And if !is_truthy(&left) => return Ok(left),
+                BinaryOp::Or if is_truthy(&left) => return Ok(left),
+                BinaryOp::And | BinaryOp::Or => return eval(right, context, source, missing),
+                _ => {}
+            }
+            let right = eval(right, context, source, missing)?;
+            let result = match op {
+                BinaryOp::Eq => loose_eq(&left, &right),
+                BinaryOp::Ne => !loose_eq(&left, &right),
+                BinaryOp::Lt => loose_cmp(&left, &right) == Some(Ordering::Less),
+                BinaryOp::Le => matches!(
+                    loose_cmp(&left, &right),
+                    Some(Ordering::Less | Ordering::Equal)
+                ),
+                BinaryOp::Gt => loose_cmp(&left, &right) == Some(Ordering::Greater),
+                BinaryOp::Ge => matches!(
+                    loose_cmp(&left, &right),
+                    Some(Ordering::Greater | Ordering::Equal)
+                ),
+                BinaryOp::And | BinaryOp::Or => unreachable!(),
+            };
+            Ok(Value::Bool(result))
+        }
+        Expr::Call(name, args, position) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, context, source, missing))
+                .collect::<ExprResult<Vec<_>>>()?;
+            call_function(name, &args, context, source, *position)
+        }
+    }
+}
+
+fn call_function(
+    name: &str,
+    args: &[Value],
+    context: &Value,
+    source: &str,
+    position: usize,
+) -> ExprResult<Value> {
+    let arity = |min: usize, max: usize| -> ExprResult<()> {
+        if args.len() < min || args.len() > max {
+            let expected = if min == max {
+                min.to_string()
+            } else if max == usize::MAX {
+                format!("at least {min}")
+            } else {
+                format!("{min} to {max}")
+            };
+            return error(
+                format!(
+                    "Function {name} expects {expected} arguments, got {}",
+                    args.len()
+                ),
+                source,
+                position,
+            );
+        }
+        Ok(())
+    };
+
+    match name {
+        "contains" => {
+            arity(2, 2)?;
+            let found = match &args[0] {
+                Value::Array(items) => items.iter().any(|item| loose_eq(item, &args[1])),
+                haystack => to_display_string(haystack).contains(&to_display_string(&args[1])),
+            };
+            Ok(Value::Bool(found))
+        }
+        "startsWith" => {
+            arity(2, 2)?;
+            Ok(Value::Bool(
+                to_display_string(&args[0]).starts_with(&to_display_string(&args[1])),
+            ))
+        }
+        "endsWith" => {
+            arity(2, 2)?;
+            Ok(Value::Bool(
+                to_display_string(&args[0]).ends_with(&to_display_string(&args[1])),
+            ))
+        }
+        "format" => {
+            arity(1, usize::MAX)?;
+            format_string(&to_display_string(&args[0]), &args[1..])
+                .map(Value::String)
+                .or_else(|message| error(message, source, position))
+        }
+        "join" => {
+            arity(1, 2)?;
+            let separator = args
+                .get(1)
+                .map(to_display_string)
+                .unwrap_or_else(|| ",".to_string());
+            let joined = match &args[0] {
+                Value::Array(items) => items
+                    .iter()
+                    .map(to_display_string)
+                    .collect::<Vec<_>>()
+                    .join(&separator),
+                value => to_display_string(value),
+            };
+            Ok(Value::String(joined))
+        }
+        "toJSON" => {
+            arity(1, 1)?;
+            Ok(Value::String(args[0].to_string()))
+        }
+        "fromJSON" => {
+            arity(1, 1)?;
+            serde_json::from_str(&to_display_string(&args[0])).or_else(|e| {
+                error(
+                    format!("Function fromJSON received invalid JSON: {e}"),
+                    source,
+                    position,
+                )
+            })
+        }
+        "always" => {
+            arity(0, 0)?;
+            Ok(Value::Bool(true))
+        }
+        "success" | "failure" => {
+            arity(0, 0)?;
+            let any_failed = context
+                .get("needs")
+                .and_then(Value::as_object)
+                .is_some_and(|needs| {
+                    needs.values().any(|need| {
+                        matches!(
+                            need.get("status").and_then(Value::as_str),
+                            Some("Failed" | "Skipped")
+                        )
+                    })
+                });
+            Ok(Value::Bool(any_failed == (name == "failure")))
+        }
+        _ => error(format!("Unknown function {name}"), source, position),
+    }
+}
+
+/// Substitute `{0}`, `{1}`, ... in a format string; `{{` and `}}` escape braces
+fn format_string(format: &str, args: &[Value]) -> std::result::Result<String, String> {
+    let mut result = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut index = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_digit() => index.push(c),
+                        _ => return Err(format!("Invalid format string {format:?}")),
+                    }
+                }
+                let arg = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| args.get(i))
+                    .ok_or_else(|| {
+                        format!("Format string {format:?} references missing argument {{{index}}}")
+                    })?;
+                result.push_str(&to_display_string(arg));
+            }
+            '}' => return Err(format!("Invalid format string {format:?}")),
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context() -> Value {
+        json!({
+            "params": { "branch": "main", "count": "5", "empty": "" },
+            "state": {
+                "files": [{ "path": "src/a.ts" }, { "path": "src/b.ts" }],
+                "labels": ["bug", "codemod"],
+                "config": { "retries": 3 }
+            },
+            "region": "eu"
+        })
+    }
+
+    #[test]
+    fn test_property_and_index_access() {
+        let context = context();
+        assert_eq!(
+            evaluate("state.files[1].path", &context).unwrap(),
+            json!("src/b.ts")
+        );
+        assert_eq!(
+            evaluate("state.config['retries']", &context).unwrap(),
+            json!(3)
+        );
+        assert_eq!(evaluate("state.files[5]", &context).unwrap(), Value::Null);
+        assert_eq!(evaluate("region", &context).unwrap(), json!("eu"));
+    }
+
+    #[test]
+    fn test_operators() {
+        let context = context();
+        assert_eq!(
+            evaluate("params.missing || 'default'", &context).unwrap(),
+            json!("default")
+        );
+        assert_eq!(
+            evaluate("params.branch && params.empty", &context).unwrap(),
+            json!("")
+        );
+        assert_eq!(
+            evaluate("params.count > 3 && state.config.retries <= 3", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("!(params.branch == 'main') || region != 'eu'", &context).unwrap(),
+            json!(false)
+        );
+        assert_eq!(evaluate("'it''s'", &context).unwrap(), json!("it's"));
+    }
+
+    #[test]
+    fn test_boolean_strings() {
+        let context = json!({ "params": { "flag": "true", "dry_run": "false", "one": "1" } });
+        assert_eq!(
+            evaluate("params.flag == true", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("false == params.dry_run", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("params.dry_run != true", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("params.one == true", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("params.flag == 1", &context).unwrap(),
+            json!(false)
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let context = context();
+        assert_eq!(
+            evaluate("contains(state.labels, 'codemod')", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("contains(params.branch, 'ai')", &context).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            evaluate("format('{0}/{1} {{x}}', params.branch, 2)", &context).unwrap(),
+            json!("main/2 {x}")
+        );
+        assert_eq!(
+            evaluate("toJSON(state.labels)", &context).unwrap(),
+            json!("[\"bug\",\"codemod\"]")
+        );
+        assert_eq!(
+            evaluate("fromJSON('[1, 2]')[1]", &context).unwrap(),
+            json!(2)
+        );
+        assert_eq!(
+            evaluate("join(state.labels, ', ')", &context).unwrap(),
+            json!("bug, codemod")
+        );
+    }
+
+    #[test]
+    fn test_status_functions() {
+        let succeeded = json!({ "needs": { "build": { "status": "Completed" } } });
+        let failed = json!({ "needs": { "build": { "status": "Completed" }, "test": { "status": "Failed" } } });
+
+        assert_eq!(evaluate("success()", &succeeded).unwrap(), json!(true));
+        assert_eq!(evaluate("failure()", &succeeded).unwrap(), json!(false));
+        assert_eq!(evaluate("success()", &failed).unwrap(), json!(false));
+        assert_eq!(evaluate("failure()", &failed).unwrap(), json!(true));
+        assert_eq!(evaluate("always()", &failed).unwrap(), json!(true));
+
+        assert!(uses_status_functions("always()").unwrap());
+        assert!(uses_status_functions("params.x == 'y' && !failure()").unwrap());
+        assert!(!uses_status_functions("contains(params.x, 'y')").unwrap());
+        assert!(evaluate("always(1)", &succeeded).is_err());
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let context = context();
+        assert_eq!(
+            interpolate(
+                "git checkout ${{ params.branch }} && echo ${{ state.labels }} ${{format('{0}', region)}}",
+                &context
+            )
+            .unwrap(),
+            "git checkout main && echo [\"bug\",\"codemod\"] eu"
+        );
+        assert_eq!(
+            interpolate("no expressions", &context).unwrap(),
+            "no expressions"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_missing_paths() {
+        let context = context();
+        let err = interpolate("rm -rf ${{ params.dir }}/", &context).unwrap_err();
+        assert_eq!(err.message, "params.dir not found");
+        assert_eq!(err.column, 19);
+
+        let err = interpolate("${{ state.files[5].path }}", &context).unwrap_err();
+        assert_eq!(err.message, "state.files[5] not found");
+        assert!(interpolate("${{ state.config.retries.max }}", &context).is_err());
+        assert!(interpolate("${{ contains(params.typo, 'x') }}", &context).is_err());
+
+        // Only an explicit fallback makes a missing value acceptable
+        assert_eq!(
+            interpolate("${{ params.dir || 'build' }}", &context).unwrap(),
+            "build"
+        );
+        assert_eq!(
+            interpolate("${{ params.dir || params.out || 'dist' }}", &context).unwrap(),
+            "dist"
+        );
+        assert!(interpolate("${{ params.dir || params.out }}", &context).is_err());
+        assert!(interpolate("${{ params.dir && 'x' }}", &context).is_err());
+
+        // Conditions treat missing values as null
+        assert_eq!(evaluate("params.dir", &context).unwrap(), Value::Null);
+        assert_eq!(evaluate("!state.files[5]", &context).unwrap(), json!(true));
+    }
+
+    #[test]
+    fn test_interpolate_renders_values() {
+        let context = json!({
+            "state": { "name": "codemod", "count": 2, "unset": null },
+            "matrix": { "region": "eu" },
+            "region": "eu"
+        });
+
+        // Strings from state and the matrix are inserted without JSON quotes,
+        // like parameters; toJSON gives the quoted form
+        assert_eq!(
+            interpolate(
+                "${{ state.name }} ${{ matrix.region }} ${{ region }} ${{ toJSON(state.name) }}",
+                &context
+            )
+            .unwrap(),
+            "codemod eu eu \"codemod\""
+        );
+        assert_eq!(
+            interpolate("[${{ state.count }}] [${{ state.unset }}]", &context).unwrap(),
+            "[2] []"
+        );
+    }
+
+    #[test]
+    fn test_error_columns() {
+        let context = context();
+        let err = interpolate("echo ${{ params.branch == }}", &context).unwrap_err();
+        assert_eq!(err.column, 27);
+
+        let err = evaluate("params.branch && unknown.value", &context).unwrap_err();
+        assert_eq!(err.message, "Unknown variable unknown");
+        assert_eq!(err.column, 18);
+
+        let err = evaluate("contains(params.branch)", &context).unwrap_err();
+        assert_eq!(err.column, 1);
+
+        let err = interpolate("echo ${{ params.branch", &context).unwrap_err();
+        assert_eq!(err.column, 6);
+
+        let err = evaluate("params.branch # 1", &context).unwrap_err();
+        assert_eq!(err.column, 15);
+    }
+}
@@ -1,5 +1,8 @@
+pub mod duration;
 pub mod error;
+pub mod expression;
 pub mod node;
+pub mod retry;
 pub mod runtime;
 pub mod state;
 pub mod state_diff;
@@ -12,17 +15,24 @@ pub mod variable;
 pub mod workflow;
 
 // Re-export types
+pub use duration::parse_duration;
 pub use error::Error;
 pub use node::Node;
+pub use retry::{Backoff, BackoffType, RetryPolicy};
 pub use runtime::{Runtime, RuntimeType};
 pub use state::{StateSchema, StateSchemaItems, StateSchemaProperty, StateSchemaType};
-pub use state_diff::{DiffOperation, FieldDiff, StateDiff, TaskDiff, WorkflowRunDiff};
+pub use state_diff::{
+    DiffOperation, FieldDiff, StateDiff, StateJournalEntry, TaskDiff, WorkflowRunDiff,
+};
 pub use step::{Step, TemplateUse};
 pub use strategy::{Strategy, StrategyType};
-pub use task::{Task, TaskStatus};
+pub use task::{LogLine, LogStream, Task, TaskStatus};
 pub use template::{Template, TemplateInput, TemplateOutput};
 pub use trigger::{Trigger, TriggerType};
-pub use variable::resolve_variables;
+pub use variable::{
+    check_expression, evaluate_condition, evaluate_expression, resolve_variables,
+    uses_status_functions, VariableContext,
+};
 pub use workflow::{Workflow, WorkflowRun, WorkflowState, WorkflowStatus};
 
 pub type Result<T> = std::result::Result<T, Error>;
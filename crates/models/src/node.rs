@@ -1,3 +1,4 @@
+use crate::retry::RetryPolicy;
 use crate::runtime::Runtime;
 use crate::step::Step;
 use crate::strategy::Strategy;
@@ -41,6 +42,11 @@ pub struct Node {
     #[ts(optional, as = "Option<Vec<String>>")]
     pub depends_on: Vec<String>,
 
+    /// Condition that must hold for the node to run, e.g. `${{ params.framework == 'react' }}`
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub r#if: Option<String>,
+
     /// Configuration for how the node is triggered
     #[serde(default)]
     #[ts(optional=nullable)]
@@ -56,6 +62,22 @@ pub struct Node {
     #[ts(optional=nullable)]
     pub runtime: Option<Runtime>,
 
+    /// Retry policy applied when any of the node's steps fails; retries rerun all steps
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub retry: Option<RetryPolicy>,
+
+    /// Maximum duration of a single attempt of the node, e.g. `30m`
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub timeout: Option<String>,
+
+    /// Complete the task, recording the error, instead of failing it when a step fails,
+    /// so dependents still run and the workflow doesn't fail
+    #[serde(default)]
+    #[ts(optional, as = "Option<bool>")]
+    pub continue_on_error: bool,
+
     /// Steps to execute within the node
     pub steps: Vec<Step>,
 
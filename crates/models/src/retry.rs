@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::duration::parse_duration;
+
+/// How the delay between attempts grows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum BackoffType {
+    /// Wait the same delay before every retry
+    Fixed,
+
+    /// Double the delay after every failed attempt
+    #[default]
+    Exponential,
+}
+
+/// Delay between attempts
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
+pub struct Backoff {
+    /// How the delay grows between attempts
+    #[serde(default)]
+    #[ts(optional, as = "Option<BackoffType>")]
+    pub r#type: BackoffType,
+
+    /// Delay before the first retry, e.g. `500ms` or `5s`
+    #[serde(default = "default_delay")]
+    #[ts(optional, as = "Option<String>")]
+    pub delay: String,
+
+    /// Upper bound for the delay between attempts
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub max_delay: Option<String>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            r#type: BackoffType::default(),
+            delay: default_delay(),
+            max_delay: None,
+        }
+    }
+}
+
+fn default_delay() -> String {
+    "1s".to_string()
+}
+
+/// Retry policy for a step or node
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+
+    /// Delay between attempts
+    #[serde(default)]
+    #[ts(optional, as = "Option<Backoff>")]
+    pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given failed attempt (starting at 1)
+    pub fn delay_after(&self, attempt: u32) -> Result<Duration, String> {
+        let delay = parse_duration(&self.backoff.delay)?;
+        let delay = match self.backoff.r#type {
+            BackoffType::Fixed => delay,
+            BackoffType::Exponential => {
+                delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            }
+        };
+
+        match &self.backoff.max_delay {
+            Some(max_delay) => Ok(delay.min(parse_duration(max_delay)?)),
+            None => Ok(delay),
+        }
+    }
+
+    /// Check that the policy is usable
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        parse_duration(&self.backoff.delay)?;
+        if let Some(max_delay) = &self.backoff.max_delay {
+            parse_duration(max_delay)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_after() {
+        let mut policy = RetryPolicy {
+            max_attempts: 5,
+            backoff: Backoff {
+                r#type: BackoffType::Exponential,
+                delay: "1s".to_string(),
+                max_delay: Some("5s".to_string()),
+            },
+        };
+
+        assert_eq!(policy.delay_after(1).unwrap(), Duration::from_secs(1));
+        assert_eq!(policy.delay_after(2).unwrap(), Duration::from_secs(2));
+        assert_eq!(policy.delay_after(3).unwrap(), Duration::from_secs(4));
+        assert_eq!(policy.delay_after(4).unwrap(), Duration::from_secs(5));
+
+        policy.backoff.r#type = BackoffType::Fixed;
+        assert_eq!(policy.delay_after(3).unwrap(), Duration::from_secs(1));
+
+        policy.max_attempts = 0;
+        assert!(policy.validate().is_err());
+    }
+}
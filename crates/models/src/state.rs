@@ -1,7 +1,12 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 use ts_rs::TS;
+
+use crate::{Error, Result};
+
 /// Type of state schema property
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, TS)]
 #[serde(rename_all = "lowercase")]
@@ -22,6 +27,32 @@ pub enum StateSchemaType {
     Boolean,
 }
 
+impl StateSchemaType {
+    /// Check whether a JSON value has this type
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            StateSchemaType::Array => value.is_array(),
+            StateSchemaType::Object => value.is_object(),
+            StateSchemaType::String => value.is_string(),
+            StateSchemaType::Number => value.is_number(),
+            StateSchemaType::Boolean => value.is_boolean(),
+        }
+    }
+}
+
+impl fmt::Display for StateSchemaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StateSchemaType::Array => "array",
+            StateSchemaType::Object => "object",
+            StateSchemaType::String => "string",
+            StateSchemaType::Number => "number",
+            StateSchemaType::Boolean => "boolean",
+        };
+        f.write_str(name)
+    }
+}
+
 /// Represents a state schema property
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
 pub struct StateSchemaProperty {
@@ -48,12 +79,87 @@ pub struct StateSchema {
     #[ts(optional=nullable)]
     pub items: Option<Box<StateSchemaItems>>,
 
+    /// For object types, the properties of the object
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub properties: Option<HashMap<String, StateSchemaProperty>>,
+
     /// Description of the state schema
     #[serde(default)]
     #[ts(optional=nullable)]
     pub description: Option<String>,
 }
 
+impl StateSchema {
+    /// Check that `items` and `properties` are only declared for types that have them
+    pub fn validate(&self) -> Result<()> {
+        if self.items.is_some() && self.r#type != StateSchemaType::Array {
+            return Err(Error::State(format!(
+                "State {} declares items but is not an array",
+                self.name
+            )));
+        }
+        if self.properties.is_some() && self.r#type != StateSchemaType::Object {
+            return Err(Error::State(format!(
+                "State {} declares properties but is not an object",
+                self.name
+            )));
+        }
+        if let Some(items) = &self.items {
+            if items.properties.is_some() && items.r#type != StateSchemaType::Object {
+                return Err(Error::State(format!(
+                    "Items of state {} declare properties but are not objects",
+                    self.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that a value matches the declared type, items and properties
+    ///
+    /// Properties that aren't declared are allowed, and declared properties
+    /// may be missing.
+    pub fn validate_value(&self, value: &Value) -> Result<()> {
+        check_type(&self.name, self.r#type, value)?;
+        check_properties(&self.name, self.properties.as_ref(), value)?;
+
+        if let (Some(items), Value::Array(values)) = (&self.items, value) {
+            for (index, item) in values.iter().enumerate() {
+                let path = format!("{}[{index}]", self.name);
+                check_type(&path, items.r#type, item)?;
+                check_properties(&path, items.properties.as_ref(), item)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_type(path: &str, expected: StateSchemaType, value: &Value) -> Result<()> {
+    if expected.matches(value) {
+        return Ok(());
+    }
+    Err(Error::State(format!(
+        "State {path} must be of type {expected}, got {value}"
+    )))
+}
+
+fn check_properties(
+    path: &str,
+    properties: Option<&HashMap<String, StateSchemaProperty>>,
+    value: &Value,
+) -> Result<()> {
+    let (Some(properties), Value::Object(obj)) = (properties, value) else {
+        return Ok(());
+    };
+    for (name, property) in properties {
+        if let Some(value) = obj.get(name) {
+            check_type(&format!("{path}.{name}"), property.r#type, value)?;
+        }
+    }
+    Ok(())
+}
+
 /// Represents the schema for items in an array
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
 pub struct StateSchemaItems {
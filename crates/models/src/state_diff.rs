@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use ts_rs::TS;
 use uuid::Uuid;
@@ -14,6 +16,12 @@ pub enum DiffOperation {
     Remove,
     /// Append to an array
     Append,
+    /// Shallow-merge an object into an existing object
+    Merge,
+    /// Remove every occurrence of a value from an array
+    RemoveFromArray,
+    /// Add a number to an existing number (missing values count as zero)
+    Increment,
 }
 
 /// Represents a diff for a single field
@@ -23,6 +31,13 @@ pub struct FieldDiff {
     pub operation: DiffOperation,
     /// The new value (for Add and Update operations)
     pub value: Option<serde_json::Value>,
+    /// Only apply the diff if the field is still at this revision
+    ///
+    /// Fields that were never written are at revision 0. Only used for
+    /// workflow state.
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub expected_revision: Option<u64>,
 }
 
 /// Represents a diff for a workflow run
@@ -50,4 +65,49 @@ pub struct StateDiff {
     pub workflow_run_id: Uuid,
     /// The fields to update
     pub fields: HashMap<String, FieldDiff>,
+    /// The task whose step produced the diff, recorded in the state journal
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub task_id: Option<Uuid>,
+    /// Name of the step that produced the diff
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub step: Option<String>,
+}
+
+/// A write to workflow state, as kept in the state journal of a run
+///
+/// The journal is append-only, so replaying it from an empty state gives
+/// the state as of any entry.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct StateJournalEntry {
+    /// Position in the journal, starting at 1
+    pub sequence: u64,
+    /// The task whose step made the write, if a task made it
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub task_id: Option<Uuid>,
+    /// Name of the step that made the write
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub step: Option<String>,
+    /// When the write was applied
+    pub recorded_at: DateTime<Utc>,
+    /// The written fields; replacing the whole state is recorded as an
+    /// update of each changed key and a removal of each removed one
+    pub fields: HashMap<String, FieldDiff>,
+    /// Values of the written keys after the write, without the removed keys
+    pub values: HashMap<String, Value>,
+}
+
+impl StateJournalEntry {
+    /// Apply the write to a state replayed up to the previous entry
+    pub fn replay(&self, state: &mut HashMap<String, Value>) {
+        for key in self.fields.keys() {
+            match self.values.get(key) {
+                Some(value) => state.insert(key.clone(), value.clone()),
+                None => state.remove(key),
+            };
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::retry::RetryPolicy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -8,6 +9,16 @@ pub struct Step {
     /// Human-readable name
     pub name: String,
 
+    /// Identifier used to reference the step's outputs (defaults to the name)
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub id: Option<String>,
+
+    /// Condition that must hold for the step to run; the step is skipped otherwise
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub r#if: Option<String>,
+
     /// Action to perform - either using a template or running a script
     #[serde(flatten)]
     pub action: StepAction,
@@ -16,6 +27,28 @@ pub struct Step {
     #[serde(default)]
     #[ts(optional, as = "Option<HashMap<String, String>>")]
     pub env: Option<HashMap<String, String>>,
+
+    /// Retry policy applied when the step fails
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub retry: Option<RetryPolicy>,
+
+    /// Maximum duration of a single attempt, e.g. `30s` or `10m`
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub timeout: Option<String>,
+
+    /// Keep running the node's remaining steps when this step fails
+    #[serde(default)]
+    #[ts(optional, as = "Option<bool>")]
+    pub continue_on_error: bool,
+}
+
+impl Step {
+    /// Key under which the outputs of this step are exposed as `steps.<key>.outputs`
+    pub fn output_key(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.name)
+    }
 }
 
 /// Represents the action a step can take - either using templates or running a script
@@ -117,6 +150,12 @@ pub struct UseJSAstGrep {
     #[serde(default)]
     #[ts(optional, as = "Option<String>")]
     pub language: Option<String>,
+
+    /// Parameters passed to the codemod's transform on top of the workflow
+    /// parameters (optional); values can reference variables like `${{ params.x }}`
+    #[serde(default)]
+    #[ts(optional, as = "Option<HashMap<String, String>>")]
+    pub params: Option<HashMap<String, String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
@@ -1,6 +1,7 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use ts_rs::TS;
 /// Type of strategy
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, TS)]
@@ -25,4 +26,210 @@ pub struct Strategy {
     #[serde(default)]
     #[ts(optional=nullable)]
     pub from_state: Option<String>,
+
+    /// Named axes whose cartesian product is expanded, e.g. `framework: [react, vue]`;
+    /// combined with `values` or `from_state` items when those are set
+    #[serde(default)]
+    #[ts(optional, as = "Option<BTreeMap<String, Vec<serde_json::Value>>>")]
+    pub axes: Option<BTreeMap<String, Vec<Value>>>,
+
+    /// Combinations to add, or extra values for the combinations they match
+    #[serde(default)]
+    #[ts(optional, as = "Option<Vec<HashMap<String, serde_json::Value>>>")]
+    pub include: Option<Vec<HashMap<String, Value>>>,
+
+    /// Partial combinations to remove
+    #[serde(default)]
+    #[ts(optional, as = "Option<Vec<HashMap<String, serde_json::Value>>>")]
+    pub exclude: Option<Vec<HashMap<String, Value>>>,
+
+    /// Maximum number of this node's tasks running at the same time
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub max_parallel: Option<usize>,
+}
+
+impl Strategy {
+    /// Expand the matrix into the values of each task
+    ///
+    /// `state_value` is the current value of the `from_state` key, if any. The
+    /// base combinations are the `values` entries or the items of the state
+    /// value, which are then crossed with every axis. `exclude` entries remove
+    /// the combinations they match, and `include` entries are merged into the
+    /// combinations whose original values they agree with, or added as new
+    /// combinations when they match none.
+    pub fn expand(&self, state_value: Option<&Value>) -> Vec<HashMap<String, Value>> {
+        let has_base = self.values.is_some() || self.from_state.is_some() || self.axes.is_some();
+
+        let mut combinations: Vec<HashMap<String, Value>> = if let Some(values) = &self.values {
+            values.clone()
+        } else if self.from_state.is_some() {
+            state_value.map(matrix_items).unwrap_or_default()
+        } else if has_base {
+            vec![HashMap::new()]
+        } else {
+            Vec::new()
+        };
+
+        for (axis, axis_values) in self.axes.iter().flatten() {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    axis_values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.insert(axis.clone(), value.clone());
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        if let Some(exclude) = &self.exclude {
+            combinations.retain(|combination| {
+                !exclude
+                    .iter()
+                    .any(|entry| entry.iter().all(|(k, v)| combination.get(k) == Some(v)))
+            });
+        }
+
+        // Values added by `include` may be overwritten by later entries, original ones may not
+        let mut expanded: Vec<(HashMap<String, Value>, HashMap<String, Value>)> = combinations
+            .into_iter()
+            .map(|combination| (combination, HashMap::new()))
+            .collect();
+        for entry in self.include.iter().flatten() {
+            let mut matched = false;
+            for (original, added) in &mut expanded {
+                let matches = entry
+                    .iter()
+                    .all(|(k, v)| original.get(k).map_or(true, |original| original == v));
+                if matches && !original.is_empty() {
+                    matched = true;
+                    for (k, v) in entry {
+                        if !original.contains_key(k) {
+                            added.insert(k.clone(), v.clone());
+                        }
+                    }
+                }
+            }
+            if !matched {
+                expanded.push((entry.clone(), HashMap::new()));
+            }
+        }
+
+        expanded
+            .into_iter()
+            .map(|(mut combination, added)| {
+                combination.extend(added);
+                combination
+            })
+            .collect()
+    }
+}
+
+/// Turn a state value into matrix items
+///
+/// Array items that are objects are used as-is and other items are exposed as
+/// `value`. Each entry of an object becomes an item with the entry's name as
+/// `key`, merged with the entry's fields if it is an object or exposed as
+/// `value` otherwise.
+fn matrix_items(state_value: &Value) -> Vec<HashMap<String, Value>> {
+    fn item(value: &Value) -> HashMap<String, Value> {
+        match value {
+            Value::Object(fields) => fields.clone().into_iter().collect(),
+            value => HashMap::from([("value".to_string(), value.clone())]),
+        }
+    }
+
+    match state_value {
+        Value::Array(items) => items.iter().map(item).collect(),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| {
+                let mut item = item(value);
+                item.insert("key".to_string(), Value::String(key.clone()));
+                item
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn strategy(yaml: &str) -> Strategy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn combinations(strategy: &Strategy, state_value: Option<&Value>) -> Vec<Value> {
+        let mut combinations: Vec<Value> = strategy
+            .expand(state_value)
+            .into_iter()
+            .map(|c| serde_json::to_value(c).unwrap())
+            .collect();
+        combinations.sort_by_key(|c| c.to_string());
+        combinations
+    }
+
+    #[test]
+    fn test_expand_axes_with_include_and_exclude() {
+        let strategy = strategy(
+            r#"
+type: matrix
+axes:
+  package: [web, api]
+  react: [17, 18]
+exclude:
+  - package: api
+    react: 17
+include:
+  - react: 18
+    experimental: true
+  - package: docs
+    react: 18
+"#,
+        );
+
+        assert_eq!(
+            combinations(&strategy, None),
+            vec![
+                json!({ "package": "api", "react": 18, "experimental": true }),
+                json!({ "package": "web", "react": 18, "experimental": true }),
+                json!({ "package": "docs", "react": 18 }),
+                json!({ "package": "web", "react": 17 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_from_state() {
+        let strategy = strategy(
+            r#"
+type: matrix
+from_state: packages
+axes:
+  react: [18]
+"#,
+        );
+
+        // Object-valued state keys yield one item per entry
+        let state = json!({ "web": { "path": "apps/web" }, "api": "apps/api" });
+        assert_eq!(
+            combinations(&strategy, Some(&state)),
+            vec![
+                json!({ "key": "api", "value": "apps/api", "react": 18 }),
+                json!({ "key": "web", "path": "apps/web", "react": 18 }),
+            ]
+        );
+
+        let state = json!([{ "path": "apps/web", "shard": 1 }]);
+        assert_eq!(
+            combinations(&strategy, Some(&state)),
+            vec![json!({ "path": "apps/web", "shard": 1, "react": 18 })]
+        );
+        assert!(strategy.expand(None).is_empty());
+    }
 }
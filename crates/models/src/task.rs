@@ -27,6 +27,19 @@ pub enum TaskStatus {
 
     /// Task will not be executed
     WontDo,
+
+    /// Task was not executed because a dependency failed
+    Skipped,
+}
+
+impl TaskStatus {
+    /// Whether the task has finished and will not change status anymore
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Completed | TaskStatus::Failed | TaskStatus::WontDo | TaskStatus::Skipped
+        )
+    }
 }
 
 /// Represents a task (runtime instance of a node)
@@ -72,11 +85,67 @@ pub struct Task {
     #[ts(optional=nullable)]
     pub error: Option<String>,
 
-    /// Logs from the task
+    /// Whether a manual trigger released the task, so it runs once its
+    /// dependencies and the concurrency limits allow
+    #[serde(default)]
+    pub triggered: bool,
+
+    /// Number of attempts made to run the task
+    #[serde(default)]
+    pub attempts: u32,
+
+    /// Number of attempts made to run each step in the task's last attempt,
+    /// keyed by step id
+    #[serde(default)]
+    pub step_attempts: HashMap<String, u32>,
+
+    /// Outputs produced by the task's steps, keyed by step id and output name
+    #[serde(default)]
+    pub outputs: HashMap<String, HashMap<String, String>>,
+
+    /// Standard output of each step, as recorded by earlier versions
+    ///
+    /// Output is now only streamed line by line, with stderr, as [`LogLine`]s
+    /// stored by the state adapter.
     #[serde(default)]
     pub logs: Vec<String>,
 }
 
+/// Stream a line of command output was written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    /// Standard output
+    Stdout,
+
+    /// Standard error
+    Stderr,
+}
+
+/// A line of output written by a task's command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS)]
+pub struct LogLine {
+    /// When the line was read
+    pub timestamp: DateTime<Utc>,
+
+    /// Stream the line was written to
+    pub stream: LogStream,
+
+    /// The line, without its trailing newline
+    pub line: String,
+}
+
+impl LogLine {
+    /// Create a log line read just now
+    pub fn new(stream: LogStream, line: String) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            stream,
+            line,
+        }
+    }
+}
+
 impl Task {
     /// Create a new task
     pub fn new(workflow_run_id: Uuid, node_id: String, is_master: bool) -> Self {
@@ -91,6 +160,10 @@ impl Task {
             started_at: None,
             ended_at: None,
             error: None,
+            triggered: false,
+            attempts: 0,
+            step_attempts: HashMap::new(),
+            outputs: HashMap::new(),
             logs: Vec::new(),
         }
     }
@@ -112,6 +185,10 @@ impl Task {
             started_at: None,
             ended_at: None,
             error: None,
+            triggered: false,
+            attempts: 0,
+            step_attempts: HashMap::new(),
+            outputs: HashMap::new(),
             logs: Vec::new(),
             is_master: false,
         }
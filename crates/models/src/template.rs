@@ -1,10 +1,13 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use ts_rs::TS;
 
+use crate::error::Error;
 use crate::runtime::Runtime;
 use crate::step::Step;
+use crate::Result;
 
 /// Represents a template input
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
@@ -30,6 +33,50 @@ pub struct TemplateInput {
     pub default: Option<String>,
 }
 
+impl TemplateInput {
+    /// Check that the declared type is supported and that the default matches it
+    ///
+    /// Defaults with `${{...}}` expressions are checked once resolved.
+    pub fn validate(&self) -> Result<()> {
+        if !matches!(self.r#type.as_str(), "string" | "number" | "boolean") {
+            return Err(Error::Template(format!(
+                "Input {} has unsupported type {}, expected string, number or boolean",
+                self.name, self.r#type
+            )));
+        }
+
+        match &self.default {
+            Some(default) if !default.contains("${{") => self.validate_value(default),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that a value matches the declared type of the input
+    pub fn validate_value(&self, value: &str) -> Result<()> {
+        self.parse_value(value).map(|_| ())
+    }
+
+    /// Convert a value to the declared type of the input
+    pub fn parse_value(&self, value: &str) -> Result<Value> {
+        let parsed = match self.r#type.as_str() {
+            "number" => value.trim().parse().ok().map(Value::Number),
+            "boolean" => match value.trim() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => Some(Value::String(value.to_string())),
+        };
+
+        parsed.ok_or_else(|| {
+            Error::Template(format!(
+                "Input {} expects a {}, got {value:?}",
+                self.name, self.r#type
+            ))
+        })
+    }
+}
+
 /// Represents a template output
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, TS)]
 pub struct TemplateOutput {
@@ -81,6 +128,82 @@ pub struct Template {
     pub env: HashMap<String, String>,
 }
 
+impl Template {
+    /// Check the inputs passed to the template without resolving them
+    ///
+    /// Values that contain `${{...}}` expressions are only type checked by
+    /// [`Template::resolve_inputs`], once resolved.
+    pub fn check_inputs(&self, provided: &HashMap<String, String>) -> Result<()> {
+        for (input, value) in self.select_inputs(provided)? {
+            if !value.contains("${{") {
+                input.validate_value(value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the inputs passed to the template, filling in defaults
+    ///
+    /// Provided values and defaults both go through `resolve` and are then
+    /// converted to the declared type, so `inputs.<name>` of a boolean input
+    /// compares to `true` rather than `'true'`.
+    pub fn resolve_inputs(
+        &self,
+        provided: &HashMap<String, String>,
+        mut resolve: impl FnMut(&str) -> Result<String>,
+    ) -> Result<HashMap<String, Value>> {
+        let mut inputs = HashMap::new();
+        for (input, value) in self.select_inputs(provided)? {
+            let value = input.parse_value(&resolve(value)?)?;
+            inputs.insert(input.name.clone(), value);
+        }
+
+        Ok(inputs)
+    }
+
+    /// Pair each input with its provided value or its default
+    fn select_inputs<'a>(
+        &'a self,
+        provided: &'a HashMap<String, String>,
+    ) -> Result<Vec<(&'a TemplateInput, &'a String)>> {
+        let mut unknown: Vec<&String> = provided
+            .keys()
+            .filter(|name| !self.inputs.iter().any(|input| &input.name == *name))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(Error::Template(format!(
+                "Unknown inputs for template {}: {}",
+                self.id,
+                unknown
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+
+        let mut inputs = Vec::new();
+        for input in &self.inputs {
+            let value = match (provided.get(&input.name), &input.default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => default,
+                (None, None) if input.required => {
+                    return Err(Error::Template(format!(
+                        "Missing required input {} for template {}",
+                        input.name, self.id
+                    )));
+                }
+                (None, None) => continue,
+            };
+            inputs.push((input, value));
+        }
+
+        Ok(inputs)
+    }
+}
+
 fn default_input_type() -> String {
     "string".to_string()
 }
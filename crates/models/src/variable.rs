@@ -1,45 +1,209 @@
-use regex::Regex;
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 
 use crate::error::Error;
+use crate::expression;
 use crate::Result;
 
-/// Resolve variables in a string
-pub fn resolve_variables(
-    input: &str,
-    params: &HashMap<String, String>,
-    state: &HashMap<String, Value>,
-    matrix_values: Option<&HashMap<String, Value>>,
-) -> Result<String> {
-    let re = Regex::new(r"\$\{\{([^}]+)\}\}").unwrap();
-    let mut result = input.to_string();
-
-    for captures in re.captures_iter(input) {
-        let full_match = captures.get(0).unwrap().as_str();
-        let inner = captures.get(1).unwrap().as_str();
-
-        let replacement =
-            // First check if it's a direct matrix value
-            if matrix_values.is_some_and(|matrix_values| matrix_values.contains_key(inner)) {
-                serde_json::to_string(matrix_values.unwrap().get(inner).unwrap()).unwrap()
-            } else if let Some(name) = inner.strip_prefix("params.") {
-                params.get(name).cloned().ok_or_else(|| {
-                    Error::VariableResolution(format!("Parameter not found: {name}"))
-                })?
-            } else if let Some(name) = inner.strip_prefix("state.") {
-                let value = state.get(name).ok_or_else(|| {
-                    Error::VariableResolution(format!("State value not found: {name}"))
-                })?;
-                serde_json::to_string(value).unwrap()
-            } else {
-                return Err(Error::VariableResolution(format!(
-                    "Unknown variable type: {inner}"
-                )));
-            };
-
-        result = result.replace(full_match, &replacement);
+/// Values that `${{ }}` expressions can refer to
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VariableContext<'a> {
+    /// Workflow parameters, available as `params.<name>`
+    pub params: Option<&'a HashMap<String, String>>,
+
+    /// Shared state, available as `state.<key>`
+    pub state: Option<&'a HashMap<String, Value>>,
+
+    /// Matrix values of the task, available as `matrix.<key>` and as bare `<key>`
+    pub matrix_values: Option<&'a HashMap<String, Value>>,
+
+    /// Step outputs, available as `steps.<step>.outputs.<name>`
+    pub steps: Option<&'a HashMap<String, HashMap<String, String>>>,
+
+    /// Template inputs, available as `inputs.<name>` with their declared type
+    pub inputs: Option<&'a HashMap<String, Value>>,
+
+    /// Results of the node's dependencies, available as `needs.<node>.status` and
+    /// `needs.<node>.outputs.<step>.<name>`
+    pub needs: Option<&'a HashMap<String, Value>>,
+}
+
+impl VariableContext<'_> {
+    /// Build the root object expressions are evaluated against
+    pub fn to_value(&self) -> Value {
+        fn strings(map: Option<&HashMap<String, String>>) -> Value {
+            map.into_iter()
+                .flatten()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect::<Map<_, _>>()
+                .into()
+        }
+
+        let matrix: Map<String, Value> = self
+            .matrix_values
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        // Matrix values are also reachable without a prefix, unless they shadow a scope
+        let mut root = matrix.clone();
+        root.insert("matrix".to_string(), matrix.into());
+        root.insert("params".to_string(), strings(self.params));
+        root.insert(
+            "inputs".to_string(),
+            self.inputs
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Map<_, _>>()
+                .into(),
+        );
+        root.insert(
+            "state".to_string(),
+            self.state
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Map<_, _>>()
+                .into(),
+        );
+        root.insert(
+            "steps".to_string(),
+            self.steps
+                .into_iter()
+                .flatten()
+                .map(|(step, outputs)| (step.clone(), json!({ "outputs": outputs })))
+                .collect::<Map<_, _>>()
+                .into(),
+        );
+
+        root.insert(
+            "needs".to_string(),
+            self.needs
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Map<_, _>>()
+                .into(),
+        );
+
+        root.into()
+    }
+}
+
+/// Resolve `${{ }}` expressions in a string
+///
+/// Strings are substituted as-is, `null` as an empty string and any other
+/// value as JSON. A path that doesn't resolve, such as a parameter that
+/// wasn't passed, is an error unless it has a `||` fallback.
+pub fn resolve_variables(input: &str, context: &VariableContext) -> Result<String> {
+    if !input.contains("${{") {
+        return Ok(input.to_string());
+    }
+
+    expression::interpolate(input, &context.to_value()).map_err(|e| expression_error(input, e))
+}
+
+/// Evaluate a single expression, with or without the surrounding `${{ }}`
+pub fn evaluate_expression(input: &str, context: &VariableContext) -> Result<Value> {
+    let inner = strip_delimiters(input);
+    expression::evaluate(inner, &context.to_value()).map_err(|e| expression_error(inner, e))
+}
+
+/// Evaluate a condition such as an `if:` field
+///
+/// Parameters are strings, so a condition that evaluates to `'false'`, such
+/// as `params.dry_run` passed as `--param dry_run=false`, is false.
+pub fn evaluate_condition(input: &str, context: &VariableContext) -> Result<bool> {
+    evaluate_expression(input, context).map(|value| match value {
+        Value::String(s) if s == "false" => false,
+        value => expression::is_truthy(&value),
+    })
+}
+
+/// Check that an expression, with or without the surrounding `${{ }}`, parses
+pub fn check_expression(input: &str) -> Result<()> {
+    let inner = strip_delimiters(input);
+    expression::check_syntax(inner).map_err(|e| expression_error(inner, e))
+}
+
+/// Whether a condition checks the results of dependencies with `success()`,
+/// `failure()` or `always()`
+///
+/// Nodes without such a condition are skipped when a dependency fails.
+pub fn uses_status_functions(input: &str) -> bool {
+    expression::uses_status_functions(strip_delimiters(input)).unwrap_or(false)
+}
+
+fn strip_delimiters(input: &str) -> &str {
+    let trimmed = input.trim();
+    trimmed
+        .strip_prefix("${{")
+        .and_then(|rest| rest.strip_suffix("}}"))
+        .unwrap_or(trimmed)
+}
+
+fn expression_error(input: &str, error: expression::ExpressionError) -> Error {
+    Error::VariableResolution(format!("{error} in `{input}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{Template, TemplateInput};
+
+    #[test]
+    fn test_condition_on_boolean_params() {
+        let params = HashMap::from([("dry_run".to_string(), "false".to_string())]);
+        let context = VariableContext {
+            params: Some(&params),
+            ..Default::default()
+        };
+
+        assert!(!evaluate_condition("${{ params.dry_run }}", &context).unwrap());
+        assert!(evaluate_condition("params.dry_run == false", &context).unwrap());
+        assert!(!evaluate_condition("params.dry_run == true", &context).unwrap());
     }
 
-    Ok(result)
+    #[test]
+    fn test_typed_template_inputs() {
+        let input = |name: &str, r#type: &str| TemplateInput {
+            name: name.to_string(),
+            r#type: r#type.to_string(),
+            required: true,
+            description: None,
+            default: None,
+        };
+        let template = Template {
+            id: "template".to_string(),
+            name: "Template".to_string(),
+            description: None,
+            runtime: None,
+            inputs: vec![input("flag", "boolean"), input("depth", "number")],
+            steps: vec![],
+            outputs: vec![],
+            env: HashMap::new(),
+        };
+        let provided = HashMap::from([
+            ("flag".to_string(), "false".to_string()),
+            ("depth".to_string(), "10".to_string()),
+        ]);
+        let inputs = template
+            .resolve_inputs(&provided, |value| Ok(value.to_string()))
+            .unwrap();
+        assert_eq!(inputs["flag"], json!(false));
+        assert_eq!(inputs["depth"], json!(10));
+
+        let context = VariableContext {
+            inputs: Some(&inputs),
+            ..Default::default()
+        };
+        assert!(!evaluate_condition("inputs.flag", &context).unwrap());
+        assert!(evaluate_condition("inputs.depth > 9", &context).unwrap());
+        assert_eq!(
+            resolve_variables("--depth=${{inputs.depth}}", &context).unwrap(),
+            "--depth=10"
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use uuid::Uuid;
@@ -8,6 +9,7 @@ use uuid::Uuid;
 use crate::node::Node;
 use crate::state::StateSchema;
 use crate::template::Template;
+use crate::{Error, Result};
 use ts_rs::TS;
 
 /// Represents a workflow definition
@@ -29,6 +31,11 @@ pub struct Workflow {
 
     /// Nodes in the workflow
     pub nodes: Vec<Node>,
+
+    /// Maximum number of tasks running at the same time (unbounded by default)
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub concurrency: Option<usize>,
 }
 
 /// Represents the state schema for a workflow
@@ -37,6 +44,34 @@ pub struct WorkflowState {
     /// Schema definitions
     #[serde(default)]
     pub schema: Vec<StateSchema>,
+
+    /// Reject writes to state keys the schema doesn't declare instead of
+    /// only warning about them
+    #[serde(default)]
+    #[ts(optional, as = "Option<bool>")]
+    pub strict: bool,
+}
+
+impl WorkflowState {
+    /// Find the schema declared for a state key
+    pub fn get(&self, key: &str) -> Option<&StateSchema> {
+        self.schema.iter().find(|schema| schema.name == key)
+    }
+
+    /// Check a value written to a state key against the schema
+    ///
+    /// Undeclared keys are an error in strict mode. Otherwise they are
+    /// accepted, returning `false` so the caller can warn about them unless
+    /// the schema is empty.
+    pub fn validate_value(&self, key: &str, value: &Value) -> Result<bool> {
+        match self.get(key) {
+            Some(schema) => schema.validate_value(value).map(|()| true),
+            None if self.strict => Err(Error::State(format!(
+                "State {key} is not declared in the workflow state schema"
+            ))),
+            None => Ok(self.schema.is_empty()),
+        }
+    }
 }
 
 /// Represents a workflow run
@@ -68,6 +103,17 @@ pub struct WorkflowRun {
     /// The absolute path to the root directory of the workflow bundle
     #[ts(optional=nullable)]
     pub bundle_path: Option<PathBuf>,
+
+    /// Where the workflow comes from: the package name in the bundle's
+    /// `codemod.yaml`, or else the path of the workflow file
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub source: Option<String>,
+
+    /// Directory the workflow run transforms
+    #[serde(default)]
+    #[ts(optional=nullable)]
+    pub target_path: Option<PathBuf>,
 }
 
 /// Status of a workflow run
@@ -91,3 +137,20 @@ pub enum WorkflowStatus {
     /// Workflow has been canceled
     Canceled,
 }
+
+impl std::str::FromStr for WorkflowStatus {
+    type Err = String;
+
+    /// Parse a status ignoring case and underscores, e.g. `awaiting_trigger`
+    fn from_str(input: &str) -> std::result::Result<Self, String> {
+        match input.replace(['_', '-'], "").to_lowercase().as_str() {
+            "pending" => Ok(Self::Pending),
+            "running" => Ok(Self::Running),
+            "completed" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            "awaitingtrigger" => Ok(Self::AwaitingTrigger),
+            "canceled" | "cancelled" => Ok(Self::Canceled),
+            _ => Err(format!("unknown workflow status `{input}`")),
+        }
+    }
+}
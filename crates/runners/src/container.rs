@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use butterflow_models::Runtime;
+
+/// Image used when a container runtime does not declare one
+pub const DEFAULT_IMAGE: &str = "alpine:latest";
+
+/// Mount point of the target repository inside the container
+pub const WORKSPACE_DIR: &str = "/workspace";
+
+/// Mount point of the workflow bundle inside the container
+pub const CODEMOD_DIR: &str = "/codemod";
+
+/// Path of the state outputs file inside the container
+const STATE_OUTPUTS_PATH: &str = "/tmp/codemod/state_outputs";
+
+/// Environment variable pointing to the state outputs file
+const STATE_OUTPUTS_ENV: &str = "STATE_OUTPUTS";
+
+/// Bind mount from the host into a container
+#[derive(Debug, Clone)]
+pub struct Mount {
+    /// Absolute path on the host
+    pub host_path: PathBuf,
+
+    /// Absolute path inside the container
+    pub container_path: String,
+
+    /// Whether the mount is read-only
+    pub read_only: bool,
+}
+
+/// Container configuration shared by the Docker and Podman runners
+#[derive(Debug, Clone)]
+pub struct ContainerConfig {
+    /// Container image
+    pub image: String,
+
+    /// Working directory inside the container
+    pub working_dir: Option<String>,
+
+    /// User to run as inside the container
+    pub user: Option<String>,
+
+    /// Network mode for the container
+    pub network: Option<String>,
+
+    /// Additional options passed verbatim to `run`
+    pub options: Vec<String>,
+
+    /// Bind mounts
+    pub mounts: Vec<Mount>,
+}
+
+impl ContainerConfig {
+    /// Create a container configuration from a node's runtime
+    pub fn from_runtime(runtime: &Runtime) -> Self {
+        let image = runtime.image.clone().unwrap_or_else(|| {
+            warn!(
+                "No image declared for {} runtime, falling back to {DEFAULT_IMAGE}",
+                runtime.r#type
+            );
+            DEFAULT_IMAGE.to_string()
+        });
+
+        Self {
+            image,
+            working_dir: runtime.working_dir.clone(),
+            user: runtime.user.clone(),
+            network: runtime.network.clone(),
+            options: runtime.options.clone().unwrap_or_default(),
+            mounts: Vec::new(),
+        }
+    }
+
+    /// Add a bind mount
+    pub fn with_mount<P: AsRef<Path>>(
+        mut self,
+        host_path: P,
+        container_path: &str,
+        read_only: bool,
+    ) -> Self {
+        // Bind mounts require absolute host paths
+        let host_path = host_path
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| host_path.as_ref().to_path_buf());
+
+        self.mounts.push(Mount {
+            host_path,
+            container_path: container_path.to_string(),
+            read_only,
+        });
+        self
+    }
+
+    /// Mount the target repository at [`WORKSPACE_DIR`] and the workflow bundle at [`CODEMOD_DIR`]
+    pub fn with_workspace<P: AsRef<Path>>(self, target_path: P, bundle_path: Option<P>) -> Self {
+        let config = self.with_mount(target_path, WORKSPACE_DIR, false);
+        match bundle_path {
+            Some(bundle_path) => config.with_mount(bundle_path, CODEMOD_DIR, true),
+            None => config,
+        }
+    }
+
+    /// Translate a host path into its location inside the container, if it is mounted
+    fn translate_path(&self, value: &str) -> Option<String> {
+        let path = Path::new(value);
+        self.mounts.iter().find_map(|mount| {
+            path.strip_prefix(&mount.host_path).ok().map(|relative| {
+                if relative.as_os_str().is_empty() {
+                    mount.container_path.clone()
+                } else {
+                    format!(
+                        "{}/{}",
+                        mount.container_path.trim_end_matches('/'),
+                        relative.to_string_lossy()
+                    )
+                }
+            })
+        })
+    }
+
+    /// Build the arguments for `docker run` / `podman run`
+    pub(crate) fn run_args(
+        &self,
+        container_name: &str,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Vec<String> {
+        let mut args = vec![
+            "run".to_string(),
+            "--rm".to_string(),
+            "--name".to_string(),
+            container_name.to_string(),
+        ];
+
+        for mount in &self.mounts {
+            let mut volume = format!(
+                "--volume={}:{}",
+                mount.host_path.display(),
+                mount.container_path
+            );
+            if mount.read_only {
+                volume.push_str(":ro");
+            }
+            args.push(volume);
+        }
+
+        // The state outputs file lives in the host's temp dir, so it gets its own mount
+        if let Some(state_outputs) = env.get(STATE_OUTPUTS_ENV) {
+            args.push(format!("--volume={state_outputs}:{STATE_OUTPUTS_PATH}"));
+        }
+
+        let working_dir = self.working_dir.clone().or_else(|| {
+            self.mounts
+                .iter()
+                .any(|m| m.container_path == WORKSPACE_DIR)
+                .then(|| WORKSPACE_DIR.to_string())
+        });
+        if let Some(working_dir) = working_dir {
+            args.push(format!("--workdir={working_dir}"));
+        }
+        if let Some(user) = &self.user {
+            args.push(format!("--user={user}"));
+        }
+        if let Some(network) = &self.network {
+            args.push(format!("--network={network}"));
+        }
+
+        let mut env: Vec<(&String, &String)> = env.iter().collect();
+        env.sort();
+        for (key, value) in env {
+            let value = if key == STATE_OUTPUTS_ENV {
+                STATE_OUTPUTS_PATH.to_string()
+            } else {
+                self.translate_path(value).unwrap_or_else(|| value.clone())
+            };
+            args.push(format!("--env={key}={value}"));
+        }
+
+        args.extend(self.options.iter().cloned());
+
+        args.push(self.image.clone());
+        args.push("sh".to_string());
+        args.push("-c".to_string());
+        args.push(command.to_string());
+
+        args
+    }
+}
+
+/// Kills a container that is still running when dropped
+///
+/// Dropping the `run` client alone leaves the container running, so runners keep
+/// a guard alive while waiting and disarm it once the container exited.
+pub(crate) struct ContainerGuard {
+    program: &'static str,
+    container_name: String,
+    armed: bool,
+}
+
+impl ContainerGuard {
+    pub(crate) fn new(program: &'static str, container_name: &str) -> Self {
+        Self {
+            program,
+            container_name: container_name.to_string(),
+            armed: true,
+        }
+    }
+
+    /// The container exited on its own
+    pub(crate) fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for ContainerGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        warn!("Killing container {}", self.container_name);
+        let mut cmd = std::process::Command::new(self.program);
+        cmd.args(["kill", &self.container_name])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        // Don't block the runtime while the container shuts down
+        std::thread::spawn(move || cmd.status());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use butterflow_models::RuntimeType;
+
+    fn runtime() -> Runtime {
+        Runtime {
+            r#type: RuntimeType::Docker,
+            image: Some("node:20".to_string()),
+            working_dir: None,
+            user: Some("1000:1000".to_string()),
+            network: Some("none".to_string()),
+            options: Some(vec!["--cpus=2".to_string()]),
+        }
+    }
+
+    #[test]
+    fn test_run_args_use_declared_runtime() {
+        let config = ContainerConfig::from_runtime(&runtime())
+            .with_workspace(Path::new("/repo"), Some(Path::new("/bundle")));
+
+        let mut env = HashMap::new();
+        env.insert("CODEMOD_PATH".to_string(), "/bundle".to_string());
+        env.insert("SCRIPT".to_string(), "/bundle/scripts/run.js".to_string());
+        env.insert("STATE_OUTPUTS".to_string(), "/tmp/abc".to_string());
+
+        let args = config.run_args("butterflow-test", "npm test", &env);
+
+        assert!(args.contains(&"--volume=/repo:/workspace".to_string()));
+        assert!(args.contains(&"--volume=/bundle:/codemod:ro".to_string()));
+        assert!(args.contains(&"--volume=/tmp/abc:/tmp/codemod/state_outputs".to_string()));
+        assert!(args.contains(&"--workdir=/workspace".to_string()));
+        assert!(args.contains(&"--user=1000:1000".to_string()));
+        assert!(args.contains(&"--network=none".to_string()));
+        assert!(args.contains(&"--cpus=2".to_string()));
+        assert!(args.contains(&"--env=CODEMOD_PATH=/codemod".to_string()));
+        assert!(args.contains(&"--env=SCRIPT=/codemod/scripts/run.js".to_string()));
+        assert!(args.contains(&"--env=STATE_OUTPUTS=/tmp/codemod/state_outputs".to_string()));
+        assert_eq!(
+            args[args.len() - 4..],
+            ["node:20", "sh", "-c", "npm test"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn test_run_args_defaults() {
+        let mut runtime = runtime();
+        runtime.image = None;
+        runtime.working_dir = Some("/src".to_string());
+
+        let config = ContainerConfig::from_runtime(&runtime).with_workspace("/repo", None);
+        let args = config.run_args("butterflow-test", "ls", &HashMap::new());
+
+        assert!(args.contains(&DEFAULT_IMAGE.to_string()));
+        assert!(args.contains(&"--workdir=/src".to_string()));
+        assert!(!args.iter().any(|arg| arg.contains(CODEMOD_DIR)));
+    }
+}
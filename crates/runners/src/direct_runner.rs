@@ -1,12 +1,13 @@
 use std::collections::HashMap;
-use std::process::Command;
+use std::path::PathBuf;
 
 use async_trait::async_trait;
+use tokio::process::Command;
 
 use butterflow_models::Error;
 use butterflow_models::Result;
 
-use crate::Runner;
+use crate::{run_streaming, LogSender, Runner};
 
 /// Direct runner (runs commands directly on the host)
 pub struct DirectRunner;
@@ -18,6 +19,33 @@ impl DirectRunner {
     }
 }
 
+/// Temporary script file, removed once the command finished or was cancelled
+struct TempScript(PathBuf);
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Run a command to completion, failing if it exits with an error
+async fn execute(cmd: Command, output: &LogSender) -> Result<String> {
+    let result = run_streaming(cmd, output)
+        .await
+        .map_err(|e| Error::Runtime(format!("Failed to execute command: {e}")))?;
+
+    // Check if the command succeeded
+    if !result.status.success() {
+        return Err(Error::Runtime(format!(
+            "Command failed with exit code {}: {}",
+            result.status.code().unwrap_or(-1),
+            result.stderr
+        )));
+    }
+
+    Ok(result.stdout)
+}
+
 impl Default for DirectRunner {
     fn default() -> Self {
         Self::new()
@@ -26,16 +54,22 @@ impl Default for DirectRunner {
 
 #[async_trait]
 impl Runner for DirectRunner {
-    async fn run_command(&self, command: &str, env: &HashMap<String, String>) -> Result<String> {
+    async fn run_command(
+        &self,
+        command: &str,
+        env: &HashMap<String, String>,
+        output: &LogSender,
+    ) -> Result<String> {
         // Check if the command starts with a shebang line
         if command.starts_with("#!/") {
             // Create a temporary file for the script
             let temp_dir = std::env::temp_dir();
             let file_name = format!("butterflow-script-{}.sh", uuid::Uuid::new_v4());
-            let script_path = temp_dir.join(file_name);
+            let script = TempScript(temp_dir.join(file_name));
+            let script_path = &script.0;
 
             // Write the script to the temporary file
-            std::fs::write(&script_path, command).map_err(|e| {
+            std::fs::write(script_path, command).map_err(|e| {
                 Error::Runtime(format!("Failed to write script to temporary file: {e}"))
             })?;
 
@@ -43,43 +77,24 @@ impl Runner for DirectRunner {
             #[cfg(unix)]
             {
                 use std::os::unix::fs::PermissionsExt;
-                let mut perms = std::fs::metadata(&script_path)
+                let mut perms = std::fs::metadata(script_path)
                     .map_err(|e| Error::Runtime(format!("Failed to get file permissions: {e}")))?
                     .permissions();
                 perms.set_mode(0o755);
-                std::fs::set_permissions(&script_path, perms)
+                std::fs::set_permissions(script_path, perms)
                     .map_err(|e| Error::Runtime(format!("Failed to set file permissions: {e}")))?;
             }
 
             // Create the command
-            let mut cmd = Command::new(&script_path);
+            let mut cmd = Command::new(script_path);
+            cmd.kill_on_drop(true);
 
             // Add environment variables
             for (key, value) in env {
                 cmd.env(key, value);
             }
 
-            // Execute the command
-            let output = cmd
-                .output()
-                .map_err(|e| Error::Runtime(format!("Failed to execute command: {e}")))?;
-
-            // Clean up the temporary file
-            std::fs::remove_file(&script_path).ok();
-
-            // Check if the command succeeded
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                return Err(Error::Runtime(format!(
-                    "Command failed with exit code {}: {}",
-                    output.status.code().unwrap_or(-1),
-                    stderr
-                )));
-            }
-
-            // Return the output
-            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-            Ok(stdout)
+            execute(cmd, output).await
         } else {
             // Determine the shell to use
             let shell = if cfg!(target_os = "windows") {
@@ -96,31 +111,75 @@ impl Runner for DirectRunner {
 
             // Create the command
             let mut cmd = Command::new(shell);
-            cmd.arg(shell_arg).arg(command);
+            cmd.arg(shell_arg).arg(command).kill_on_drop(true);
 
             // Add environment variables
             for (key, value) in env {
                 cmd.env(key, value);
             }
 
-            // Execute the command
-            let output = cmd
-                .output()
-                .map_err(|e| Error::Runtime(format!("Failed to execute command: {e}")))?;
-
-            // Check if the command succeeded
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                return Err(Error::Runtime(format!(
-                    "Command failed with exit code {}: {}",
-                    output.status.code().unwrap_or(-1),
-                    stderr
-                )));
-            }
+            execute(cmd, output).await
+        }
+    }
+}
 
-            // Return the output
-            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-            Ok(stdout)
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use butterflow_models::LogStream;
+
+    #[tokio::test]
+    async fn test_streams_stdout_and_stderr() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let stdout = DirectRunner::new()
+            .run_command(
+                "echo one; echo two >&2; echo three",
+                &HashMap::new(),
+                &sender,
+            )
+            .await
+            .unwrap();
+        drop(sender);
+
+        assert_eq!(stdout, "one\nthree\n");
+        let mut lines = Vec::new();
+        while let Some(line) = receiver.recv().await {
+            lines.push((line.stream, line.line));
         }
+        lines.sort_by_key(|(stream, _)| *stream == LogStream::Stderr);
+        assert_eq!(
+            lines,
+            vec![
+                (LogStream::Stdout, "one".to_string()),
+                (LogStream::Stdout, "three".to_string()),
+                (LogStream::Stderr, "two".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_background_processes() {
+        let temp_dir = std::env::temp_dir().join(format!("butterflow-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        let marker = temp_dir.join("marker");
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let command = format!(
+            "(sleep 1; touch {}) >/dev/null 2>&1 & sleep 5",
+            marker.display()
+        );
+        let result = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            DirectRunner::new().run_command(&command, &HashMap::new(), &sender),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The backgrounded subshell would have created the marker by now
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        let created = marker.exists();
+        std::fs::remove_dir_all(&temp_dir).ok();
+        assert!(!created);
     }
 }
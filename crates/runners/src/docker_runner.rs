@@ -1,70 +1,65 @@
 use std::collections::HashMap;
-use std::process::Command;
 
 use async_trait::async_trait;
+use tokio::process::Command;
 
 use butterflow_models::Error;
 use butterflow_models::Result;
 
-use crate::Runner;
+use crate::container::{ContainerConfig, ContainerGuard};
+use crate::{run_streaming, LogSender, Runner};
 
 /// Docker runner (runs commands in Docker containers)
-pub struct DockerRunner;
+pub struct DockerRunner {
+    config: ContainerConfig,
+}
 
 impl DockerRunner {
     /// Create a new Docker runner
-    pub fn new() -> Self {
-        Self
-    }
-}
-
-impl Default for DockerRunner {
-    fn default() -> Self {
-        Self::new()
+    pub fn new(config: ContainerConfig) -> Self {
+        Self { config }
     }
 }
 
 #[async_trait]
 impl Runner for DockerRunner {
-    async fn run_command(&self, command: &str, env: &HashMap<String, String>) -> Result<String> {
-        // Create environment variables array
-        let env_args: Vec<String> = env
-            .iter()
-            .map(|(key, value)| format!("--env={key}={value}"))
-            .collect();
-
+    async fn run_command(
+        &self,
+        command: &str,
+        env: &HashMap<String, String>,
+        output: &LogSender,
+    ) -> Result<String> {
         // Create a unique container name
         let container_name = format!("butterflow-{}", uuid::Uuid::new_v4());
 
         // Create the docker command
         let mut cmd = Command::new("docker");
-        cmd.arg("run")
-            .arg("--rm")
-            .arg("--name")
-            .arg(&container_name)
-            .args(env_args)
-            .arg("alpine:latest")
-            .arg("sh")
-            .arg("-c")
-            .arg(command);
+        cmd.args(self.config.run_args(&container_name, command, env))
+            .kill_on_drop(true);
+
+        // Stops the container if the step is cancelled, e.g. by a timeout
+        let guard = ContainerGuard::new("docker", &container_name);
 
         // Execute the command
-        let output = cmd
-            .output()
+        let result = run_streaming(cmd, output)
+            .await
             .map_err(|e| Error::Docker(format!("Failed to execute docker command: {e}")))?;
 
+        guard.disarm();
+
         // Check if the command succeeded
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
+        if !result.status.success() {
             return Err(Error::Docker(format!(
                 "Docker command failed with exit code {}: {}",
-                output.status.code().unwrap_or(-1),
-                stderr
+                result.status.code().unwrap_or(-1),
+                result.stderr
             )));
         }
 
-        // Return the output
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        Ok(stdout)
+        Ok(result.stdout)
+    }
+
+    fn inherits_host_env(&self) -> bool {
+        false
     }
 }
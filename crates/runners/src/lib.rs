@@ -1,16 +1,135 @@
 use std::collections::HashMap;
+use std::process::{ExitStatus, Stdio};
 
 use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
 
-use butterflow_models::Result;
+use butterflow_models::{LogLine, LogStream, Result};
+
+/// Receives the output of a command line by line while it runs
+pub type LogSender = UnboundedSender<LogLine>;
 
 /// Runner trait for executing commands
 #[async_trait]
 pub trait Runner: Send + Sync {
     /// Run a command
-    async fn run_command(&self, command: &str, env: &HashMap<String, String>) -> Result<String>;
+    ///
+    /// Each line the command writes to stdout or stderr is sent to `output` as
+    /// soon as it is read. Returns the complete stdout once the command exits.
+    ///
+    /// Dropping the returned future must stop the command, which is how step and
+    /// node timeouts are enforced.
+    async fn run_command(
+        &self,
+        command: &str,
+        env: &HashMap<String, String>,
+        output: &LogSender,
+    ) -> Result<String>;
+
+    /// Whether commands see the environment of the parent process
+    fn inherits_host_env(&self) -> bool {
+        true
+    }
+}
+
+/// Output of a command that ran to completion
+pub(crate) struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Kills a process group that is still running when dropped
+///
+/// `kill_on_drop` only reaches the process that was spawned, so commands a
+/// script started in the background would outlive a timeout and overlap with
+/// the retry.
+#[cfg(unix)]
+struct ProcessGroupGuard(Option<libc::pid_t>);
+
+#[cfg(unix)]
+impl ProcessGroupGuard {
+    /// The command exited on its own
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: killpg has no memory safety requirements
+            unsafe {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Spawn a command and stream its stdout and stderr to `output`
+///
+/// On Unix the command runs in its own process group, which is killed as a
+/// whole if the returned future is dropped before the command exited.
+pub(crate) async fn run_streaming(
+    mut cmd: Command,
+    output: &LogSender,
+) -> std::io::Result<CommandOutput> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let mut child = cmd.spawn()?;
+    #[cfg(unix)]
+    let group = ProcessGroupGuard(child.id().map(|id| id as libc::pid_t));
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let (stdout, stderr) = tokio::try_join!(
+        read_lines(stdout, LogStream::Stdout, output),
+        read_lines(stderr, LogStream::Stderr, output),
+    )?;
+    let status = child.wait().await?;
+    #[cfg(unix)]
+    group.disarm();
+
+    Ok(CommandOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Forward every line of a stream, returning everything that was read
+async fn read_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    stream: LogStream,
+    output: &LogSender,
+) -> std::io::Result<String> {
+    let mut reader = BufReader::new(reader);
+    let mut collected = String::new();
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(collected);
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        collected.push_str(&line);
+        // Nobody may be listening, which doesn't affect the command
+        let _ = output.send(LogLine::new(
+            stream,
+            line.trim_end_matches(['\n', '\r']).to_string(),
+        ));
+    }
 }
 
+pub mod container;
 pub mod direct_runner;
 pub mod docker_runner;
 pub mod podman_runner;
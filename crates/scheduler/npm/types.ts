@@ -263,6 +263,11 @@ export type UseJSAstGrep = {
    * Language to process (optional)
    */
   language?: string;
+  /**
+   * Parameters passed to the codemod's transform on top of the workflow
+   * parameters (optional); values can reference variables like `${{ params.x }}`
+   */
+  params?: { [key in string]?: string };
 };
 export type Strategy = {
   /**
@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet};
 
-use log::{debug, warn};
+use log::debug;
 #[cfg(feature = "wasm")]
 use serde::Serialize;
 #[cfg(feature = "wasm")]
@@ -11,7 +11,9 @@ use wasm_bindgen::prelude::*;
 
 use butterflow_models::node::NodeType;
 use butterflow_models::trigger::TriggerType;
-use butterflow_models::{Error, Result, Strategy, StrategyType, Task, TaskStatus, WorkflowRun};
+use butterflow_models::{
+    uses_status_functions, Error, Result, Strategy, StrategyType, Task, TaskStatus, WorkflowRun,
+};
 
 #[cfg(feature = "wasm")]
 #[wasm_bindgen(typescript_custom_section)]
@@ -31,6 +33,7 @@ interface MatrixTaskChanges {
 interface RunnableTaskChanges {
     tasks_to_await_trigger: Uuid[];
     runnable_tasks: Uuid[];
+    tasks_to_skip: Uuid[];
 }
 "#;
 
@@ -47,6 +50,8 @@ pub struct MatrixTaskChanges {
 pub struct RunnableTaskChanges {
     pub tasks_to_await_trigger: Vec<Uuid>,
     pub runnable_tasks: Vec<Uuid>,
+    /// Tasks that won't run because a dependency failed
+    pub tasks_to_skip: Vec<Uuid>,
 }
 
 #[cfg(not(feature = "wasm"))]
@@ -199,25 +204,25 @@ impl Scheduler {
 
         for node in &workflow_run.workflow.nodes {
             // Check if the node has a matrix strategy
-            if let Some(Strategy {
-                r#type: StrategyType::Matrix,
-                values,
-                from_state: _, // Corrected variable name
-            }) = &node.strategy
+            if let Some(
+                strategy @ Strategy {
+                    r#type: StrategyType::Matrix,
+                    ..
+                },
+            ) = &node.strategy
             {
                 // Create a master task for the matrix
                 let master_task = Task::new(workflow_run.id, node.id.clone(), true);
                 tasks.push(master_task.clone());
 
-                // If the matrix uses values, create tasks for each value
-                if let Some(values) = values {
-                    for value in values {
-                        // Create a task for each matrix value
+                // If the matrix is static, create a task for each combination
+                if strategy.from_state.is_none() {
+                    for matrix_values in strategy.expand(None) {
                         let task = Task::new_matrix(
                             workflow_run.id,
                             node.id.clone(),
                             master_task.id,
-                            value.clone(),
+                            matrix_values,
                         );
                         tasks.push(task);
                     }
@@ -246,11 +251,13 @@ impl Scheduler {
         let mut master_tasks_to_update = Vec::new();
 
         for node in &workflow_run.workflow.nodes {
-            if let Some(Strategy {
-                r#type: StrategyType::Matrix,
-                from_state: Some(state_key), // Only process matrix nodes using from_state
-                .. // Use .. to ignore other fields like `values`
-            }) = &node.strategy
+            if let Some(
+                strategy @ Strategy {
+                    r#type: StrategyType::Matrix,
+                    from_state: Some(state_key), // Only process matrix nodes using from_state
+                    ..
+                },
+            ) = &node.strategy
             {
                 debug!(
                     "Calculating changes for matrix node '{}' using state key '{}'",
@@ -275,29 +282,17 @@ impl Scheduler {
                     master_tasks_to_update.push(master_task_id);
                 }
 
-                // Get the current value from the state
-                let state_value = state.get(state_key);
-
                 // --- Calculate Values for Current State Items ---
-                let mut current_item_values = Vec::new();
-
-                match state_value {
-                    Some(serde_json::Value::Array(items)) => {
-                        for item in items {
-                            current_item_values.push(item.clone());
-                        }
-                        debug!("Found {} items in state array '{}'", items.len(), state_key);
-                    }
-                    Some(serde_json::Value::Object(_obj)) => {
-                        // Object mapping not fully supported yet
-                        warn!("Matrix from_state for object key '{state_key}' is not yet fully supported, skipping.");
-                        continue; // Skip this node
-                    }
-                    _ => {
-                        // State key not found or not an array/object
-                        debug!("State key '{}' for matrix node '{}' is missing or not an array/object.", state_key, node.id);
-                    }
-                }
+                let current_combinations = strategy.expand(state.get(state_key));
+                debug!(
+                    "Found {} matrix combinations from state key '{}'",
+                    current_combinations.len(),
+                    state_key
+                );
+                let current_item_values: Vec<serde_json::Value> = current_combinations
+                    .iter()
+                    .map(|combination| serde_json::json!(combination))
+                    .collect();
 
                 // --- Compare with Existing Tasks ---
                 // Store existing tasks keyed by their matrix_values for comparison
@@ -326,24 +321,11 @@ impl Scheduler {
                 let current_item_values_set: HashSet<_> =
                     current_item_values.iter().cloned().collect();
 
-                for item_value in current_item_values {
+                for (item_value, matrix_data) in
+                    current_item_values.into_iter().zip(current_combinations)
+                {
                     if !existing_child_values.contains(&item_value) {
                         // Task for this value doesn't exist, need to create it
-                        let matrix_data = match item_value.as_object() {
-                            Some(obj) => obj
-                                .iter()
-                                .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), serde_json::Value::String(s.to_string()))))
-                                .collect::<HashMap<_, _>>(),
-                            None => {
-                                warn!(
-                                    "Matrix item for node '{}' is not a JSON object, skipping: {:?}",
-                                    node.id,
-                                    item_value
-                                );
-                                continue; // Skip this item
-                            }
-                        };
-
                         let new_task = Task::new_matrix(
                             workflow_run_id,
                             node.id.clone(),
@@ -363,10 +345,7 @@ impl Scheduler {
                     if !current_item_values_set.contains(task_value) {
                         // This task's value is no longer in the current state
                         // Mark as WontDo only if it's not already in a terminal state
-                        if !matches!(
-                            task.status,
-                            TaskStatus::Completed | TaskStatus::Failed | TaskStatus::WontDo
-                        ) {
+                        if !task.status.is_terminal() {
                             debug!(
                                 "Need to mark task {} (value {:?}) for node '{}' as WontDo",
                                 task.id, task_value, node.id
@@ -393,10 +372,15 @@ impl Scheduler {
     ) -> Result<RunnableTaskChanges> {
         let mut runnable_tasks = Vec::new();
         let mut tasks_to_await_trigger = Vec::new();
+        let mut tasks_to_skip = Vec::new();
 
         for task in tasks {
-            // Only consider pending tasks and non-master tasks
-            if task.status != TaskStatus::Pending || task.is_master {
+            // Only consider tasks that haven't started and non-master tasks
+            if !matches!(
+                task.status,
+                TaskStatus::Pending | TaskStatus::AwaitingTrigger
+            ) || task.is_master
+            {
                 continue;
             }
 
@@ -408,40 +392,61 @@ impl Scheduler {
                 .find(|n| n.id == task.node_id)
                 .ok_or_else(|| Error::NodeNotFound(task.node_id.clone()))?;
 
-            // Check if the node has a manual trigger
-            if node.r#type == NodeType::Manual
-                || node
-                    .trigger
-                    .as_ref()
-                    .map(|t| t.r#type == TriggerType::Manual)
-                    .unwrap_or(false)
-            {
-                tasks_to_await_trigger.push(task.id);
-                continue;
-            }
-
-            // Check if all dependencies are satisfied
-            let mut dependencies_satisfied = true;
+            // Check whether the dependencies finished, and whether any of them failed
+            let mut dependencies_finished = true;
+            let mut failed_dependency = None;
             for dep_id in &node.depends_on {
                 // Find all tasks for this dependency
                 let dep_tasks: Vec<&Task> = tasks.iter().filter(|t| t.node_id == *dep_id).collect();
 
-                // If there are no tasks for this dependency, it's not satisfied
+                // If there are no tasks for this dependency, it hasn't finished
                 if dep_tasks.is_empty() {
-                    dependencies_satisfied = false;
-                    break;
+                    dependencies_finished = false;
+                    continue;
                 }
 
-                // Check if all tasks for this dependency are completed
-                let all_completed = dep_tasks.iter().all(|t| t.status == TaskStatus::Completed);
+                if dep_tasks
+                    .iter()
+                    .any(|t| matches!(t.status, TaskStatus::Failed | TaskStatus::Skipped))
+                {
+                    failed_dependency.get_or_insert(dep_id);
+                }
+                if !dep_tasks.iter().all(|t| t.status.is_terminal()) {
+                    dependencies_finished = false;
+                }
+            }
 
-                if !all_completed {
-                    dependencies_satisfied = false;
-                    break;
+            // Nodes that check `failure()` or `always()` wait for all dependencies to
+            // finish; all others are skipped as soon as one of them fails
+            let checks_status = node.r#if.as_deref().is_some_and(uses_status_functions);
+            if let Some(dep_id) = failed_dependency {
+                if !checks_status {
+                    debug!(
+                        "Skipping task {} for node '{}': dependency '{}' failed",
+                        task.id, node.id, dep_id
+                    );
+                    tasks_to_skip.push(task.id);
+                    continue;
                 }
             }
 
-            if dependencies_satisfied {
+            if task.status != TaskStatus::Pending {
+                continue;
+            }
+
+            // Check if the node has a manual trigger that hasn't been given yet
+            let manual = node.r#type == NodeType::Manual
+                || node
+                    .trigger
+                    .as_ref()
+                    .map(|t| t.r#type == TriggerType::Manual)
+                    .unwrap_or(false);
+            if manual && !task.triggered {
+                tasks_to_await_trigger.push(task.id);
+                continue;
+            }
+
+            if dependencies_finished {
                 runnable_tasks.push(task.id);
             }
         }
@@ -449,6 +454,7 @@ impl Scheduler {
         Ok(RunnableTaskChanges {
             tasks_to_await_trigger,
             runnable_tasks,
+            tasks_to_skip,
         })
     }
 }
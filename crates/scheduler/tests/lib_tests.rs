@@ -1,6 +1,6 @@
 #![cfg(test)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use butterflow_scheduler::Scheduler;
 use serde_json::json;
@@ -23,6 +23,7 @@ fn create_basic_node(id: &str, depends_on: Vec<&str>) -> Node {
         description: None,
         r#type: NodeType::Automatic,
         depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: None,
         trigger: None,
         strategy: None,
         runtime: Some(Runtime {
@@ -35,9 +36,17 @@ fn create_basic_node(id: &str, depends_on: Vec<&str>) -> Node {
         }),
         steps: vec![Step {
             name: "Step 1".to_string(),
+            id: None,
+            r#if: None,
             action: StepAction::RunScript(format!("echo 'Running {id}'")),
             env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
         }],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
         env: HashMap::new(),
     }
 }
@@ -53,11 +62,16 @@ fn create_matrix_node_values(
         description: None,
         r#type: NodeType::Automatic,
         depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: None,
         trigger: None,
         strategy: Some(Strategy {
             r#type: StrategyType::Matrix,
             values: Some(values),
             from_state: None,
+            axes: None,
+            include: None,
+            exclude: None,
+            max_parallel: None,
         }),
         runtime: Some(Runtime {
             r#type: RuntimeType::Direct,
@@ -69,9 +83,17 @@ fn create_matrix_node_values(
         }),
         steps: vec![Step {
             name: "Step 1".to_string(),
+            id: None,
+            r#if: None,
             action: StepAction::RunScript(format!("echo 'Running matrix {id}'")),
             env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
         }],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
         env: HashMap::new(),
     }
 }
@@ -83,11 +105,16 @@ fn create_matrix_node_from_state(id: &str, depends_on: Vec<&str>, state_key: &st
         description: None,
         r#type: NodeType::Automatic,
         depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: None,
         trigger: None,
         strategy: Some(Strategy {
             r#type: StrategyType::Matrix,
             values: None,
             from_state: Some(state_key.to_string()),
+            axes: None,
+            include: None,
+            exclude: None,
+            max_parallel: None,
         }),
         runtime: Some(Runtime {
             r#type: RuntimeType::Direct,
@@ -99,9 +126,17 @@ fn create_matrix_node_from_state(id: &str, depends_on: Vec<&str>, state_key: &st
         }),
         steps: vec![Step {
             name: "Step 1".to_string(),
+            id: None,
+            r#if: None,
             action: StepAction::RunScript(format!("echo 'Running matrix from state {id}'")),
             env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
         }],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
         env: HashMap::new(),
     }
 }
@@ -118,6 +153,7 @@ fn create_manual_node(
         description: None,
         r#type: node_type,
         depends_on: depends_on.into_iter().map(String::from).collect(),
+        r#if: None,
         trigger: trigger_type.map(|t| Trigger { r#type: t }),
         strategy: None,
         runtime: Some(Runtime {
@@ -130,9 +166,17 @@ fn create_manual_node(
         }),
         steps: vec![Step {
             name: "Step 1".to_string(),
+            id: None,
+            r#if: None,
             action: StepAction::RunScript(format!("echo 'Running manual {id}'")),
             env: None,
+            retry: None,
+            timeout: None,
+            continue_on_error: false,
         }],
+        retry: None,
+        timeout: None,
+        continue_on_error: false,
         env: HashMap::new(),
     }
 }
@@ -143,6 +187,7 @@ fn create_test_workflow(nodes: Vec<Node>) -> Workflow {
         state: None,
         templates: vec![],
         nodes,
+        concurrency: None,
     }
 }
 
@@ -156,6 +201,8 @@ fn create_test_run(workflow: Workflow) -> WorkflowRun {
         started_at: chrono::Utc::now(),
         ended_at: None,
         bundle_path: None,
+        source: None,
+        target_path: None,
     }
 }
 
@@ -242,6 +289,44 @@ async fn test_calculate_initial_tasks_matrix_from_state() {
     );
 }
 
+#[tokio::test]
+async fn test_calculate_initial_tasks_matrix_axes() {
+    let scheduler = Scheduler::new();
+    let mut node = create_matrix_node_values("node1", vec![], vec![]);
+    let strategy = node.strategy.as_mut().unwrap();
+    strategy.values = None;
+    strategy.axes = Some(BTreeMap::from([
+        ("os".to_string(), vec![json!("linux"), json!("macos")]),
+        ("node".to_string(), vec![json!(18), json!(20)]),
+    ]));
+    strategy.exclude = Some(vec![HashMap::from([
+        ("os".to_string(), json!("macos")),
+        ("node".to_string(), json!(18)),
+    ])]);
+    strategy.include = Some(vec![HashMap::from([
+        ("os".to_string(), json!("windows")),
+        ("node".to_string(), json!(20)),
+    ])]);
+    let run = create_test_run(create_test_workflow(vec![node]));
+
+    let tasks = scheduler.calculate_initial_tasks(&run).await.unwrap();
+
+    let mut combinations: Vec<_> = tasks
+        .iter()
+        .filter(|t| !t.is_master)
+        .map(|t| {
+            let values = t.matrix_values.as_ref().unwrap();
+            (values["os"].clone(), values["node"].clone())
+        })
+        .map(|(os, node)| format!("{}-{}", os.as_str().unwrap(), node))
+        .collect();
+    combinations.sort();
+    assert_eq!(
+        combinations,
+        vec!["linux-18", "linux-20", "macos-20", "windows-20"]
+    );
+}
+
 #[tokio::test]
 async fn test_calculate_matrix_task_changes_no_master_yet() {
     let workflow = create_test_workflow(vec![
@@ -358,6 +443,46 @@ async fn test_calculate_matrix_task_changes_add_remove() {
     assert_eq!(changes.master_tasks_to_update[0], master_task.id);
 }
 
+#[tokio::test]
+async fn test_calculate_matrix_task_changes_object_state() {
+    let mut node = create_matrix_node_from_state("node1", vec![], "shards");
+    node.strategy.as_mut().unwrap().axes =
+        Some(BTreeMap::from([("mode".to_string(), vec![json!("check")])]));
+    let workflow = create_test_workflow(vec![node]);
+    let run = create_test_run(workflow);
+
+    // Each key of an object becomes a matrix item; object values contribute their fields
+    let state = HashMap::from([(
+        "shards".to_string(),
+        json!({"web": {"team": "frontend", "files": ["a.ts"]}, "api": "backend"}),
+    )]);
+
+    let scheduler = Scheduler::new();
+    let changes = scheduler
+        .calculate_matrix_task_changes(run.id, &run, &[], &state)
+        .await
+        .unwrap();
+
+    let children: Vec<_> = changes
+        .new_tasks
+        .iter()
+        .filter(|t| !t.is_master)
+        .map(|t| t.matrix_values.clone().unwrap())
+        .collect();
+    assert_eq!(children.len(), 2);
+    assert!(children.contains(&HashMap::from([
+        ("key".to_string(), json!("web")),
+        ("team".to_string(), json!("frontend")),
+        ("files".to_string(), json!(["a.ts"])),
+        ("mode".to_string(), json!("check")),
+    ])));
+    assert!(children.contains(&HashMap::from([
+        ("key".to_string(), json!("api")),
+        ("value".to_string(), json!("backend")),
+        ("mode".to_string(), json!("check")),
+    ])));
+}
+
 #[tokio::test]
 async fn test_calculate_matrix_task_changes_state_key_missing() {
     let workflow = create_test_workflow(vec![create_matrix_node_from_state(
@@ -423,6 +548,66 @@ async fn test_find_runnable_tasks_simple_dependency() {
     assert_eq!(runnable4.runnable_tasks.len(), 0);
 }
 
+#[tokio::test]
+async fn test_find_runnable_tasks_skipped_dependency() {
+    let scheduler = Scheduler::new();
+    let workflow = create_test_workflow(vec![
+        create_basic_node("node1", vec![]),
+        create_basic_node("node2", vec!["node1"]),
+    ]);
+    let run = create_test_run(workflow);
+
+    // A dependency skipped by its condition counts as satisfied
+    let mut tasks = vec![
+        Task::new(run.id, "node1".to_string(), false),
+        Task::new(run.id, "node2".to_string(), false),
+    ];
+    tasks[0].status = TaskStatus::WontDo;
+    let runnable = scheduler.find_runnable_tasks(&run, &tasks).await.unwrap();
+    assert_eq!(runnable.runnable_tasks, vec![tasks[1].id]);
+
+    // A failed dependency does not, and its dependents are skipped
+    tasks[0].status = TaskStatus::Failed;
+    let runnable = scheduler.find_runnable_tasks(&run, &tasks).await.unwrap();
+    assert!(runnable.runnable_tasks.is_empty());
+    assert_eq!(runnable.tasks_to_skip, vec![tasks[1].id]);
+}
+
+#[tokio::test]
+async fn test_find_runnable_tasks_failure_propagation() {
+    let scheduler = Scheduler::new();
+    let mut cleanup = create_basic_node("cleanup", vec!["node1", "node2"]);
+    cleanup.r#if = Some("${{ always() }}".to_string());
+    let workflow = create_test_workflow(vec![
+        create_basic_node("node1", vec![]),
+        create_basic_node("node2", vec![]),
+        create_basic_node("node3", vec!["node1"]),
+        cleanup,
+    ]);
+    let run = create_test_run(workflow);
+
+    let mut tasks = vec![
+        Task::new(run.id, "node1".to_string(), false),
+        Task::new(run.id, "node2".to_string(), false),
+        Task::new(run.id, "node3".to_string(), false),
+        Task::new(run.id, "cleanup".to_string(), false),
+    ];
+
+    // node3 is skipped as soon as node1 fails, cleanup waits for node2
+    tasks[0].status = TaskStatus::Failed;
+    tasks[1].status = TaskStatus::Running;
+    let runnable = scheduler.find_runnable_tasks(&run, &tasks).await.unwrap();
+    assert_eq!(runnable.tasks_to_skip, vec![tasks[2].id]);
+    assert!(runnable.runnable_tasks.is_empty());
+
+    // Once every dependency finished, cleanup runs regardless of failures
+    tasks[1].status = TaskStatus::Completed;
+    tasks[2].status = TaskStatus::Skipped;
+    let runnable = scheduler.find_runnable_tasks(&run, &tasks).await.unwrap();
+    assert_eq!(runnable.runnable_tasks, vec![tasks[3].id]);
+    assert!(runnable.tasks_to_skip.is_empty());
+}
+
 #[tokio::test]
 async fn test_find_runnable_tasks_matrix_dependency() {
     let scheduler = Scheduler::new();
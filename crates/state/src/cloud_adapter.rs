@@ -1,20 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use reqwest::{header, Client};
+use fs2::FileExt;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::{Mutex, MutexGuard};
 use uuid::Uuid;
 
 use butterflow_models::{
-    DiffOperation, Error, FieldDiff, Result, StateDiff, Task, TaskDiff, WorkflowRun,
-    WorkflowRunDiff,
+    Backoff, BackoffType, DiffOperation, Error, FieldDiff, LogLine, Result, RetryPolicy, StateDiff,
+    StateJournalEntry, Task, TaskDiff, WorkflowRun, WorkflowRunDiff, WorkflowState,
 };
 
-use crate::StateAdapter;
+use crate::diff::validate_value;
+use crate::locks::{read, write};
+use crate::{RunOrder, RunPage, RunQuery, StateAdapter};
+
+/// Header that lets the API recognize a write it has already applied
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
 
 /// API request for the sync endpoint
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct SyncRequest {
     #[serde(rename = "type")]
     request_type: String,
@@ -22,15 +35,19 @@ struct SyncRequest {
     workflow_run_id: Option<Uuid>,
     #[serde(skip_serializing_if = "Option::is_none")]
     task_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    step: Option<String>,
     fields: HashMap<String, SyncField>,
 }
 
 /// Field for the sync request
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct SyncField {
     operation: DiffOperation,
     #[serde(skip_serializing_if = "Option::is_none")]
     value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_revision: Option<u64>,
 }
 
 /// API response for sync endpoint
@@ -41,7 +58,77 @@ struct SyncResponse {
     error: Option<String>,
 }
 
+/// API response for the workflow runs endpoint
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RunsResponse {
+    Page(RunPage),
+    List(Vec<WorkflowRun>),
+}
+
+/// A write that couldn't be delivered yet
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingWrite {
+    /// Sent as the idempotency key, the same for every attempt
+    #[serde(default = "Uuid::new_v4")]
+    id: Uuid,
+
+    /// What the write does, used in error messages
+    action: String,
+
+    /// Request to send
+    body: WriteBody,
+}
+
+/// Request body of a write
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum WriteBody {
+    /// Sent to the sync endpoint
+    Sync(SyncRequest),
+
+    /// Sent to the logs endpoint of a task
+    Logs { task_id: Uuid, lines: Vec<LogLine> },
+}
+
+impl PendingWrite {
+    /// Whether the write is a compare-and-swap, which only makes sense right away
+    fn expects_revision(&self) -> bool {
+        match &self.body {
+            WriteBody::Sync(request) => request
+                .fields
+                .values()
+                .any(|field| field.expected_revision.is_some()),
+            WriteBody::Logs { .. } => false,
+        }
+    }
+}
+
+/// Why a request to the API failed
+enum SendError {
+    /// The API couldn't be reached or kept failing, the request may succeed later
+    Unavailable(Error),
+
+    /// The API refused the request
+    Rejected(Error),
+}
+
+impl From<SendError> for Error {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::Unavailable(error) | SendError::Rejected(error) => error,
+        }
+    }
+}
+
 /// API state adapter (sends state updates to an API)
+///
+/// Requests are retried with backoff while the API is unreachable or
+/// answers with a server error. Writes that still can't be delivered are
+/// queued and sent, in order, before any later request. Every write carries
+/// an `Idempotency-Key` header that stays the same across retries, so the
+/// API can tell a write it already applied, e.g. when only the response got
+/// lost, from a new one.
 pub struct CloudStateAdapter {
     /// API endpoint
     endpoint: String,
@@ -51,6 +138,41 @@ pub struct CloudStateAdapter {
 
     /// HTTP client
     client: Client,
+
+    /// State schemas of the workflow runs saved through this adapter
+    schemas: RwLock<HashMap<Uuid, WorkflowState>>,
+
+    /// How requests are retried while the API is unavailable
+    retry_policy: RetryPolicy,
+
+    /// Writes waiting for the API to become available, oldest first
+    queue: Mutex<VecDeque<PendingWrite>>,
+
+    /// File the queue is kept in, if it should survive the process
+    ///
+    /// Other processes may share the file, so it's locked and read again
+    /// whenever the queue changes.
+    queue_path: Option<PathBuf>,
+}
+
+/// The queue of a [`CloudStateAdapter`], and the lock on its file if it has one
+struct QueueGuard<'a> {
+    queue: MutexGuard<'a, VecDeque<PendingWrite>>,
+    _file_lock: Option<File>,
+}
+
+impl Deref for QueueGuard<'_> {
+    type Target = VecDeque<PendingWrite>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+impl DerefMut for QueueGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.queue
+    }
 }
 
 impl CloudStateAdapter {
@@ -60,7 +182,316 @@ impl CloudStateAdapter {
             endpoint,
             auth_token,
             client: Client::new(),
+            schemas: RwLock::default(),
+            retry_policy: RetryPolicy {
+                max_attempts: 5,
+                backoff: Backoff {
+                    r#type: BackoffType::Exponential,
+                    delay: "200ms".to_string(),
+                    max_delay: Some("5s".to_string()),
+                },
+            },
+            queue: Mutex::default(),
+            queue_path: None,
+        }
+    }
+
+    /// Retry requests according to `retry_policy` instead of the default
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Keep undelivered writes in a file, picking up the writes a previous
+    /// process left there
+    ///
+    /// The file should only be shared by adapters for the same endpoint,
+    /// since whichever one gets to the queued writes first delivers them.
+    pub fn with_queue_path(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        self.queue = Mutex::new(load_queue(&path)?);
+        self.queue_path = Some(path);
+        Ok(self)
+    }
+
+    /// Number of writes waiting to be delivered
+    pub async fn queued_writes(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    /// Try to deliver queued writes, returning how many are still waiting
+    pub async fn flush_queue(&self) -> Result<usize> {
+        let mut queue = self.lock_queue().await?;
+        self.drain(&mut queue).await?;
+        Ok(queue.len())
+    }
+
+    /// Lock the queue, and its file if it has one, picking up the writes
+    /// other processes added to the file since it was last read
+    async fn lock_queue(&self) -> Result<QueueGuard<'_>> {
+        let mut queue = self.queue.lock().await;
+        let Some(path) = &self.queue_path else {
+            return Ok(QueueGuard {
+                queue,
+                _file_lock: None,
+            });
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        // Another process may hold the lock while it delivers writes, so
+        // wait for it without blocking the runtime
+        while let Err(e) = lock_file.try_lock_exclusive() {
+            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(e.into());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        *queue = load_queue(path)?;
+        Ok(QueueGuard {
+            queue,
+            _file_lock: Some(lock_file),
+        })
+    }
+
+    /// Check values that replace state keys against the workflow run's schema
+    ///
+    /// Values computed from the current state, such as appends, are checked
+    /// by the server.
+    fn validate_state(
+        &self,
+        workflow_run_id: Uuid,
+        fields: &HashMap<String, FieldDiff>,
+    ) -> Result<()> {
+        let schemas = read(&self.schemas);
+        let Some(schema) = schemas.get(&workflow_run_id) else {
+            return Ok(());
+        };
+        for (key, field_diff) in fields {
+            if let (DiffOperation::Add | DiffOperation::Update, Some(value)) =
+                (&field_diff.operation, &field_diff.value)
+            {
+                validate_value(schema, key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a request, retrying up to `max_attempts` times while the API is
+    /// unavailable
+    ///
+    /// Any other response is returned as is, so callers can handle it.
+    async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        max_attempts: u32,
+    ) -> std::result::Result<Response, SendError> {
+        let mut attempt = 1;
+        loop {
+            let result = request()
+                .header(header::AUTHORIZATION, self.get_auth_header())
+                .send()
+                .await;
+            let error = match result {
+                Ok(response) if !is_unavailable(response.status()) => return Ok(response),
+                Ok(response) => Error::Other(format!("API unavailable: {}", response.status())),
+                // A request that can't be built won't work any better later
+                Err(e) if e.is_builder() => return Err(SendError::Rejected(e.into())),
+                Err(e) => e.into(),
+            };
+
+            if attempt >= max_attempts {
+                return Err(SendError::Unavailable(error));
+            }
+            let delay = self.retry_policy.delay_after(attempt).unwrap_or_default();
+            log::debug!("Request failed ({error}), retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Send a read request, after delivering the queued writes it may depend on
+    async fn fetch(&self, request: impl Fn() -> RequestBuilder) -> Result<Response> {
+        self.flush_queue().await?;
+        Ok(self.send(request, self.retry_policy.max_attempts).await?)
+    }
+
+    /// Send a write, queueing it if the API stays unavailable
+    async fn submit(&self, pending: PendingWrite) -> Result<()> {
+        {
+            let mut queue = self.lock_queue().await?;
+            // Earlier writes go first, so the API sees them in order
+            if !queue.is_empty() {
+                self.drain(&mut queue).await?;
+            }
+            if !queue.is_empty() {
+                let error = Error::Other(format!(
+                    "API unavailable, {} writes are waiting to be sent",
+                    queue.len()
+                ));
+                return self.enqueue(&mut queue, pending, error);
+            }
+        }
+
+        match self.deliver(&pending, self.retry_policy.max_attempts).await {
+            Err(SendError::Unavailable(error)) => {
+                let mut queue = self.lock_queue().await?;
+                self.enqueue(&mut queue, pending, error)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Queue a write that couldn't be delivered because of `error`
+    fn enqueue(
+        &self,
+        queue: &mut VecDeque<PendingWrite>,
+        pending: PendingWrite,
+        error: Error,
+    ) -> Result<()> {
+        // The revisions a compare-and-swap expects may be stale by the time
+        // it gets delivered, so the caller has to retry it instead
+        if pending.expects_revision() {
+            return Err(error);
+        }
+        log::warn!("Failed to {}, queueing it: {error}", pending.action);
+        queue.push_back(pending);
+        self.save_queue(queue)
+    }
+
+    /// Deliver queued writes in order, stopping at the first one the API
+    /// can't take yet
+    async fn drain(&self, queue: &mut VecDeque<PendingWrite>) -> Result<()> {
+        let queued = queue.len();
+        while let Some(pending) = queue.front() {
+            match self.deliver(pending, 1).await {
+                Ok(()) => {}
+                Err(SendError::Unavailable(_)) => break,
+                // Whoever made the write is long gone, so all we can do is log
+                Err(SendError::Rejected(error)) => {
+                    log::error!(
+                        "Dropping queued write, failed to {}: {error}",
+                        pending.action
+                    )
+                }
+            }
+            queue.pop_front();
+        }
+
+        if queue.len() != queued {
+            self.save_queue(queue)?;
+        }
+        Ok(())
+    }
+
+    /// Write the queue to its file, if any, removing the file once empty
+    ///
+    /// Only called with the file locked, see [`Self::lock_queue`].
+    fn save_queue(&self, queue: &VecDeque<PendingWrite>) -> Result<()> {
+        let Some(path) = &self.queue_path else {
+            return Ok(());
+        };
+
+        if queue.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
         }
+
+        let mut content = Vec::new();
+        for pending in queue {
+            serde_json::to_writer(&mut content, pending)?;
+            content.push(b'\n');
+        }
+        // Replace the file in one step, `with_queue_path` reads it unlocked
+        let temp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&temp_path, content)?;
+        if let Err(e) = fs::rename(&temp_path, path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Send a write and check that the API accepted it
+    async fn deliver(
+        &self,
+        pending: &PendingWrite,
+        max_attempts: u32,
+    ) -> std::result::Result<(), SendError> {
+        let response = self
+            .send(
+                || {
+                    match &pending.body {
+                        WriteBody::Sync(request) => {
+                            self.client.post(self.get_sync_url()).json(request)
+                        }
+                        WriteBody::Logs { task_id, lines } => self
+                            .client
+                            .post(self.get_task_logs_url(*task_id))
+                            .json(lines),
+                    }
+                    .header(IDEMPOTENCY_KEY, pending.id.to_string())
+                },
+                max_attempts,
+            )
+            .await?;
+
+        // The server rejects diffs whose expected revisions are stale
+        if response.status() == StatusCode::CONFLICT {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(SendError::Rejected(Error::StateConflict(error_text)));
+        }
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(SendError::Rejected(Error::Other(format!(
+                "Failed to {}: {error_text}",
+                pending.action
+            ))));
+        }
+
+        if let WriteBody::Sync(_) = pending.body {
+            let sync_response: SyncResponse = response
+                .json()
+                .await
+                .map_err(|e| SendError::Rejected(e.into()))?;
+            if !sync_response.success {
+                return Err(SendError::Rejected(Error::Other(format!(
+                    "Failed to {}: {}",
+                    pending.action,
+                    sync_response
+                        .error
+                        .unwrap_or_else(|| "Unknown error".to_string())
+                ))));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a request to the sync endpoint
+    async fn sync(&self, action: &str, request: SyncRequest) -> Result<()> {
+        self.submit(PendingWrite {
+            id: Uuid::new_v4(),
+            action: action.to_string(),
+            body: WriteBody::Sync(request),
+        })
+        .await
     }
 
     /// Get the base URL for the API
@@ -103,18 +534,91 @@ impl CloudStateAdapter {
         format!("{}/runs/{}/state", self.get_base_url(), workflow_run_id)
     }
 
+    /// Build the task logs URL
+    fn get_task_logs_url(&self, task_id: Uuid) -> String {
+        format!("{}/logs", self.get_task_url(task_id))
+    }
+
+    /// Build the state revisions URL
+    fn get_state_revisions_url(&self, workflow_run_id: Uuid) -> String {
+        format!("{}/revisions", self.get_state_url(workflow_run_id))
+    }
+
+    /// Build the state journal URL
+    fn get_state_journal_url(&self, workflow_run_id: Uuid) -> String {
+        format!("{}/journal", self.get_state_url(workflow_run_id))
+    }
+
     /// Convert FieldDiff to SyncField
     fn convert_field_diff(&self, field_diff: &FieldDiff) -> SyncField {
         SyncField {
             operation: field_diff.operation.clone(),
             value: field_diff.value.clone(),
+            expected_revision: field_diff.expected_revision,
         }
     }
 }
 
+/// Query string for a run query, repeating `status` and `param` for each value
+fn run_query_params(query: &RunQuery) -> Vec<(&'static str, String)> {
+    let mut params = Vec::new();
+    for status in &query.statuses {
+        params.push(("status", format!("{status:?}")));
+    }
+    if let Some(started_after) = query.started_after {
+        params.push(("started_after", started_after.to_rfc3339()));
+    }
+    if let Some(started_before) = query.started_before {
+        params.push(("started_before", started_before.to_rfc3339()));
+    }
+    if let Some(source) = &query.source {
+        params.push(("source", source.clone()));
+    }
+    if let Some(target_path) = &query.target_path {
+        params.push(("target_path", target_path.display().to_string()));
+    }
+    for (key, value) in &query.params {
+        params.push(("param", format!("{key}={value}")));
+    }
+    if query.order == RunOrder::OldestFirst {
+        params.push(("order", "oldest_first".to_string()));
+    }
+    if let Some(limit) = query.limit {
+        params.push(("limit", limit.to_string()));
+    }
+    if let Some(cursor) = &query.cursor {
+        params.push(("cursor", cursor.clone()));
+    }
+    params
+}
+
+/// Whether a response means the API is unavailable for now
+fn is_unavailable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Read the writes queued in `path`, one JSON object per line
+fn load_queue(path: &Path) -> Result<VecDeque<PendingWrite>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(VecDeque::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
 #[async_trait]
 impl StateAdapter for CloudStateAdapter {
-    async fn save_workflow_run(&mut self, workflow_run: &WorkflowRun) -> Result<()> {
+    async fn save_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()> {
+        if let Some(schema) = &workflow_run.workflow.state {
+            write(&self.schemas).insert(workflow_run.id, schema.clone());
+        }
+
         let workflow_run_value = serde_json::to_value(workflow_run)?;
 
         if let Value::Object(obj) = workflow_run_value {
@@ -126,6 +630,7 @@ impl StateAdapter for CloudStateAdapter {
                     SyncField {
                         operation: DiffOperation::Add,
                         value: Some(value),
+                        expected_revision: None,
                     },
                 );
             }
@@ -134,44 +639,17 @@ impl StateAdapter for CloudStateAdapter {
                 request_type: "workflow_run".to_string(),
                 workflow_run_id: Some(workflow_run.id),
                 task_id: None,
+                step: None,
                 fields,
             };
 
-            let response = self
-                .client
-                .post(self.get_sync_url())
-                .header(header::AUTHORIZATION, self.get_auth_header())
-                .json(&request)
-                .send()
-                .await?;
-
-            if !response.status().is_success() {
-                let error_text = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
-                return Err(Error::Other(format!(
-                    "Failed to save workflow run: {error_text}"
-                )));
-            }
-
-            let sync_response: SyncResponse = response.json().await?;
-            if !sync_response.success {
-                return Err(Error::Other(format!(
-                    "Failed to save workflow run: {}",
-                    sync_response
-                        .error
-                        .unwrap_or_else(|| "Unknown error".to_string())
-                )));
-            }
-
-            Ok(())
+            self.sync("save workflow run", request).await
         } else {
             Err(Error::Other("Failed to serialize workflow run".to_string()))
         }
     }
 
-    async fn apply_workflow_run_diff(&mut self, diff: &WorkflowRunDiff) -> Result<()> {
+    async fn apply_workflow_run_diff(&self, diff: &WorkflowRunDiff) -> Result<()> {
         let mut fields = HashMap::new();
         for (key, field_diff) in &diff.fields {
             fields.insert(key.clone(), self.convert_field_diff(field_diff));
@@ -181,46 +659,16 @@ impl StateAdapter for CloudStateAdapter {
             request_type: "workflow_run".to_string(),
             workflow_run_id: Some(diff.workflow_run_id),
             task_id: None,
+            step: None,
             fields,
         };
 
-        let response = self
-            .client
-            .post(self.get_sync_url())
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!(
-                "Failed to apply workflow run diff: {error_text}"
-            )));
-        }
-
-        let sync_response: SyncResponse = response.json().await?;
-        if !sync_response.success {
-            return Err(Error::Other(format!(
-                "Failed to apply workflow run diff: {}",
-                sync_response
-                    .error
-                    .unwrap_or_else(|| "Unknown error".to_string())
-            )));
-        }
-
-        Ok(())
+        self.sync("apply workflow run diff", request).await
     }
 
     async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
         let response = self
-            .client
-            .get(self.get_workflow_run_url(workflow_run_id))
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .send()
+            .fetch(|| self.client.get(self.get_workflow_run_url(workflow_run_id)))
             .await?;
 
         if !response.status().is_success() {
@@ -237,12 +685,13 @@ impl StateAdapter for CloudStateAdapter {
         Ok(workflow_run)
     }
 
-    async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>> {
+    async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage> {
         let response = self
-            .client
-            .get(self.get_workflow_runs_url())
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .send()
+            .fetch(|| {
+                self.client
+                    .get(self.get_workflow_runs_url())
+                    .query(&run_query_params(query))
+            })
             .await?;
 
         if !response.status().is_success() {
@@ -255,18 +704,41 @@ impl StateAdapter for CloudStateAdapter {
             )));
         }
 
-        let workflow_runs: Vec<WorkflowRun> = response.json().await?;
+        match response.json().await? {
+            RunsResponse::Page(page) => Ok(page),
+            // Servers that return a plain list leave the query to the client
+            RunsResponse::List(workflow_runs) => query.apply(workflow_runs),
+        }
+    }
 
-        let limited_runs = if workflow_runs.len() > limit {
-            workflow_runs.into_iter().take(limit).collect()
-        } else {
-            workflow_runs
-        };
+    async fn delete_workflow_runs(&self, workflow_run_ids: &[Uuid]) -> Result<()> {
+        for &workflow_run_id in workflow_run_ids {
+            // Deleting is never queued, the caller expects the run to be gone
+            let response = self
+                .fetch(|| {
+                    self.client
+                        .delete(self.get_workflow_run_url(workflow_run_id))
+                })
+                .await?;
 
-        Ok(limited_runs)
+            // The server removes the tasks, state and logs along with the run
+            if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+                let error_text = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+                return Err(Error::Other(format!(
+                    "Failed to delete workflow run: {error_text}"
+                )));
+            }
+
+            write(&self.schemas).remove(&workflow_run_id);
+        }
+
+        Ok(())
     }
 
-    async fn save_task(&mut self, task: &Task) -> Result<()> {
+    async fn save_task(&self, task: &Task) -> Result<()> {
         let task_value = serde_json::to_value(task)?;
 
         if let Value::Object(obj) = task_value {
@@ -278,6 +750,7 @@ impl StateAdapter for CloudStateAdapter {
                     SyncField {
                         operation: DiffOperation::Add,
                         value: Some(value),
+                        expected_revision: None,
                     },
                 );
             }
@@ -286,42 +759,17 @@ impl StateAdapter for CloudStateAdapter {
                 request_type: "task".to_string(),
                 workflow_run_id: None,
                 task_id: Some(task.id),
+                step: None,
                 fields,
             };
 
-            let response = self
-                .client
-                .post(self.get_sync_url())
-                .header(header::AUTHORIZATION, self.get_auth_header())
-                .json(&request)
-                .send()
-                .await?;
-
-            if !response.status().is_success() {
-                let error_text = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
-                return Err(Error::Other(format!("Failed to save task: {error_text}")));
-            }
-
-            let sync_response: SyncResponse = response.json().await?;
-            if !sync_response.success {
-                return Err(Error::Other(format!(
-                    "Failed to save task: {}",
-                    sync_response
-                        .error
-                        .unwrap_or_else(|| "Unknown error".to_string())
-                )));
-            }
-
-            Ok(())
+            self.sync("save task", request).await
         } else {
             Err(Error::Other("Failed to serialize task".to_string()))
         }
     }
 
-    async fn apply_task_diff(&mut self, diff: &TaskDiff) -> Result<()> {
+    async fn apply_task_diff(&self, diff: &TaskDiff) -> Result<()> {
         let mut fields = HashMap::new();
         for (key, field_diff) in &diff.fields {
             fields.insert(key.clone(), self.convert_field_diff(field_diff));
@@ -331,15 +779,16 @@ impl StateAdapter for CloudStateAdapter {
             request_type: "task".to_string(),
             workflow_run_id: None,
             task_id: Some(diff.task_id),
+            step: None,
             fields,
         };
 
+        self.sync("apply task diff", request).await
+    }
+
+    async fn get_task(&self, task_id: Uuid) -> Result<Task> {
         let response = self
-            .client
-            .post(self.get_sync_url())
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .json(&request)
-            .send()
+            .fetch(|| self.client.get(self.get_task_url(task_id)))
             .await?;
 
         if !response.status().is_success() {
@@ -347,30 +796,19 @@ impl StateAdapter for CloudStateAdapter {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!(
-                "Failed to apply task diff: {error_text}"
-            )));
-        }
-
-        let sync_response: SyncResponse = response.json().await?;
-        if !sync_response.success {
-            return Err(Error::Other(format!(
-                "Failed to apply task diff: {}",
-                sync_response
-                    .error
-                    .unwrap_or_else(|| "Unknown error".to_string())
-            )));
+            return Err(Error::Other(format!("Failed to get task: {error_text}")));
         }
 
-        Ok(())
+        let task = response.json().await?;
+        Ok(task)
     }
 
-    async fn get_task(&self, task_id: Uuid) -> Result<Task> {
+    async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
         let response = self
-            .client
-            .get(self.get_task_url(task_id))
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .send()
+            .fetch(|| {
+                self.client
+                    .get(self.get_tasks_for_workflow_url(workflow_run_id))
+            })
             .await?;
 
         if !response.status().is_success() {
@@ -378,19 +816,32 @@ impl StateAdapter for CloudStateAdapter {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!("Failed to get task: {error_text}")));
+            return Err(Error::Other(format!("Failed to get tasks: {error_text}")));
         }
 
-        let task = response.json().await?;
-        Ok(task)
+        let tasks: Vec<Task> = response.json().await?;
+        Ok(tasks)
     }
 
-    async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
+    async fn append_task_logs(&self, task_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        self.submit(PendingWrite {
+            id: Uuid::new_v4(),
+            action: "append task logs".to_string(),
+            body: WriteBody::Logs {
+                task_id,
+                lines: lines.to_vec(),
+            },
+        })
+        .await
+    }
+
+    async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>> {
         let response = self
-            .client
-            .get(self.get_tasks_for_workflow_url(workflow_run_id))
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .send()
+            .fetch(|| {
+                self.client
+                    .get(self.get_task_logs_url(task_id))
+                    .query(&[("offset", offset)])
+            })
             .await?;
 
         if !response.status().is_success() {
@@ -398,18 +849,26 @@ impl StateAdapter for CloudStateAdapter {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!("Failed to get tasks: {error_text}")));
+            return Err(Error::Other(format!(
+                "Failed to get task logs: {error_text}"
+            )));
         }
 
-        let tasks: Vec<Task> = response.json().await?;
-        Ok(tasks)
+        let lines: Vec<LogLine> = response.json().await?;
+        Ok(lines)
     }
 
     async fn update_state(
-        &mut self,
+        &self,
         workflow_run_id: Uuid,
         state: HashMap<String, Value>,
     ) -> Result<()> {
+        if let Some(schema) = read(&self.schemas).get(&workflow_run_id) {
+            for (key, value) in &state {
+                validate_value(schema, key, value)?;
+            }
+        }
+
         let mut fields = HashMap::new();
         for (key, value) in &state {
             fields.insert(
@@ -417,6 +876,7 @@ impl StateAdapter for CloudStateAdapter {
                 SyncField {
                     operation: DiffOperation::Update,
                     value: Some(value.clone()),
+                    expected_revision: None,
                 },
             );
         }
@@ -425,41 +885,16 @@ impl StateAdapter for CloudStateAdapter {
             request_type: "state".to_string(),
             workflow_run_id: Some(workflow_run_id),
             task_id: None,
+            step: None,
             fields,
         };
 
-        let response = self
-            .client
-            .post(self.get_sync_url())
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .json(&request)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!(
-                "Failed to update state: {error_text}"
-            )));
-        }
-
-        let sync_response: SyncResponse = response.json().await?;
-        if !sync_response.success {
-            return Err(Error::Other(format!(
-                "Failed to update state: {}",
-                sync_response
-                    .error
-                    .unwrap_or_else(|| "Unknown error".to_string())
-            )));
-        }
-
-        Ok(())
+        self.sync("update state", request).await
     }
 
-    async fn apply_state_diff(&mut self, diff: &StateDiff) -> Result<()> {
+    async fn apply_state_diff(&self, diff: &StateDiff) -> Result<()> {
+        self.validate_state(diff.workflow_run_id, &diff.fields)?;
+
         let mut fields = HashMap::new();
         for (key, field_diff) in &diff.fields {
             fields.insert(key.clone(), self.convert_field_diff(field_diff));
@@ -468,16 +903,17 @@ impl StateAdapter for CloudStateAdapter {
         let request = SyncRequest {
             request_type: "state".to_string(),
             workflow_run_id: Some(diff.workflow_run_id),
-            task_id: None,
+            task_id: diff.task_id,
+            step: diff.step.clone(),
             fields,
         };
 
+        self.sync("apply state diff", request).await
+    }
+
+    async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
         let response = self
-            .client
-            .post(self.get_sync_url())
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .json(&request)
-            .send()
+            .fetch(|| self.client.get(self.get_state_url(workflow_run_id)))
             .await?;
 
         if !response.status().is_success() {
@@ -485,30 +921,38 @@ impl StateAdapter for CloudStateAdapter {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!(
-                "Failed to apply state diff: {error_text}"
-            )));
+            return Err(Error::Other(format!("Failed to get state: {error_text}")));
         }
 
-        let sync_response: SyncResponse = response.json().await?;
-        if !sync_response.success {
+        let state: HashMap<String, Value> = response.json().await?;
+        Ok(state)
+    }
+
+    async fn get_state_revisions(&self, workflow_run_id: Uuid) -> Result<HashMap<String, u64>> {
+        let response = self
+            .fetch(|| {
+                self.client
+                    .get(self.get_state_revisions_url(workflow_run_id))
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
             return Err(Error::Other(format!(
-                "Failed to apply state diff: {}",
-                sync_response
-                    .error
-                    .unwrap_or_else(|| "Unknown error".to_string())
+                "Failed to get state revisions: {error_text}"
             )));
         }
 
-        Ok(())
+        let revisions: HashMap<String, u64> = response.json().await?;
+        Ok(revisions)
     }
 
-    async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
+    async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
         let response = self
-            .client
-            .get(self.get_state_url(workflow_run_id))
-            .header(header::AUTHORIZATION, self.get_auth_header())
-            .send()
+            .fetch(|| self.client.get(self.get_state_journal_url(workflow_run_id)))
             .await?;
 
         if !response.status().is_success() {
@@ -516,10 +960,335 @@ impl StateAdapter for CloudStateAdapter {
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(Error::Other(format!("Failed to get state: {error_text}")));
+            return Err(Error::Other(format!(
+                "Failed to get state journal: {error_text}"
+            )));
         }
 
-        let state: HashMap<String, Value> = response.json().await?;
-        Ok(state)
+        let entries: Vec<StateJournalEntry> = response.json().await?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Minimal HTTP server that records requests and answers with a settable status
+    ///
+    /// Like a real server, it applies a write only once per idempotency key.
+    struct MockServer {
+        endpoint: String,
+        status: Arc<AtomicU16>,
+        /// Number of upcoming requests to answer with 503 regardless of `status`
+        failures: Arc<AtomicUsize>,
+        /// Number of upcoming requests to apply without sending a response
+        lost_responses: Arc<AtomicUsize>,
+        requests: Arc<std::sync::Mutex<Vec<(String, String, Value)>>>,
+        /// Idempotency keys of all requests received, in order
+        keys: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let status = Arc::new(AtomicU16::new(200));
+            let failures = Arc::new(AtomicUsize::new(0));
+            let lost_responses = Arc::new(AtomicUsize::new(0));
+            let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+            let (server_status, server_failures, server_lost_responses) =
+                (status.clone(), failures.clone(), lost_responses.clone());
+            let (server_requests, server_keys) = (requests.clone(), keys.clone());
+            tokio::spawn(async move {
+                let mut applied_keys = std::collections::HashSet::new();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut stream = BufReader::new(stream);
+
+                    let mut request_line = String::new();
+                    stream.read_line(&mut request_line).await.unwrap();
+                    let mut parts = request_line.split_whitespace();
+                    let method = parts.next().unwrap_or_default().to_string();
+                    let path = parts.next().unwrap_or_default().to_string();
+
+                    let mut content_length = 0;
+                    let mut key = None;
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                            if name.eq_ignore_ascii_case(IDEMPOTENCY_KEY) {
+                                key = Some(value.trim().to_string());
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    stream.read_exact(&mut body).await.unwrap();
+
+                    let status = match server_failures.fetch_update(
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                        |n| n.checked_sub(1),
+                    ) {
+                        Ok(_) => 503,
+                        Err(_) => server_status.load(Ordering::SeqCst),
+                    };
+                    if let Some(key) = &key {
+                        server_keys.lock().unwrap().push(key.clone());
+                    }
+                    let repeated =
+                        key.is_some_and(|key| status == 200 && !applied_keys.insert(key));
+                    if status == 200 && !repeated {
+                        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                        server_requests.lock().unwrap().push((method, path, body));
+                    }
+                    if server_lost_responses
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok()
+                    {
+                        continue;
+                    }
+
+                    let body = r#"{"success":true}"#;
+                    let response = format!(
+                        "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+
+            Self {
+                endpoint,
+                status,
+                failures,
+                lost_responses,
+                requests,
+                keys,
+            }
+        }
+
+        fn set_status(&self, status: u16) {
+            self.status.store(status, Ordering::SeqCst);
+        }
+
+        /// Paths and bodies of the requests answered with 200
+        fn requests(&self) -> Vec<(String, String, Value)> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn adapter(&self) -> CloudStateAdapter {
+            CloudStateAdapter::new(self.endpoint.clone(), "token".to_string()).with_retry_policy(
+                RetryPolicy {
+                    max_attempts: 2,
+                    backoff: Backoff {
+                        r#type: BackoffType::Fixed,
+                        delay: "1ms".to_string(),
+                        max_delay: None,
+                    },
+                },
+            )
+        }
+    }
+
+    fn state_diff(workflow_run_id: Uuid, key: &str, expected_revision: Option<u64>) -> StateDiff {
+        StateDiff {
+            workflow_run_id,
+            fields: HashMap::from([(
+                key.to_string(),
+                FieldDiff {
+                    operation: DiffOperation::Update,
+                    value: Some(Value::from(1)),
+                    expected_revision,
+                },
+            )]),
+            task_id: None,
+            step: None,
+        }
+    }
+
+    /// State keys written by the sync requests the server accepted, in order
+    fn synced_keys(server: &MockServer) -> Vec<String> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|(_, path, _)| path.ends_with("/sync"))
+            .flat_map(|(_, _, body)| {
+                body["fields"]
+                    .as_object()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_retried() {
+        let server = MockServer::start().await;
+        let adapter = server.adapter();
+        let workflow_run_id = Uuid::new_v4();
+
+        server.failures.store(1, Ordering::SeqCst);
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "a", Some(0)))
+            .await
+            .unwrap();
+        assert_eq!(adapter.queued_writes().await, 0);
+        assert_eq!(synced_keys(&server), ["a"]);
+
+        server.failures.store(1, Ordering::SeqCst);
+        adapter.get_state(workflow_run_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lost_responses_are_not_applied_twice() {
+        let server = MockServer::start().await;
+        let adapter = server.adapter();
+        let workflow_run_id = Uuid::new_v4();
+
+        // The server applies the write, but the client never hears back
+        server.lost_responses.store(1, Ordering::SeqCst);
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "a", None))
+            .await
+            .unwrap();
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "b", None))
+            .await
+            .unwrap();
+        assert_eq!(adapter.queued_writes().await, 0);
+        assert_eq!(synced_keys(&server), ["a", "b"]);
+
+        // The retry was sent with the same key, the next write with a new one
+        let keys = server.keys.lock().unwrap().clone();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_queued_while_unavailable() {
+        let server = MockServer::start().await;
+        let adapter = server.adapter();
+        let workflow_run_id = Uuid::new_v4();
+
+        server.set_status(503);
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "a", None))
+            .await
+            .unwrap();
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "b", None))
+            .await
+            .unwrap();
+        assert_eq!(adapter.queued_writes().await, 2);
+        assert!(server.requests().is_empty());
+
+        // A compare-and-swap fails right away instead of being queued
+        let error = adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "c", Some(1)))
+            .await
+            .unwrap_err();
+        assert!(!matches!(error, Error::StateConflict(_)));
+        assert_eq!(adapter.queued_writes().await, 2);
+
+        // Queued writes are delivered in order before the next one
+        server.set_status(200);
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "d", None))
+            .await
+            .unwrap();
+        assert_eq!(adapter.queued_writes().await, 0);
+        assert_eq!(synced_keys(&server), ["a", "b", "d"]);
+    }
+
+    #[tokio::test]
+    async fn test_conflicts_are_not_retried() {
+        let server = MockServer::start().await;
+        let adapter = server.adapter();
+
+        server.set_status(409);
+        let error = adapter
+            .apply_state_diff(&state_diff(Uuid::new_v4(), "a", Some(0)))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::StateConflict(_)));
+        assert_eq!(adapter.queued_writes().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_survives_restart() {
+        let server = MockServer::start().await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let queue_path = temp_dir.path().join("queue.jsonl");
+        let workflow_run_id = Uuid::new_v4();
+
+        server.set_status(502);
+        let adapter = server.adapter().with_queue_path(&queue_path).unwrap();
+        adapter
+            .apply_state_diff(&state_diff(workflow_run_id, "a", None))
+            .await
+            .unwrap();
+        adapter.append_task_logs(Uuid::new_v4(), &[]).await.unwrap();
+        drop(adapter);
+        assert!(queue_path.exists());
+
+        server.set_status(200);
+        let adapter = server.adapter().with_queue_path(&queue_path).unwrap();
+        assert_eq!(adapter.queued_writes().await, 2);
+        assert_eq!(adapter.flush_queue().await.unwrap(), 0);
+        assert!(!queue_path.exists());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].1.ends_with("/sync"));
+        assert!(requests[1].1.ends_with("/logs"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_file_is_shared() {
+        let server = MockServer::start().await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let queue_path = temp_dir.path().join("queue.jsonl");
+        let workflow_run_id = Uuid::new_v4();
+
+        // Two processes queueing writes for the same endpoint
+        server.set_status(502);
+        let first = server.adapter().with_queue_path(&queue_path).unwrap();
+        let second = server.adapter().with_queue_path(&queue_path).unwrap();
+        first
+            .apply_state_diff(&state_diff(workflow_run_id, "a", None))
+            .await
+            .unwrap();
+        second
+            .apply_state_diff(&state_diff(workflow_run_id, "b", None))
+            .await
+            .unwrap();
+        first
+            .apply_state_diff(&state_diff(workflow_run_id, "c", None))
+            .await
+            .unwrap();
+        assert_eq!(first.queued_writes().await, 3);
+
+        server.set_status(200);
+        assert_eq!(second.flush_queue().await.unwrap(), 0);
+        assert_eq!(first.flush_queue().await.unwrap(), 0);
+        assert!(!queue_path.exists());
+        assert_eq!(synced_keys(&server), ["a", "b", "c"]);
     }
 }
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use butterflow_models::{
+    DiffOperation, Error, FieldDiff, Result, StateDiff, StateJournalEntry, WorkflowState,
+};
+use chrono::Utc;
+
+/// Apply field diffs to a workflow run or task
+///
+/// `Add`, `Update` and `Append` replace the field, `Remove` clears it. The
+/// other operations behave as they do for state.
+pub(crate) fn apply_record_diff<T: Serialize + DeserializeOwned>(
+    record: &T,
+    fields: &HashMap<String, FieldDiff>,
+) -> Result<T> {
+    let mut record_value = serde_json::to_value(record)?;
+
+    if let Value::Object(obj) = &mut record_value {
+        for (field, field_diff) in fields {
+            let current = obj.remove(field);
+            let value = match field_diff.operation {
+                DiffOperation::Append => field_diff.value.clone().or(current),
+                _ => apply_operation(field, current, field_diff)?,
+            };
+            if let Some(value) = value {
+                obj.insert(field.clone(), value);
+            }
+        }
+    }
+
+    Ok(serde_json::from_value(record_value)?)
+}
+
+/// Apply field diffs to the shared state of a workflow run
+///
+/// Every written key gets its revision bumped. The diff is rejected as a
+/// whole, leaving the state untouched, if any field expects a revision other
+/// than the current one, an operation doesn't fit the current value or a
+/// resulting value doesn't match `schema`.
+pub(crate) fn apply_state_diff(
+    state: &mut HashMap<String, Value>,
+    revisions: &mut HashMap<String, u64>,
+    fields: &HashMap<String, FieldDiff>,
+    schema: Option<&WorkflowState>,
+) -> Result<()> {
+    for (field, field_diff) in fields {
+        let revision = revisions.get(field).copied().unwrap_or(0);
+        if let Some(expected) = field_diff.expected_revision {
+            if expected != revision {
+                return Err(Error::StateConflict(format!(
+                    "State key '{field}' is at revision {revision}, expected {expected}"
+                )));
+            }
+        }
+    }
+
+    let mut updated = state.clone();
+    for (field, field_diff) in fields {
+        let current = updated.remove(field);
+        if let Some(value) = apply_operation(field, current, field_diff)? {
+            updated.insert(field.clone(), value);
+        }
+    }
+    if let Some(schema) = schema {
+        for field in fields.keys() {
+            if let Some(value) = updated.get(field) {
+                validate_value(schema, field, value)?;
+            }
+        }
+    }
+
+    for field in fields.keys() {
+        *revisions.entry(field.clone()).or_insert(0) += 1;
+    }
+    *state = updated;
+    Ok(())
+}
+
+/// Replace the shared state of a workflow run, bumping the revision of every
+/// key whose value changed
+///
+/// Returns the changes as a diff, to record in the state journal.
+pub(crate) fn replace_state(
+    state: &mut HashMap<String, Value>,
+    revisions: &mut HashMap<String, u64>,
+    new_state: HashMap<String, Value>,
+    schema: Option<&WorkflowState>,
+) -> Result<HashMap<String, FieldDiff>> {
+    if let Some(schema) = schema {
+        for (key, value) in &new_state {
+            validate_value(schema, key, value)?;
+        }
+    }
+
+    let removed = state
+        .keys()
+        .filter(|key| !new_state.contains_key(*key))
+        .map(|key| (key.clone(), DiffOperation::Remove, None));
+    let changed = new_state
+        .iter()
+        .filter(|(key, value)| state.get(*key) != Some(value))
+        .map(|(key, value)| (key.clone(), DiffOperation::Update, Some(value.clone())));
+    let changes = removed
+        .chain(changed)
+        .map(|(key, operation, value)| {
+            let field_diff = FieldDiff {
+                operation,
+                value,
+                expected_revision: None,
+            };
+            (key, field_diff)
+        })
+        .collect::<HashMap<_, _>>();
+
+    for key in changes.keys() {
+        *revisions.entry(key.clone()).or_insert(0) += 1;
+    }
+    *state = new_state;
+    Ok(changes)
+}
+
+/// Build the journal entry for a write to state, from the state after it
+pub(crate) fn journal_entry(
+    sequence: u64,
+    diff: &StateDiff,
+    state: &HashMap<String, Value>,
+) -> StateJournalEntry {
+    let values = diff
+        .fields
+        .keys()
+        .filter_map(|key| Some((key.clone(), state.get(key)?.clone())))
+        .collect();
+    StateJournalEntry {
+        sequence,
+        task_id: diff.task_id,
+        step: diff.step.clone(),
+        recorded_at: Utc::now(),
+        fields: diff.fields.clone(),
+        values,
+    }
+}
+
+/// Check a state value against the schema, warning about undeclared keys
+pub(crate) fn validate_value(schema: &WorkflowState, key: &str, value: &Value) -> Result<()> {
+    if !schema.validate_value(key, value)? {
+        log::warn!("State {key} is not declared in the workflow state schema");
+    }
+    Ok(())
+}
+
+/// Compute the new value of a field, or `None` to remove it
+pub fn apply_operation(
+    field: &str,
+    current: Option<Value>,
+    field_diff: &FieldDiff,
+) -> Result<Option<Value>> {
+    let Some(value) = field_diff.value.clone() else {
+        // Nothing to apply, except for removals which don't need a value
+        return Ok(match field_diff.operation {
+            DiffOperation::Remove => None,
+            _ => current,
+        });
+    };
+
+    Ok(match (&field_diff.operation, current) {
+        (DiffOperation::Add | DiffOperation::Update, _) => Some(value),
+        (DiffOperation::Remove, _) => None,
+        // If the existing value is an array, append to it
+        (DiffOperation::Append, Some(Value::Array(mut arr))) => {
+            arr.push(value);
+            Some(Value::Array(arr))
+        }
+        // Otherwise, replace it with an array containing both values
+        (DiffOperation::Append, Some(existing)) => Some(Value::Array(vec![existing, value])),
+        // Field doesn't exist yet, create an array with just this value
+        (DiffOperation::Append, None) => Some(Value::Array(vec![value])),
+        (DiffOperation::Merge, current) => {
+            let Value::Object(patch) = value else {
+                return Err(Error::State(format!(
+                    "Cannot merge a non-object value into '{field}'"
+                )));
+            };
+            match current {
+                Some(Value::Object(mut obj)) => {
+                    obj.extend(patch);
+                    Some(Value::Object(obj))
+                }
+                None | Some(Value::Null) => Some(Value::Object(patch)),
+                Some(_) => {
+                    return Err(Error::State(format!(
+                        "Cannot merge into '{field}' because it is not an object"
+                    )))
+                }
+            }
+        }
+        (DiffOperation::RemoveFromArray, Some(Value::Array(mut arr))) => {
+            arr.retain(|item| *item != value);
+            Some(Value::Array(arr))
+        }
+        (DiffOperation::RemoveFromArray, None) => None,
+        (DiffOperation::RemoveFromArray, Some(_)) => {
+            return Err(Error::State(format!(
+                "Cannot remove an item from '{field}' because it is not an array"
+            )))
+        }
+        (DiffOperation::Increment, current) => Some(increment(field, current, &value)?),
+    })
+}
+
+/// Add a number to the current value, treating a missing value as zero
+fn increment(field: &str, current: Option<Value>, by: &Value) -> Result<Value> {
+    let current = match current {
+        None | Some(Value::Null) => Value::from(0),
+        Some(current) => current,
+    };
+    let (Value::Number(current), Value::Number(by)) = (&current, by) else {
+        return Err(Error::State(format!(
+            "Cannot increment '{field}' because it or the increment is not a number"
+        )));
+    };
+
+    if let (Some(current), Some(by)) = (current.as_i64(), by.as_i64()) {
+        if let Some(sum) = current.checked_add(by) {
+            return Ok(Value::from(sum));
+        }
+    }
+    match (current.as_f64(), by.as_f64()) {
+        (Some(current), Some(by)) => Ok(Value::from(current + by)),
+        _ => Err(Error::State(format!("Cannot increment '{field}'"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn diff(operation: DiffOperation, value: Value) -> FieldDiff {
+        FieldDiff {
+            operation,
+            value: Some(value),
+            expected_revision: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_state_diff_operations() {
+        let mut state = HashMap::from([
+            ("config".to_string(), json!({"a": 1, "b": 2})),
+            ("failures".to_string(), json!(["x", "y", "x"])),
+            ("count".to_string(), json!(2)),
+        ]);
+        let mut revisions = HashMap::new();
+
+        apply_state_diff(
+            &mut state,
+            &mut revisions,
+            &HashMap::from([
+                (
+                    "config".to_string(),
+                    diff(DiffOperation::Merge, json!({"b": 3, "c": 4})),
+                ),
+                (
+                    "failures".to_string(),
+                    diff(DiffOperation::RemoveFromArray, json!("x")),
+                ),
+                (
+                    "count".to_string(),
+                    diff(DiffOperation::Increment, json!(3)),
+                ),
+                (
+                    "new".to_string(),
+                    diff(DiffOperation::Increment, json!(1.5)),
+                ),
+            ]),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(state["config"], json!({"a": 1, "b": 3, "c": 4}));
+        assert_eq!(state["failures"], json!(["y"]));
+        assert_eq!(state["count"], json!(5));
+        assert_eq!(state["new"], json!(1.5));
+        assert_eq!(revisions["count"], 1);
+        assert_eq!(revisions["new"], 1);
+    }
+
+    #[test]
+    fn test_apply_state_diff_rejects_stale_revision() {
+        let mut state = HashMap::new();
+        let mut revisions = HashMap::new();
+        let mut update = |value: Value, expected_revision| {
+            let field_diff = FieldDiff {
+                expected_revision,
+                ..diff(DiffOperation::Update, value)
+            };
+            apply_state_diff(
+                &mut state,
+                &mut revisions,
+                &HashMap::from([("key".to_string(), field_diff)]),
+                None,
+            )
+        };
+
+        // A missing key is at revision 0
+        update(json!(1), Some(0)).unwrap();
+        update(json!(2), Some(1)).unwrap();
+        let error = update(json!(3), Some(1)).unwrap_err();
+        assert!(matches!(error, Error::StateConflict(_)));
+        update(json!(3), None).unwrap();
+
+        assert_eq!(state["key"], json!(3));
+        assert_eq!(revisions["key"], 3);
+    }
+
+    #[test]
+    fn test_apply_state_diff_is_all_or_nothing() {
+        let mut state = HashMap::from([("name".to_string(), json!("x"))]);
+        let mut revisions = HashMap::new();
+
+        let result = apply_state_diff(
+            &mut state,
+            &mut revisions,
+            &HashMap::from([
+                (
+                    "count".to_string(),
+                    diff(DiffOperation::Increment, json!(1)),
+                ),
+                ("name".to_string(), diff(DiffOperation::Increment, json!(1))),
+            ]),
+            None,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(state, HashMap::from([("name".to_string(), json!("x"))]));
+        assert!(revisions.is_empty());
+    }
+
+    #[test]
+    fn test_apply_state_diff_checks_schema() {
+        let schema: WorkflowState = serde_json::from_value(json!({
+            "strict": true,
+            "schema": [{
+                "name": "files",
+                "type": "array",
+                "items": {"type": "object", "properties": {"path": {"type": "string"}}},
+            }],
+        }))
+        .unwrap();
+        let mut state = HashMap::new();
+        let mut revisions = HashMap::new();
+        let mut apply = |key: &str, operation, value| {
+            apply_state_diff(
+                &mut state,
+                &mut revisions,
+                &HashMap::from([(key.to_string(), diff(operation, value))]),
+                Some(&schema),
+            )
+        };
+
+        apply("files", DiffOperation::Append, json!({"path": "a.ts"})).unwrap();
+        // Appending turns the array into a value that no longer matches
+        assert!(apply("files", DiffOperation::Append, json!({"path": 1})).is_err());
+        assert!(apply("files", DiffOperation::Update, json!("a.ts")).is_err());
+        assert!(apply("other", DiffOperation::Update, json!(1)).is_err());
+
+        assert_eq!(state["files"], json!([{"path": "a.ts"}]));
+    }
+}
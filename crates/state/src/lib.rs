@@ -4,32 +4,64 @@ use async_trait::async_trait;
 use serde_json::Value;
 use uuid::Uuid;
 
-use butterflow_models::{Result, StateDiff, Task, TaskDiff, WorkflowRun, WorkflowRunDiff};
+use butterflow_models::{
+    LogLine, Result, StateDiff, StateJournalEntry, Task, TaskDiff, WorkflowRun, WorkflowRunDiff,
+};
 
 pub mod cloud_adapter;
+mod diff;
 pub mod local_adapter;
+mod locks;
 pub mod mock_adapter;
+mod query;
+pub mod sqlite_adapter;
+
+pub use diff::apply_operation;
+pub use query::{RunOrder, RunPage, RunQuery};
 
 /// State adapter trait for persisting workflow state
+///
+/// All methods take `&self` so a single adapter can be shared by tasks running
+/// in parallel. Implementations synchronize internally, and apply each diff
+/// atomically with respect to other writes to the same record.
 #[async_trait]
 pub trait StateAdapter: Send + Sync {
     /// Save a workflow run
-    async fn save_workflow_run(&mut self, workflow_run: &WorkflowRun) -> Result<()>;
+    async fn save_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()>;
 
     /// Apply a diff to a workflow run
-    async fn apply_workflow_run_diff(&mut self, diff: &WorkflowRunDiff) -> Result<()>;
+    async fn apply_workflow_run_diff(&self, diff: &WorkflowRunDiff) -> Result<()>;
 
     /// Get a workflow run
     async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun>;
 
-    /// List workflow runs
-    async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>>;
+    /// Find the workflow runs matching a query, a page at a time
+    async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage>;
+
+    /// List the most recently started workflow runs
+    async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>> {
+        let query = RunQuery {
+            limit: Some(limit),
+            ..RunQuery::default()
+        };
+        Ok(self.query_workflow_runs(&query).await?.runs)
+    }
+
+    /// Delete workflow runs along with their tasks, state and logs
+    ///
+    /// IDs of runs that don't exist are ignored.
+    async fn delete_workflow_runs(&self, workflow_run_ids: &[Uuid]) -> Result<()>;
+
+    /// Delete a workflow run along with its tasks, state and logs
+    async fn delete_workflow_run(&self, workflow_run_id: Uuid) -> Result<()> {
+        self.delete_workflow_runs(&[workflow_run_id]).await
+    }
 
     /// Save a task
-    async fn save_task(&mut self, task: &Task) -> Result<()>;
+    async fn save_task(&self, task: &Task) -> Result<()>;
 
     /// Apply a diff to a task
-    async fn apply_task_diff(&mut self, diff: &TaskDiff) -> Result<()>;
+    async fn apply_task_diff(&self, diff: &TaskDiff) -> Result<()>;
 
     /// Get a task
     async fn get_task(&self, task_id: Uuid) -> Result<Task>;
@@ -37,16 +69,37 @@ pub trait StateAdapter: Send + Sync {
     /// Get all tasks for a workflow run
     async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>>;
 
+    /// Append lines of command output to the log of a task
+    async fn append_task_logs(&self, task_id: Uuid, lines: &[LogLine]) -> Result<()>;
+
+    /// Get the log of a task, skipping the first `offset` lines
+    async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>>;
+
     /// Update workflow state
     async fn update_state(
-        &mut self,
+        &self,
         workflow_run_id: Uuid,
         state: HashMap<String, Value>,
     ) -> Result<()>;
 
     /// Apply a diff to workflow state
-    async fn apply_state_diff(&mut self, diff: &StateDiff) -> Result<()>;
+    ///
+    /// Fails with `Error::StateConflict` without changing anything if a field
+    /// expects a revision other than the current one.
+    async fn apply_state_diff(&self, diff: &StateDiff) -> Result<()>;
 
     /// Get workflow state
     async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>>;
+
+    /// Get the revision of each workflow state key
+    ///
+    /// Every write to a key bumps its revision; keys that were never written
+    /// are missing, which is the same as revision 0.
+    async fn get_state_revisions(&self, workflow_run_id: Uuid) -> Result<HashMap<String, u64>>;
+
+    /// Get every write to workflow state, oldest first
+    ///
+    /// Adapters append an entry for each applied diff and each state update
+    /// that changed something, and never rewrite earlier entries.
+    async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>>;
 }
@@ -1,16 +1,22 @@
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
 use async_trait::async_trait;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use uuid::Uuid;
 
 use butterflow_models::{
-    DiffOperation, Error, Result, StateDiff, Task, TaskDiff, WorkflowRun, WorkflowRunDiff,
+    Error, LogLine, Result, StateDiff, StateJournalEntry, Task, TaskDiff, WorkflowRun,
+    WorkflowRunDiff, WorkflowState,
 };
 
-use crate::StateAdapter;
+use crate::diff::{apply_record_diff, apply_state_diff, journal_entry, replace_state};
+use crate::locks::{read, write, RecordLocks};
+use crate::{RunPage, RunQuery, StateAdapter};
 
 /// Local state adapter (stores state in local files)
 pub struct LocalStateAdapter {
@@ -18,10 +24,19 @@ pub struct LocalStateAdapter {
     base_dir: PathBuf,
 
     /// Workflow runs
-    workflow_runs: HashMap<Uuid, WorkflowRun>,
+    workflow_runs: RwLock<HashMap<Uuid, WorkflowRun>>,
 
     /// Tasks
-    tasks: HashMap<Uuid, Task>,
+    tasks: RwLock<HashMap<Uuid, Task>>,
+
+    /// Serialize writes to the same workflow run or task
+    record_locks: RecordLocks,
+
+    /// Serialize writes to the state of the same workflow run
+    state_locks: RecordLocks,
+
+    /// Serialize access to the log of the same task
+    log_locks: RecordLocks,
 }
 
 impl Default for LocalStateAdapter {
@@ -45,8 +60,11 @@ impl LocalStateAdapter {
 
         Self {
             base_dir: data_dir,
-            workflow_runs: HashMap::new(),
-            tasks: HashMap::new(),
+            workflow_runs: RwLock::default(),
+            tasks: RwLock::default(),
+            record_locks: RecordLocks::default(),
+            state_locks: RecordLocks::default(),
+            log_locks: RecordLocks::default(),
         }
     }
 
@@ -59,8 +77,11 @@ impl LocalStateAdapter {
 
         Self {
             base_dir: base_dir.as_ref().to_path_buf(),
-            workflow_runs: HashMap::new(),
-            tasks: HashMap::new(),
+            workflow_runs: RwLock::default(),
+            tasks: RwLock::default(),
+            record_locks: RecordLocks::default(),
+            state_locks: RecordLocks::default(),
+            log_locks: RecordLocks::default(),
         }
     }
 
@@ -83,6 +104,25 @@ impl LocalStateAdapter {
             .join(format!("{workflow_run_id}.json"))
     }
 
+    /// Get the path to a task's log file
+    fn task_log_path(&self, task_id: Uuid) -> PathBuf {
+        self.base_dir.join("logs").join(format!("{task_id}.jsonl"))
+    }
+
+    /// Get the path to the state revisions file
+    fn state_revisions_path(&self, workflow_run_id: Uuid) -> PathBuf {
+        self.base_dir
+            .join("state")
+            .join(format!("{workflow_run_id}.revisions.json"))
+    }
+
+    /// Get the path to the state journal file
+    fn state_journal_path(&self, workflow_run_id: Uuid) -> PathBuf {
+        self.base_dir
+            .join("state")
+            .join(format!("{workflow_run_id}.journal.jsonl"))
+    }
+
     /// Load a workflow run from disk
     fn load_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
         let path = self.workflow_run_path(workflow_run_id);
@@ -121,28 +161,68 @@ impl LocalStateAdapter {
         Ok(task)
     }
 
-    /// Load workflow state from disk
-    fn load_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
-        let path = self.state_path(workflow_run_id);
+    /// Load the tasks on disk that match `filter`
+    fn load_tasks(&self, filter: impl Fn(&Task) -> bool) -> Result<Vec<Task>> {
+        // Create the tasks directory if it doesn't exist
+        let dir = self.base_dir.join("tasks");
+        fs::create_dir_all(&dir)?;
 
-        // Check if the file exists
-        if !path.exists() {
-            return Ok(HashMap::new());
+        // List all files in the directory
+        let entries = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .path()
+                    .extension()
+                    .map(|ext| ext == "json")
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+
+        // Load the tasks
+        let mut tasks = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+            let file_name = path.file_stem().unwrap().to_string_lossy();
+            if let Ok(task_id) = Uuid::parse_str(&file_name) {
+                match self.load_task(task_id) {
+                    Ok(task) if filter(&task) => tasks.push(task),
+                    Ok(_) => {}
+                    // Deleted since the directory was listed
+                    Err(_) if !path.exists() => {}
+                    Err(e) => return Err(e),
+                }
+            }
         }
 
-        // Read the file
-        let content = fs::read_to_string(path)?;
+        Ok(tasks)
+    }
 
-        // Parse the JSON
-        let state = serde_json::from_str(&content)?;
+    /// Get a workflow run from the cache or disk
+    fn cached_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
+        if let Some(workflow_run) = read(&self.workflow_runs).get(&workflow_run_id) {
+            return Ok(workflow_run.clone());
+        }
+        self.load_workflow_run(workflow_run_id)
+    }
 
-        Ok(state)
+    /// Get a task from the cache or disk
+    fn cached_task(&self, task_id: Uuid) -> Result<Task> {
+        if let Some(task) = read(&self.tasks).get(&task_id) {
+            return Ok(task.clone());
+        }
+        self.load_task(task_id)
     }
-}
 
-#[async_trait]
-impl StateAdapter for LocalStateAdapter {
-    async fn save_workflow_run(&mut self, workflow_run: &WorkflowRun) -> Result<()> {
+    /// Get the state schema of a workflow run, if the run was saved
+    fn schema(&self, workflow_run_id: Uuid) -> Option<WorkflowState> {
+        self.cached_workflow_run(workflow_run_id)
+            .ok()
+            .and_then(|workflow_run| workflow_run.workflow.state)
+    }
+
+    /// Write a workflow run to disk and the cache
+    fn store_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()> {
         // Create the workflow_runs directory if it doesn't exist
         let dir = self.base_dir.join("workflow_runs");
         fs::create_dir_all(&dir)?;
@@ -152,99 +232,174 @@ impl StateAdapter for LocalStateAdapter {
 
         // Write to disk
         let path = self.workflow_run_path(workflow_run.id);
-        fs::write(path, json)?;
+        write_atomically(&path, &json)?;
 
         // Update in-memory cache
-        self.workflow_runs
-            .insert(workflow_run.id, workflow_run.clone());
+        write(&self.workflow_runs).insert(workflow_run.id, workflow_run.clone());
 
         Ok(())
     }
 
-    async fn apply_workflow_run_diff(&mut self, diff: &WorkflowRunDiff) -> Result<()> {
-        // Get the workflow run
-        let mut workflow_run = self.get_workflow_run(diff.workflow_run_id).await?;
+    /// Write a task to disk and the cache
+    fn store_task(&self, task: &Task) -> Result<()> {
+        // Create the tasks directory if it doesn't exist
+        let dir = self.base_dir.join("tasks");
+        fs::create_dir_all(&dir)?;
 
-        // Apply the diff
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                DiffOperation::Add | DiffOperation::Update | DiffOperation::Append => {
-                    if let Some(value) = &field_diff.value {
-                        // Convert the workflow run to a JSON value
-                        let mut workflow_run_value = serde_json::to_value(&workflow_run)?;
+        // Serialize the task
+        let json = serde_json::to_string_pretty(task)?;
 
-                        // Update the field
-                        if let serde_json::Value::Object(obj) = &mut workflow_run_value {
-                            obj.insert(field.clone(), value.clone());
-                        }
+        // Write to disk
+        let path = self.task_path(task.id);
+        write_atomically(&path, &json)?;
 
-                        // Convert back to a workflow run
-                        workflow_run = serde_json::from_value(workflow_run_value)?;
-                    }
-                }
-                DiffOperation::Remove => {
-                    // Convert the workflow run to a JSON value
-                    let mut workflow_run_value = serde_json::to_value(&workflow_run)?;
+        // Update in-memory cache
+        write(&self.tasks).insert(task.id, task.clone());
 
-                    // Remove the field
-                    if let serde_json::Value::Object(obj) = &mut workflow_run_value {
-                        obj.remove(field);
-                    }
+        Ok(())
+    }
 
-                    // Convert back to a workflow run
-                    workflow_run = serde_json::from_value(workflow_run_value)?;
-                }
-            }
+    /// Write workflow state and its revisions to disk
+    fn store_state(
+        &self,
+        workflow_run_id: Uuid,
+        state: &HashMap<String, Value>,
+        revisions: &HashMap<String, u64>,
+    ) -> Result<()> {
+        // Create the state directory if it doesn't exist
+        let dir = self.base_dir.join("state");
+        fs::create_dir_all(&dir)?;
+
+        // Revisions go first: if writing the state fails, they are ahead of
+        // it and compare-and-swap reports a conflict instead of losing data
+        write_atomically(
+            &self.state_revisions_path(workflow_run_id),
+            &serde_json::to_string_pretty(revisions)?,
+        )?;
+        write_atomically(
+            &self.state_path(workflow_run_id),
+            &serde_json::to_string_pretty(state)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Load workflow state from disk
+    fn load_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
+        load_map(&self.state_path(workflow_run_id))
+    }
+
+    /// Load workflow state revisions from disk
+    fn load_state_revisions(&self, workflow_run_id: Uuid) -> Result<HashMap<String, u64>> {
+        load_map(&self.state_revisions_path(workflow_run_id))
+    }
+
+    /// Load the state journal from disk
+    fn load_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
+        let path = self.state_journal_path(workflow_run_id);
+        if !path.exists() {
+            return Ok(Vec::new());
         }
 
+        fs::read_to_string(path)?
+            .lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    /// Append a write to the state journal, with the state after it
+    ///
+    /// Must be called while holding the state lock of the run.
+    fn journal(&self, diff: &StateDiff, state: &HashMap<String, Value>) -> Result<()> {
+        let path = self.state_journal_path(diff.workflow_run_id);
+        let sequence = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().count() as u64 + 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut line = serde_json::to_string(&journal_entry(sequence, diff, state))?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Load a JSON object from disk, or an empty map if the file doesn't exist
+fn load_map<T: DeserializeOwned>(path: &Path) -> Result<HashMap<String, T>> {
+    // Check if the file exists
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    // Read the file
+    let content = fs::read_to_string(path)?;
+
+    // Parse the JSON
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Replace the content of a file
+///
+/// The content goes to a temporary file in the same directory that is then
+/// renamed over `path`, so readers, which don't take the record locks, see
+/// either the old or the new content and never a truncated file.
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{file_name}.{}.tmp", Uuid::new_v4()));
+    if let Err(e) = fs::write(&temp_path, content).and_then(|()| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Remove a file, doing nothing if it doesn't exist
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl StateAdapter for LocalStateAdapter {
+    async fn save_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()> {
+        let _guard = self.record_locks.lock(workflow_run.id).await;
+        self.store_workflow_run(workflow_run)
+    }
+
+    async fn apply_workflow_run_diff(&self, diff: &WorkflowRunDiff) -> Result<()> {
+        let _guard = self.record_locks.lock(diff.workflow_run_id).await;
+
+        // Get the workflow run and apply the diff
+        let workflow_run = self.cached_workflow_run(diff.workflow_run_id)?;
+        let workflow_run = apply_record_diff(&workflow_run, &diff.fields)?;
+
         // Save the updated workflow run
-        self.save_workflow_run(&workflow_run).await
+        self.store_workflow_run(&workflow_run)
     }
 
     async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
-        // Check if the workflow run is in the cache
-        if let Some(workflow_run) = self.workflow_runs.get(&workflow_run_id) {
-            return Ok(workflow_run.clone());
-        }
-
-        // Load from disk
-        self.load_workflow_run(workflow_run_id)
+        self.cached_workflow_run(workflow_run_id)
     }
 
-    async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>> {
+    async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage> {
         // Create the workflow_runs directory if it doesn't exist
         let dir = self.base_dir.join("workflow_runs");
         fs::create_dir_all(&dir)?;
 
-        // List all files in the directory
-        let mut entries = fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry
-                    .path()
-                    .extension()
-                    .map(|ext| ext == "json")
-                    .unwrap_or(false)
-            })
-            .collect::<Vec<_>>();
-
-        // Sort by modification time (newest first)
-        entries.sort_by(|a, b| {
-            let a_time = a
-                .metadata()
-                .and_then(|m| m.modified())
-                .unwrap_or_else(|_| std::time::SystemTime::now());
-            let b_time = b
-                .metadata()
-                .and_then(|m| m.modified())
-                .unwrap_or_else(|_| std::time::SystemTime::now());
-            b_time.cmp(&a_time)
-        });
-
-        // Load the workflow runs
+        // Load every run, skipping files that aren't runs
         let mut workflow_runs = Vec::new();
-        for entry in entries.iter().take(limit) {
+        for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
             let path = entry.path();
+            if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                continue;
+            }
             let file_name = path.file_stem().unwrap().to_string_lossy();
             if let Ok(workflow_run_id) = Uuid::parse_str(&file_name) {
                 if let Ok(workflow_run) = self.load_workflow_run(workflow_run_id) {
@@ -253,175 +408,230 @@ impl StateAdapter for LocalStateAdapter {
             }
         }
 
-        Ok(workflow_runs)
+        query.apply(workflow_runs)
     }
 
-    async fn save_task(&mut self, task: &Task) -> Result<()> {
-        // Create the tasks directory if it doesn't exist
-        let dir = self.base_dir.join("tasks");
-        fs::create_dir_all(&dir)?;
-
-        // Serialize the task
-        let json = serde_json::to_string_pretty(task)?;
-
-        // Write to disk
-        let path = self.task_path(task.id);
-        fs::write(path, json)?;
+    async fn delete_workflow_runs(&self, workflow_run_ids: &[Uuid]) -> Result<()> {
+        // Find the tasks of all runs in a single pass over the task files
+        let tasks = self.load_tasks(|task| workflow_run_ids.contains(&task.workflow_run_id))?;
+        for task in tasks {
+            let _log_guard = self.log_locks.lock(task.id).await;
+            let _guard = self.record_locks.lock(task.id).await;
+            remove_file(&self.task_log_path(task.id))?;
+            remove_file(&self.task_path(task.id))?;
+            write(&self.tasks).remove(&task.id);
+        }
 
-        // Update in-memory cache
-        self.tasks.insert(task.id, task.clone());
+        // The run goes last, so an interrupted delete can be retried
+        for &workflow_run_id in workflow_run_ids {
+            let _guard = self.record_locks.lock(workflow_run_id).await;
+            remove_file(&self.state_journal_path(workflow_run_id))?;
+            remove_file(&self.state_revisions_path(workflow_run_id))?;
+            remove_file(&self.state_path(workflow_run_id))?;
+            remove_file(&self.workflow_run_path(workflow_run_id))?;
+            write(&self.workflow_runs).remove(&workflow_run_id);
+        }
 
         Ok(())
     }
 
-    async fn get_task(&self, task_id: Uuid) -> Result<Task> {
-        // Check if the task is in the cache
-        if let Some(task) = self.tasks.get(&task_id) {
-            return Ok(task.clone());
-        }
+    async fn save_task(&self, task: &Task) -> Result<()> {
+        let _guard = self.record_locks.lock(task.id).await;
+        self.store_task(task)
+    }
 
-        // Load from disk
-        self.load_task(task_id)
+    async fn get_task(&self, task_id: Uuid) -> Result<Task> {
+        self.cached_task(task_id)
     }
 
     async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
-        // Create the tasks directory if it doesn't exist
-        let dir = self.base_dir.join("tasks");
+        self.load_tasks(|task| task.workflow_run_id == workflow_run_id)
+    }
+
+    async fn append_task_logs(&self, task_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let _guard = self.log_locks.lock(task_id).await;
+
+        // Create the logs directory if it doesn't exist
+        let dir = self.base_dir.join("logs");
         fs::create_dir_all(&dir)?;
 
-        // List all files in the directory
-        let entries = fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry
-                    .path()
-                    .extension()
-                    .map(|ext| ext == "json")
-                    .unwrap_or(false)
-            })
-            .collect::<Vec<_>>();
+        // One JSON object per line, so appending never rewrites the file
+        let mut content = String::new();
+        for line in lines {
+            content.push_str(&serde_json::to_string(line)?);
+            content.push('\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.task_log_path(task_id))?
+            .write_all(content.as_bytes())?;
 
-        // Load the tasks
-        let mut tasks = Vec::new();
-        for entry in entries {
-            let path = entry.path();
-            let file_name = path.file_stem().unwrap().to_string_lossy();
-            if let Ok(task_id) = Uuid::parse_str(&file_name) {
-                if let Ok(task) = self.load_task(task_id) {
-                    if task.workflow_run_id == workflow_run_id {
-                        tasks.push(task);
-                    }
-                }
-            }
+        Ok(())
+    }
+
+    async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>> {
+        let _guard = self.log_locks.lock(task_id).await;
+
+        let path = self.task_log_path(task_id);
+        if !path.exists() {
+            return Ok(Vec::new());
         }
 
-        Ok(tasks)
+        fs::read_to_string(path)?
+            .lines()
+            .skip(offset)
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
     }
 
     async fn update_state(
-        &mut self,
+        &self,
         workflow_run_id: Uuid,
         state: HashMap<String, Value>,
     ) -> Result<()> {
-        // Create the state directory if it doesn't exist
-        let dir = self.base_dir.join("state");
-        fs::create_dir_all(&dir)?;
+        let _guard = self.state_locks.lock(workflow_run_id).await;
 
-        // Serialize the state
-        let json = serde_json::to_string_pretty(&state)?;
+        let mut current = self.load_state(workflow_run_id)?;
+        let mut revisions = self.load_state_revisions(workflow_run_id)?;
+        let schema = self.schema(workflow_run_id);
+        let fields = replace_state(&mut current, &mut revisions, state, schema.as_ref())?;
 
-        // Write to disk
-        let path = self.state_path(workflow_run_id);
-        fs::write(path, json)?;
-
-        Ok(())
+        self.store_state(workflow_run_id, &current, &revisions)?;
+        if fields.is_empty() {
+            return Ok(());
+        }
+        let diff = StateDiff {
+            workflow_run_id,
+            fields,
+            task_id: None,
+            step: None,
+        };
+        self.journal(&diff, &current)
     }
 
-    async fn apply_task_diff(&mut self, diff: &TaskDiff) -> Result<()> {
-        // Get the task
-        let mut task = self.get_task(diff.task_id).await?;
+    async fn apply_task_diff(&self, diff: &TaskDiff) -> Result<()> {
+        let _guard = self.record_locks.lock(diff.task_id).await;
 
-        // Apply the diff
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                DiffOperation::Add | DiffOperation::Update | DiffOperation::Append => {
-                    if let Some(value) = &field_diff.value {
-                        // Convert the task to a JSON value
-                        let mut task_value = serde_json::to_value(&task)?;
+        // Get the task and apply the diff
+        let task = self.cached_task(diff.task_id)?;
+        let task = apply_record_diff(&task, &diff.fields)?;
 
-                        // Update the field
-                        if let serde_json::Value::Object(obj) = &mut task_value {
-                            obj.insert(field.clone(), value.clone());
-                        }
+        // Save the updated task
+        self.store_task(&task)
+    }
 
-                        // Convert back to a task
-                        task = serde_json::from_value(task_value)?;
-                    }
-                }
-                DiffOperation::Remove => {
-                    // Convert the task to a JSON value
-                    let mut task_value = serde_json::to_value(&task)?;
+    async fn apply_state_diff(&self, diff: &StateDiff) -> Result<()> {
+        let _guard = self.state_locks.lock(diff.workflow_run_id).await;
 
-                    // Remove the field
-                    if let serde_json::Value::Object(obj) = &mut task_value {
-                        obj.remove(field);
-                    }
+        // Get the current state and apply the diff
+        let mut state = self.load_state(diff.workflow_run_id)?;
+        let mut revisions = self.load_state_revisions(diff.workflow_run_id)?;
+        let schema = self.schema(diff.workflow_run_id);
+        apply_state_diff(&mut state, &mut revisions, &diff.fields, schema.as_ref())?;
 
-                    // Convert back to a task
-                    task = serde_json::from_value(task_value)?;
-                }
-            }
-        }
+        // Save the updated state, then record the write
+        self.store_state(diff.workflow_run_id, &state, &revisions)?;
+        self.journal(diff, &state)
+    }
 
-        // Save the updated task
-        self.save_task(&task).await
+    async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
+        self.load_state(workflow_run_id)
     }
 
-    async fn apply_state_diff(&mut self, diff: &StateDiff) -> Result<()> {
-        // Get the current state
-        let mut state = self.get_state(diff.workflow_run_id).await?;
+    async fn get_state_revisions(&self, workflow_run_id: Uuid) -> Result<HashMap<String, u64>> {
+        self.load_state_revisions(workflow_run_id)
+    }
 
-        // Apply the diff
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                DiffOperation::Add | DiffOperation::Update => {
-                    if let Some(value) = &field_diff.value {
-                        state.insert(field.clone(), value.clone());
-                    }
-                }
-                DiffOperation::Remove => {
-                    state.remove(field);
-                }
-                DiffOperation::Append => {
-                    // TODO: We may want reconsider this when we add state validation
-                    if let Some(new_value) = &field_diff.value {
-                        if let Some(existing) = state.get_mut(field) {
-                            // If the existing value is an array, append to it
-                            if let serde_json::Value::Array(arr) = existing {
-                                arr.push(new_value.clone());
-                            } else {
-                                // If the existing value is not an array, replace it with a new array containing both values
-                                let old_value = existing.clone();
-                                *existing =
-                                    serde_json::Value::Array(vec![old_value, new_value.clone()]);
-                            }
-                        } else {
-                            // Field doesn't exist yet, create a new array with just this value
-                            state.insert(
-                                field.clone(),
-                                serde_json::Value::Array(vec![new_value.clone()]),
-                            );
-                        }
-                    }
+    async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
+        let _guard = self.state_locks.lock(workflow_run_id).await;
+        self.load_state_journal(workflow_run_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use butterflow_models::{DiffOperation, FieldDiff};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_state_diffs() {
+        let dir = tempfile::tempdir().unwrap();
+        let adapter = Arc::new(LocalStateAdapter::with_base_dir(dir.path()));
+        let workflow_run_id = Uuid::new_v4();
+
+        // Tasks share one adapter, so diffs to the same state must not race
+        let writers = (0..4).map(|writer| {
+            let adapter = Arc::clone(&adapter);
+            tokio::spawn(async move {
+                for item in 0..25 {
+                    adapter
+                        .apply_state_diff(&StateDiff {
+                            workflow_run_id,
+                            fields: HashMap::from([(
+                                "items".to_string(),
+                                FieldDiff {
+                                    operation: DiffOperation::Append,
+                                    value: Some(serde_json::json!(writer * 100 + item)),
+                                    expected_revision: None,
+                                },
+                            )]),
+                            task_id: None,
+                            step: None,
+                        })
+                        .await
+                        .unwrap();
                 }
-            }
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
         }
 
-        // Save the updated state
-        self.update_state(diff.workflow_run_id, state).await
+        let state = adapter.get_state(workflow_run_id).await.unwrap();
+        assert_eq!(state["items"].as_array().unwrap().len(), 100);
     }
 
-    async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
-        self.load_state(workflow_run_id)
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reads_during_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let adapter = Arc::new(LocalStateAdapter::with_base_dir(dir.path()));
+        let workflow_run_id = Uuid::new_v4();
+        let mut task = Task::new(workflow_run_id, "node".to_string(), false);
+        task.logs = vec!["x".repeat(10_000)];
+        adapter.save_task(&task).await.unwrap();
+
+        let writer = {
+            let adapter = Arc::clone(&adapter);
+            tokio::spawn(async move {
+                for item in 0..50 {
+                    adapter.save_task(&task).await.unwrap();
+                    adapter
+                        .update_state(
+                            workflow_run_id,
+                            HashMap::from([("item".to_string(), serde_json::json!(item))]),
+                        )
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+
+        // Readers don't take the locks, but never see a file being written
+        while !writer.is_finished() {
+            assert_eq!(adapter.get_tasks(workflow_run_id).await.unwrap().len(), 1);
+            adapter.get_state(workflow_run_id).await.unwrap();
+        }
+        writer.await.unwrap();
+
+        // No temporary files are left behind
+        for dir in ["tasks", "state"] {
+            for entry in fs::read_dir(adapter.base_dir.join(dir)).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(!name.to_string_lossy().ends_with(".tmp"), "{name:?}");
+            }
+        }
     }
 }
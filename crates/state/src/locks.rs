@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+/// Number of locks kept before unused ones are dropped
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Locks keyed by record id, so writes to different records don't wait on each other
+#[derive(Default)]
+pub(crate) struct RecordLocks {
+    locks: Mutex<HashMap<Uuid, Arc<AsyncMutex<()>>>>,
+}
+
+impl RecordLocks {
+    /// Wait for exclusive access to a record
+    pub(crate) async fn lock(&self, id: Uuid) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            // Locks only referenced by the map are neither held nor awaited
+            if locks.len() >= PRUNE_THRESHOLD {
+                locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            }
+            Arc::clone(locks.entry(id).or_default())
+        };
+
+        lock.lock_owned().await
+    }
+}
+
+// Cached maps stay consistent even if a holder panicked, so poisoning is ignored
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
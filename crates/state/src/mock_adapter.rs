@@ -1,16 +1,24 @@
 use std::collections::HashMap;
+use std::sync::RwLock;
 
 use uuid::Uuid;
 
-use butterflow_models::{Result, Task, WorkflowRun};
+use butterflow_models::{
+    LogLine, Result, StateDiff, StateJournalEntry, Task, WorkflowRun, WorkflowState,
+};
 
-use crate::StateAdapter;
+use crate::diff::{apply_record_diff, apply_state_diff, journal_entry, replace_state};
+use crate::locks::{read, write};
+use crate::{RunPage, RunQuery, StateAdapter};
 
 // Mock state adapter for testing
 pub struct MockStateAdapter {
-    workflow_runs: HashMap<Uuid, WorkflowRun>,
-    tasks: HashMap<Uuid, Task>,
-    state: HashMap<String, serde_json::Value>,
+    workflow_runs: RwLock<HashMap<Uuid, WorkflowRun>>,
+    tasks: RwLock<HashMap<Uuid, Task>>,
+    state: RwLock<HashMap<String, serde_json::Value>>,
+    revisions: RwLock<HashMap<String, u64>>,
+    logs: RwLock<HashMap<Uuid, Vec<LogLine>>>,
+    journals: RwLock<HashMap<Uuid, Vec<StateJournalEntry>>>,
 }
 
 impl Default for MockStateAdapter {
@@ -22,55 +30,56 @@ impl Default for MockStateAdapter {
 impl MockStateAdapter {
     pub fn new() -> Self {
         Self {
-            workflow_runs: HashMap::new(),
-            tasks: HashMap::new(),
-            state: HashMap::new(),
+            workflow_runs: RwLock::new(HashMap::new()),
+            tasks: RwLock::new(HashMap::new()),
+            state: RwLock::new(HashMap::new()),
+            revisions: RwLock::new(HashMap::new()),
+            logs: RwLock::new(HashMap::new()),
+            journals: RwLock::new(HashMap::new()),
         }
     }
+
+    /// Get the state schema of a saved workflow run
+    fn schema(&self, workflow_run_id: Uuid) -> Option<WorkflowState> {
+        read(&self.workflow_runs)
+            .get(&workflow_run_id)
+            .and_then(|workflow_run| workflow_run.workflow.state.clone())
+    }
+
+    /// Record a write to the state of a workflow run
+    fn journal(&self, diff: &StateDiff, state: &HashMap<String, serde_json::Value>) {
+        let mut journals = write(&self.journals);
+        let journal = journals.entry(diff.workflow_run_id).or_default();
+        journal.push(journal_entry(journal.len() as u64 + 1, diff, state));
+    }
 }
 
 #[async_trait::async_trait]
 impl StateAdapter for MockStateAdapter {
-    async fn save_workflow_run(&mut self, workflow_run: &WorkflowRun) -> Result<()> {
-        self.workflow_runs
-            .insert(workflow_run.id, workflow_run.clone());
+    async fn save_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()> {
+        write(&self.workflow_runs).insert(workflow_run.id, workflow_run.clone());
         Ok(())
     }
 
     async fn apply_workflow_run_diff(
-        &mut self,
+        &self,
         diff: &butterflow_models::WorkflowRunDiff,
     ) -> Result<()> {
-        let mut workflow_run = self.get_workflow_run(diff.workflow_run_id).await?;
-
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                butterflow_models::DiffOperation::Add
-                | butterflow_models::DiffOperation::Update
-                | butterflow_models::DiffOperation::Append => {
-                    if let Some(value) = &field_diff.value {
-                        let mut workflow_run_value = serde_json::to_value(&workflow_run)?;
-                        if let serde_json::Value::Object(obj) = &mut workflow_run_value {
-                            obj.insert(field.clone(), value.clone());
-                        }
-                        workflow_run = serde_json::from_value(workflow_run_value)?;
-                    }
-                }
-                butterflow_models::DiffOperation::Remove => {
-                    let mut workflow_run_value = serde_json::to_value(&workflow_run)?;
-                    if let serde_json::Value::Object(obj) = &mut workflow_run_value {
-                        obj.remove(field);
-                    }
-                    workflow_run = serde_json::from_value(workflow_run_value)?;
-                }
-            }
-        }
-
-        self.save_workflow_run(&workflow_run).await
+        let mut workflow_runs = write(&self.workflow_runs);
+        let workflow_run = workflow_runs
+            .get_mut(&diff.workflow_run_id)
+            .ok_or_else(|| {
+                butterflow_models::Error::Other(format!(
+                    "Workflow run {} not found",
+                    diff.workflow_run_id
+                ))
+            })?;
+        *workflow_run = apply_record_diff(workflow_run, &diff.fields)?;
+        Ok(())
     }
 
     async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
-        self.workflow_runs
+        read(&self.workflow_runs)
             .get(&workflow_run_id)
             .cloned()
             .ok_or_else(|| {
@@ -78,116 +87,119 @@ impl StateAdapter for MockStateAdapter {
             })
     }
 
-    async fn list_workflow_runs(&self, limit: usize) -> Result<Vec<WorkflowRun>> {
-        let mut runs: Vec<WorkflowRun> = self.workflow_runs.values().cloned().collect();
-        runs.sort_by(|a, b| b.started_at.cmp(&a.started_at));
-        Ok(runs.into_iter().take(limit).collect())
+    async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage> {
+        query.apply(read(&self.workflow_runs).values().cloned())
     }
 
-    async fn save_task(&mut self, task: &Task) -> Result<()> {
-        self.tasks.insert(task.id, task.clone());
+    async fn delete_workflow_runs(&self, workflow_run_ids: &[Uuid]) -> Result<()> {
+        let mut tasks = write(&self.tasks);
+        let mut logs = write(&self.logs);
+        tasks.retain(|task_id, task| {
+            let deleted = workflow_run_ids.contains(&task.workflow_run_id);
+            if deleted {
+                logs.remove(task_id);
+            }
+            !deleted
+        });
+
+        // The state is shared by all runs, so it stays
+        write(&self.journals).retain(|id, _| !workflow_run_ids.contains(id));
+        write(&self.workflow_runs).retain(|id, _| !workflow_run_ids.contains(id));
         Ok(())
     }
 
-    async fn apply_task_diff(&mut self, diff: &butterflow_models::TaskDiff) -> Result<()> {
-        let mut task = self.get_task(diff.task_id).await?;
-
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                butterflow_models::DiffOperation::Add
-                | butterflow_models::DiffOperation::Update
-                | butterflow_models::DiffOperation::Append => {
-                    if let Some(value) = &field_diff.value {
-                        let mut task_value = serde_json::to_value(&task)?;
-                        if let serde_json::Value::Object(obj) = &mut task_value {
-                            obj.insert(field.clone(), value.clone());
-                        }
-                        task = serde_json::from_value(task_value)?;
-                    }
-                }
-                butterflow_models::DiffOperation::Remove => {
-                    let mut task_value = serde_json::to_value(&task)?;
-                    if let serde_json::Value::Object(obj) = &mut task_value {
-                        obj.remove(field);
-                    }
-                    task = serde_json::from_value(task_value)?;
-                }
-            }
-        }
+    async fn save_task(&self, task: &Task) -> Result<()> {
+        write(&self.tasks).insert(task.id, task.clone());
+        Ok(())
+    }
 
-        self.save_task(&task).await
+    async fn apply_task_diff(&self, diff: &butterflow_models::TaskDiff) -> Result<()> {
+        let mut tasks = write(&self.tasks);
+        let task = tasks.get_mut(&diff.task_id).ok_or_else(|| {
+            butterflow_models::Error::Other(format!("Task {} not found", diff.task_id))
+        })?;
+        *task = apply_record_diff(task, &diff.fields)?;
+        Ok(())
     }
 
     async fn get_task(&self, task_id: Uuid) -> Result<Task> {
-        self.tasks
+        read(&self.tasks)
             .get(&task_id)
             .cloned()
             .ok_or_else(|| butterflow_models::Error::Other(format!("Task {task_id} not found")))
     }
 
     async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
-        Ok(self
-            .tasks
+        Ok(read(&self.tasks)
             .values()
             .filter(|t| t.workflow_run_id == workflow_run_id)
             .cloned()
             .collect())
     }
 
+    async fn append_task_logs(&self, task_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        write(&self.logs)
+            .entry(task_id)
+            .or_default()
+            .extend_from_slice(lines);
+        Ok(())
+    }
+
+    async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>> {
+        Ok(read(&self.logs)
+            .get(&task_id)
+            .map(|lines| lines.iter().skip(offset).cloned().collect())
+            .unwrap_or_default())
+    }
+
     async fn update_state(
-        &mut self,
-        _workflow_run_id: Uuid,
+        &self,
+        workflow_run_id: Uuid,
         state: HashMap<String, serde_json::Value>,
     ) -> Result<()> {
-        self.state = state;
+        let schema = self.schema(workflow_run_id);
+
+        // Lock in the same order everywhere so readers see matching revisions
+        let mut revisions = write(&self.revisions);
+        let mut current = write(&self.state);
+        let fields = replace_state(&mut current, &mut revisions, state, schema.as_ref())?;
+        if !fields.is_empty() {
+            let diff = StateDiff {
+                workflow_run_id,
+                fields,
+                task_id: None,
+                step: None,
+            };
+            self.journal(&diff, &current);
+        }
         Ok(())
     }
 
-    async fn apply_state_diff(&mut self, diff: &butterflow_models::StateDiff) -> Result<()> {
-        let mut state = self.get_state(diff.workflow_run_id).await?;
-
-        for (field, field_diff) in &diff.fields {
-            match field_diff.operation {
-                butterflow_models::DiffOperation::Add
-                | butterflow_models::DiffOperation::Update => {
-                    if let Some(value) = &field_diff.value {
-                        state.insert(field.clone(), value.clone());
-                    }
-                }
-                butterflow_models::DiffOperation::Remove => {
-                    state.remove(field);
-                }
-                butterflow_models::DiffOperation::Append => {
-                    if let Some(new_value) = &field_diff.value {
-                        if let Some(existing) = state.get_mut(field) {
-                            // If the existing value is an array, append to it
-                            if let serde_json::Value::Array(arr) = existing {
-                                arr.push(new_value.clone());
-                            } else {
-                                // If the existing value is not an array, replace it with a new array containing both values
-                                let old_value = existing.clone();
-                                *existing =
-                                    serde_json::Value::Array(vec![old_value, new_value.clone()]);
-                            }
-                        } else {
-                            // Field doesn't exist yet, create a new array with just this value
-                            state.insert(
-                                field.clone(),
-                                serde_json::Value::Array(vec![new_value.clone()]),
-                            );
-                        }
-                    }
-                }
-            }
-        }
+    async fn apply_state_diff(&self, diff: &StateDiff) -> Result<()> {
+        let schema = self.schema(diff.workflow_run_id);
 
-        self.update_state(diff.workflow_run_id, state).await
+        let mut revisions = write(&self.revisions);
+        let mut state = write(&self.state);
+        apply_state_diff(&mut state, &mut revisions, &diff.fields, schema.as_ref())?;
+        self.journal(diff, &state);
+        Ok(())
     }
 
     async fn get_state(
         &self,
         _workflow_run_id: Uuid,
     ) -> Result<HashMap<String, serde_json::Value>> {
-        Ok(self.state.clone())
+        Ok(read(&self.state).clone())
+    }
+
+    async fn get_state_revisions(&self, _workflow_run_id: Uuid) -> Result<HashMap<String, u64>> {
+        Ok(read(&self.revisions).clone())
+    }
+
+    async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
+        Ok(read(&self.journals)
+            .get(&workflow_run_id)
+            .cloned()
+            .unwrap_or_default())
     }
 }
@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use butterflow_models::{Error, Result, WorkflowRun, WorkflowStatus};
+
+/// Which workflow runs to list, and in what order
+///
+/// Runs match if they pass every filter that is set. Pages are sorted by
+/// start time, and `cursor` continues after the last run of the previous
+/// page, so runs saved in the meantime don't shift later pages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunQuery {
+    /// Only runs with one of these statuses, any status if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<WorkflowStatus>,
+
+    /// Only runs started at or after this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_after: Option<DateTime<Utc>>,
+
+    /// Only runs started before this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_before: Option<DateTime<Utc>>,
+
+    /// Only runs of the workflow with this source, e.g. a package name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// Only runs whose target is this directory or below it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_path: Option<PathBuf>,
+
+    /// Only runs with all of these parameter values
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub params: HashMap<String, String>,
+
+    /// Order of the runs
+    #[serde(default)]
+    pub order: RunOrder,
+
+    /// Maximum number of runs per page, all runs if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+
+    /// `next_cursor` of the previous page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// Order of listed workflow runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunOrder {
+    /// Most recently started first
+    #[default]
+    NewestFirst,
+
+    /// Least recently started first
+    OldestFirst,
+}
+
+/// A page of workflow runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunPage {
+    /// Runs on this page
+    pub runs: Vec<WorkflowRun>,
+
+    /// Cursor for the next page, if there are more runs
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+}
+
+/// Position of a run in the listing: start time in microseconds, then ID
+pub(crate) type RunKey = (i64, Uuid);
+
+impl RunQuery {
+    /// Check a run against the filters, ignoring the cursor
+    pub fn matches(&self, workflow_run: &WorkflowRun) -> bool {
+        (self.statuses.is_empty() || self.statuses.contains(&workflow_run.status))
+            && self
+                .started_after
+                .map_or(true, |after| workflow_run.started_at >= after)
+            && self
+                .started_before
+                .map_or(true, |before| workflow_run.started_at < before)
+            && self
+                .source
+                .as_ref()
+                .map_or(true, |source| workflow_run.source.as_ref() == Some(source))
+            && self.target_path.as_ref().map_or(true, |target_path| {
+                workflow_run
+                    .target_path
+                    .as_ref()
+                    .is_some_and(|path| path.starts_with(target_path))
+            })
+            && self
+                .params
+                .iter()
+                .all(|(key, value)| workflow_run.params.get(key) == Some(value))
+    }
+
+    /// Filter, sort and paginate runs in memory
+    pub fn apply(&self, workflow_runs: impl IntoIterator<Item = WorkflowRun>) -> Result<RunPage> {
+        let cursor = self.parse_cursor()?;
+        let mut runs = workflow_runs
+            .into_iter()
+            .filter(|run| {
+                self.matches(run)
+                    && cursor.map_or(true, |cursor| self.is_after(run_key(run), cursor))
+            })
+            .collect::<Vec<_>>();
+        runs.sort_by(|a, b| self.compare(run_key(a), run_key(b)));
+        Ok(self.paginate(runs))
+    }
+
+    /// Cut matching runs, already in order, down to a page
+    ///
+    /// `runs` may hold one run more than the limit, to tell whether there is
+    /// a next page.
+    pub(crate) fn paginate(&self, mut runs: Vec<WorkflowRun>) -> RunPage {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let next_cursor = if runs.len() > limit {
+            runs.truncate(limit);
+            runs.last().map(|run| {
+                let (started_at, id) = run_key(run);
+                format!("{started_at}:{id}")
+            })
+        } else {
+            None
+        };
+        RunPage { runs, next_cursor }
+    }
+
+    /// Decode the cursor into the key of the last run of the previous page
+    pub(crate) fn parse_cursor(&self) -> Result<Option<RunKey>> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        let invalid = || Error::Other(format!("Invalid cursor: {cursor}"));
+        let (started_at, id) = cursor.split_once(':').ok_or_else(invalid)?;
+        Ok(Some((
+            started_at.parse().map_err(|_| invalid())?,
+            id.parse().map_err(|_| invalid())?,
+        )))
+    }
+
+    /// Whether a run comes after the cursor in the order of the query
+    fn is_after(&self, key: RunKey, cursor: RunKey) -> bool {
+        self.compare(key, cursor) == Ordering::Greater
+    }
+
+    /// Compare run keys in the order of the query
+    fn compare(&self, a: RunKey, b: RunKey) -> Ordering {
+        match self.order {
+            RunOrder::NewestFirst => b.cmp(&a),
+            RunOrder::OldestFirst => a.cmp(&b),
+        }
+    }
+}
+
+/// Key a run is listed by
+pub(crate) fn run_key(workflow_run: &WorkflowRun) -> RunKey {
+    (workflow_run.started_at.timestamp_micros(), workflow_run.id)
+}
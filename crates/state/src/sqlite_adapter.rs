@@ -0,0 +1,922 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use uuid::Uuid;
+
+use butterflow_models::{
+    Error, LogLine, Result, StateDiff, StateJournalEntry, Task, TaskDiff, WorkflowRun,
+    WorkflowRunDiff, WorkflowState,
+};
+
+use crate::diff::{apply_record_diff, apply_state_diff, journal_entry, replace_state};
+use crate::{RunOrder, RunPage, RunQuery, StateAdapter};
+
+/// Version of the database schema, stored in `PRAGMA user_version`
+const SCHEMA_VERSION: i32 = 4;
+
+/// How long to wait for another process holding the database lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// SQLite state adapter (stores state in a single database file)
+///
+/// Every write, including applying a diff, runs in its own transaction, so
+/// concurrent writers never overwrite each other's changes and a crash leaves
+/// either the old or the new record behind. Tasks are indexed by workflow run.
+///
+/// Each call checks out its own connection, so reads don't wait for other
+/// calls and writers only contend on SQLite's own write lock.
+pub struct SqliteStateAdapter {
+    /// Database file, or `None` for an in-memory database
+    path: Option<PathBuf>,
+
+    /// Idle connections ready to be checked out
+    idle: Mutex<Vec<Connection>>,
+
+    /// The only connection of an in-memory database, which can't be reopened
+    memory: Option<Mutex<Connection>>,
+}
+
+/// A connection checked out of the adapter
+enum PooledConnection<'a> {
+    /// Returned to the idle list when dropped
+    File(Option<Connection>, &'a Mutex<Vec<Connection>>),
+    /// Held exclusively until dropped
+    Memory(MutexGuard<'a, Connection>),
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Self::File(connection, _) => connection.as_ref().expect("connection is checked out"),
+            Self::Memory(connection) => connection,
+        }
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match self {
+            Self::File(connection, _) => connection.as_mut().expect("connection is checked out"),
+            Self::Memory(connection) => connection,
+        }
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Self::File(connection, idle) = self {
+            if let Some(connection) = connection.take() {
+                idle.lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(connection);
+            }
+        }
+    }
+}
+
+impl SqliteStateAdapter {
+    /// Open the database in the default data directory
+    pub fn new() -> Result<Self> {
+        let data_dir = dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("butterflow");
+
+        Self::with_path(data_dir.join("state.db"))
+    }
+
+    /// Open the database at a custom path, creating it if it doesn't exist
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let mut connection = open_connection(&path)?;
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            path: Some(path),
+            idle: Mutex::new(vec![connection]),
+            memory: None,
+        })
+    }
+
+    /// Open a database that only lives in memory
+    pub fn in_memory() -> Result<Self> {
+        let mut connection = Connection::open_in_memory().map_err(sqlite_error)?;
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            path: None,
+            idle: Mutex::new(Vec::new()),
+            memory: Some(Mutex::new(connection)),
+        })
+    }
+
+    /// Check out an idle connection, opening a new one if all are in use
+    fn connection(&self) -> Result<PooledConnection<'_>> {
+        if let Some(memory) = &self.memory {
+            return Ok(PooledConnection::Memory(
+                memory.lock().unwrap_or_else(PoisonError::into_inner),
+            ));
+        }
+
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let connection = match (idle, &self.path) {
+            (Some(connection), _) => connection,
+            (None, Some(path)) => open_connection(path)?,
+            (None, None) => unreachable!("file databases always have a path"),
+        };
+
+        Ok(PooledConnection::File(Some(connection), &self.idle))
+    }
+}
+
+/// Open a connection to a database file
+fn open_connection(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path).map_err(sqlite_error)?;
+    connection
+        .busy_timeout(BUSY_TIMEOUT)
+        .map_err(sqlite_error)?;
+    // Write-ahead logging lets readers run alongside a writer, and full
+    // syncs make committed transactions survive a crash
+    connection
+        .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+        .map_err(sqlite_error)?;
+    connection
+        .pragma_update(None, "synchronous", "FULL")
+        .map_err(sqlite_error)?;
+    Ok(connection)
+}
+
+/// Create or upgrade the database schema
+///
+/// Runs in a single write transaction, so processes opening the same database
+/// at once don't apply a migration twice.
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    let version: i32 = transaction
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(sqlite_error)?;
+
+    if version > SCHEMA_VERSION {
+        return Err(Error::State(format!(
+            "State database schema version {version} is newer than the supported version {SCHEMA_VERSION}"
+        )));
+    }
+
+    if version < 1 {
+        transaction
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS workflow_runs (
+                    id TEXT PRIMARY KEY,
+                    started_at INTEGER NOT NULL,
+                    data TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS workflow_runs_started_at
+                    ON workflow_runs (started_at);
+                CREATE TABLE IF NOT EXISTS tasks (
+                    id TEXT PRIMARY KEY,
+                    workflow_run_id TEXT NOT NULL,
+                    data TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS tasks_workflow_run_id
+                    ON tasks (workflow_run_id);
+                CREATE TABLE IF NOT EXISTS state (
+                    workflow_run_id TEXT PRIMARY KEY,
+                    data TEXT NOT NULL
+                );",
+            )
+            .map_err(sqlite_error)?;
+    }
+
+    if version < 2 {
+        transaction
+            .execute_batch("ALTER TABLE state ADD COLUMN revisions TEXT NOT NULL DEFAULT '{}';")
+            .map_err(sqlite_error)?;
+    }
+
+    if version < 3 {
+        transaction
+            .execute_batch(
+                "CREATE TABLE task_logs (
+                    id INTEGER PRIMARY KEY,
+                    task_id TEXT NOT NULL,
+                    data TEXT NOT NULL
+                );
+                CREATE INDEX task_logs_task_id ON task_logs (task_id, id);",
+            )
+            .map_err(sqlite_error)?;
+    }
+
+    if version < 4 {
+        transaction
+            .execute_batch(
+                "CREATE TABLE state_journal (
+                    workflow_run_id TEXT NOT NULL,
+                    sequence INTEGER NOT NULL,
+                    data TEXT NOT NULL,
+                    PRIMARY KEY (workflow_run_id, sequence)
+                );",
+            )
+            .map_err(sqlite_error)?;
+    }
+
+    transaction
+        .pragma_update(None, "user_version", SCHEMA_VERSION)
+        .map_err(sqlite_error)?;
+    transaction.commit().map_err(sqlite_error)
+}
+
+/// Start a transaction that takes the write lock right away
+///
+/// Taking the lock up front makes read-modify-write cycles atomic across
+/// processes sharing the database.
+fn write_transaction(connection: &mut Connection) -> Result<Transaction<'_>> {
+    connection
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(sqlite_error)
+}
+
+fn load_workflow_run(connection: &Connection, workflow_run_id: Uuid) -> Result<WorkflowRun> {
+    let data: Option<String> = connection
+        .query_row(
+            "SELECT data FROM workflow_runs WHERE id = ?1",
+            params![workflow_run_id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_error)?;
+
+    match data {
+        Some(data) => Ok(serde_json::from_str(&data)?),
+        None => Err(Error::Other(format!(
+            "Workflow run {workflow_run_id} not found"
+        ))),
+    }
+}
+
+fn store_workflow_run(connection: &Connection, workflow_run: &WorkflowRun) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO workflow_runs (id, started_at, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET started_at = excluded.started_at, data = excluded.data",
+            params![
+                workflow_run.id.to_string(),
+                workflow_run.started_at.timestamp_micros(),
+                serde_json::to_string(workflow_run)?,
+            ],
+        )
+        .map_err(sqlite_error)?;
+    Ok(())
+}
+
+fn load_task(connection: &Connection, task_id: Uuid) -> Result<Task> {
+    let data: Option<String> = connection
+        .query_row(
+            "SELECT data FROM tasks WHERE id = ?1",
+            params![task_id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_error)?;
+
+    match data {
+        Some(data) => Ok(serde_json::from_str(&data)?),
+        None => Err(Error::Other(format!("Task {task_id} not found"))),
+    }
+}
+
+fn store_task(connection: &Connection, task: &Task) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO tasks (id, workflow_run_id, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET
+                workflow_run_id = excluded.workflow_run_id, data = excluded.data",
+            params![
+                task.id.to_string(),
+                task.workflow_run_id.to_string(),
+                serde_json::to_string(task)?,
+            ],
+        )
+        .map_err(sqlite_error)?;
+    Ok(())
+}
+
+/// Get the state schema of a workflow run, if the run was saved
+fn load_schema(connection: &Connection, workflow_run_id: Uuid) -> Option<WorkflowState> {
+    load_workflow_run(connection, workflow_run_id)
+        .ok()
+        .and_then(|workflow_run| workflow_run.workflow.state)
+}
+
+fn load_state(connection: &Connection, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
+    load_state_column(connection, "data", workflow_run_id)
+}
+
+fn load_state_revisions(
+    connection: &Connection,
+    workflow_run_id: Uuid,
+) -> Result<HashMap<String, u64>> {
+    load_state_column(connection, "revisions", workflow_run_id)
+}
+
+fn load_state_column<T: DeserializeOwned>(
+    connection: &Connection,
+    column: &str,
+    workflow_run_id: Uuid,
+) -> Result<HashMap<String, T>> {
+    let data: Option<String> = connection
+        .query_row(
+            &format!("SELECT {column} FROM state WHERE workflow_run_id = ?1"),
+            params![workflow_run_id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_error)?;
+
+    match data {
+        Some(data) => Ok(serde_json::from_str(&data)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+fn store_state(
+    connection: &Connection,
+    workflow_run_id: Uuid,
+    state: &HashMap<String, Value>,
+    revisions: &HashMap<String, u64>,
+) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO state (workflow_run_id, data, revisions) VALUES (?1, ?2, ?3)
+             ON CONFLICT (workflow_run_id)
+             DO UPDATE SET data = excluded.data, revisions = excluded.revisions",
+            params![
+                workflow_run_id.to_string(),
+                serde_json::to_string(state)?,
+                serde_json::to_string(revisions)?
+            ],
+        )
+        .map_err(sqlite_error)?;
+    Ok(())
+}
+
+/// Record a write to state, with the state after it
+fn append_state_journal(
+    connection: &Connection,
+    diff: &StateDiff,
+    state: &HashMap<String, Value>,
+) -> Result<()> {
+    let workflow_run_id = diff.workflow_run_id.to_string();
+    let sequence: i64 = connection
+        .query_row(
+            "SELECT COALESCE(MAX(sequence), 0) + 1 FROM state_journal WHERE workflow_run_id = ?1",
+            params![workflow_run_id],
+            |row| row.get(0),
+        )
+        .map_err(sqlite_error)?;
+    let entry = journal_entry(sequence as u64, diff, state);
+    connection
+        .execute(
+            "INSERT INTO state_journal (workflow_run_id, sequence, data) VALUES (?1, ?2, ?3)",
+            params![workflow_run_id, sequence, serde_json::to_string(&entry)?],
+        )
+        .map_err(sqlite_error)?;
+    Ok(())
+}
+
+fn sqlite_error(error: rusqlite::Error) -> Error {
+    Error::State(format!("SQLite error: {error}"))
+}
+
+#[async_trait]
+impl StateAdapter for SqliteStateAdapter {
+    async fn save_workflow_run(&self, workflow_run: &WorkflowRun) -> Result<()> {
+        store_workflow_run(&*self.connection()?, workflow_run)
+    }
+
+    async fn apply_workflow_run_diff(&self, diff: &WorkflowRunDiff) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        let workflow_run = load_workflow_run(&transaction, diff.workflow_run_id)?;
+        let workflow_run = apply_record_diff(&workflow_run, &diff.fields)?;
+        store_workflow_run(&transaction, &workflow_run)?;
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn get_workflow_run(&self, workflow_run_id: Uuid) -> Result<WorkflowRun> {
+        load_workflow_run(&*self.connection()?, workflow_run_id)
+    }
+
+    async fn query_workflow_runs(&self, query: &RunQuery) -> Result<RunPage> {
+        let (direction, comparison) = match query.order {
+            RunOrder::NewestFirst => ("DESC", "<"),
+            RunOrder::OldestFirst => ("ASC", ">"),
+        };
+        let cursor = query.parse_cursor()?;
+
+        // The time range and cursor narrow down the rows, the other filters
+        // need the run itself
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT data FROM workflow_runs
+                 WHERE started_at >= ?1 AND started_at < ?2
+                    AND (?3 IS NULL OR started_at {comparison} ?3
+                        OR (started_at = ?3 AND id {comparison} ?4))
+                 ORDER BY started_at {direction}, id {direction}"
+            ))
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(
+                params![
+                    query
+                        .started_after
+                        .map_or(i64::MIN, |after| after.timestamp_micros()),
+                    query
+                        .started_before
+                        .map_or(i64::MAX, |before| before.timestamp_micros()),
+                    cursor.map(|(started_at, _)| started_at),
+                    cursor.map(|(_, id)| id.to_string()),
+                ],
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(sqlite_error)?;
+
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let mut workflow_runs = Vec::new();
+        for data in rows {
+            let workflow_run: WorkflowRun = serde_json::from_str(&data.map_err(sqlite_error)?)?;
+            if query.matches(&workflow_run) {
+                workflow_runs.push(workflow_run);
+                // A run past the limit tells that there is a next page
+                if workflow_runs.len() > limit {
+                    break;
+                }
+            }
+        }
+
+        Ok(query.paginate(workflow_runs))
+    }
+
+    async fn delete_workflow_runs(&self, workflow_run_ids: &[Uuid]) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        for workflow_run_id in workflow_run_ids {
+            transaction
+                .execute(
+                    "DELETE FROM task_logs WHERE task_id IN
+                        (SELECT id FROM tasks WHERE workflow_run_id = ?1)",
+                    params![workflow_run_id.to_string()],
+                )
+                .map_err(sqlite_error)?;
+            for table in ["tasks", "state", "state_journal"] {
+                transaction
+                    .execute(
+                        &format!("DELETE FROM {table} WHERE workflow_run_id = ?1"),
+                        params![workflow_run_id.to_string()],
+                    )
+                    .map_err(sqlite_error)?;
+            }
+            transaction
+                .execute(
+                    "DELETE FROM workflow_runs WHERE id = ?1",
+                    params![workflow_run_id.to_string()],
+                )
+                .map_err(sqlite_error)?;
+        }
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn save_task(&self, task: &Task) -> Result<()> {
+        store_task(&*self.connection()?, task)
+    }
+
+    async fn apply_task_diff(&self, diff: &TaskDiff) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        let task = load_task(&transaction, diff.task_id)?;
+        let task = apply_record_diff(&task, &diff.fields)?;
+        store_task(&transaction, &task)?;
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn get_task(&self, task_id: Uuid) -> Result<Task> {
+        load_task(&*self.connection()?, task_id)
+    }
+
+    async fn get_tasks(&self, workflow_run_id: Uuid) -> Result<Vec<Task>> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT data FROM tasks WHERE workflow_run_id = ?1")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(params![workflow_run_id.to_string()], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(sqlite_error)?;
+
+        let mut tasks = Vec::new();
+        for data in rows {
+            tasks.push(serde_json::from_str(&data.map_err(sqlite_error)?)?);
+        }
+
+        Ok(tasks)
+    }
+
+    async fn append_task_logs(&self, task_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        {
+            let mut statement = transaction
+                .prepare_cached("INSERT INTO task_logs (task_id, data) VALUES (?1, ?2)")
+                .map_err(sqlite_error)?;
+            for line in lines {
+                statement
+                    .execute(params![task_id.to_string(), serde_json::to_string(line)?])
+                    .map_err(sqlite_error)?;
+            }
+        }
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn get_task_logs(&self, task_id: Uuid, offset: usize) -> Result<Vec<LogLine>> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT data FROM task_logs WHERE task_id = ?1 ORDER BY id LIMIT -1 OFFSET ?2")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(params![task_id.to_string(), offset as i64], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(sqlite_error)?;
+
+        let mut lines = Vec::new();
+        for data in rows {
+            lines.push(serde_json::from_str(&data.map_err(sqlite_error)?)?);
+        }
+
+        Ok(lines)
+    }
+
+    async fn update_state(
+        &self,
+        workflow_run_id: Uuid,
+        state: HashMap<String, Value>,
+    ) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        let mut current = load_state(&transaction, workflow_run_id)?;
+        let mut revisions = load_state_revisions(&transaction, workflow_run_id)?;
+        let schema = load_schema(&transaction, workflow_run_id);
+        let fields = replace_state(&mut current, &mut revisions, state, schema.as_ref())?;
+        store_state(&transaction, workflow_run_id, &current, &revisions)?;
+        if !fields.is_empty() {
+            let diff = StateDiff {
+                workflow_run_id,
+                fields,
+                task_id: None,
+                step: None,
+            };
+            append_state_journal(&transaction, &diff, &current)?;
+        }
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn apply_state_diff(&self, diff: &StateDiff) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = write_transaction(&mut connection)?;
+
+        let mut state = load_state(&transaction, diff.workflow_run_id)?;
+        let mut revisions = load_state_revisions(&transaction, diff.workflow_run_id)?;
+        let schema = load_schema(&transaction, diff.workflow_run_id);
+        apply_state_diff(&mut state, &mut revisions, &diff.fields, schema.as_ref())?;
+        store_state(&transaction, diff.workflow_run_id, &state, &revisions)?;
+        append_state_journal(&transaction, diff, &state)?;
+
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    async fn get_state(&self, workflow_run_id: Uuid) -> Result<HashMap<String, Value>> {
+        load_state(&*self.connection()?, workflow_run_id)
+    }
+
+    async fn get_state_revisions(&self, workflow_run_id: Uuid) -> Result<HashMap<String, u64>> {
+        load_state_revisions(&*self.connection()?, workflow_run_id)
+    }
+
+    async fn get_state_journal(&self, workflow_run_id: Uuid) -> Result<Vec<StateJournalEntry>> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT data FROM state_journal WHERE workflow_run_id = ?1 ORDER BY sequence")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(params![workflow_run_id.to_string()], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(sqlite_error)?;
+
+        let mut entries = Vec::new();
+        for data in rows {
+            entries.push(serde_json::from_str(&data.map_err(sqlite_error)?)?);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use butterflow_models::{
+        DiffOperation, FieldDiff, LogStream, TaskStatus, Workflow, WorkflowStatus,
+    };
+    use chrono::{Duration as ChronoDuration, Utc};
+
+    fn workflow_run(started_ago: i64) -> WorkflowRun {
+        WorkflowRun {
+            id: Uuid::new_v4(),
+            workflow: Workflow {
+                version: "1".to_string(),
+                state: None,
+                templates: vec![],
+                nodes: vec![],
+                concurrency: None,
+            },
+            status: WorkflowStatus::Pending,
+            params: HashMap::new(),
+            bundle_path: None,
+            source: None,
+            target_path: None,
+            tasks: vec![],
+            started_at: Utc::now() - ChronoDuration::minutes(started_ago),
+            ended_at: None,
+        }
+    }
+
+    fn update(field: &str, value: Value) -> HashMap<String, FieldDiff> {
+        HashMap::from([(
+            field.to_string(),
+            FieldDiff {
+                operation: DiffOperation::Update,
+                value: Some(value),
+                expected_revision: None,
+            },
+        )])
+    }
+
+    #[tokio::test]
+    async fn test_persists_runs_and_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+
+        let older = workflow_run(10);
+        let newer = workflow_run(1);
+        let task = Task::new(newer.id, "node".to_string(), false);
+        {
+            let adapter = SqliteStateAdapter::with_path(&path).unwrap();
+            adapter.save_workflow_run(&older).await.unwrap();
+            adapter.save_workflow_run(&newer).await.unwrap();
+            adapter.save_task(&task).await.unwrap();
+            adapter
+                .save_task(&Task::new(older.id, "node".to_string(), false))
+                .await
+                .unwrap();
+            adapter
+                .apply_task_diff(&TaskDiff {
+                    task_id: task.id,
+                    fields: update("status", serde_json::json!(TaskStatus::Completed)),
+                })
+                .await
+                .unwrap();
+            adapter
+                .apply_workflow_run_diff(&WorkflowRunDiff {
+                    workflow_run_id: newer.id,
+                    fields: update("status", serde_json::json!(WorkflowStatus::Completed)),
+                })
+                .await
+                .unwrap();
+            adapter
+                .append_task_logs(
+                    task.id,
+                    &[
+                        LogLine::new(LogStream::Stdout, "first".to_string()),
+                        LogLine::new(LogStream::Stderr, "second".to_string()),
+                    ],
+                )
+                .await
+                .unwrap();
+        }
+
+        // Everything is still there after reopening the database
+        let adapter = SqliteStateAdapter::with_path(&path).unwrap();
+        let runs = adapter.list_workflow_runs(10).await.unwrap();
+        assert_eq!(
+            runs.iter().map(|run| run.id).collect::<Vec<_>>(),
+            vec![newer.id, older.id]
+        );
+        assert_eq!(runs[0].status, WorkflowStatus::Completed);
+        assert_eq!(adapter.list_workflow_runs(1).await.unwrap().len(), 1);
+
+        let tasks = adapter.get_tasks(newer.id).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].status, TaskStatus::Completed);
+        assert!(adapter.get_task(Uuid::new_v4()).await.is_err());
+
+        let logs = adapter.get_task_logs(task.id, 1).await.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].stream, LogStream::Stderr);
+        assert_eq!(logs[0].line, "second");
+
+        // Deleting a run takes its tasks and logs with it
+        adapter.delete_workflow_run(newer.id).await.unwrap();
+        assert!(adapter.get_workflow_run(newer.id).await.is_err());
+        assert!(adapter.get_tasks(newer.id).await.unwrap().is_empty());
+        assert!(adapter.get_task_logs(task.id, 0).await.unwrap().is_empty());
+        assert_eq!(adapter.get_tasks(older.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_queries_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let adapter = SqliteStateAdapter::with_path(dir.path().join("state.db")).unwrap();
+
+        let mut runs = Vec::new();
+        for started_ago in 0..5 {
+            let mut run = workflow_run(started_ago);
+            run.status = if started_ago % 2 == 0 {
+                WorkflowStatus::Failed
+            } else {
+                WorkflowStatus::Completed
+            };
+            run.source = Some("@org/react-19".to_string());
+            run.params = HashMap::from([("mode".to_string(), started_ago.to_string())]);
+            adapter.save_workflow_run(&run).await.unwrap();
+            runs.push(run);
+        }
+        adapter
+            .save_workflow_run(&WorkflowRun {
+                status: WorkflowStatus::Failed,
+                ..workflow_run(2)
+            })
+            .await
+            .unwrap();
+
+        // Failed runs of the package, two per page, newest first
+        let mut query = RunQuery {
+            statuses: vec![WorkflowStatus::Failed],
+            source: Some("@org/react-19".to_string()),
+            limit: Some(2),
+            ..RunQuery::default()
+        };
+        let page = adapter.query_workflow_runs(&query).await.unwrap();
+        let ids = page.runs.iter().map(|run| run.id).collect::<Vec<_>>();
+        assert_eq!(ids, [runs[0].id, runs[2].id]);
+
+        query.cursor = page.next_cursor;
+        let page = adapter.query_workflow_runs(&query).await.unwrap();
+        let ids = page.runs.iter().map(|run| run.id).collect::<Vec<_>>();
+        assert_eq!(ids, [runs[4].id]);
+        assert!(page.next_cursor.is_none());
+
+        // Time range and params, oldest first
+        let query = RunQuery {
+            started_after: Some(runs[3].started_at),
+            params: HashMap::from([("mode".to_string(), "1".to_string())]),
+            order: RunOrder::OldestFirst,
+            ..RunQuery::default()
+        };
+        let page = adapter.query_workflow_runs(&query).await.unwrap();
+        let ids = page.runs.iter().map(|run| run.id).collect::<Vec<_>>();
+        assert_eq!(ids, [runs[1].id]);
+    }
+
+    #[tokio::test]
+    async fn test_journals_state_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let adapter = SqliteStateAdapter::with_path(dir.path().join("state.db")).unwrap();
+        let workflow_run_id = Uuid::new_v4();
+        let task_id = Uuid::new_v4();
+
+        adapter
+            .update_state(
+                workflow_run_id,
+                HashMap::from([
+                    ("a".to_string(), Value::from(1)),
+                    ("b".to_string(), Value::from(1)),
+                ]),
+            )
+            .await
+            .unwrap();
+        adapter
+            .apply_state_diff(&StateDiff {
+                workflow_run_id,
+                fields: update("a", Value::from(2)),
+                task_id: Some(task_id),
+                step: Some("Write".to_string()),
+            })
+            .await
+            .unwrap();
+        adapter
+            .update_state(
+                workflow_run_id,
+                HashMap::from([("a".to_string(), Value::from(2))]),
+            )
+            .await
+            .unwrap();
+
+        let journal = adapter.get_state_journal(workflow_run_id).await.unwrap();
+        assert_eq!(
+            journal
+                .iter()
+                .map(|entry| entry.sequence)
+                .collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(journal[1].task_id, Some(task_id));
+        assert_eq!(journal[1].step.as_deref(), Some("Write"));
+        assert_eq!(journal[1].values["a"], Value::from(2));
+
+        // Replaying the journal gives the state after each write
+        let mut state = HashMap::new();
+        for entry in &journal[..2] {
+            entry.replay(&mut state);
+        }
+        assert_eq!(state["a"], Value::from(2));
+        assert_eq!(state["b"], Value::from(1));
+        journal[2].replay(&mut state);
+        assert!(!state.contains_key("b"));
+        assert_eq!(state, adapter.get_state(workflow_run_id).await.unwrap());
+
+        adapter.delete_workflow_run(workflow_run_id).await.unwrap();
+        assert!(adapter
+            .get_state_journal(workflow_run_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_state_diffs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        let workflow_run_id = Uuid::new_v4();
+
+        // Separate connections behave like separate processes
+        let writers = (0..4).map(|writer| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                let adapter = SqliteStateAdapter::with_path(path).unwrap();
+                for item in 0..25 {
+                    adapter
+                        .apply_state_diff(&StateDiff {
+                            workflow_run_id,
+                            fields: HashMap::from([(
+                                "items".to_string(),
+                                FieldDiff {
+                                    operation: DiffOperation::Append,
+                                    value: Some(serde_json::json!(writer * 100 + item)),
+                                    expected_revision: None,
+                                },
+                            )]),
+                            task_id: None,
+                            step: None,
+                        })
+                        .await
+                        .unwrap();
+                }
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
+        }
+
+        let adapter = SqliteStateAdapter::with_path(&path).unwrap();
+        let state = adapter.get_state(workflow_run_id).await.unwrap();
+        assert_eq!(state["items"].as_array().unwrap().len(), 100);
+    }
+}
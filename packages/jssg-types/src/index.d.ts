@@ -1,7 +1,92 @@
+/**
+ * A codemod module is loaded once per worker and its `transform` called for
+ * every file that worker picks up, so top-level variables keep their values
+ * between files. Which files share a worker isn't defined, and the module
+ * may be loaded again at any time, so only use top-level variables for
+ * caches that don't change the result.
+ */
 declare module "codemod:ast-grep" {
   export * from "@codemod.com/jssg-types/main";
 }
 
+/**
+ * Writes to the state of the workflow running the codemod. They are applied
+ * once the step has processed every file, and dropped for files whose
+ * transform throws.
+ */
+declare module "codemod:workflow" {
+  /** Set a state key, before the appends and increments of any file; when several files set it, one of the values wins */
+  export function setState(key: string, value: unknown): void;
+  /** Append a value to the array at a state key */
+  export function appendState(key: string, value: unknown): void;
+  /** Add to the number at a state key, counting from zero */
+  export function increment(key: string, by?: number): void;
+  /** Append a finding to the array at a state key, tagged with the current file */
+  export function report(key: string, finding: Record<string, unknown>): void;
+
+  const workflow: {
+    setState: typeof setState;
+    appendState: typeof appendState;
+    increment: typeof increment;
+    report: typeof report;
+  };
+  export default workflow;
+}
+
+/**
+ * Changes to files other than the one being transformed. They are applied
+ * together once every file has been processed, all of them or none, and
+ * dropped for files whose transform throws. If the changes of two files,
+ * returned content included, touch the same path, both files are skipped. Relative paths are relative to
+ * the directory of the current file, and every path must be under the
+ * target directory.
+ */
+declare module "codemod:files" {
+  /** Write a file, creating it and its directory if needed */
+  export function writeFile(path: string, content: string): void;
+  /** Rename or move the current file, along with the content the transform returns */
+  export function renameFile(to: string): void;
+  /** Delete a file, the current one by default */
+  export function deleteFile(path?: string): void;
+
+  const files: {
+    writeFile: typeof writeFile;
+    renameFile: typeof renameFile;
+    deleteFile: typeof deleteFile;
+  };
+  export default files;
+}
+
+/**
+ * Read-only access to the project the codemod runs on. Files the step has
+ * already transformed are read with their new content. Relative paths are
+ * relative to the directory of the current file, and reading anything
+ * outside of the project root, symlinks included, fails.
+ */
+declare module "codemod:project" {
+  import type { SgRoot } from "@codemod.com/jssg-types/main";
+
+  /** Absolute path of the project root */
+  export function root(): string;
+  /** Resolve an import of the current file to the absolute path of the project file it refers to */
+  export function resolveImport(specifier: string): Promise<string | null>;
+  /** Read a project file */
+  export function readFile(path: string): Promise<string>;
+  /** Parse a project file, in the language its extension implies unless given */
+  export function parseFile(path: string, language?: string): Promise<SgRoot>;
+  /** List the files under a directory of the project, the whole project by default, leaving out ignored files */
+  export function listFiles(dir?: string): Promise<string[]>;
+
+  const project: {
+    root: typeof root;
+    resolveImport: typeof resolveImport;
+    readFile: typeof readFile;
+    parseFile: typeof parseFile;
+    listFiles: typeof listFiles;
+  };
+  export default project;
+}
+
 declare module "codemod:ast-grep/langs/angular" {
   export { default } from "@codemod.com/jssg-types/langs/angular";
 }
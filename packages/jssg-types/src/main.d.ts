@@ -327,6 +327,18 @@ export interface Range {
   end: Position;
 }
 
+/** Second argument of a codemod's `transform`; frozen, so read-only */
+export interface TransformOptions {
+  /** Workflow parameters, overridden by the step's `params` or `--param` values */
+  readonly params: Readonly<Record<string, string>>;
+  /** Matrix values of the task running the codemod, empty outside of matrix nodes */
+  readonly matrixValues: Readonly<Record<string, unknown>>;
+  /** Snapshot of the workflow state taken when the step started */
+  readonly state: Readonly<Record<string, unknown>>;
+  /** Language the file was parsed as */
+  readonly language: string;
+}
+
 /**
  * Reference
  * https://tree-sitter.github.io/tree-sitter/using-parsers#static-node-types